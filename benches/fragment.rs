@@ -0,0 +1,119 @@
+//! Benchmarks the fragment path of a live muxer
+//!
+//! Run with `cargo bench --bench fragment`. Besides the time per fragment, the number of
+//! allocations per fragment after warmup is printed, counted by the global allocator.
+//! The `fragment/frame` group compares the access unit fast path with `encode_video`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use mp4e::{Codec, Mp4e};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::io::Cursor;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+/// Allocator counting every allocation
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const SPS: [u8; 6] = [0x67, 0x42, 0xC0, 0x0D, 0xF4, 0x01];
+const PPS: [u8; 4] = [0x68, 0xCE, 0x3C, 0x80];
+const IDR: [u8; 5] = [0x65, 0x88, 0x84, 0x21, 0xA0];
+const SLICE: [u8; 4] = [0x41, 0x9A, 0x21, 0x6C];
+
+/// Sets up a fragmented muxer and writes the moov box and the first fragments
+fn warm_muxer(writer: &mut Cursor<Vec<u8>>) -> Mp4e<'_, Cursor<Vec<u8>>> {
+    let mut muxer = Mp4e::new_with_fragment(writer);
+    muxer.set_video_track(320, 240, Codec::AVC);
+    // A fragment per frame
+    muxer.set_audio_fragment_duration(0);
+    muxer
+        .encode_video_au(&[&SPS, &PPS, &IDR], 33, true)
+        .unwrap();
+    // The moov box and the first fragments size the reused buffers
+    for _ in 0..10 {
+        muxer.encode_video_au(&[&SLICE], 33, false).unwrap();
+    }
+    muxer
+}
+
+fn fragment_benchmark(c: &mut Criterion) {
+    const FRAGMENTS: usize = 10000;
+    let mut writer = Cursor::new(Vec::with_capacity(64 * FRAGMENTS + 4096));
+    let mut muxer = warm_muxer(&mut writer);
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    for _ in 0..FRAGMENTS {
+        muxer.encode_video_au(&[&SLICE], 33, false).unwrap();
+    }
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+    println!(
+        "fragment/encode_video_au: {:.2} allocations per fragment after warmup",
+        allocations as f64 / FRAGMENTS as f64
+    );
+
+    c.bench_function("fragment/encode_video_au", |b| {
+        b.iter_custom(|iterations| {
+            // Written to a buffer large enough for the whole run
+            let mut writer = Cursor::new(Vec::with_capacity(64 * iterations as usize + 4096));
+            let mut muxer = warm_muxer(&mut writer);
+            let start = Instant::now();
+            for _ in 0..iterations {
+                muxer
+                    .encode_video_au(black_box(&[&SLICE]), 33, false)
+                    .unwrap();
+            }
+            start.elapsed()
+        })
+    });
+}
+
+/// Compares the access unit fast path with `encode_video`, which splits and parses the
+/// Annex-B frame, on the same frames
+fn encode_video_benchmark(c: &mut Criterion) {
+    let mut frame = vec![0, 0, 0, 1];
+    frame.extend_from_slice(&SLICE);
+    let mut group = c.benchmark_group("fragment/frame");
+    group.bench_function("encode_video_au", |b| {
+        b.iter_custom(|iterations| {
+            let mut writer = Cursor::new(Vec::with_capacity(64 * iterations as usize + 4096));
+            let mut muxer = warm_muxer(&mut writer);
+            let start = Instant::now();
+            for _ in 0..iterations {
+                muxer
+                    .encode_video_au(black_box(&[&SLICE]), 33, false)
+                    .unwrap();
+            }
+            start.elapsed()
+        })
+    });
+    group.bench_function("encode_video", |b| {
+        b.iter_custom(|iterations| {
+            let mut writer = Cursor::new(Vec::with_capacity(64 * iterations as usize + 4096));
+            let mut muxer = warm_muxer(&mut writer);
+            let start = Instant::now();
+            for _ in 0..iterations {
+                muxer.encode_video(black_box(&frame), 33).unwrap();
+            }
+            start.elapsed()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, fragment_benchmark, encode_video_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,137 @@
+//! Benchmarks the writes of large video frames to the output
+//!
+//! Run with `cargo bench --bench sample_write`. The output counts the calls to `write`
+//! and `write_vectored`, the number of calls per frame is printed for a writer taking
+//! vectored writes and for one writing a single buffer per call, like a plain file
+//! descriptor wrapper without `write_vectored`, where each call is a syscall.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use mp4e::{Codec, Mp4e};
+use std::hint::black_box;
+use std::io::{Cursor, IoSlice, Seek, SeekFrom, Write};
+use std::time::Instant;
+
+/// Output counting its write calls
+struct CountingWriter {
+    inner: Cursor<Vec<u8>>,
+    /// Whether `write_vectored` writes all the buffers or only the first non-empty one
+    vectored: bool,
+    calls: usize,
+}
+
+impl CountingWriter {
+    fn new(vectored: bool) -> Self {
+        CountingWriter {
+            inner: Cursor::new(Vec::new()),
+            vectored,
+            calls: 0,
+        }
+    }
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.calls += 1;
+        self.inner.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        if !self.vectored {
+            // The default implementation of the standard library
+            let buf = bufs.iter().find(|buf| !buf.is_empty());
+            return self.write(buf.map_or(&[][..], |buf| buf));
+        }
+        self.calls += 1;
+        self.inner.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for CountingWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+const SPS: [u8; 6] = [0x67, 0x42, 0xC0, 0x0D, 0xF4, 0x01];
+const PPS: [u8; 4] = [0x68, 0xCE, 0x3C, 0x80];
+
+/// A frame of four 64 KiB slices
+fn large_frame(key: bool) -> Vec<Vec<u8>> {
+    let header = if key { 0x65 } else { 0x41 };
+    (0..4)
+        .map(|_| {
+            let mut slice = vec![0x5A; 64 * 1024];
+            slice[0] = header;
+            slice
+        })
+        .collect()
+}
+
+/// Writes the frames to the muxer, the parameter sets with the first one
+fn encode(muxer: &mut Mp4e<'_, CountingWriter>, frames: usize) {
+    let (key, frame) = (large_frame(true), large_frame(false));
+    let mut nalus: Vec<&[u8]> = vec![&SPS, &PPS];
+    nalus.extend(key.iter().map(|slice| slice.as_slice()));
+    muxer.encode_video_au(&nalus, 33, true).unwrap();
+    let nalus: Vec<&[u8]> = frame.iter().map(|slice| slice.as_slice()).collect();
+    for _ in 1..frames {
+        muxer.encode_video_au(black_box(&nalus), 33, false).unwrap();
+    }
+}
+
+fn new_muxer(writer: &mut CountingWriter, fragment: bool) -> Mp4e<'_, CountingWriter> {
+    let mut muxer = if fragment {
+        Mp4e::new_with_fragment(writer)
+    } else {
+        Mp4e::new(writer)
+    };
+    muxer.set_video_track(1920, 1080, Codec::AVC);
+    // A fragment per frame
+    muxer.set_audio_fragment_duration(0);
+    muxer
+}
+
+fn sample_write_benchmark(c: &mut Criterion) {
+    const FRAMES: usize = 100;
+    let mut group = c.benchmark_group("sample_write");
+    for fragment in [false, true] {
+        for vectored in [false, true] {
+            let name = format!(
+                "{}/{}",
+                if fragment { "fragment" } else { "progressive" },
+                if vectored { "vectored" } else { "single" }
+            );
+            let mut writer = CountingWriter::new(vectored);
+            let mut muxer = new_muxer(&mut writer, fragment);
+            encode(&mut muxer, FRAMES);
+            drop(muxer);
+            println!(
+                "sample_write/{}: {:.2} write calls per frame",
+                name,
+                writer.calls as f64 / FRAMES as f64
+            );
+
+            group.bench_function(name, |b| {
+                b.iter_custom(|iterations| {
+                    let mut elapsed = Default::default();
+                    for _ in 0..iterations {
+                        let mut writer = CountingWriter::new(vectored);
+                        let mut muxer = new_muxer(&mut writer, fragment);
+                        let start = Instant::now();
+                        encode(&mut muxer, 10);
+                        elapsed += start.elapsed();
+                    }
+                    elapsed
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sample_write_benchmark);
+criterion_main!(benches);
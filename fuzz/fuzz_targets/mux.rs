@@ -0,0 +1,42 @@
+//! Feeds arbitrary video and audio data to the muxer
+//!
+//! Run with `cargo fuzz run mux` from the repository root.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use mp4e::{Codec, Mp4e, NaluFormat};
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte selects the muxer configuration, the rest is the stream
+    let (config, stream) = match data.split_first() {
+        Some((&config, stream)) => (config, stream),
+        None => return,
+    };
+    let mut buffer = Cursor::new(Vec::new());
+    let mut muxer = if config & 0x01 == 0 {
+        Mp4e::new(&mut buffer)
+    } else {
+        Mp4e::new_with_fragment(&mut buffer)
+    };
+    let codec = if config & 0x02 == 0 {
+        Codec::AVC
+    } else {
+        Codec::HEVC
+    };
+    muxer.set_video_track(320, 240, codec);
+    muxer.set_audio_track(48000, 2, Codec::AACLC);
+    if config & 0x04 != 0 {
+        let size = [1, 2, 4][(config >> 3) as usize % 3];
+        muxer.set_nalu_format(NaluFormat::LengthPrefixed { size });
+    }
+    let frame_size = 16 + (config >> 5) as usize * 48;
+    for (i, frame) in stream.chunks(frame_size).enumerate() {
+        // Errors are fine, panics are not
+        let _ = match i % 4 {
+            1 => muxer.encode_video_with_pts(frame, 33, i as u32 * 33),
+            3 => muxer.encode_audio(frame, 1024),
+            _ => muxer.encode_video(frame, 33),
+        };
+    }
+    let _ = muxer.flush();
+});
@@ -0,0 +1,128 @@
+//! AAC decoder configuration helpers
+use crate::types::Codec;
+use crate::util::{get_sample_rate_idx, BitWriter};
+
+/// AAC audio object types
+#[derive(Clone, Copy)]
+pub enum AacProfile {
+    /// AAC Main
+    Main = 1,
+    /// AAC Low Complexity
+    LC = 2,
+    /// AAC Scalable Sample Rate
+    SSR = 3,
+    /// AAC Long Term Prediction
+    LTP = 4,
+    /// HE-AAC (AAC-LC with SBR)
+    HE = 5,
+    /// HE-AAC v2 (AAC-LC with SBR and PS)
+    HEV2 = 29,
+}
+
+/// Get the AAC profile of an audio codec
+///
+/// # Returns
+/// * The AAC profile, or None if the codec is not an AAC variant
+pub(crate) fn aac_profile(codec: &Codec) -> Option<AacProfile> {
+    match codec {
+        Codec::AACMAIN => Some(AacProfile::Main),
+        Codec::AACLC => Some(AacProfile::LC),
+        Codec::AACSSR => Some(AacProfile::SSR),
+        Codec::AACLTP => Some(AacProfile::LTP),
+        Codec::HEAAC => Some(AacProfile::HE),
+        Codec::HEAACV2 => Some(AacProfile::HEV2),
+        _ => None,
+    }
+}
+
+/// Write a sampling frequency index, with the explicit 24-bit rate for non-standard rates
+fn put_sample_rate(writer: &mut BitWriter, sample_rate: u32) {
+    match get_sample_rate_idx(sample_rate) {
+        Some(idx) => writer.put_bits(idx, 4),
+        None => {
+            writer.put_bits(0x0f, 4);
+            writer.put_bits(sample_rate, 24);
+        }
+    }
+}
+
+/// Build an AAC AudioSpecificConfig (ISO/IEC 14496-3)
+///
+/// HE-AAC and HE-AAC v2 use explicit hierarchical signaling: the core AAC-LC stream
+/// runs at half of `sample_rate`, and HE-AAC v2 codes a mono core that parametric
+/// stereo expands to `channels`. Non-standard sample rates are written with the
+/// frequency index escape. Channel counts without a channel configuration (7 or more
+/// than 8) are written as configuration 0.
+///
+/// # Arguments
+/// * `profile` - The AAC audio object type
+/// * `sample_rate` - The output sample rate in Hz
+/// * `channels` - The output channel count
+///
+/// # Returns
+/// * The AudioSpecificConfig bytes
+///
+/// # Example
+/// ```
+/// use mp4e::aac::{aac_asc, AacProfile};
+///
+/// assert_eq!(aac_asc(AacProfile::LC, 48000, 2), vec![0x11, 0x90]);
+/// assert_eq!(aac_asc(AacProfile::HE, 48000, 2), vec![0x2B, 0x11, 0x88, 0x00]);
+/// assert_eq!(aac_asc(AacProfile::LC, 50000, 2).len(), 5);
+/// ```
+pub fn aac_asc(profile: AacProfile, sample_rate: u32, channels: u32) -> Vec<u8> {
+    let channel_config = match channels {
+        1..=6 => channels,
+        8 => 7,
+        _ => 0,
+    };
+    let mut writer = BitWriter::new();
+    writer.put_bits(profile as u32, 5);
+    match profile {
+        AacProfile::HE | AacProfile::HEV2 => {
+            put_sample_rate(&mut writer, sample_rate / 2);
+            let core_channels = match profile {
+                AacProfile::HEV2 => 1,
+                _ => channel_config,
+            };
+            writer.put_bits(core_channels, 4);
+            // extensionSamplingFrequencyIndex and the core audio object type
+            put_sample_rate(&mut writer, sample_rate);
+            writer.put_bits(AacProfile::LC as u32, 5);
+        }
+        _ => {
+            put_sample_rate(&mut writer, sample_rate);
+            writer.put_bits(channel_config, 4);
+        }
+    }
+    // GASpecificConfig: frameLengthFlag, dependsOnCoreCoder, extensionFlag
+    writer.put_bits(0, 3);
+    writer.into_bytes()
+}
+
+/// Get a silent AAC frame of 1024 samples, to fill gaps in the audio
+///
+/// # Arguments
+/// * `profile` - The AAC audio object type
+/// * `channels` - The channel count
+///
+/// # Returns
+/// * The raw data block of a silent frame, or None if there is none for the profile
+///   and channel count (only AAC-LC mono and stereo are available)
+///
+/// # Example
+/// ```
+/// use mp4e::aac::{aac_silent_frame, AacProfile};
+///
+/// assert!(aac_silent_frame(AacProfile::LC, 2).is_some());
+/// assert!(aac_silent_frame(AacProfile::HE, 2).is_none());
+/// ```
+pub fn aac_silent_frame(profile: AacProfile, channels: u32) -> Option<&'static [u8]> {
+    match (profile, channels) {
+        // single_channel_element with zero spectral data, then END
+        (AacProfile::LC, 1) => Some(&[0x00, 0xC8, 0x00, 0x80, 0x23, 0x80]),
+        // channel_pair_element with a common window and zero spectral data, then END
+        (AacProfile::LC, 2) => Some(&[0x21, 0x00, 0x49, 0x90, 0x02, 0x19, 0x00, 0x23, 0x80]),
+        _ => None,
+    }
+}
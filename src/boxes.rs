@@ -1,10 +1,18 @@
-use crate::types::{Codec, SampleInfo, SampleType, Track, TrackType};
-use std::io::{Error, Seek, Write};
+//! Writers of the individual boxes, for building custom container layouts
+//!
+//! The writers that take a `Seek` writer fill in the box sizes once the box is complete.
+//! The others write fixed-size boxes and return the number of bytes written.
+
+use crate::types::{Codec, MasteringDisplay, SampleInfo, SampleType, Track, TrackType, UserBoxes};
+use crate::util::{ms_to_timescale, rescale};
+use std::io::{Error, ErrorKind, IoSlice, Seek, SeekFrom, Write};
 
 macro_rules! mp4_box {
     ($cursor:expr, $box_name:expr, $body:block) => {{
         use std::io::SeekFrom;
-        let mp4_box_start_pos = ($cursor.seek(SeekFrom::Current(4))? - 4);
+        // The box starts where the cursor is, its size is written once the body is known
+        let mp4_box_start_pos = $cursor.stream_position()?;
+        $cursor.seek(SeekFrom::Start(mp4_box_start_pos + 4))?;
         $cursor.write_all($box_name)?;
         $body
         let end_pos = $cursor.stream_position()?;
@@ -17,7 +25,7 @@ macro_rules! mp4_box {
 
 }
 
-fn write_hdlr<Writer>(video: bool, cursor: &mut Writer) -> Result<(), Error>
+fn write_hdlr<Writer>(track_type: &TrackType, cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
@@ -26,18 +34,28 @@ where
         cursor.write_all(&[0x00; 4])?;
         // pre_defined
         cursor.write_all(&[0x00; 4])?;
-        if video {
-            cursor.write_all(b"vide")?;
-            // reserved
-            cursor.write_all(&[0x00; 12])?;
-            // name
-            cursor.write_all(b"VideoHandler\x00")?;
-        } else {
-            cursor.write_all(b"soun")?;
-            // reserved
-            cursor.write_all(&[0x00; 12])?;
-            // name
-            cursor.write_all(b"SoundHandler\x00")?;
+        match track_type {
+            TrackType::Video => {
+                cursor.write_all(b"vide")?;
+                // reserved
+                cursor.write_all(&[0x00; 12])?;
+                // name
+                cursor.write_all(b"VideoHandler\x00")?;
+            }
+            TrackType::Audio => {
+                cursor.write_all(b"soun")?;
+                // reserved
+                cursor.write_all(&[0x00; 12])?;
+                // name
+                cursor.write_all(b"SoundHandler\x00")?;
+            }
+            TrackType::Caption => {
+                cursor.write_all(b"clcp")?;
+                // reserved
+                cursor.write_all(&[0x00; 12])?;
+                // name
+                cursor.write_all(b"ClosedCaptionHandler\x00")?;
+            }
         }
     })
 }
@@ -63,19 +81,74 @@ where
     })
 }
 
-fn write_stsc<Writer>(fragment: bool, cursor: &mut Writer) -> Result<(), Error>
+fn write_nmhd<Writer>(cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"nmhd", {
+        // version & flag
+        cursor.write_all(&[0x00; 4])?;
+    })
+}
+
+/// A chunk of the sample table: its offset, its number of samples and the sample
+/// description of its samples
+pub(crate) type Chunk = (u64, u32, u32);
+
+/// Groups the samples of a track into chunks
+///
+/// Every sample is its own chunk, unless `compact` is set: then a sample directly
+/// following the previous one in the file joins its chunk, if they share a sample
+/// description.
+pub(crate) fn sample_chunks(samples: &[SampleInfo], compact: bool) -> Vec<Chunk> {
+    let mut chunks: Vec<Chunk> = vec![];
+    let mut end = None;
+    for sample in samples {
+        match chunks.last_mut() {
+            Some(chunk)
+                if compact && end == Some(sample.offset) && chunk.2 == sample.description_index =>
+            {
+                chunk.1 += 1;
+            }
+            _ => chunks.push((sample.offset, 1, sample.description_index)),
+        }
+        end = Some(sample.offset + sample.sample_size as u64);
+    }
+    chunks
+}
+
+pub(crate) fn write_stsc<Writer>(
+    chunks: &[Chunk],
+    fragment: bool,
+    cursor: &mut Writer,
+) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
+    // A new entry starts with each run of chunks sharing a number of samples and a
+    // sample description
+    let mut entries: Vec<(u32, u32, u32)> = vec![];
+    for (chunk, (_, sample_count, description_index)) in chunks.iter().enumerate() {
+        if entries.last().map(|entry| (entry.1, entry.2))
+            != Some((*sample_count, *description_index))
+        {
+            entries.push((chunk as u32 + 1, *sample_count, *description_index));
+        }
+    }
+    if entries.is_empty() {
+        entries.push((1, 1, 1));
+    }
     mp4_box!(cursor, b"stsc", {
         cursor.write_all(&[0x00; 4])?;
         if fragment {
             cursor.write_all(&[0x00; 4])?;
         } else {
-            cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
-            cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
-            cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
-            cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
+            cursor.write_all(&(entries.len() as u32).to_be_bytes())?;
+            for (first_chunk, samples_per_chunk, description_index) in entries {
+                cursor.write_all(&first_chunk.to_be_bytes())?;
+                cursor.write_all(&samples_per_chunk.to_be_bytes())?;
+                cursor.write_all(&description_index.to_be_bytes())?;
+            }
         }
     })
 }
@@ -89,24 +162,41 @@ where
     })
 }
 
-fn write_dref<Writer>(cursor: &mut Writer) -> Result<(), Error>
+/// Writes a url box pointing to the file at `location`, which holds sample data
+fn write_external_url<Writer>(location: &str, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"url ", {
+        // The data is not in this file
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(location.as_bytes())?;
+        cursor.write_all(&[0x00])?;
+    })
+}
+
+fn write_dref<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
     mp4_box!(cursor, b"dref", {
         // version & flag
         cursor.write_all(&[0x00; 4])?;
-        cursor.write_all(b"\x00\x00\x00\x01")?;
+        let entry_count = 1 + track.data_references.len() as u32;
+        cursor.write_all(&entry_count.to_be_bytes())?;
         write_url(cursor)?;
+        for location in &track.data_references {
+            write_external_url(location, cursor)?;
+        }
     })
 }
 
-fn write_dinf<Writer>(cursor: &mut Writer) -> Result<(), Error>
+fn write_dinf<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
     mp4_box!(cursor, b"dinf", {
-        write_dref(cursor)?;
+        write_dref(track, cursor)?;
     })
 }
 
@@ -123,31 +213,170 @@ where
 fn write_dops<Writer>(
     channel_count: u32,
     sample_rate: u32,
+    pre_skip: u16,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
-    mp4_box!(cursor, b"dops", {
+    mp4_box!(cursor, b"dOps", {
+        // Version
         cursor.write_all(&[0x00])?;
-        cursor.write_all(&(channel_count as u16).to_be_bytes())?;
-        cursor.write_all(&[0x00; 2])?;
+        cursor.write_all(&[channel_count as u8])?;
+        cursor.write_all(&pre_skip.to_be_bytes())?;
         cursor.write_all(&sample_rate.to_be_bytes())?;
+        // Output gain
         cursor.write_all(&[0x00; 2])?;
+        // Channel mapping family 0, mono or stereo
         cursor.write_all(&[0x00])?;
     })
 }
 
-fn write_stsz<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+/// Gets the pre-skip of an Opus track in the track's timescale
+fn opus_pre_skip(track: &Track) -> u32 {
+    match track.codec {
+        Codec::OPUS => (track.pre_skip as u64 * track.timescale as u64 / 48000) as u32,
+        _ => 0,
+    }
+}
+
+/// Gets the roll distance of the samples of an Opus track
+///
+/// An Opus decoder needs 80 ms of audio to converge, which also covers the pre-skip,
+/// so decoding starts that many frames before a sample. Frames are assumed to last
+/// 20 ms until the first one is known.
+fn opus_roll_distance(track: &Track) -> i16 {
+    let pre_roll = ms_to_timescale(80, track.timescale).max(opus_pre_skip(track) as u64);
+    let frame_duration = track
+        .samples
+        .first()
+        .map_or(ms_to_timescale(20, track.timescale), |sample| {
+            sample.sample_delta as u64
+        })
+        .max(1);
+    -(pre_roll.div_ceil(frame_duration).min(i16::MAX as u64) as i16)
+}
+
+/// Writes the sample group description of the roll group of an Opus track
+fn write_sgpd_roll<Writer>(roll_distance: i16, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"sgpd", {
+        // Version 1 with the length of the entries
+        cursor.write_all(&[0x01, 0x00, 0x00, 0x00])?;
+        cursor.write_all(b"roll")?;
+        cursor.write_all(&2u32.to_be_bytes())?;
+        cursor.write_all(&1u32.to_be_bytes())?;
+        cursor.write_all(&roll_distance.to_be_bytes())?;
+    })
+}
+
+/// Writes a sample to group box putting all the samples in the roll group
+fn write_sbgp_roll<Writer>(sample_count: u32, cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
-    mp4_box!(cursor, b"stsz", {
+    mp4_box!(cursor, b"sbgp", {
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(b"roll")?;
+        cursor.write_all(&1u32.to_be_bytes())?;
+        cursor.write_all(&sample_count.to_be_bytes())?;
+        // The first entry of the sgpd box in the stbl box
+        cursor.write_all(&1u32.to_be_bytes())?;
+    })
+}
+
+/// Gets the number of temporal sub-layers of a track, from its HEVC SPS and its samples
+fn temporal_layer_count(track: &Track) -> u8 {
+    // sps_max_sub_layers_minus1 follows the 2-byte NAL unit header and the VPS id
+    let from_sps = track
+        .sps
+        .as_deref()
+        .and_then(|sps| sps.get(2))
+        .map_or(1, |byte| ((byte >> 1) & 0x07) + 1);
+    let from_samples = track
+        .samples
+        .iter()
+        .map(|sample| sample.temporal_id + 1)
+        .max()
+        .unwrap_or(1);
+    from_sps.max(from_samples)
+}
+
+/// Writes the sample group description of the temporal levels of a track
+///
+/// Entry `n` describes the samples of temporal sub-layer `n - 1`. Only the samples of
+/// sub-layer 0 decode without the other levels.
+fn write_sgpd_tele<Writer>(layers: u8, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"sgpd", {
+        // Version 1 with the length of the entries
+        cursor.write_all(&[0x01, 0x00, 0x00, 0x00])?;
+        cursor.write_all(b"tele")?;
+        cursor.write_all(&1u32.to_be_bytes())?;
+        cursor.write_all(&(layers as u32).to_be_bytes())?;
+        for layer in 0..layers {
+            // level_independently_decodable
+            cursor.write_all(&[if layer == 0 { 0x80 } else { 0x00 }])?;
+        }
+    })
+}
+
+/// Writes a sample to group box mapping each sample to the entry of its temporal level
+fn write_sbgp_tele<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    let mut runs: Vec<(u32, u8)> = vec![];
+    for sample in samples {
+        match runs.last_mut() {
+            Some((count, temporal_id)) if *temporal_id == sample.temporal_id => *count += 1,
+            _ => runs.push((1, sample.temporal_id)),
+        }
+    }
+    mp4_box!(cursor, b"sbgp", {
         cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(b"tele")?;
+        cursor.write_all(&(runs.len() as u32).to_be_bytes())?;
+        for (count, temporal_id) in runs {
+            cursor.write_all(&count.to_be_bytes())?;
+            // The entries of the sgpd box in the stbl box, starting at 1
+            cursor.write_all(&(temporal_id as u32 + 1).to_be_bytes())?;
+        }
+    })
+}
+
+fn write_stsz<Writer>(
+    samples: &[SampleInfo],
+    compact: bool,
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    // In compact mode, samples of the same size only have the sample_size field
+    let single_size = match samples.first() {
+        Some(first)
+            if compact
+                && samples
+                    .iter()
+                    .all(|sample| sample.sample_size == first.sample_size) =>
+        {
+            Some(first.sample_size)
+        }
+        _ => None,
+    };
+    mp4_box!(cursor, b"stsz", {
         cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(&single_size.unwrap_or(0).to_be_bytes())?;
         cursor.write_all(&(samples.len() as u32).to_be_bytes())?;
-        for sample in samples.iter() {
-            cursor.write_all(&sample.sample_size.to_be_bytes())?;
+        if single_size.is_none() {
+            for sample in samples.iter() {
+                cursor.write_all(&sample.sample_size.to_be_bytes())?;
+            }
         }
     })
 }
@@ -160,7 +389,7 @@ where
         cursor.write_all(&[0x00; 4])?;
         let entry_point = cursor.stream_position()?;
         cursor.seek(SeekFrom::Current(4))?;
-        let mut random_access_count = 0 as u32;
+        let mut random_access_count = 0_u32;
         for (i, sample) in samples.iter().enumerate() {
             if sample.random_access {
                 cursor.write_all(&(i as u32 + 1).to_be_bytes())?;
@@ -174,28 +403,43 @@ where
     })
 }
 
-fn write_co64<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+/// Writes the dependency of each sample, derived from the same flags as the stss box
+fn write_sdtp<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
-    mp4_box!(cursor, b"co64", {
+    mp4_box!(cursor, b"sdtp", {
         cursor.write_all(&[0x00; 4])?;
-        cursor.write_all(&(samples.len() as u32).to_be_bytes())?;
         for sample in samples.iter() {
-            cursor.write_all(&sample.offset.to_be_bytes())?;
+            // sample_depends_on: 2 for sync samples, 1 for samples predicted from others
+            let flags: u8 = if sample.random_access { 0x20 } else { 0x10 };
+            cursor.write_all(&[flags])?;
+        }
+    })
+}
+
+fn write_co64<Writer>(chunks: &[Chunk], cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"co64", {
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(&(chunks.len() as u32).to_be_bytes())?;
+        for (offset, _, _) in chunks.iter() {
+            cursor.write_all(&offset.to_be_bytes())?;
         }
     })
 }
 
-fn write_stco<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+fn write_stco<Writer>(chunks: &[Chunk], cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
     mp4_box!(cursor, b"stco", {
         cursor.write_all(&[0x00; 4])?;
-        cursor.write_all(&(samples.len() as u32).to_be_bytes())?;
-        for sample in samples.iter() {
-            cursor.write_all(&(sample.offset as u32).to_be_bytes())?;
+        cursor.write_all(&(chunks.len() as u32).to_be_bytes())?;
+        for (offset, _, _) in chunks.iter() {
+            cursor.write_all(&(*offset as u32).to_be_bytes())?;
         }
     })
 }
@@ -244,6 +488,7 @@ where
 fn write_opus<Writer>(
     channel_count: u32,
     sample_rate: u32,
+    pre_skip: u16,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
@@ -258,13 +503,50 @@ where
         cursor.write_all(&[0x00, 0x10])?; //16 bits per sample
         cursor.write_all(&[0x00; 4])?;
         cursor.write_all(&(sample_rate << 16).to_be_bytes())?;
-        write_dops(channel_count, sample_rate, cursor)?;
+        write_dops(channel_count, sample_rate, pre_skip, cursor)?;
     })
 }
 
+/// Get the maximum and average bitrate of an audio track
+///
+/// The configured bitrate is used if there is one, otherwise the rates are measured
+/// from the samples: the average over the whole track and the maximum over one second windows.
+fn audio_bitrates(track: &Track) -> (u32, u32) {
+    if let Some(bitrate) = track.bitrate {
+        return bitrate;
+    }
+    let timescale = track.timescale as u64;
+    let duration: u64 = track.samples.iter().map(|s| s.sample_delta as u64).sum();
+    if timescale == 0 || duration == 0 {
+        return (0, 0);
+    }
+    let total_bytes: u64 = track.samples.iter().map(|s| s.sample_size as u64).sum();
+    let avg_bitrate = total_bytes * 8 * timescale / duration;
+
+    let mut max_bitrate = avg_bitrate;
+    let mut window = 0;
+    let mut window_bytes = 0;
+    let mut time = 0;
+    for sample in track.samples.iter() {
+        if time / timescale != window {
+            max_bitrate = max_bitrate.max(window_bytes * 8);
+            window = time / timescale;
+            window_bytes = 0;
+        }
+        window_bytes += sample.sample_size as u64;
+        time += sample.sample_delta as u64;
+    }
+    // The last window, or the only one of a track shorter than a second
+    max_bitrate = max_bitrate.max(window_bytes * 8);
+    (
+        max_bitrate.min(u32::MAX as u64) as u32,
+        avg_bitrate.min(u32::MAX as u64) as u32,
+    )
+}
+
 fn write_esds<Writer>(
-    channel_count: u32,
-    dsi: &Option<[u8; 2]>,
+    track: &Track,
+    object_type_indication: u8,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
@@ -272,50 +554,59 @@ where
 {
     mp4_box!(cursor, b"esds", {
         cursor.write_all(&[0x00; 4])?;
+        // descriptor sizes are stored 7 bits per byte, the high bit marks continuation
         let od_size_of_size = |size: u32| -> u32 {
             let mut size_of_size = 1;
             let mut i = size;
             while i > 0x7f {
                 size_of_size += 1;
-                i -= 0x7f;
+                i >>= 7;
             }
             size_of_size
         };
-        let write_od_len = |mut size: u32, cursor: &mut Writer| -> Result<(), Error> {
-            while size > 0x7F {
-                size -= 0x7F;
-                cursor.write_all(&[0xff])?;
+        let write_od_len = |size: u32, cursor: &mut Writer| -> Result<(), Error> {
+            for i in (0..od_size_of_size(size)).rev() {
+                let more = if i > 0 { 0x80 } else { 0x00 };
+                cursor.write_all(&[((size >> (7 * i)) & 0x7f) as u8 | more])?;
             }
-            cursor.write_all(&[size as u8])?;
             Ok(())
         };
-        if let Some(ref dsi) = dsi.as_ref() {
-            let dsi_bytes = dsi.len() as u32;
-            let dsi_size_size = od_size_of_size(dsi_bytes);
-            let dcd_bytes = dsi_bytes + dsi_size_size + 1 + (1 + 1 + 3 + 4 + 4);
-            let dcd_size_size = od_size_of_size(dcd_bytes);
-            let esd_bytes = dcd_bytes + dcd_size_size + 1 + 3;
-            cursor.write_all(&[0x03])?;
-            write_od_len(esd_bytes, cursor)?;
-            cursor.write_all(&[0x00; 3])?;
-            cursor.write_all(&[0x04])?;
-            write_od_len(dcd_bytes, cursor)?;
-            cursor.write_all(&[0x40])?;
-            cursor.write_all(&[5 << 2])?;
-            cursor.write_all(&[0x00])?;
-            cursor.write_all(&((channel_count * 6144 / 8) as u16).to_be_bytes())?;
-            cursor.write_all(&[0x00; 8])?;
+        // Codecs without decoder configuration, such as MP3, have no DecoderSpecificInfo
+        let dsi = track.dsi.as_deref().unwrap_or_default();
+        let dsi_bytes = dsi.len() as u32;
+        let dsi_size_size = od_size_of_size(dsi_bytes);
+        let dsi_descriptor_bytes = if dsi.is_empty() {
+            0
+        } else {
+            dsi_bytes + dsi_size_size + 1
+        };
+        let dcd_bytes = dsi_descriptor_bytes + (1 + 1 + 3 + 4 + 4);
+        let dcd_size_size = od_size_of_size(dcd_bytes);
+        let esd_bytes = dcd_bytes + dcd_size_size + 1 + 3;
+        cursor.write_all(&[0x03])?;
+        write_od_len(esd_bytes, cursor)?;
+        cursor.write_all(&[0x00; 3])?;
+        cursor.write_all(&[0x04])?;
+        write_od_len(dcd_bytes, cursor)?;
+        cursor.write_all(&[object_type_indication])?;
+        cursor.write_all(&[5 << 2])?;
+        cursor.write_all(&[0x00])?;
+        // bufferSizeDB
+        cursor.write_all(&((track.channel_count * 6144 / 8) as u16).to_be_bytes())?;
+        let (max_bitrate, avg_bitrate) = audio_bitrates(track);
+        cursor.write_all(&max_bitrate.to_be_bytes())?;
+        cursor.write_all(&avg_bitrate.to_be_bytes())?;
+        if !dsi.is_empty() {
             cursor.write_all(&[0x05])?;
             write_od_len(dsi_bytes, cursor)?;
-            cursor.write_all(&dsi[..])?;
+            cursor.write_all(dsi)?;
         }
     })
 }
 
 fn write_mp4a<Writer>(
-    channel_count: u32,
-    sample_rate: u32,
-    dsi: &Option<[u8; 2]>,
+    track: &Track,
+    object_type_indication: u8,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
@@ -327,11 +618,11 @@ where
         cursor.write_all(&[0x00, 0x01])?;
 
         cursor.write_all(&[0x00; 8])?;
-        cursor.write_all(&(channel_count as u16).to_be_bytes())?;
+        cursor.write_all(&(track.channel_count as u16).to_be_bytes())?;
         cursor.write_all(&[0x00, 0x10])?; //16 bits per sample
         cursor.write_all(&[0x00; 4])?;
-        cursor.write_all(&(sample_rate << 16).to_be_bytes())?;
-        write_esds(channel_count, dsi, cursor)?;
+        cursor.write_all(&(track.sample_rate << 16).to_be_bytes())?;
+        write_esds(track, object_type_indication, cursor)?;
     })
 }
 
@@ -361,17 +652,31 @@ where
     })
 }
 
+/// Returns the compressorname field of a visual sample entry: the length of the name
+/// in its first byte, followed by the name and padded with zeros to 32 bytes
+fn compressor_name(name: &Option<String>) -> [u8; 32] {
+    let mut field = [0u8; 32];
+    if let Some(name) = name {
+        let length = name.len().min(31);
+        field[0] = length as u8;
+        field[1..=length].copy_from_slice(&name.as_bytes()[..length]);
+    }
+    field
+}
+
 fn write_avc1<Writer>(
+    sample_entry: &[u8; 4],
     width: u16,
     height: u16,
     sps: &Option<Vec<u8>>,
     pps: &Option<Vec<u8>>,
+    compressor_name: &[u8; 32],
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
-    mp4_box!(cursor, b"avc1", {
+    mp4_box!(cursor, sample_entry, {
         cursor.write_all(&[0x00; 6])?;
         cursor.write_all(&[0x00, 0x01])?;
         cursor.write_all(&[0x00; 16])?;
@@ -382,13 +687,39 @@ where
         cursor.write_all(&0x00480000u32.to_be_bytes())?;
         cursor.write_all(&[0x00; 4])?;
         cursor.write_all(&[0x00, 0x01])?;
-        cursor.write_all(&[0x00; 32])?;
+        cursor.write_all(compressor_name)?;
         cursor.write_all(&[0x00, 0x18])?;
-        cursor.write_all(&(-1 as i16).to_be_bytes())?;
+        cursor.write_all(&(-1_i16).to_be_bytes())?;
         write_avcc(sps, pps, cursor)?;
     })
 }
 
+fn write_jpeg<Writer>(
+    width: u16,
+    height: u16,
+    compressor_name: &[u8; 32],
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"jpeg", {
+        cursor.write_all(&[0x00; 6])?;
+        cursor.write_all(&[0x00, 0x01])?;
+        cursor.write_all(&[0x00; 16])?;
+
+        cursor.write_all(&width.to_be_bytes())?;
+        cursor.write_all(&height.to_be_bytes())?;
+        cursor.write_all(&0x00480000u32.to_be_bytes())?;
+        cursor.write_all(&0x00480000u32.to_be_bytes())?;
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(&[0x00, 0x01])?;
+        cursor.write_all(compressor_name)?;
+        cursor.write_all(&[0x00, 0x18])?;
+        cursor.write_all(&(-1_i16).to_be_bytes())?;
+    })
+}
+
 fn write_hvcc<Writer>(
     vps: &Option<Vec<u8>>,
     sps: &Option<Vec<u8>>,
@@ -455,137 +786,441 @@ where
     })
 }
 
-fn write_hvc1<Writer>(
-    width: u16,
-    height: u16,
-    vps: &Option<Vec<u8>>,
-    sps: &Option<Vec<u8>>,
-    pps: &Option<Vec<u8>>,
+fn write_mdcv<Writer>(
+    mastering_display: &MasteringDisplay,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
-    mp4_box!(cursor, b"hvc1", {
+    mp4_box!(cursor, b"mdcv", {
+        for primary in mastering_display.display_primaries.iter() {
+            cursor.write_all(&primary[0].to_be_bytes())?;
+            cursor.write_all(&primary[1].to_be_bytes())?;
+        }
+        cursor.write_all(&mastering_display.white_point[0].to_be_bytes())?;
+        cursor.write_all(&mastering_display.white_point[1].to_be_bytes())?;
+        cursor.write_all(&mastering_display.max_luminance.to_be_bytes())?;
+        cursor.write_all(&mastering_display.min_luminance.to_be_bytes())?;
+    })
+}
+
+fn write_clli<Writer>(content_light_level: &[u16; 2], cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"clli", {
+        // max_content_light_level
+        cursor.write_all(&content_light_level[0].to_be_bytes())?;
+        // max_pic_average_light_level
+        cursor.write_all(&content_light_level[1].to_be_bytes())?;
+    })
+}
+
+fn write_hvc1<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    // hev1 allows parameter sets in the samples as well as in the hvcC box
+    let sample_entry = if track.inband_parameter_sets {
+        b"hev1"
+    } else {
+        b"hvc1"
+    };
+    mp4_box!(cursor, sample_entry, {
         cursor.write_all(&[0x00; 6])?;
         cursor.write_all(&[0x00, 0x01])?;
         cursor.write_all(&[0x00; 16])?;
-        cursor.write_all(&width.to_be_bytes())?;
-        cursor.write_all(&height.to_be_bytes())?;
+        cursor.write_all(&(track.width as u16).to_be_bytes())?;
+        cursor.write_all(&(track.height as u16).to_be_bytes())?;
         cursor.write_all(&0x00480000u32.to_be_bytes())?;
         cursor.write_all(&0x00480000u32.to_be_bytes())?;
         cursor.write_all(&[0x00; 4])?;
         cursor.write_all(&[0x00, 0x01])?;
-        cursor.write_all(&[0x00; 32])?;
+        cursor.write_all(&compressor_name(&track.compressor_name))?;
         cursor.write_all(&[0x00, 0x18])?;
-        cursor.write_all(&(-1 as i16).to_be_bytes())?;
-        write_hvcc(vps, sps, pps, cursor)?;
+        cursor.write_all(&(-1_i16).to_be_bytes())?;
+        write_hvcc(&track.vps, &track.sps, &track.pps, cursor)?;
+        if let Some(mastering_display) = track.mastering_display.as_ref() {
+            write_mdcv(mastering_display, cursor)?;
+        }
+        if let Some(content_light_level) = track.content_light_level.as_ref() {
+            write_clli(content_light_level, cursor)?;
+        }
     })
 }
 
-fn write_stsd<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
+fn write_pitm<Writer>(item_id: u16, cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
-    mp4_box!(cursor, b"stsd", {
+    mp4_box!(cursor, b"pitm", {
         cursor.write_all(&[0x00; 4])?;
-        cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
-        if let TrackType::Video = track.track_type {
-            match track.codec {
-                Codec::HEVC => {
-                    write_hvc1(
-                        track.width as u16,
-                        track.height as u16,
-                        &track.vps,
-                        &track.sps,
-                        &track.pps,
-                        cursor,
-                    )?;
-                }
-                Codec::AVC => {
-                    write_avc1(
-                        track.width as u16,
-                        track.height as u16,
-                        &track.sps,
-                        &track.pps,
-                        cursor,
-                    )?;
-                }
-                _ => {}
-            }
-        } else {
-            match track.codec {
-                Codec::AACLC
-                | Codec::AACMAIN
-                | Codec::AACSSR
-                | Codec::AACLTP
-                | Codec::HEAAC
-                | Codec::HEAACV2 => {
-                    write_mp4a(track.channel_count, track.sample_rate, &track.dsi, cursor)?;
-                }
-                Codec::OPUS => {
-                    //
-                    write_opus(track.channel_count, track.sample_rate, cursor)?;
-                }
-                _ => {}
-            }
-        }
+        cursor.write_all(&item_id.to_be_bytes())?;
     })
 }
 
-fn write_stts<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+fn write_iinf<Writer>(item_id: u16, cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
-    mp4_box!(cursor, b"stts", {
+    mp4_box!(cursor, b"iinf", {
         cursor.write_all(&[0x00; 4])?;
-        // entry count
-        let entry_count_idx = cursor.stream_position()?;
-        cursor.seek(SeekFrom::Current(4))?;
-        let mut entry_count: u32 = 0;
-        let mut cnt: u32 = 1;
-        for i in 0..samples.len() {
-            if i == samples.len() - 1 || samples[i].sample_delta != samples[i + 1].sample_delta {
-                cursor.write_all(&cnt.to_be_bytes())?;
-                cursor.write_all(&samples[i].sample_delta.to_be_bytes())?;
-                cnt = 0;
-                entry_count += 1;
-            }
-            cnt += 1;
-        }
-        let end_pos = cursor.stream_position()?;
-        cursor.seek(SeekFrom::Start(entry_count_idx))?;
-        cursor.write_all(&entry_count.to_be_bytes())?;
-        cursor.seek(SeekFrom::Start(end_pos))?;
+        // entry_count
+        cursor.write_all(&[0x00, 0x01])?;
+        write_infe(item_id, cursor)?;
     })
 }
 
-fn write_ctts<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+fn write_infe<Writer>(item_id: u16, cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
-    let mut has_ctts = false;
-    for sample in samples.iter() {
-        if sample.sample_ct_offset != 0 {
-            has_ctts = true;
-            break;
-        }
-    }
-    if !has_ctts {
-        return Ok(());
-    }
-    mp4_box!(cursor, b"ctts", {
+    mp4_box!(cursor, b"infe", {
+        // version 2
+        cursor.write_all(&[0x02, 0x00, 0x00, 0x00])?;
+        cursor.write_all(&item_id.to_be_bytes())?;
+        // item_protection_index
+        cursor.write_all(&[0x00; 2])?;
+        cursor.write_all(b"hvc1")?;
+        // empty item_name
+        cursor.write_all(&[0x00])?;
+    })
+}
+
+fn write_iloc<Writer>(
+    item_id: u16,
+    data_offset: u32,
+    data_size: u32,
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"iloc", {
         cursor.write_all(&[0x00; 4])?;
-        let entry_count_idx = cursor.stream_position()?;
-        cursor.seek(SeekFrom::Current(4))?;
-        let mut entry_count: u32 = 0;
-        let mut cnt: u32 = 1;
-        for i in 0..samples.len() {
-            if i == samples.len() - 1
-                || samples[i].sample_ct_offset != samples[i + 1].sample_ct_offset
-            {
-                cursor.write_all(&cnt.to_be_bytes())?;
-                cursor.write_all(&samples[i].sample_ct_offset.to_be_bytes())?;
+        // offset_size 4, length_size 4, base_offset_size 0, reserved
+        cursor.write_all(&[0x44, 0x00])?;
+        // item_count
+        cursor.write_all(&[0x00, 0x01])?;
+        cursor.write_all(&item_id.to_be_bytes())?;
+        // data_reference_index, the data is in this file
+        cursor.write_all(&[0x00; 2])?;
+        // extent_count
+        cursor.write_all(&[0x00, 0x01])?;
+        cursor.write_all(&data_offset.to_be_bytes())?;
+        cursor.write_all(&data_size.to_be_bytes())?;
+    })
+}
+
+fn write_iprp<Writer>(item_id: u16, track: &Track, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"iprp", {
+        write_ipco(track, cursor)?;
+        write_ipma(item_id, cursor)?;
+    })
+}
+
+fn write_ipco<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"ipco", {
+        write_hvcc(&track.vps, &track.sps, &track.pps, cursor)?;
+        write_ispe(track.width, track.height, cursor)?;
+    })
+}
+
+fn write_ispe<Writer>(width: u32, height: u32, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"ispe", {
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(&width.to_be_bytes())?;
+        cursor.write_all(&height.to_be_bytes())?;
+    })
+}
+
+fn write_ipma<Writer>(item_id: u16, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"ipma", {
+        cursor.write_all(&[0x00; 4])?;
+        // entry_count
+        cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
+        cursor.write_all(&item_id.to_be_bytes())?;
+        // association_count
+        cursor.write_all(&[0x02])?;
+        // hvcC (property 1) is essential, ispe (property 2) is not
+        cursor.write_all(&[0x81, 0x02])?;
+    })
+}
+
+fn write_pict_hdlr<Writer>(cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"hdlr", {
+        cursor.write_all(&[0x00; 4])?;
+        // pre_defined
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(b"pict")?;
+        // reserved
+        cursor.write_all(&[0x00; 12])?;
+        // empty name
+        cursor.write_all(&[0x00])?;
+    })
+}
+
+/// Writes the payload of an iTunes metadata item: a data box with the type of the value
+///
+/// # Arguments
+/// * `type_indicator` - The well-known type of the value, e.g. 13 for a JPEG image
+/// * `value` - The value of the item
+///
+/// # Returns
+/// * The payload of the item box
+pub(crate) fn itunes_item(type_indicator: u32, value: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(value.len() + 16);
+    payload.extend_from_slice(&(value.len() as u32 + 16).to_be_bytes());
+    payload.extend_from_slice(b"data");
+    payload.extend_from_slice(&type_indicator.to_be_bytes());
+    // Default locale
+    payload.extend_from_slice(&[0x00; 4]);
+    payload.extend_from_slice(value);
+    payload
+}
+
+fn write_mdir_hdlr<Writer>(cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"hdlr", {
+        cursor.write_all(&[0x00; 4])?;
+        // pre_defined
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(b"mdir")?;
+        // reserved, the first field is the manufacturer
+        cursor.write_all(b"appl")?;
+        cursor.write_all(&[0x00; 8])?;
+        // empty name
+        cursor.write_all(&[0x00])?;
+    })
+}
+
+/// Writes the meta box of the movie's udta box holding the iTunes metadata list
+///
+/// # Arguments
+/// * `items` - The type and payload of the items of the ilst box
+/// * `cursor` - The output
+fn write_itunes_meta<Writer>(items: &[([u8; 4], Vec<u8>)], cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"meta", {
+        cursor.write_all(&[0x00; 4])?;
+        write_mdir_hdlr(cursor)?;
+        let result: Result<(), Error> = mp4_box!(cursor, b"ilst", {
+            for (box_type, payload) in items {
+                write_raw_box(box_type, payload, cursor)?;
+            }
+        });
+        result?;
+    })
+}
+
+/// Writes the file-level meta box of a HEIF image with a single HEVC coded item
+///
+/// # Arguments
+/// * `track` - The video track holding the parameter sets and the image dimensions
+/// * `data_offset` - The offset of the coded image in the file
+/// * `data_size` - The size of the coded image
+/// * `cursor` - The output
+pub(crate) fn write_heif_meta<Writer>(
+    track: &Track,
+    data_offset: u32,
+    data_size: u32,
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"meta", {
+        cursor.write_all(&[0x00; 4])?;
+        write_pict_hdlr(cursor)?;
+        write_pitm(1, cursor)?;
+        write_iinf(1, cursor)?;
+        write_iloc(1, data_offset, data_size, cursor)?;
+        write_iprp(1, track, cursor)?;
+    })
+}
+
+fn write_stsd<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"stsd", {
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
+        let entry_pos = cursor.stream_position()?;
+        if let TrackType::Video = track.track_type {
+            match track.codec {
+                Codec::HEVC => {
+                    write_hvc1(track, cursor)?;
+                }
+                Codec::MJPEG => {
+                    write_jpeg(
+                        track.width as u16,
+                        track.height as u16,
+                        &compressor_name(&track.compressor_name),
+                        cursor,
+                    )?;
+                }
+                Codec::AVC => {
+                    // avc3 allows parameter sets in the samples as well as in the avcC box
+                    let sample_entry = if track.inband_parameter_sets {
+                        b"avc3"
+                    } else {
+                        b"avc1"
+                    };
+                    write_avc1(
+                        sample_entry,
+                        track.width as u16,
+                        track.height as u16,
+                        &track.sps,
+                        &track.pps,
+                        &compressor_name(&track.compressor_name),
+                        cursor,
+                    )?;
+                }
+                _ => {}
+            }
+        } else {
+            match track.codec {
+                Codec::AACLC
+                | Codec::AACMAIN
+                | Codec::AACSSR
+                | Codec::AACLTP
+                | Codec::HEAAC
+                | Codec::HEAACV2 => {
+                    // Audio ISO/IEC 14496-3
+                    write_mp4a(track, track.object_type_indication.unwrap_or(0x40), cursor)?;
+                }
+                Codec::VORBIS => {
+                    // Vorbis, as registered by the MP4 registration authority
+                    write_mp4a(track, track.object_type_indication.unwrap_or(0xdd), cursor)?;
+                }
+                Codec::MP3 => {
+                    // Audio ISO/IEC 11172-3, MPEG-2 layer III (0x69) has the same syntax
+                    write_mp4a(track, track.object_type_indication.unwrap_or(0x6b), cursor)?;
+                }
+                Codec::OPUS => {
+                    write_opus(
+                        track.channel_count,
+                        track.sample_rate,
+                        track.pre_skip,
+                        cursor,
+                    )?;
+                }
+                Codec::CEA608 => {
+                    write_c608(cursor)?;
+                }
+                _ => {}
+            }
+        }
+        // Every sample entry starts with 6 reserved bytes and the data_reference_index,
+        // written as 1 by the sample entry writers
+        let end_pos = cursor.stream_position()?;
+        if track.data_reference_index != 1 && end_pos > entry_pos {
+            cursor.seek(SeekFrom::Start(entry_pos + 14))?;
+            cursor.write_all(&track.data_reference_index.to_be_bytes())?;
+            cursor.seek(SeekFrom::Start(end_pos))?;
+        }
+    })
+}
+
+fn write_c608<Writer>(cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"c608", {
+        // reserved
+        cursor.write_all(&[0x00; 6])?;
+        // data_reference_index
+        cursor.write_all(&1u16.to_be_bytes())?;
+    })
+}
+
+/// Builds a c608 caption sample from the CEA-608 byte pairs of field 1 and field 2
+pub(crate) fn c608_sample(fields: &[Vec<u8>; 2]) -> Vec<u8> {
+    let mut sample = Vec::new();
+    for (field, name) in fields.iter().zip([b"cdat", b"cdt2"]) {
+        if !field.is_empty() {
+            sample.extend_from_slice(&(field.len() as u32 + 8).to_be_bytes());
+            sample.extend_from_slice(name);
+            sample.extend_from_slice(field);
+        }
+    }
+    sample
+}
+
+fn write_stts<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"stts", {
+        cursor.write_all(&[0x00; 4])?;
+        // entry count
+        let entry_count_idx = cursor.stream_position()?;
+        cursor.seek(SeekFrom::Current(4))?;
+        let mut entry_count: u32 = 0;
+        let mut cnt: u32 = 1;
+        for i in 0..samples.len() {
+            if i == samples.len() - 1 || samples[i].sample_delta != samples[i + 1].sample_delta {
+                cursor.write_all(&cnt.to_be_bytes())?;
+                cursor.write_all(&samples[i].sample_delta.to_be_bytes())?;
+                cnt = 0;
+                entry_count += 1;
+            }
+            cnt += 1;
+        }
+        let end_pos = cursor.stream_position()?;
+        cursor.seek(SeekFrom::Start(entry_count_idx))?;
+        cursor.write_all(&entry_count.to_be_bytes())?;
+        cursor.seek(SeekFrom::Start(end_pos))?;
+    })
+}
+
+fn write_ctts<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    let mut has_ctts = false;
+    for sample in samples.iter() {
+        if sample.sample_ct_offset != 0 {
+            has_ctts = true;
+            break;
+        }
+    }
+    if !has_ctts {
+        return Ok(());
+    }
+    mp4_box!(cursor, b"ctts", {
+        cursor.write_all(&[0x00; 4])?;
+        let entry_count_idx = cursor.stream_position()?;
+        cursor.seek(SeekFrom::Current(4))?;
+        let mut entry_count: u32 = 0;
+        let mut cnt: u32 = 1;
+        for i in 0..samples.len() {
+            if i == samples.len() - 1
+                || samples[i].sample_ct_offset != samples[i + 1].sample_ct_offset
+            {
+                cursor.write_all(&cnt.to_be_bytes())?;
+                cursor.write_all(&samples[i].sample_ct_offset.to_be_bytes())?;
                 cnt = 0;
                 entry_count += 1;
             }
@@ -598,6 +1233,36 @@ where
     })
 }
 
+/// Writes the composition to decode timeline mapping of samples with composition offsets
+fn write_cslg<Writer>(samples: &[SampleInfo], cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    let mut decode_time: i64 = 0;
+    let mut least = i32::MAX;
+    let mut greatest = i32::MIN;
+    let mut start = i64::MAX;
+    let mut end = i64::MIN;
+    for sample in samples.iter() {
+        let composition_time = decode_time + sample.sample_ct_offset as i64;
+        least = least.min(sample.sample_ct_offset);
+        greatest = greatest.max(sample.sample_ct_offset);
+        start = start.min(composition_time);
+        end = end.max(composition_time + sample.sample_delta as i64);
+        decode_time += sample.sample_delta as i64;
+    }
+    let clamp = |value: i64| value.clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+    mp4_box!(cursor, b"cslg", {
+        cursor.write_all(&[0x00; 4])?;
+        // compositionToDTSShift makes all decode times at most the composition times
+        cursor.write_all(&0i32.max(least.saturating_neg()).to_be_bytes())?;
+        cursor.write_all(&least.to_be_bytes())?;
+        cursor.write_all(&greatest.to_be_bytes())?;
+        cursor.write_all(&clamp(start).to_be_bytes())?;
+        cursor.write_all(&clamp(end).to_be_bytes())?;
+    })
+}
+
 fn write_stbl<Writer>(track: &Track, fragment: bool, cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
@@ -606,20 +1271,51 @@ where
         write_stsd(track, cursor)?;
         write_stts(&track.samples, cursor)?;
         write_ctts(&track.samples, cursor)?;
-        write_stsc(fragment, cursor)?;
-        write_stsz(&track.samples, cursor)?;
-        if track.samples.len() > 0 {
-            let last_sample = track.samples.last().unwrap();
-            if last_sample.offset > 0xffffffff {
-                write_co64(&track.samples, cursor)?;
+        if track.composition_shift
+            && track
+                .samples
+                .iter()
+                .any(|sample| sample.sample_ct_offset != 0)
+        {
+            write_cslg(&track.samples, cursor)?;
+        }
+        let chunks = sample_chunks(&track.samples, track.compact_tables);
+        write_stsc(&chunks, fragment, cursor)?;
+        write_stsz(&track.samples, track.compact_tables, cursor)?;
+        if !chunks.is_empty() {
+            // 32-bit chunk offsets only cover the first 4GB of the file
+            if chunks.iter().any(|(offset, _, _)| *offset > 0xffffffff) {
+                write_co64(&chunks, cursor)?;
             } else {
-                write_stco(&track.samples, cursor)?;
+                write_stco(&chunks, cursor)?;
             }
         }
         if !fragment {
+            // Without stss every sample is a sync sample
+            let all_sync = track.samples.iter().all(|sample| sample.random_access);
             if let TrackType::Video = track.track_type {
-                //stss
+                if !all_sync {
+                    write_stss(&track.samples, cursor)?;
+                }
+                if track.sample_dependencies {
+                    write_sdtp(&track.samples, cursor)?;
+                }
+            } else if !all_sync {
+                // Audio frames marked as depending on the frames before them
                 write_stss(&track.samples, cursor)?;
+                write_sdtp(&track.samples, cursor)?;
+            }
+        }
+        if let Codec::OPUS = track.codec {
+            write_sgpd_roll(opus_roll_distance(track), cursor)?;
+            if !track.samples.is_empty() {
+                write_sbgp_roll(track.samples.len() as u32, cursor)?;
+            }
+        }
+        if track.temporal_layers {
+            write_sgpd_tele(temporal_layer_count(track), cursor)?;
+            if !track.samples.is_empty() {
+                write_sbgp_tele(&track.samples, cursor)?;
             }
         }
     })
@@ -637,13 +1333,106 @@ where
             TrackType::Audio => {
                 write_smhd(cursor)?;
             }
+            TrackType::Caption => {
+                write_nmhd(cursor)?;
+            }
         }
-        write_dinf(cursor)?;
+        write_dinf(track, cursor)?;
         write_stbl(track, fragment, cursor)?;
     })
 }
 
-fn write_tkhd<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
+/// Converts a duration in a track's timescale to the movie timescale
+///
+/// The result is rounded to the nearest unit, so it is within half a unit of the exact
+/// duration, e.g. 1001 units at 90 kHz last 11 ms rather than 11.12 ms.
+pub(crate) fn movie_duration(duration: u32, timescale: u32, movie_timescale: u32) -> u32 {
+    rescale(duration as u64, timescale, movie_timescale).min(u32::MAX as u64) as u32
+}
+
+/// Whether the track needs an edit list
+fn has_edits(track: &Track) -> bool {
+    track.edit_media_time.is_some() || track.edit_delay > 0 || opus_pre_skip(track) > 0
+}
+
+/// Gets the media time where the presentation starts, after the pre-skip of Opus tracks
+pub(crate) fn edit_media_time(track: &Track) -> u32 {
+    track.edit_media_time.unwrap_or(0) + opus_pre_skip(track)
+}
+
+/// Converts the presentation duration of a track to the movie timescale
+///
+/// With an edit list, the track is presented after its delay and from its media time on.
+pub(crate) fn track_movie_duration(track: &Track, movie_timescale: u32) -> u32 {
+    let media_time = edit_media_time(track);
+    let delay = movie_duration(track.edit_delay, track.timescale, movie_timescale);
+    let duration = movie_duration(
+        track.duration.saturating_sub(media_time),
+        track.timescale,
+        movie_timescale,
+    );
+    delay.saturating_add(duration)
+}
+
+fn write_elst<Writer>(
+    track: &Track,
+    fragment: bool,
+    movie_timescale: u32,
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"elst", {
+        // version & flag
+        cursor.write_all(&[0x00; 4])?;
+        let entry_count: u32 = if track.edit_delay > 0 { 2 } else { 1 };
+        cursor.write_all(&entry_count.to_be_bytes())?;
+        if track.edit_delay > 0 {
+            // empty edit, nothing is presented before the track starts
+            let delay = movie_duration(track.edit_delay, track.timescale, movie_timescale);
+            cursor.write_all(&delay.to_be_bytes())?;
+            cursor.write_all(&(-1i32).to_be_bytes())?;
+            cursor.write_all(&0x00010000u32.to_be_bytes())?;
+        }
+        // the whole track from media_time on, at normal rate, up to the end of the
+        // fragments when the duration is 0
+        let media_time = edit_media_time(track);
+        let segment_duration = if fragment {
+            0
+        } else {
+            movie_duration(
+                track.duration.saturating_sub(media_time),
+                track.timescale,
+                movie_timescale,
+            )
+        };
+        cursor.write_all(&segment_duration.to_be_bytes())?;
+        cursor.write_all(&media_time.to_be_bytes())?;
+        cursor.write_all(&0x00010000u32.to_be_bytes())?;
+    })
+}
+
+fn write_edts<Writer>(
+    track: &Track,
+    fragment: bool,
+    movie_timescale: u32,
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"edts", {
+        write_elst(track, fragment, movie_timescale, cursor)?;
+    })
+}
+
+fn write_tkhd<Writer>(
+    track: &Track,
+    fragment: bool,
+    movie_timescale: u32,
+    cursor: &mut Writer,
+) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
@@ -658,19 +1447,22 @@ where
         cursor.write_all(&track.id.to_be_bytes())?;
         // reserved
         cursor.write_all(&[0x00; 4])?;
-        // duration
-        cursor.write_all(&(track.duration / (track.timescale / 1000)).to_be_bytes())?; //
+        // duration, as presented through the edit list, of the samples of the moov box
+        let duration = if fragment {
+            0
+        } else {
+            track_movie_duration(track, movie_timescale)
+        };
+        cursor.write_all(&duration.to_be_bytes())?;
         cursor.write_all(&[0; 12])?;
         const VOLUME: u16 = 0x0100;
         cursor.write_all(&VOLUME.to_be_bytes())?;
         // reserved
         cursor.write_all(&[0x00; 2])?;
         // matrix
-        cursor.write_all(&0x00010000u32.to_be_bytes())?;
-        cursor.write_all(&[0x00; 12])?;
-        cursor.write_all(&0x00010000u32.to_be_bytes())?;
-        cursor.write_all(&[0x00; 12])?;
-        cursor.write_all(&0x40000000u32.to_be_bytes())?;
+        for value in track.matrix.iter() {
+            cursor.write_all(&value.to_be_bytes())?;
+        }
         if let TrackType::Video = track.track_type {
             cursor.write_all(&(track.width * 0x10000).to_be_bytes())?;
             cursor.write_all(&(track.height * 0x10000).to_be_bytes())?;
@@ -690,8 +1482,10 @@ where
     Writer: Write + Seek,
 {
     mp4_box!(cursor, b"mdia", {
-        write_mdhd(track.timescale, track.duration, &language, cursor)?;
-        write_hdlr(matches!(track.track_type, TrackType::Video), cursor)?;
+        // The samples of the fragments are not part of the duration
+        let duration = if fragment { 0 } else { track.duration };
+        write_mdhd(track.timescale, duration, language, cursor)?;
+        write_hdlr(&track.track_type, cursor)?;
         write_minf(track, fragment, cursor)?;
     })
 }
@@ -700,7 +1494,7 @@ fn write_mvhd<Writer>(
     create_time: u64,
     duration: u32,
     timescale: u32,
-    track_ids: u32,
+    next_track_id: u32,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
@@ -724,39 +1518,79 @@ where
         }
 
         // timescale
-        const TIMESCALE: u32 = 1000;
-        cursor.write_all(&TIMESCALE.to_be_bytes())?;
+        cursor.write_all(&timescale.to_be_bytes())?;
         // duration
-        let duration = duration / (timescale / TIMESCALE);
         if create_time != 0 {
             cursor.write_all(&(duration as u64).to_be_bytes())?;
         } else {
             cursor.write_all(&(duration).to_be_bytes())?;
         }
-        // Write playback rate (0x00010000 = 1.0, normal speed)
-        const RATE: u32 = 0x00010000;
-        cursor.write_all(&RATE.to_be_bytes())?;
-        // Write playback volume (0x0100 = 1.0, full volume)
-        const VOLUME: u16 = 0x0100;
-        cursor.write_all(&VOLUME.to_be_bytes())?;
-        // reserved
-        cursor.write_all(&[0x00; 10])?;
-        // Write unity matrix for video transform
-        cursor.write_all(&0x00010000u32.to_be_bytes())?;
-        cursor.write_all(&[0x00; 12])?;
-        cursor.write_all(&0x00010000u32.to_be_bytes())?;
-        cursor.write_all(&[0x00; 12])?;
-        cursor.write_all(&0x40000000u32.to_be_bytes())?;
-        // pre_defined
-        cursor.write_all(&[0x00; 24])?;
-        // next_track_id
-        cursor.write_all(&track_ids.to_be_bytes())?;
+        // Write playback rate (0x00010000 = 1.0, normal speed)
+        const RATE: u32 = 0x00010000;
+        cursor.write_all(&RATE.to_be_bytes())?;
+        // Write playback volume (0x0100 = 1.0, full volume)
+        const VOLUME: u16 = 0x0100;
+        cursor.write_all(&VOLUME.to_be_bytes())?;
+        // reserved
+        cursor.write_all(&[0x00; 10])?;
+        // Write unity matrix for video transform
+        cursor.write_all(&0x00010000u32.to_be_bytes())?;
+        cursor.write_all(&[0x00; 12])?;
+        cursor.write_all(&0x00010000u32.to_be_bytes())?;
+        cursor.write_all(&[0x00; 12])?;
+        cursor.write_all(&0x40000000u32.to_be_bytes())?;
+        // pre_defined
+        cursor.write_all(&[0x00; 24])?;
+        // next_track_id
+        cursor.write_all(&next_track_id.to_be_bytes())?;
+    })
+}
+
+fn write_kind<Writer>(scheme: &str, value: &str, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"kind", {
+        // version & flag
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(scheme.as_bytes())?;
+        cursor.write_all(&[0x00])?;
+        cursor.write_all(value.as_bytes())?;
+        cursor.write_all(&[0x00])?;
+    })
+}
+
+fn write_name<Writer>(name: &str, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    // QuickTime user data atom, the name without terminator
+    mp4_box!(cursor, b"name", {
+        cursor.write_all(name.as_bytes())?;
+    })
+}
+
+fn write_track_udta<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    if track.kinds.is_empty() && track.name.is_none() {
+        return Ok(());
+    }
+    mp4_box!(cursor, b"udta", {
+        for (scheme, value) in track.kinds.iter() {
+            write_kind(scheme, value, cursor)?;
+        }
+        if let Some(name) = &track.name {
+            write_name(name, cursor)?;
+        }
     })
 }
 
 fn write_track<Writer>(
     language: &[u8; 3],
     fragment: bool,
+    movie_timescale: u32,
     track: &Track,
     cursor: &mut Writer,
 ) -> Result<(), Error>
@@ -764,8 +1598,12 @@ where
     Writer: Write + Seek,
 {
     mp4_box!(cursor, b"trak", {
-        write_tkhd(track, cursor)?;
-        write_mdia(track, fragment, &language, cursor)?;
+        write_tkhd(track, fragment, movie_timescale, cursor)?;
+        if has_edits(track) {
+            write_edts(track, fragment, movie_timescale, cursor)?;
+        }
+        write_mdia(track, fragment, language, cursor)?;
+        write_track_udta(track, cursor)?;
     })
 }
 
@@ -781,17 +1619,55 @@ where
     Ok(())
 }
 
+/// Writes the mehd box holding the duration of all the fragments in the movie timescale
+///
+/// The duration is 0, unknown, until the last fragment is written and it is updated in
+/// place; version 1 with a 64-bit duration keeps the size of the box whatever the duration.
+fn write_mehd<Writer>(cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"mehd", {
+        cursor.write_all(&[0x01, 0x00, 0x00, 0x00])?;
+        cursor.write_all(&0u64.to_be_bytes())?;
+    })
+}
+
 fn write_mvex<Writer>(tracks: &[&Option<Track>], cursor: &mut Writer) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
     mp4_box!(cursor, b"mvex", {
+        write_mehd(cursor)?;
         write_trexs(tracks, cursor)?;
     })
 }
+
+/// Finds the fragment_duration field of the mehd box in a moov box
+///
+/// # Returns
+/// * The offset of the 64-bit duration from the start of the moov box, or None if the
+///   moov box has no mehd box
+pub(crate) fn mehd_duration_offset(moov: &[u8]) -> Option<usize> {
+    let child = |buf: &[u8], box_type: &[u8; 4]| {
+        let mut pos = 8;
+        while pos + 8 <= buf.len() {
+            let size = u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+            if &buf[pos + 4..pos + 8] == box_type {
+                return Some(pos);
+            }
+            pos += (size as usize).max(8);
+        }
+        None
+    };
+    let mvex = child(moov, b"mvex")?;
+    let mehd = mvex + child(&moov[mvex..], b"mehd")?;
+    Some(mehd + 12)
+}
 fn write_tracks<Writer>(
     language: &[u8; 3],
     fragment: bool,
+    movie_timescale: u32,
     tracks: &[&Option<Track>],
     cursor: &mut Writer,
 ) -> Result<(), Error>
@@ -800,135 +1676,347 @@ where
 {
     for track in tracks.iter() {
         if let Some(track) = track.as_ref() {
-            write_track(&language, fragment, track, cursor)?;
+            write_track(language, fragment, movie_timescale, track, cursor)?;
         }
     }
     Ok(())
 }
+/// Writes the moov box of a file with the given tracks
+///
+/// # Arguments
+/// * `tracks` - The tracks of the file, `None` entries are skipped
+/// * `create_time` - The creation time in seconds since 1904, or 0 to leave it unset
+/// * `movie_timescale` - The time scale of the movie and track header durations
+/// * `language` - The ISO 639-2 language code of the media
+/// * `fragment` - Whether the samples follow in fragments, which adds an mvex box
+/// * `cursor` - The output
 pub fn write_moov<Writer>(
-    video_track: &Option<Track>,
-    audio_track: &Option<Track>,
+    tracks: &[&Option<Track>],
+    create_time: u64,
+    movie_timescale: u32,
+    language: &[u8; 3],
+    fragment: bool,
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    write_moov_with_boxes(
+        tracks,
+        create_time,
+        movie_timescale,
+        language,
+        fragment,
+        &UserBoxes::default(),
+        cursor,
+    )
+}
+
+/// Writes a box with the given type and payload
+///
+/// # Arguments
+/// * `box_type` - The four-character code of the box
+/// * `payload` - The content of the box, written as it is after the box header
+/// * `cursor` - The output
+pub fn write_raw_box<Writer>(
+    box_type: &[u8; 4],
+    payload: &[u8],
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, box_type, {
+        cursor.write_all(payload)?;
+    })
+}
+
+/// Writes the moov box of a file, with additional boxes given by the application
+///
+/// Same as `write_moov`, followed by a udta box holding the udta boxes of `user_boxes`
+/// when there are any, then by its moov boxes, all written as they are after their
+/// box header.
+///
+/// # Arguments
+/// * `tracks` - The tracks of the file, `None` entries are skipped
+/// * `create_time` - The creation time in seconds since 1904, or 0 to leave it unset
+/// * `movie_timescale` - The time scale of the movie and track header durations
+/// * `language` - The ISO 639-2 language code of the media
+/// * `fragment` - Whether the samples follow in fragments, which adds an mvex box
+/// * `user_boxes` - The boxes added to the moov box and to the movie's udta box
+/// * `cursor` - The output
+pub fn write_moov_with_boxes<Writer>(
+    tracks: &[&Option<Track>],
     create_time: u64,
-    track_ids: u32,
+    movie_timescale: u32,
     language: &[u8; 3],
     fragment: bool,
+    user_boxes: &UserBoxes,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
     mp4_box!(cursor, b"moov", {
+        // The movie lasts as long as its longest track
+        let duration = tracks
+            .iter()
+            .filter_map(|track| track.as_ref())
+            .map(|track| track_movie_duration(track, movie_timescale))
+            .max()
+            .unwrap_or(0);
+        // Track IDs may be sparse, the next one must be above all of them
+        let next_track_id = tracks
+            .iter()
+            .filter_map(|track| track.as_ref())
+            .map(|track| track.id)
+            .max()
+            .unwrap_or(0)
+            + 1;
+        // The duration of the fragments is in the mehd box
         write_mvhd(
             create_time,
-            video_track.as_ref().unwrap().duration,
-            video_track.as_ref().unwrap().timescale,
-            track_ids,
+            if fragment { 0 } else { duration },
+            movie_timescale,
+            next_track_id,
             cursor,
         )?;
-        write_tracks(language, fragment, &[video_track, audio_track], cursor)?;
+        write_tracks(language, fragment, movie_timescale, tracks, cursor)?;
         if fragment {
-            write_mvex(&[video_track, audio_track], cursor)?;
+            write_mvex(tracks, cursor)?;
+        }
+        if !user_boxes.udta.is_empty() || !user_boxes.ilst.is_empty() {
+            let result: Result<(), Error> = mp4_box!(cursor, b"udta", {
+                for (box_type, payload) in user_boxes.udta.iter() {
+                    write_raw_box(box_type, payload, cursor)?;
+                }
+                if !user_boxes.ilst.is_empty() {
+                    write_itunes_meta(&user_boxes.ilst, cursor)?;
+                }
+            });
+            result?;
+        }
+        for (box_type, payload) in user_boxes.moov.iter() {
+            write_raw_box(box_type, payload, cursor)?;
         }
     })
 }
 
-fn write_tfhd<Writer>(track: &Track, sample_duration: u32, cursor: &mut Writer) -> Result<(), Error>
+/// tfhd flag: the default_sample_duration field is present
+const TFHD_DEFAULT_SAMPLE_DURATION: u32 = 0x000008;
+/// tfhd flag: the default_sample_flags field is present
+const TFHD_DEFAULT_SAMPLE_FLAGS: u32 = 0x000020;
+/// tfhd flag: the data offsets of the trun boxes are relative to the start of the moof box
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x020000;
+
+fn write_tfhd<Writer>(
+    track: &Track,
+    sample_duration: u32,
+    constant_duration: bool,
+    cursor: &mut Writer,
+) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
+    let video = matches!(track.track_type, TrackType::Video);
+    // Audio samples take the duration of the first sample unless the trun has durations,
+    // a constant frame duration is only written once
+    let default_duration = !video || constant_duration;
+    // Every traf has the same base, no base_data_offset field points into the file
+    let mut flags = TFHD_DEFAULT_BASE_IS_MOOF;
+    if default_duration {
+        flags |= TFHD_DEFAULT_SAMPLE_DURATION;
+    }
+    if video {
+        flags |= TFHD_DEFAULT_SAMPLE_FLAGS;
+    }
     mp4_box!(cursor, b"tfhd", {
-        if let TrackType::Video = track.track_type {
-            cursor.write_all(&0x20020u32.to_be_bytes())?;
-            cursor.write_all(&track.id.to_be_bytes())?;
-            cursor.write_all(&0x1010000u32.to_be_bytes())?;
-        } else {
-            cursor.write_all(&0x20008u32.to_be_bytes())?;
-            cursor.write_all(&track.id.to_be_bytes())?;
+        cursor.write_all(&flags.to_be_bytes())?;
+        cursor.write_all(&track.id.to_be_bytes())?;
+        if default_duration {
             cursor.write_all(&sample_duration.to_be_bytes())?;
         }
+        if video {
+            // Video samples are non-sync samples unless the trun says otherwise
+            cursor.write_all(&0x1010000u32.to_be_bytes())?;
+        }
+    })
+}
+
+fn write_tfdt<Writer>(base_media_decode_time: u64, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"tfdt", {
+        // version 1 & flag
+        cursor.write_all(&[0x01, 0x00, 0x00, 0x00])?;
+        cursor.write_all(&base_media_decode_time.to_be_bytes())?;
     })
 }
 
+/// Writes a trun box whose data offset is filled in by `write_moof_samples`
+///
+/// The position of the data offset field is stored in `data_offset_pos`.
 fn write_trun<Writer>(
     track: &Track,
-    moof_pos: u64,
-    data_size: u32,
-    sample_duration: u32,
-    ct_offset: i32,
-    sample_type: SampleType,
+    samples: &[SampleInfo],
+    constant_duration: bool,
+    data_offset_pos: &mut u64,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
+    // The tfhd default duration is the duration of the first sample
+    let durations = !constant_duration;
+    // Like the ctts box, composition offsets are only written when a sample has one
+    let ct_offsets = samples.iter().any(|sample| sample.sample_ct_offset != 0);
     mp4_box!(cursor, b"trun", {
-        let data_offset_pos;
         if let TrackType::Video = track.track_type {
-            if let SampleType::RandomAccess = sample_type {
-                let flags: u32 = 0x001 | 0x004 | 0x100 | 0x200 | 0x800;
-                cursor.write_all(&flags.to_be_bytes())?;
-                cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
-                data_offset_pos = cursor.stream_position()?;
-                cursor.seek(SeekFrom::Current(4))?;
+            let random_access = samples.first().is_some_and(|sample| sample.random_access);
+            let mut flags: u32 = 0x001 | 0x200;
+            if durations {
+                flags |= 0x100;
+            }
+            if ct_offsets {
+                flags |= 0x800;
+            }
+            if random_access {
+                flags |= 0x004;
+            }
+            cursor.write_all(&flags.to_be_bytes())?;
+            cursor.write_all(&(samples.len() as u32).to_be_bytes())?;
+            *data_offset_pos = cursor.stream_position()?;
+            cursor.write_all(&[0x00; 4])?;
+            if random_access {
                 cursor.write_all(&0x2000000u32.to_be_bytes())?;
-                cursor.write_all(&sample_duration.to_be_bytes())?;
-                cursor.write_all(&data_size.to_be_bytes())?;
-                cursor.write_all(&ct_offset.to_be_bytes())?;
-            } else {
-                let flags: u32 = 0x001 | 0x100 | 0x200 | 0x800;
-                cursor.write_all(&flags.to_be_bytes())?;
-                cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
-                data_offset_pos = cursor.stream_position()?;
-                cursor.seek(SeekFrom::Current(4))?;
-                cursor.write_all(&sample_duration.to_be_bytes())?;
-                cursor.write_all(&data_size.to_be_bytes())?;
-                cursor.write_all(&ct_offset.to_be_bytes())?;
+            }
+            for sample in samples {
+                if durations {
+                    cursor.write_all(&sample.sample_delta.to_be_bytes())?;
+                }
+                cursor.write_all(&sample.sample_size.to_be_bytes())?;
+                if ct_offsets {
+                    cursor.write_all(&sample.sample_ct_offset.to_be_bytes())?;
+                }
             }
         } else {
-            let flags: u32 = 0x001 | 0x200;
+            // Sample flags are only written when some audio frames depend on earlier ones
+            let sample_flags = !samples.iter().all(|sample| sample.random_access);
+            let mut flags: u32 = 0x001 | 0x200;
+            if durations {
+                flags |= 0x100;
+            }
+            if sample_flags {
+                flags |= 0x400;
+            }
             cursor.write_all(&flags.to_be_bytes())?;
-            cursor.write_all(&[0x00, 0x00, 0x00, 0x01])?;
-            data_offset_pos = cursor.stream_position()?;
-            cursor.seek(SeekFrom::Current(4))?;
-            cursor.write_all(&data_size.to_be_bytes())?;
+            cursor.write_all(&(samples.len() as u32).to_be_bytes())?;
+            *data_offset_pos = cursor.stream_position()?;
+            cursor.write_all(&[0x00; 4])?;
+            for sample in samples {
+                if durations {
+                    cursor.write_all(&sample.sample_delta.to_be_bytes())?;
+                }
+                cursor.write_all(&sample.sample_size.to_be_bytes())?;
+                if sample_flags {
+                    let flags: u32 = if sample.random_access {
+                        0x2000000
+                    } else {
+                        0x1010000
+                    };
+                    cursor.write_all(&flags.to_be_bytes())?;
+                }
+            }
         }
-        let end_pos = cursor.stream_position()?;
-        let data_offset = (end_pos - moof_pos + 8) as u32;
-        cursor.seek(SeekFrom::Start(data_offset_pos))?;
-        cursor.write_all(&data_offset.to_be_bytes())?;
-        cursor.seek(SeekFrom::Start(end_pos)).unwrap();
+    })
+}
+
+/// User type of the Smooth Streaming TrackFragmentExtendedHeaderBox (tfxd)
+pub const TFXD_UUID: [u8; 16] = [
+    0x6d, 0x1d, 0x9b, 0x05, 0x42, 0xd5, 0x44, 0xe6, 0x80, 0xe2, 0x14, 0x1d, 0xaf, 0xf7, 0x57, 0xb2,
+];
+
+/// User type of the Smooth Streaming TfrfBox (tfrf) referencing the next fragments
+pub const TFRF_UUID: [u8; 16] = [
+    0xd4, 0x80, 0x7e, 0xf2, 0xca, 0x39, 0x46, 0x95, 0x8e, 0x54, 0x26, 0xcb, 0x9e, 0x46, 0xa7, 0x9f,
+];
+
+/// Writes the tfxd box with the absolute time and the duration of a fragment
+fn write_tfxd<Writer>(time: u64, duration: u64, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"uuid", {
+        cursor.write_all(&TFXD_UUID)?;
+        // version 1 with 64-bit times
+        cursor.write_all(&[0x01, 0x00, 0x00, 0x00])?;
+        cursor.write_all(&time.to_be_bytes())?;
+        cursor.write_all(&duration.to_be_bytes())?;
+    })
+}
+
+/// Writes a tfrf box referencing none of the next fragments, which are not known yet
+fn write_tfrf<Writer>(cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"uuid", {
+        cursor.write_all(&TFRF_UUID)?;
+        cursor.write_all(&[0x01, 0x00, 0x00, 0x00])?;
+        // fragment_count
+        cursor.write_all(&[0x00])?;
     })
 }
 
 fn write_traf<Writer>(
-    moof_pos: u64,
     track: &Track,
-    data: &[u8],
-    sample_duration: u32,
-    ct_offset: i32,
-    sample_type: SampleType,
+    samples: &[SampleInfo],
+    smooth_streaming: bool,
+    data_offset_pos: &mut u64,
     cursor: &mut Writer,
 ) -> Result<(), Error>
 where
     Writer: Write + Seek,
 {
     mp4_box!(cursor, b"traf", {
-        write_tfhd(track, sample_duration, cursor)?;
-        write_trun(
-            track,
-            moof_pos,
-            data.len() as u32 + 4,
-            sample_duration,
-            ct_offset,
-            sample_type,
-            cursor,
-        )?;
+        let default_duration = samples.first().map_or(0, |sample| sample.sample_delta);
+        let constant_duration = samples
+            .iter()
+            .all(|sample| sample.sample_delta == default_duration);
+        write_tfhd(track, default_duration, constant_duration, cursor)?;
+        write_tfdt(track.fragment_decode_time, cursor)?;
+        write_trun(track, samples, constant_duration, data_offset_pos, cursor)?;
+        if let Codec::OPUS = track.codec {
+            write_sbgp_roll(samples.len() as u32, cursor)?;
+        }
+        if track.temporal_layers {
+            write_sbgp_tele(samples, cursor)?;
+        }
+        if smooth_streaming {
+            let duration = samples
+                .iter()
+                .map(|sample| sample.sample_delta as u64)
+                .sum();
+            write_tfxd(track.fragment_decode_time, duration, cursor)?;
+            write_tfrf(cursor)?;
+        }
     })
 }
 
+/// Writes the moof box of a fragment holding a single sample
+///
+/// # Arguments
+/// * `fragment_id` - The sequence number of the fragment
+/// * `data_size` - The size of the sample in the mdat box that follows
+/// * `duration` - The duration of the sample in the track's timescale
+/// * `track` - The track of the sample, its `fragment_decode_time` is the decode time
+/// * `ct_offset` - The composition time offset of the sample
+/// * `sample_type` - The sample type
+/// * `cursor` - The output
 pub fn write_moof<Writer>(
     fragment_id: u32,
-    data: &[u8],
+    data_size: u32,
     duration: u32,
     track: &Track,
     ct_offset: i32,
@@ -938,40 +2026,297 @@ pub fn write_moof<Writer>(
 where
     Writer: Write + Seek,
 {
-    mp4_box!(cursor, b"moof", {
-        let moof_pos = cursor.stream_position()? - 8;
+    let sample = SampleInfo {
+        random_access: !matches!(track.track_type, TrackType::Video)
+            || matches!(sample_type, SampleType::RandomAccess),
+        offset: 0,
+        sample_size: data_size,
+        sample_delta: duration,
+        sample_ct_offset: ct_offset,
+        description_index: 1,
+        temporal_id: 0,
+    };
+    write_moof_samples(fragment_id, track, &[sample], cursor)
+}
+
+/// Writes the moof box of a fragment holding several samples of a track
+///
+/// The samples follow each other in the mdat box right after the moof box. For video
+/// tracks, only the first sample may be a sync sample.
+///
+/// # Arguments
+/// * `fragment_id` - The sequence number of the fragment
+/// * `track` - The track of the samples, its `fragment_decode_time` is the decode time
+/// * `samples` - The size, duration, composition time offset and sync flag of each sample
+/// * `cursor` - The output
+pub fn write_moof_samples<Writer>(
+    fragment_id: u32,
+    track: &Track,
+    samples: &[SampleInfo],
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    write_moof_samples_before(fragment_id, track, samples, 0, cursor)
+}
+
+/// Writes the moof box of a fragment whose mdat box follows other boxes
+///
+/// A fragment is laid out as moof then mdat. A packager may place boxes between them,
+/// e.g. a sidx or an emsg box; their size moves the samples further from the moof
+/// box, so it is added to the data offset of the trun box. Boxes written before the
+/// moof box, such as styp and sidx at the start of a segment, need no adjustment.
+///
+/// # Arguments
+/// * `fragment_id` - The sequence number of the fragment
+/// * `track` - The track of the samples, its `fragment_decode_time` is the decode time
+/// * `samples` - The size, duration, composition time offset and sync flag of each sample
+/// * `boxes_size` - The size of the boxes written between the moof and the mdat box
+/// * `cursor` - The output
+///
+/// # Example
+/// ```
+/// use std::io::{Cursor, Write};
+/// use mp4e::boxes::{write_mdat, write_moof_samples_before, write_styp};
+/// use mp4e::{Codec, SampleInfo, Track, TrackType};
+///
+/// let track = Track::new(1, TrackType::Audio, Codec::AACLC, 48000);
+/// let sample = SampleInfo {
+///     random_access: true,
+///     offset: 0,
+///     sample_size: 3,
+///     sample_delta: 1024,
+///     sample_ct_offset: 0,
+///     description_index: 1,
+///     temporal_id: 0,
+/// };
+/// let mut cursor = Cursor::new(Vec::new());
+/// // The styp box starts the segment, before the moof box
+/// write_styp(&mut cursor).unwrap();
+/// write_moof_samples_before(1, &track, &[sample], 24, &mut cursor).unwrap();
+/// // A 24-byte free box between the moof and the mdat box
+/// cursor.write_all(b"\0\0\0\x18free").unwrap();
+/// cursor.write_all(&[0; 16]).unwrap();
+/// write_mdat(&[&[0x21, 0x10, 0x04]], false, &mut cursor).unwrap();
+/// ```
+pub fn write_moof_samples_before<Writer>(
+    fragment_id: u32,
+    track: &Track,
+    samples: &[SampleInfo],
+    boxes_size: u32,
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    write_moof_box(fragment_id, track, samples, boxes_size, false, cursor)
+}
+
+/// Writes the moof box of a Smooth Streaming fragment holding several samples of a track
+///
+/// Same as `write_moof_samples`, with the uuid boxes Smooth Streaming clients read in
+/// the traf box: a tfxd box (user type `TFXD_UUID`) with the absolute time and the
+/// duration of the fragment in the track's timescale, and a tfrf box (user type
+/// `TFRF_UUID`) referencing none of the next fragments, as they are not written yet.
+///
+/// # Arguments
+/// * `fragment_id` - The sequence number of the fragment
+/// * `track` - The track of the samples, its `fragment_decode_time` is the decode time
+/// * `samples` - The size, duration, composition time offset and sync flag of each sample
+/// * `cursor` - The output
+///
+/// # Example
+/// ```
+/// use std::io::Cursor;
+/// use mp4e::boxes::write_smooth_moof_samples;
+/// use mp4e::{Codec, SampleInfo, Track, TrackType};
+///
+/// let track = Track::new(1, TrackType::Audio, Codec::AACLC, 48000);
+/// let sample = SampleInfo {
+///     random_access: true,
+///     offset: 0,
+///     sample_size: 3,
+///     sample_delta: 1024,
+///     sample_ct_offset: 0,
+///     description_index: 1,
+///     temporal_id: 0,
+/// };
+/// let mut cursor = Cursor::new(Vec::new());
+/// write_smooth_moof_samples(1, &track, &[sample], &mut cursor).unwrap();
+/// ```
+pub fn write_smooth_moof_samples<Writer>(
+    fragment_id: u32,
+    track: &Track,
+    samples: &[SampleInfo],
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    write_moof_box(fragment_id, track, samples, 0, true, cursor)
+}
+
+/// Writes the moof box of a fragment and patches the data offset of its trun box
+fn write_moof_box<Writer>(
+    fragment_id: u32,
+    track: &Track,
+    samples: &[SampleInfo],
+    boxes_size: u32,
+    smooth_streaming: bool,
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    let moof_pos = cursor.stream_position()?;
+    let mut data_offset_pos = 0;
+    let result: Result<(), Error> = mp4_box!(cursor, b"moof", {
         write_mfhd(fragment_id, cursor)?;
         write_traf(
-            moof_pos,
             track,
-            data,
-            duration,
-            ct_offset,
-            sample_type,
+            samples,
+            smooth_streaming,
+            &mut data_offset_pos,
             cursor,
         )?;
-    })
+    });
+    result?;
+    // With default-base-is-moof, the data offset is counted from the start of the moof
+    // box: the samples start after the moof box, the boxes in between and the header of
+    // the mdat box
+    let end_pos = cursor.stream_position()?;
+    let data_offset = (end_pos - moof_pos) as u32 + boxes_size + 8;
+    cursor.seek(SeekFrom::Start(data_offset_pos))?;
+    cursor.write_all(&data_offset.to_be_bytes())?;
+    cursor.seek(SeekFrom::Start(end_pos))?;
+    Ok(())
 }
 
-pub fn write_mdat<Writer>(buf: &[u8], video: bool, writer: &mut Writer) -> Result<u64, Error>
+/// Size of a sample in the mdat box, video NAL units each take a 4-byte length prefix
+pub fn sample_data_size(data: &[&[u8]], video: bool) -> u32 {
+    data.iter()
+        .map(|nalu| nalu.len() as u32 + if video { 4 } else { 0 })
+        .sum()
+}
+
+/// Writes the parts of a sample, video NAL units each get a 4-byte length prefix
+///
+/// The length prefixes and the data are passed to the writer together as a vectored
+/// write, so a writer supporting them, such as a socket, takes a sample of up to 16
+/// parts in one call. Other writers get the parts one at a time.
+///
+/// # Arguments
+/// * `data` - The parts of the sample, in order
+/// * `video` - Whether each part gets a 4-byte length prefix (video NAL units)
+/// * `writer` - The output
+///
+/// # Returns
+/// * `Ok(())` on success, or an error if writing fails
+///
+/// # Example
+/// ```
+/// use mp4e::boxes::write_sample_data;
+///
+/// let mut out = Vec::new();
+/// write_sample_data(&[&[0x65, 0x88], &[0x06]], true, &mut out).unwrap();
+/// assert_eq!(out, [0, 0, 0, 2, 0x65, 0x88, 0, 0, 0, 1, 0x06]);
+/// ```
+pub fn write_sample_data<Writer>(
+    data: &[&[u8]],
+    video: bool,
+    writer: &mut Writer,
+) -> Result<(), Error>
 where
     Writer: Write,
 {
-    let mut box_size = buf.len() as u32 + 8;
-    if video {
-        box_size += 4;
+    write_sample_parts(&[], data, video, writer)
+}
+
+/// Writes a header followed by the parts of a sample with vectored writes
+fn write_sample_parts<Writer>(
+    header: &[u8],
+    data: &[&[u8]],
+    video: bool,
+    writer: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write,
+{
+    // The parts go out in batches built on the stack, so writing allocates nothing
+    const BATCH: usize = 16;
+    let mut header = header;
+    for batch in data.chunks(BATCH) {
+        let mut prefixes = [[0u8; 4]; BATCH];
+        for (prefix, buf) in prefixes.iter_mut().zip(batch) {
+            *prefix = (buf.len() as u32).to_be_bytes();
+        }
+        let mut slices = [IoSlice::new(&[]); 2 * BATCH + 1];
+        slices[0] = IoSlice::new(header);
+        let mut count = 1;
+        for (prefix, buf) in prefixes.iter().zip(batch) {
+            if video {
+                slices[count] = IoSlice::new(prefix);
+                count += 1;
+            }
+            slices[count] = IoSlice::new(buf);
+            count += 1;
+        }
+        write_all_vectored(&mut slices[..count], writer)?;
+        header = &[];
     }
-    writer.write_all(&box_size.to_be_bytes())?;
-    writer.write_all(b"mdat")?;
-    if video {
-        let nal_size_buf = (buf.len() as u32).to_be_bytes();
-        writer.write_all(&nal_size_buf)?;
+    if data.is_empty() {
+        writer.write_all(header)?;
+    }
+    Ok(())
+}
+
+/// Writes all the slices, as the unstable `Write::write_all_vectored` does
+fn write_all_vectored<Writer>(
+    mut slices: &mut [IoSlice<'_>],
+    writer: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write,
+{
+    // Advancing by 0 bytes only drops the leading empty slices, a write of 0 bytes then
+    // means the writer takes no more data; the empty slices after written data are
+    // dropped along with it below
+    IoSlice::advance_slices(&mut slices, 0);
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => {
+                return Err(Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write the whole sample",
+                ))
+            }
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
     }
-    writer.write_all(buf)?;
+    Ok(())
+}
+
+/// Writes an mdat box holding the samples of a fragment, returns the size of the box
+///
+/// Video NAL units each get a 4-byte length prefix, other data is written as it is.
+/// The box header and the samples go to the writer in a single vectored write.
+pub fn write_mdat<Writer>(data: &[&[u8]], video: bool, writer: &mut Writer) -> Result<u64, Error>
+where
+    Writer: Write,
+{
+    let box_size = sample_data_size(data, video) + 8;
+    let mut header = [0u8; 8];
+    header[..4].copy_from_slice(&box_size.to_be_bytes());
+    header[4..].copy_from_slice(b"mdat");
+    write_sample_parts(&header, data, video, writer)?;
 
     Ok(box_size as u64)
 }
 
+/// Writes the ftyp box of an ISO base media file, returns the size of the box
 pub fn write_ftyp<Writer>(writer: &mut Writer) -> Result<u64, Error>
 where
     Writer: Write,
@@ -980,10 +2325,210 @@ where
     Ok(32)
 }
 
+pub(crate) fn write_heif_ftyp<Writer>(writer: &mut Writer) -> Result<u64, Error>
+where
+    Writer: Write,
+{
+    writer.write_all(b"\x00\x00\x00\x18ftypheic\x00\x00\x00\x00mif1heic")?;
+    Ok(24)
+}
+
+/// User type of the uuid box carrying the CRC-32 of the preceding moof and mdat boxes
+pub const FRAGMENT_CHECKSUM_UUID: [u8; 16] = [
+    0xb9, 0xe8, 0x27, 0x46, 0xdf, 0xec, 0x40, 0xf2, 0x81, 0xb6, 0xfb, 0xbd, 0x6f, 0xcc, 0x7c, 0x66,
+];
+
+/// Writes the sidx box indexing the fragment that follows it, returns the size of the box
+///
+/// The fragment is a single subsegment of the track's samples starting at its
+/// `fragment_decode_time`, so a player can find the fragment of a time from the sidx
+/// boxes alone.
+///
+/// # Arguments
+/// * `track` - The track of the samples, its `fragment_decode_time` is the decode time
+/// * `samples` - The samples of the fragment
+/// * `referenced_size` - The size of the fragment, from the first byte of its moof box
+///   to the end of its last box
+/// * `writer` - The output
+pub(crate) fn write_sidx<Writer>(
+    track: &Track,
+    samples: &[SampleInfo],
+    referenced_size: u32,
+    writer: &mut Writer,
+) -> Result<u64, Error>
+where
+    Writer: Write,
+{
+    let mut earliest = i64::MAX;
+    let mut decode_time = track.fragment_decode_time as i64;
+    for sample in samples {
+        earliest = earliest.min(decode_time + sample.sample_ct_offset as i64);
+        decode_time += sample.sample_delta as i64;
+    }
+    let duration = decode_time - track.fragment_decode_time as i64;
+    let starts_with_sap = samples.first().is_some_and(|sample| sample.random_access);
+    let mut sidx = [0u8; 52];
+    sidx[..8].copy_from_slice(b"\x00\x00\x00\x34sidx");
+    // Version 1, with 64-bit times
+    sidx[8] = 0x01;
+    sidx[12..16].copy_from_slice(&track.id.to_be_bytes());
+    sidx[16..20].copy_from_slice(&track.timescale.to_be_bytes());
+    sidx[20..28].copy_from_slice(&(earliest.max(0) as u64).to_be_bytes());
+    // first_offset 0: the fragment follows the sidx box, reserved and reference_count 1
+    sidx[38..40].copy_from_slice(&1u16.to_be_bytes());
+    // reference_type 0, the reference is to media
+    sidx[40..44].copy_from_slice(&(referenced_size & 0x7FFF_FFFF).to_be_bytes());
+    sidx[44..48].copy_from_slice(&(duration.clamp(0, u32::MAX as i64) as u32).to_be_bytes());
+    if starts_with_sap {
+        // starts_with_SAP, SAP type 1 and no SAP delta time
+        sidx[48] = 0x90;
+    }
+    writer.write_all(&sidx)?;
+    Ok(52)
+}
+
+pub(crate) fn write_checksum_uuid<Writer>(crc: u32, writer: &mut Writer) -> Result<u64, Error>
+where
+    Writer: Write,
+{
+    writer.write_all(b"\x00\x00\x00\x1cuuid")?;
+    writer.write_all(&FRAGMENT_CHECKSUM_UUID)?;
+    writer.write_all(&crc.to_be_bytes())?;
+    Ok(28)
+}
+
+fn write_tfra<Writer>(track: &Track, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"tfra", {
+        // version 1 & flag
+        cursor.write_all(&[0x01, 0x00, 0x00, 0x00])?;
+        cursor.write_all(&track.id.to_be_bytes())?;
+        // traf, trun and sample numbers are 1 byte each
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(&(track.random_access_points.len() as u32).to_be_bytes())?;
+        for (time, moof_offset) in &track.random_access_points {
+            cursor.write_all(&time.to_be_bytes())?;
+            cursor.write_all(&moof_offset.to_be_bytes())?;
+            // each fragment has one traf with one trun, starting with the sync sample
+            cursor.write_all(&[0x01, 0x01, 0x01])?;
+        }
+    })
+}
+
+fn write_mfro<Writer>(mfra_size: u32, cursor: &mut Writer) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"mfro", {
+        cursor.write_all(&[0x00; 4])?;
+        cursor.write_all(&mfra_size.to_be_bytes())?;
+    })
+}
+
+/// Writes the mfra box indexing the fragments that start with a sync sample
+///
+/// A tfra box is written for each track with random access points, the mfro box at
+/// the end gives the size of the mfra box so readers can find it from the end of the file.
+pub(crate) fn write_mfra<Writer>(
+    tracks: &[&Option<Track>],
+    cursor: &mut Writer,
+) -> Result<(), Error>
+where
+    Writer: Write + Seek,
+{
+    mp4_box!(cursor, b"mfra", {
+        let mfra_pos = cursor.stream_position()? - 8;
+        for track in tracks.iter().copied().flatten() {
+            if !track.random_access_points.is_empty() {
+                write_tfra(track, cursor)?;
+            }
+        }
+        // the mfro box takes 16 bytes
+        let mfra_size = cursor.stream_position()? + 16 - mfra_pos;
+        write_mfro(mfra_size as u32, cursor)?;
+    })
+}
+
+/// Writes a free box of the given size (at least 8 bytes), returns the size of the box
+pub(crate) fn write_free<Writer>(size: u32, writer: &mut Writer) -> Result<u64, Error>
+where
+    Writer: Write,
+{
+    writer.write_all(&size.to_be_bytes())?;
+    writer.write_all(b"free")?;
+    writer.write_all(&vec![0x00; size as usize - 8])?;
+    Ok(size as u64)
+}
+
+/// Estimates the size of a moov box holding the sample tables of `sample_count` samples
+///
+/// Each sample takes at most 40 bytes: its size, its time to sample and composition
+/// offset entries, the 64-bit offset of its chunk, its sync sample and dependency
+/// entries, with some slack. The rest of the box, with the decoder configurations and
+/// the application's boxes, fits in 4 KB for common streams.
+pub(crate) fn estimate_moov_size(sample_count: u32) -> u32 {
+    (4096 + 40 * sample_count as u64).min(u32::MAX as u64) as u32
+}
+
+/// Writes the styp box starting a media segment, returns the size of the box
+pub fn write_styp<Writer>(writer: &mut Writer) -> Result<u64, Error>
+where
+    Writer: Write,
+{
+    writer.write_all(b"\x00\x00\x00\x18stypmsdh\x00\x00\x00\x00msdhmsix")?;
+    Ok(24)
+}
+
+/// Writes the header of an mdat box with a 64-bit size of 16, returns the size of the header
+///
+/// The header must be replaced with `mdat_header` once the data has been written.
 pub fn write_mdat_header<Writer>(writer: &mut Writer) -> Result<u64, Error>
 where
     Writer: Write,
 {
-    writer.write_all(b"\x00\x00\x00\x01mdat\x00\x00\x00\x00\x00\x00\x00\x10")?;
+    writer.write_all(&mdat_header(16, false)?)?;
     Ok(16)
 }
+
+/// Builds the 16 bytes written in front of the data of an mdat box
+///
+/// Boxes that fit a 32-bit size get the common 8-byte header, preceded by an empty free
+/// box so the data does not move; larger ones get the 64-bit large size header.
+///
+/// # Arguments
+/// * `size` - The size of the mdat box with a 16-byte header
+/// * `wide` - Whether the empty box is a QuickTime wide box instead of a free box
+///
+/// # Returns
+/// * The header bytes, or an `InvalidInput` error if `size` is smaller than the 16-byte header
+///
+/// # Example
+/// ```
+/// use mp4e::boxes::mdat_header;
+///
+/// assert_eq!(&mdat_header(24, false).unwrap()[..], b"\0\0\0\x08free\0\0\0\x10mdat");
+/// assert_eq!(&mdat_header(24, true).unwrap()[..8], b"\0\0\0\x08wide");
+/// assert_eq!(&mdat_header((1 << 32) + 16, true).unwrap()[..8], b"\0\0\0\x01mdat");
+/// assert!(mdat_header(8, false).is_err());
+/// ```
+pub fn mdat_header(size: u64, wide: bool) -> Result<[u8; 16], Error> {
+    if size < 16 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "mdat size is smaller than its header",
+        ));
+    }
+    let mut header = [0u8; 16];
+    if size - 8 <= u32::MAX as u64 {
+        header[3] = 8;
+        header[4..8].copy_from_slice(if wide { b"wide" } else { b"free" });
+        header[8..12].copy_from_slice(&((size - 8) as u32).to_be_bytes());
+        header[12..].copy_from_slice(b"mdat");
+    } else {
+        header[..8].copy_from_slice(b"\x00\x00\x00\x01mdat");
+        header[8..].copy_from_slice(&size.to_be_bytes());
+    }
+    Ok(header)
+}
@@ -0,0 +1,99 @@
+use crate::mp4e::Mp4e;
+use crate::tee::Mp4eTee;
+use std::io::{Error, Seek, Write};
+
+/// Sink of elementary stream samples written into a container format
+///
+/// The encode loop of an application can be written against this trait, leaving the
+/// choice of the output container (a progressive or fragmented MP4 file, a tee of two
+/// muxers, or another container format) to the setup code. The tracks are set up on the
+/// concrete muxer before the samples are added.
+///
+/// # Example
+/// ```
+/// use std::io::{Cursor, Error};
+/// use mp4e::{Codec, Container, Mp4e};
+///
+/// fn record(output: &mut impl Container, frames: &[Vec<u8>]) -> Result<(), Error> {
+///     for frame in frames {
+///         output.add_video_sample(frame, 33)?;
+///     }
+///     output.finalize()
+/// }
+///
+/// let mut buffer = Vec::new();
+/// let mut writer = Cursor::new(&mut buffer);
+/// let mut muxer = Mp4e::new(&mut writer);
+/// muxer.set_video_track(1920, 1080, Codec::AVC);
+/// record(&mut muxer, &[vec![0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x0D]]).unwrap();
+/// ```
+pub trait Container {
+    /// Adds a video frame
+    ///
+    /// # Arguments
+    /// * `data` - The video frame data
+    /// * `duration` - The duration of the video frame in milliseconds
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn add_video_sample(&mut self, data: &[u8], duration: u32) -> Result<(), Error>;
+
+    /// Adds an audio frame
+    ///
+    /// # Arguments
+    /// * `data` - The audio frame data
+    /// * `samples` - The number of audio samples in this frame
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn add_audio_sample(&mut self, data: &[u8], samples: u32) -> Result<(), Error>;
+
+    /// Writes the remaining data and completes the output
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn finalize(&mut self) -> Result<(), Error>;
+}
+
+impl<'a, Writer> Container for Mp4e<'a, Writer>
+where
+    Writer: Write + Seek,
+{
+    /// Same as `Mp4e::encode_video`
+    fn add_video_sample(&mut self, data: &[u8], duration: u32) -> Result<(), Error> {
+        self.encode_video(data, duration)
+    }
+
+    /// Same as `Mp4e::encode_audio`
+    fn add_audio_sample(&mut self, data: &[u8], samples: u32) -> Result<(), Error> {
+        self.encode_audio(data, samples)
+    }
+
+    /// Same as `Mp4e::flush`
+    fn finalize(&mut self) -> Result<(), Error> {
+        self.flush()
+    }
+}
+
+impl<'a, 'b, First, Second> Container for Mp4eTee<'a, 'b, First, Second>
+where
+    First: Write + Seek,
+    Second: Write + Seek,
+{
+    /// Same as `Mp4eTee::encode_video`
+    fn add_video_sample(&mut self, data: &[u8], duration: u32) -> Result<(), Error> {
+        self.encode_video(data, duration)
+    }
+
+    /// Same as `Mp4eTee::encode_audio`
+    fn add_audio_sample(&mut self, data: &[u8], samples: u32) -> Result<(), Error> {
+        self.encode_audio(data, samples)
+    }
+
+    /// Flushes both muxers, the second one even if the first one fails
+    fn finalize(&mut self) -> Result<(), Error> {
+        let first = self.first_mut().flush();
+        let second = self.second_mut().flush();
+        first.and(second)
+    }
+}
@@ -3,17 +3,167 @@
 #![doc = include_str!("../README.md")]
 #![doc = include_str!("../LICENSE")]
 
-mod boxes;
+pub mod aac;
+pub mod boxes;
+mod container;
 mod mp4e;
 pub mod nalu;
+mod scheduler;
+mod tee;
 mod types;
 mod util;
-pub use mp4e::Mp4e;
-pub use types::Codec;
+mod validate;
+pub use boxes::FRAGMENT_CHECKSUM_UUID;
+pub use boxes::{TFRF_UUID, TFXD_UUID};
+pub use container::Container;
+pub use mp4e::{Mp4e, MuxerState, OnKeyframeGap, OnNalu};
+pub use scheduler::{ChunkScheduler, QueueState, SampleScheduler};
+pub use tee::Mp4eTee;
+pub use types::{
+    Codec, CompatibilityProfile, ImageFormat, MasteringDisplay, NaluFormat, ParameterSets,
+    SampleInfo, SampleType, SeiPolicy, SyncReport, Track, TrackType, UserBoxes, VideoProfile,
+};
+pub use util::{ms_to_timescale, timescale_to_ms};
 
 #[cfg(test)]
 mod tests {
 
+    /// Builds an Annex-B AVC access unit, with parameter sets before key frames
+    fn avc_frame(key: bool) -> Vec<u8> {
+        let mut frame = Vec::new();
+        if key {
+            frame.extend_from_slice(&[0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x0D, 0xF4, 0x01]);
+            frame.extend_from_slice(&[0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80]);
+            frame.extend_from_slice(&[0, 0, 0, 1, 0x65, 0x88, 0x84, 0x21, 0xA0]);
+        } else {
+            frame.extend_from_slice(&[0, 0, 0, 1, 0x41, 0x9A, 0x21, 0x6C]);
+        }
+        frame
+    }
+
+    /// Finds the payload of the first box with the given type
+    fn find_box<'a>(buffer: &'a [u8], box_type: &[u8; 4]) -> Option<&'a [u8]> {
+        let pos = buffer.windows(4).position(|w| w == box_type)?;
+        let size = u32::from_be_bytes([
+            buffer[pos - 4],
+            buffer[pos - 3],
+            buffer[pos - 2],
+            buffer[pos - 1],
+        ]) as usize;
+        Some(&buffer[pos + 4..pos - 4 + size])
+    }
+
+    /// In-memory output shared by its clones, counting the write calls
+    ///
+    /// Writes past the capacity fail as on a full disk, and the bytes of the dropped range
+    /// are not kept, so that outputs larger than the memory can be written.
+    #[derive(Clone)]
+    struct MockWriter {
+        output: std::rc::Rc<std::cell::RefCell<MockOutput>>,
+    }
+
+    struct MockOutput {
+        data: Vec<u8>,
+        pos: u64,
+        len: u64,
+        calls: usize,
+        capacity: u64,
+        dropped: std::ops::Range<u64>,
+    }
+
+    impl Default for MockWriter {
+        fn default() -> Self {
+            MockWriter::new(u64::MAX, 0..0)
+        }
+    }
+
+    impl MockWriter {
+        fn new(capacity: u64, dropped: std::ops::Range<u64>) -> Self {
+            let output = MockOutput {
+                data: Vec::new(),
+                pos: 0,
+                len: 0,
+                calls: 0,
+                capacity,
+                dropped,
+            };
+            MockWriter {
+                output: std::rc::Rc::new(std::cell::RefCell::new(output)),
+            }
+        }
+
+        /// Gets the bytes kept, without the dropped range
+        fn data(&self) -> Vec<u8> {
+            self.output.borrow().data.clone()
+        }
+
+        /// Gets the number of write calls
+        fn calls(&self) -> usize {
+            self.output.borrow().calls
+        }
+    }
+
+    impl MockOutput {
+        fn put(&mut self, buf: &[u8]) -> std::io::Result<()> {
+            let end = self.pos + buf.len() as u64;
+            if end > self.capacity {
+                return Err(std::io::Error::other("no space left on device"));
+            }
+            let (pos, dropped, kept) = (self.pos, self.dropped.clone(), &mut self.data);
+            let mut store = |from: u64, to: u64, index: u64| {
+                if from < to {
+                    let data = &buf[(from - pos) as usize..(to - pos) as usize];
+                    let index = index as usize;
+                    if kept.len() < index + data.len() {
+                        kept.resize(index + data.len(), 0);
+                    }
+                    kept[index..][..data.len()].copy_from_slice(data);
+                }
+            };
+            // The bytes before and after the dropped range are kept
+            store(pos, end.min(dropped.start), pos);
+            let from = pos.max(dropped.end);
+            store(from, end, from - (dropped.end - dropped.start));
+            self.pos = end;
+            self.len = self.len.max(end);
+            Ok(())
+        }
+    }
+
+    impl std::io::Write for MockWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut output = self.output.borrow_mut();
+            output.calls += 1;
+            output.put(buf)?;
+            Ok(buf.len())
+        }
+
+        fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+            let mut output = self.output.borrow_mut();
+            output.calls += 1;
+            for buf in bufs {
+                output.put(buf)?;
+            }
+            Ok(bufs.iter().map(|buf| buf.len()).sum())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl std::io::Seek for MockWriter {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            let mut output = self.output.borrow_mut();
+            output.pos = match pos {
+                std::io::SeekFrom::Start(pos) => pos,
+                std::io::SeekFrom::End(offset) => (output.len as i64 + offset) as u64,
+                std::io::SeekFrom::Current(offset) => (output.pos as i64 + offset) as u64,
+            };
+            Ok(output.pos)
+        }
+    }
+
     #[test]
     fn parse_nalu_test() {
         use crate::nalu::split_nalu;
@@ -31,4 +181,3577 @@ mod tests {
         assert!(iter.next().unwrap().eq(&nalu1[3..]));
         assert!(iter.next().is_none());
     }
+
+    #[test]
+    fn tee_test() {
+        use crate::{Codec, Mp4e, Mp4eTee};
+        use std::io::Cursor;
+        let mut file = Cursor::new(Vec::new());
+        let mut socket = Vec::new();
+        let mut recorder = Mp4e::new(&mut file);
+        recorder.set_video_track(320, 240, Codec::AVC);
+        let mut live = Mp4e::new_with_fragment(&mut socket);
+        live.set_video_track(320, 240, Codec::AVC);
+
+        let mut tee = Mp4eTee::new(recorder, live);
+        for i in 0..3 {
+            tee.encode_video(&avc_frame(i == 0), 33).unwrap();
+        }
+        let (mut recorder, _) = tee.into_inner();
+        recorder.flush().unwrap();
+
+        let file = file.into_inner();
+        let stsz = find_box(&file, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &3u32.to_be_bytes());
+        assert_eq!(socket.windows(4).filter(|w| w == b"moof").count(), 3);
+
+        // The second muxer gets the frames even when the first one fails
+        let mut full = MockWriter::new(0, 0..0);
+        let mut socket = Vec::new();
+        let mut recorder = Mp4e::new(&mut full);
+        recorder.set_video_track(320, 240, Codec::AVC);
+        let mut live = Mp4e::new_with_fragment(&mut socket);
+        live.set_video_track(320, 240, Codec::AVC);
+        let mut tee = Mp4eTee::new(recorder, live);
+        for i in 0..3 {
+            assert!(tee.encode_video(&avc_frame(i == 0), 33).is_err());
+        }
+        drop(tee);
+        assert_eq!(socket.windows(4).filter(|w| w == b"moof").count(), 3);
+    }
+
+    #[test]
+    fn container_test() {
+        use crate::{Codec, Container, Mp4e, Mp4eTee};
+        use std::io::Cursor;
+        // The encode loop only knows the container trait
+        fn encode(output: &mut impl Container) {
+            for i in 0..3 {
+                output.add_video_sample(&avc_frame(i == 0), 33).unwrap();
+                output.add_audio_sample(&[0x21, 0x10, 0x04], 1024).unwrap();
+            }
+            output.finalize().unwrap();
+        }
+        fn muxer(writer: &mut Cursor<Vec<u8>>) -> Mp4e<'_, Cursor<Vec<u8>>> {
+            let mut muxer = Mp4e::new(writer);
+            muxer.set_create_time(0);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_audio_track(48000, 2, Codec::AACLC);
+            muxer
+        }
+
+        let mut direct = Cursor::new(Vec::new());
+        let mut mp4e = muxer(&mut direct);
+        for i in 0..3 {
+            mp4e.encode_video(&avc_frame(i == 0), 33).unwrap();
+            mp4e.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        }
+        mp4e.flush().unwrap();
+        drop(mp4e);
+        let mut generic = Cursor::new(Vec::new());
+        encode(&mut muxer(&mut generic));
+        assert_eq!(generic.get_ref(), direct.get_ref());
+
+        let (mut first, mut second) = (Cursor::new(Vec::new()), Cursor::new(Vec::new()));
+        encode(&mut Mp4eTee::new(muxer(&mut first), muxer(&mut second)));
+        assert_eq!(first.get_ref(), direct.get_ref());
+        assert_eq!(second.get_ref(), direct.get_ref());
+    }
+
+    #[test]
+    fn codec_names_test() {
+        use crate::{Codec, Mp4e};
+        let names = [
+            "avc",
+            "H264",
+            "hevc",
+            "h.265",
+            "aac_lc",
+            "AAC",
+            "aac-main",
+            "aac_ssr",
+            "aac_ltp",
+            "he_aac",
+            "HE-AAC-v2",
+            "opus",
+            "vorbis",
+            "cea608",
+            "mjpeg",
+            "mp3",
+            "avc3",
+            "Opus",
+        ];
+        let codecs: Vec<Codec> = names.iter().map(|name| name.parse().unwrap()).collect();
+        assert!(matches!(codecs[1], Codec::AVC));
+        assert!(matches!(codecs[3], Codec::HEVC));
+        assert!(matches!(codecs[10], Codec::HEAACV2));
+        assert!(matches!(codecs[16], Codec::AVC));
+        assert!(matches!(codecs[17], Codec::OPUS));
+        let err = "h266".parse::<Codec>().err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert!("".parse::<Codec>().is_err());
+
+        // The codecs with a code of their own come back from it
+        for codec in codecs.iter() {
+            if let Some(fourcc) = codec.to_fourcc() {
+                let parsed = Codec::from_fourcc(&fourcc).unwrap();
+                assert_eq!(parsed.to_fourcc(), Some(fourcc));
+            }
+        }
+        assert_eq!(
+            codecs
+                .iter()
+                .filter(|codec| codec.to_fourcc().is_none())
+                .count(),
+            7
+        );
+
+        // The code is the sample entry written by the muxer
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.flush().unwrap();
+        let stsd = find_box(&buffer, b"stsd").unwrap();
+        assert_eq!(
+            Some([stsd[12], stsd[13], stsd[14], stsd[15]]),
+            Codec::AVC.to_fourcc()
+        );
+    }
+
+    #[test]
+    fn esds_bitrate_test() {
+        use crate::{Codec, Mp4e};
+        use std::io::Cursor;
+        let mut buffer = Vec::new();
+        let mut writer = Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        for _ in 0..100 {
+            muxer.encode_audio(&[0; 400], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        let esds = find_box(&buffer, b"esds").unwrap();
+        assert_eq!(esds[11], 0x40);
+        let avg_bitrate = u32::from_be_bytes([esds[20], esds[21], esds[22], esds[23]]);
+        assert_eq!(avg_bitrate, 400 * 8 * 48000 / 1024);
+
+        // A burst in the last second sets the maximum bitrate
+        let mut buffer = Vec::new();
+        let mut writer = Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        for i in 0..87 {
+            let size = if i < 47 { 400 } else { 2000 };
+            muxer.encode_audio(&vec![0; size], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        let esds = find_box(&buffer, b"esds").unwrap();
+        let max_bitrate = u32::from_be_bytes([esds[16], esds[17], esds[18], esds[19]]);
+        assert_eq!(max_bitrate, 40 * 2000 * 8);
+    }
+
+    #[test]
+    fn mdat_nalu_prefix_test() {
+        let mut buffer = Vec::new();
+        let size =
+            crate::boxes::write_mdat(&[&[0x06, 0x05], &[0x65, 0x88, 0x84]], true, &mut buffer)
+                .unwrap();
+        assert_eq!(size, 21);
+        assert_eq!(
+            buffer,
+            [
+                0, 0, 0, 21, b'm', b'd', b'a', b't', 0, 0, 0, 2, 0x06, 0x05, 0, 0, 0, 3, 0x65,
+                0x88, 0x84
+            ]
+        );
+    }
+
+    #[test]
+    fn fragment_sample_per_frame_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        // A P picture made of two slices
+        let mut frame = avc_frame(false);
+        frame.extend_from_slice(&[0, 0, 0, 1, 0x41, 0x4A, 0x21, 0x6C]);
+        muxer.encode_video(&frame, 33).unwrap();
+
+        assert_eq!(buffer.windows(4).filter(|w| w == b"moof").count(), 2);
+        let mdat = &buffer[buffer.len() - 24..];
+        assert_eq!(&mdat[..8], &[0, 0, 0, 24, b'm', b'd', b'a', b't']);
+        assert_eq!(&mdat[8..12], &[0, 0, 0, 4]);
+        assert_eq!(&mdat[16..20], &[0, 0, 0, 4]);
+    }
+
+    #[test]
+    fn sei_policy_test() {
+        use crate::{Codec, Mp4e, SeiPolicy};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_sei_policy(SeiPolicy::KeepHdrOnly);
+        // User data unregistered SEI is dropped, registered user data (captions) is kept
+        let key_frame = avc_frame(true);
+        let mut frame = key_frame[..18].to_vec();
+        frame.extend_from_slice(&[0, 0, 0, 1, 0x06, 0x05, 0x01, 0x00, 0x80]);
+        frame.extend_from_slice(&[0, 0, 0, 1, 0x06, 0x04, 0x01, 0xB5, 0x80]);
+        frame.extend_from_slice(&key_frame[18..]);
+        muxer.encode_video(&frame, 33).unwrap();
+
+        let mdat = &buffer[buffer.len() - 26..];
+        assert_eq!(&mdat[..8], &[0, 0, 0, 26, b'm', b'd', b'a', b't']);
+        assert_eq!(&mdat[12..14], &[0x06, 0x04]);
+        assert_eq!(mdat[21], 0x65);
+    }
+
+    #[test]
+    fn caption_track_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_caption_track();
+        let key_frame = avc_frame(true);
+        let mut frame = key_frame[..18].to_vec();
+        frame.extend_from_slice(&[0, 0, 0, 1, 0x06, 0x04, 0x11]);
+        frame.extend_from_slice(&[
+            0xB5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03, 0xC2, 0xFF, 0xFC, 0x94, 0x2C, 0xFD,
+            0x80, 0x80, 0xFF, 0x80,
+        ]);
+        frame.extend_from_slice(&key_frame[18..]);
+        muxer.encode_video(&frame, 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.flush().unwrap();
+
+        assert!(find_box(&buffer, b"c608").is_some());
+        assert_eq!(&find_box(&buffer, b"cdat").unwrap()[..2], &[0x94, 0x2C]);
+        assert_eq!(&find_box(&buffer, b"cdt2").unwrap()[..2], &[0x80, 0x80]);
+        assert!(buffer.windows(4).any(|w| w == b"clcp"));
+    }
+
+    #[test]
+    fn push_raw_sample_test() {
+        use crate::{Codec, Mp4e, SampleInfo, TrackType};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        // A P frame encoded elsewhere, already length-prefixed
+        let sample = SampleInfo {
+            random_access: false,
+            offset: 0,
+            sample_size: 0,
+            sample_delta: 2970,
+            sample_ct_offset: 0,
+            description_index: 1,
+            temporal_id: 0,
+        };
+        muxer
+            .push_raw_sample(
+                TrackType::Video,
+                &[0, 0, 0, 4, 0x41, 0x9A, 0x21, 0x6C],
+                sample,
+            )
+            .unwrap();
+        muxer.flush().unwrap();
+
+        let stsz = find_box(&buffer, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &[0, 0, 0, 2]);
+        assert_eq!(&stsz[12..20], &[0, 0, 0, 9, 0, 0, 0, 8]);
+        let stco = find_box(&buffer, b"stco").unwrap();
+        let offset = u32::from_be_bytes([stco[12], stco[13], stco[14], stco[15]]) as usize;
+        assert_eq!(
+            &buffer[offset..offset + 8],
+            &[0, 0, 0, 4, 0x41, 0x9A, 0x21, 0x6C]
+        );
+    }
+
+    #[test]
+    fn slice_continuation_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        // Second slice of the same picture, first_mb_in_slice = 120
+        muxer
+            .encode_video(&[0, 0, 0, 1, 0x41, 0x03, 0xC8, 0x21, 0x6C], 33)
+            .unwrap();
+        muxer.flush().unwrap();
+
+        let stsz = find_box(&buffer, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &[0, 0, 0, 2]);
+        assert_eq!(&stsz[16..20], &[0, 0, 0, 17]);
+    }
+
+    #[test]
+    fn rewrite_fragment_test() {
+        use crate::mp4e::find_checksum_box;
+        use crate::{Codec, Mp4e, FRAGMENT_CHECKSUM_UUID};
+        use std::io::{Cursor, ErrorKind};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_record_fragments(true);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+
+        muxer
+            .rewrite_fragment(1, &[0, 0, 0, 5, 0x65, 1, 2, 3, 4])
+            .unwrap();
+        // The sizes in the boxes of the fragment are not rewritten
+        let error = muxer.rewrite_fragment(2, &[0, 0, 0, 1, 0x41]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(muxer.rewrite_fragment(3, &[]).is_err());
+        // Later fragments are appended after the patched one
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+
+        assert_eq!(buffer.windows(4).filter(|w| w == b"moof").count(), 3);
+        let pos = buffer.windows(4).position(|w| w == b"mdat").unwrap();
+        assert_eq!(&buffer[pos + 4..pos + 13], &[0, 0, 0, 5, 0x65, 1, 2, 3, 4]);
+
+        // The checksum box is found past other boxes, not past the next fragment
+        let mut checksum_box = b"\x00\x00\x00\x1cuuid".to_vec();
+        checksum_box.extend_from_slice(&FRAGMENT_CHECKSUM_UUID);
+        checksum_box.extend_from_slice(&[0; 4]);
+        let free = b"\x00\x00\x00\x0afree\x00\x00";
+        let moof = b"\x00\x00\x00\x08moof";
+        let output = [&free[..], &checksum_box].concat();
+        assert_eq!(
+            find_checksum_box(&mut Cursor::new(output), 0).unwrap(),
+            Some(10)
+        );
+        let output = [&moof[..], &checksum_box].concat();
+        assert_eq!(
+            find_checksum_box(&mut Cursor::new(output), 0).unwrap(),
+            None
+        );
+        assert_eq!(find_checksum_box(&mut Cursor::new(free), 0).unwrap(), None);
+    }
+
+    #[test]
+    fn all_intra_stss_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_all_intra(true);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.flush().unwrap();
+        assert!(find_box(&buffer, b"stss").is_none());
+
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.flush().unwrap();
+        assert_eq!(
+            &find_box(&buffer, b"stss").unwrap()[4..12],
+            &[0, 0, 0, 1, 0, 0, 0, 1]
+        );
+    }
+
+    #[test]
+    fn stsc_description_index_test() {
+        use crate::SampleInfo;
+        use std::convert::TryInto;
+        let samples: Vec<SampleInfo> = [1, 1, 2, 2, 1]
+            .iter()
+            .map(|&description_index| SampleInfo {
+                random_access: true,
+                offset: 0,
+                sample_size: 0,
+                sample_delta: 1024,
+                sample_ct_offset: 0,
+                description_index,
+                temporal_id: 0,
+            })
+            .collect();
+        let mut buffer = Vec::new();
+        let chunks = crate::boxes::sample_chunks(&samples, false);
+        crate::boxes::write_stsc(&chunks, false, &mut std::io::Cursor::new(&mut buffer)).unwrap();
+        let stsc = find_box(&buffer, b"stsc").unwrap();
+        assert_eq!(&stsc[4..8], &[0, 0, 0, 3]);
+        let entries: Vec<[u32; 3]> = stsc[8..]
+            .chunks(12)
+            .map(|entry| {
+                let at = |i: usize| u32::from_be_bytes(entry[i..i + 4].try_into().unwrap());
+                [at(0), at(4), at(8)]
+            })
+            .collect();
+        assert_eq!(entries, [[1, 1, 1], [3, 1, 2], [5, 1, 1]]);
+    }
+
+    #[test]
+    fn mjpeg_test() {
+        use crate::{Codec, Mp4e};
+        let image = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x00, 0x00, 0x01, 0xFF, 0xD9];
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(640, 480, Codec::MJPEG);
+        for _ in 0..3 {
+            muxer.encode_video(&image, 33).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        assert!(find_box(&buffer, b"jpeg").is_some());
+        assert!(find_box(&buffer, b"stss").is_none());
+        let stsz = find_box(&buffer, b"stsz").unwrap();
+        assert_eq!(&stsz[8..16], &[0, 0, 0, 3, 0, 0, 0, 10]);
+        // The images are written as they are, without NAL unit length prefixes
+        let mdat = buffer.windows(4).position(|w| w == b"mdat").unwrap();
+        assert_eq!(&buffer[mdat + 4..mdat + 14], &image);
+    }
+
+    #[test]
+    fn compact_sample_tables_test() {
+        use crate::{Codec, Mp4e};
+        let image = [0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x00, 0x00, 0x01, 0xFF, 0xD9];
+        let mux = |compact: bool| {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(640, 480, Codec::MJPEG);
+            muxer.set_video_frame_rate(30, 1).unwrap();
+            muxer.set_compact_sample_tables(compact);
+            // One hour at 30 fps
+            for _ in 0..108_000 {
+                muxer.encode_video(&image, 0).unwrap();
+            }
+            muxer.flush().unwrap();
+            muxer.validate().unwrap();
+            buffer
+        };
+        let moov_size = |buffer: &[u8]| {
+            let moov = buffer.windows(4).position(|w| w == b"moov").unwrap() - 4;
+            u32::from_be_bytes([
+                buffer[moov],
+                buffer[moov + 1],
+                buffer[moov + 2],
+                buffer[moov + 3],
+            ])
+        };
+        assert!(moov_size(&mux(false)) > 800_000);
+
+        let buffer = mux(true);
+        assert!(moov_size(&buffer) < 4096);
+        // One stts entry, a single sample size and one chunk holding every sample
+        let stts = find_box(&buffer, b"stts").unwrap();
+        assert_eq!(
+            &stts[4..16],
+            &[0, 0, 0, 1, 0, 1, 0xA5, 0xE0, 0, 0, 0x0B, 0xB8]
+        );
+        let stsz = find_box(&buffer, b"stsz").unwrap();
+        assert_eq!(&stsz[4..], &[0, 0, 0, 10, 0, 1, 0xA5, 0xE0]);
+        let stsc = find_box(&buffer, b"stsc").unwrap();
+        assert_eq!(
+            &stsc[4..],
+            &[0, 0, 0, 1, 0, 0, 0, 1, 0, 1, 0xA5, 0xE0, 0, 0, 0, 1]
+        );
+        let stco = find_box(&buffer, b"stco").unwrap();
+        assert_eq!(&stco[4..8], &[0, 0, 0, 1]);
+        let mdat = buffer.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+        assert_eq!(&stco[8..12], &(mdat as u32).to_be_bytes());
+    }
+
+    #[test]
+    fn next_track_id_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        // The first video track is replaced, leaving track ID 1 unused
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.flush().unwrap();
+
+        let mvhd = find_box(&buffer, b"mvhd").unwrap();
+        assert_eq!(&mvhd[mvhd.len() - 4..], &[0, 0, 0, 4]);
+    }
+
+    #[test]
+    fn heif_image_test() {
+        use crate::{Codec, Mp4e};
+        let mut image = vec![0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+        image.extend_from_slice(&[0, 0, 0, 1, 0x42, 0x01, 0x01, 0x01, 0x60, 0x00]);
+        image.extend_from_slice(&[0, 0, 0, 1, 0x44, 0x01, 0xC1, 0x72]);
+        image.extend_from_slice(&[0, 0, 0, 1, 0x26, 0x01, 0xAF, 0x09]);
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(1280, 720, Codec::HEVC);
+        muxer.encode_image(&image).unwrap();
+        muxer.flush().unwrap();
+        assert!(muxer.encode_image(&image).is_err());
+
+        assert_eq!(&buffer[4..12], b"ftypheic");
+        assert!(find_box(&buffer, b"moov").is_none());
+        assert!(find_box(&buffer, b"hvcC").is_some());
+        assert_eq!(
+            find_box(&buffer, b"ispe").unwrap(),
+            &[0, 0, 0, 0, 0, 0, 0x05, 0, 0, 0, 0x02, 0xD0]
+        );
+        // The extent of the item is the length-prefixed slice in the mdat box
+        let iloc = find_box(&buffer, b"iloc").unwrap();
+        let offset = u32::from_be_bytes([iloc[14], iloc[15], iloc[16], iloc[17]]) as usize;
+        assert_eq!(&iloc[18..22], &[0, 0, 0, 8]);
+        assert_eq!(&buffer[offset - 4..offset], b"mdat");
+        assert_eq!(&buffer[offset..], &[0, 0, 0, 4, 0x26, 0x01, 0xAF, 0x09]);
+    }
+
+    #[test]
+    fn legacy_profile_test() {
+        use crate::{Codec, CompatibilityProfile, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_compatibility_profile(CompatibilityProfile::Legacy);
+        muxer
+            .encode_video_with_pts(&avc_frame(true), 33, 33)
+            .unwrap();
+        assert!(muxer
+            .encode_video_with_pts(&avc_frame(false), 33, 99)
+            .is_err());
+        muxer
+            .encode_video_with_pts(&avc_frame(false), 33, 66)
+            .unwrap();
+        muxer.flush().unwrap();
+
+        assert!(find_box(&buffer, b"ctts").is_none());
+        assert!(find_box(&buffer, b"elst").is_none());
+        assert_eq!(&find_box(&buffer, b"stsz").unwrap()[8..12], &[0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn fragment_checksum_test() {
+        use crate::util::Crc32;
+        use crate::{Codec, Mp4e, FRAGMENT_CHECKSUM_UUID};
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.value(), 0xCBF43926);
+
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_fragment_checksum(true);
+        muxer.set_segment_index(true);
+        muxer.set_record_fragments(true);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer
+            .rewrite_fragment(2, &[0, 0, 0, 4, 0x41, 0x9A, 0x21, 0x6D])
+            .unwrap();
+
+        // Checks every fragment against the checksum box that follows it
+        let verify = |buffer: &[u8]| -> Vec<bool> {
+            let mut results = vec![];
+            let mut pos = 0;
+            let mut moof = 0;
+            while pos + 8 <= buffer.len() {
+                let size = u32::from_be_bytes([
+                    buffer[pos],
+                    buffer[pos + 1],
+                    buffer[pos + 2],
+                    buffer[pos + 3],
+                ]) as usize;
+                match &buffer[pos + 4..pos + 8] {
+                    b"moof" => moof = pos,
+                    b"uuid" => {
+                        assert_eq!(&buffer[pos + 8..pos + 24], &FRAGMENT_CHECKSUM_UUID);
+                        let mut crc = Crc32::new();
+                        crc.update(&buffer[moof..pos]);
+                        results.push(crc.value().to_be_bytes() == buffer[pos + 24..pos + 28]);
+                    }
+                    _ => {}
+                }
+                pos += size;
+            }
+            results
+        };
+        assert_eq!(verify(&buffer), [true, true]);
+        let last = buffer.len() - 29;
+        buffer[last] ^= 0x01;
+        assert_eq!(verify(&buffer), [true, false]);
+    }
+
+    #[test]
+    fn movie_timescale_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        assert!(muxer.set_movie_timescale(0).is_err());
+        muxer.set_movie_timescale(24000).unwrap();
+        for i in 0..3 {
+            muxer.encode_video(&avc_frame(i == 0), 33).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // 3 frames of 2970 ticks at 90kHz
+        let mvhd = find_box(&buffer, b"mvhd").unwrap();
+        assert_eq!(&mvhd[12..20], &[0, 0, 0x5D, 0xC0, 0, 0, 0x09, 0x48]);
+        let tkhd = find_box(&buffer, b"tkhd").unwrap();
+        assert_eq!(&tkhd[20..24], &[0, 0, 0x09, 0x48]);
+        let mdhd = find_box(&buffer, b"mdhd").unwrap();
+        assert_eq!(&mdhd[12..20], &[0, 1, 0x5F, 0x90, 0, 0, 0x22, 0xCE]);
+    }
+
+    #[test]
+    fn box_at_offset_test() {
+        use crate::boxes::write_moov;
+        use std::io::{Cursor, Seek, SeekFrom};
+        let mut boxes = Cursor::new(Vec::new());
+        write_moov(&[&None], 0, 1000, b"und", false, &mut boxes).unwrap();
+        let boxes = boxes.into_inner();
+
+        // The same nested boxes appended after existing data
+        let mut shifted = Cursor::new(vec![0xAA; 3]);
+        shifted.seek(SeekFrom::End(0)).unwrap();
+        write_moov(&[&None], 0, 1000, b"und", false, &mut shifted).unwrap();
+        let shifted = shifted.into_inner();
+        assert_eq!(&shifted[..3], &[0xAA; 3]);
+        assert_eq!(&shifted[3..], &boxes[..]);
+        assert_eq!(&boxes[..4], &(boxes.len() as u32).to_be_bytes());
+    }
+
+    #[test]
+    fn public_boxes_test() {
+        use crate::boxes::{write_ftyp, write_mdat, write_moof, write_moov};
+        use crate::{Codec, SampleType, Track, TrackType};
+        use std::io::Cursor;
+        let mut output = Cursor::new(Vec::new());
+        write_ftyp(&mut output).unwrap();
+        let track = Track {
+            width: 320,
+            height: 240,
+            ..Track::new(1, TrackType::Video, Codec::AVC, 90000)
+        };
+        let mut moov = Cursor::new(Vec::new());
+        write_moov(&[&Some(track)], 0, 1000, b"und", true, &mut moov).unwrap();
+        let track = Track::new(1, TrackType::Video, Codec::AVC, 90000);
+        let mut moof = Cursor::new(Vec::new());
+        write_moof(1, 8, 3000, &track, 0, SampleType::RandomAccess, &mut moof).unwrap();
+        let mut mdat = Vec::new();
+        write_mdat(&[&[0x65, 0x88, 0x84, 0x21]], true, &mut mdat).unwrap();
+
+        assert_eq!(&output.get_ref()[4..8], b"ftyp");
+        assert!(find_box(moov.get_ref(), b"mvex").is_some());
+        assert!(find_box(moov.get_ref(), b"tkhd").is_some());
+        assert_eq!(&moof.get_ref()[4..8], b"moof");
+        assert_eq!(mdat.len(), 16);
+    }
+
+    #[test]
+    fn take_segment_test() {
+        use crate::{Codec, Mp4e};
+        let mut output = Vec::new();
+        let mut muxer = Mp4e::new_with_fragment(&mut output);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_segmenting(true);
+        let mut segments = Vec::new();
+        for i in 0..5 {
+            muxer.encode_video(&avc_frame(i % 3 == 0), 33).unwrap();
+            while let Some(segment) = muxer.take_segment() {
+                segments.push(segment);
+            }
+        }
+        muxer.finish_segment().unwrap();
+        segments.extend(muxer.take_segment());
+        assert!(muxer.take_segment().is_none());
+        drop(muxer);
+        assert!(output.is_empty());
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!(&segments[0][4..8], b"ftyp");
+        assert!(find_box(&segments[0], b"moov").is_some());
+        for (segment, fragments) in segments[1..].iter().zip([3, 2].iter()) {
+            assert_eq!(&segment[4..8], b"styp");
+            let count = segment.windows(4).filter(|w| w == b"moof").count();
+            assert_eq!(count, *fragments);
+        }
+    }
+
+    #[test]
+    fn audio_fragment_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        for _ in 0..40 {
+            muxer.encode_audio(&[0x21; 10], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // Half a second at 48 kHz is reached with the 24th frame, flush writes the rest
+        let sample_counts: Vec<u32> = buffer
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"trun")
+            .map(|(pos, _)| u32::from_be_bytes(buffer[pos + 8..pos + 12].try_into().unwrap()))
+            .collect();
+        assert_eq!(sample_counts, [1, 24, 16]);
+        let moof_pos = buffer.windows(4).rposition(|w| w == b"moof").unwrap() - 4;
+        let moof_size = u32::from_be_bytes(buffer[moof_pos..moof_pos + 4].try_into().unwrap());
+        let mdat = &buffer[moof_pos + moof_size as usize..];
+        assert_eq!(&mdat[4..8], b"mdat");
+        assert_eq!(&mdat[..4], &(8 + 16 * 10u32).to_be_bytes());
+    }
+
+    #[test]
+    fn mfra_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        for i in 0..6 {
+            muxer.encode_video(&avc_frame(i % 3 == 0), 33).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // The mfro box at the end gives the size of the mfra box
+        let mfro = &buffer[buffer.len() - 16..];
+        assert_eq!(&mfro[4..8], b"mfro");
+        let mfra_size = u32::from_be_bytes(mfro[12..16].try_into().unwrap()) as usize;
+        let mfra = &buffer[buffer.len() - mfra_size..];
+        assert_eq!(&mfra[4..8], b"mfra");
+        let tfra = find_box(mfra, b"tfra").unwrap();
+        assert_eq!(&tfra[..4], &[1, 0, 0, 0]);
+        assert_eq!(&tfra[4..8], &1u32.to_be_bytes());
+        assert_eq!(&tfra[12..16], &2u32.to_be_bytes());
+        for (i, entry) in tfra[16..].chunks(19).enumerate() {
+            let time = u64::from_be_bytes(entry[..8].try_into().unwrap());
+            let offset = u64::from_be_bytes(entry[8..16].try_into().unwrap()) as usize;
+            assert_eq!(time, i as u64 * 3 * 2970);
+            assert_eq!(&buffer[offset + 4..offset + 8], b"moof");
+            assert_eq!(&entry[16..], &[1, 1, 1]);
+        }
+    }
+
+    #[test]
+    fn validate_test() {
+        use crate::validate::validate_structure;
+        use crate::{Codec, Mp4e};
+        use std::io::Cursor;
+        let mut buffer = Vec::new();
+        let mut writer = Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        for i in 0..6 {
+            muxer.encode_video(&avc_frame(i % 3 == 0), 33).unwrap();
+            muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+        drop(muxer);
+
+        // One sample more in stsz than in the other tables
+        let mut corrupted = buffer.clone();
+        let pos = corrupted.windows(4).position(|w| w == b"stsz").unwrap();
+        corrupted[pos + 15] += 1;
+        assert!(validate_structure(&mut Cursor::new(&corrupted)).is_err());
+
+        // Sync sample beyond the last sample
+        let mut corrupted = buffer.clone();
+        let pos = corrupted.windows(4).position(|w| w == b"stss").unwrap();
+        corrupted[pos + 15] = 7;
+        assert!(validate_structure(&mut Cursor::new(&corrupted)).is_err());
+
+        // Truncated output
+        let truncated = &buffer[..buffer.len() - 1];
+        assert!(validate_structure(&mut Cursor::new(truncated)).is_err());
+
+        // Sample counts and offsets of a crafted file are reported, they do not allocate
+        // their tables or overflow
+        for (sample_size, sample_count) in [(0u32, u32::MAX), (1, u32::MAX)] {
+            let mut corrupted = buffer.clone();
+            let pos = corrupted.windows(4).position(|w| w == b"stsz").unwrap();
+            corrupted[pos + 8..pos + 12].copy_from_slice(&sample_size.to_be_bytes());
+            corrupted[pos + 12..pos + 16].copy_from_slice(&sample_count.to_be_bytes());
+            assert!(validate_structure(&mut Cursor::new(&corrupted)).is_err());
+        }
+        let mut corrupted = buffer.clone();
+        let pos = corrupted.windows(4).position(|w| w == b"stco").unwrap();
+        corrupted[pos + 12..pos + 16].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(validate_structure(&mut Cursor::new(&corrupted)).is_err());
+
+        let mut buffer = Vec::new();
+        let mut writer = Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+    }
+
+    #[test]
+    fn composition_offset_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        // I P B B, the I frame is presented two frames after its decode time
+        for (i, pts) in [99, 198, 132, 165].iter().enumerate() {
+            muxer
+                .encode_video_with_pts(&avc_frame(i == 0), 33, *pts)
+                .unwrap();
+        }
+        muxer.flush().unwrap();
+
+        let ctts = find_box(&buffer, b"ctts").unwrap();
+        let entry_count = u32::from_be_bytes(ctts[4..8].try_into().unwrap()) as usize;
+        let offsets: Vec<i32> = ctts[8..8 + entry_count * 8]
+            .chunks(8)
+            .map(|entry| i32::from_be_bytes(entry[4..8].try_into().unwrap()))
+            .collect();
+        assert_eq!(offsets, [2970, 8910, 0]);
+        let elst = find_box(&buffer, b"elst").unwrap();
+        assert_eq!(&elst[4..8], &1u32.to_be_bytes());
+        // media time 0 at normal rate
+        assert_eq!(&elst[12..20], &[0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn composition_shift_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_composition_shift_box(true).unwrap();
+        for (i, pts) in [99, 198, 132, 165].iter().enumerate() {
+            muxer
+                .encode_video_with_pts(&avc_frame(i == 0), 33, *pts)
+                .unwrap();
+        }
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+
+        let cslg = find_box(&buffer, b"cslg").unwrap();
+        let values: Vec<i32> = cslg[4..24]
+            .chunks(4)
+            .map(|value| i32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+            .collect();
+        // No shift, offsets 0 to 8910, presented from 2970 until the end of the last frame
+        assert_eq!(values, [0, 0, 8910, 2970, 14850]);
+    }
+
+    #[test]
+    fn inband_parameter_sets_test() {
+        use crate::{Codec, Mp4e};
+        let mut idr = vec![0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x42, 0x01, 0x01, 0x01, 0x60, 0x00]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x44, 0x01, 0xC1, 0x72]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x26, 0x01, 0xAF, 0x09]);
+        let trail = [0, 0, 0, 1, 0x02, 0x01, 0xD0, 0x11];
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(1280, 720, Codec::HEVC);
+        muxer.set_inband_parameter_sets(true).unwrap();
+        for frame in [&idr[..], &trail, &idr[..]].iter() {
+            muxer.encode_video(frame, 33).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        assert!(find_box(&buffer, b"hev1").is_some());
+        assert!(find_box(&buffer, b"hvc1").is_none());
+        assert!(find_box(&buffer, b"hvcC").is_some());
+        // Both IDR samples start with the VPS, SPS and PPS
+        let mdats: Vec<usize> = buffer
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"mdat")
+            .map(|(pos, _)| pos + 4)
+            .collect();
+        assert_eq!(mdats.len(), 3);
+        for pos in [mdats[0], mdats[2]].iter() {
+            assert_eq!(&buffer[*pos..*pos + 6], &[0, 0, 0, 6, 0x40, 0x01]);
+        }
+        assert_eq!(&buffer[mdats[1]..mdats[1] + 6], &[0, 0, 0, 4, 0x02, 0x01]);
+
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        assert!(muxer.set_inband_parameter_sets(true).is_err());
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_inband_parameter_sets(true).unwrap();
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.flush().unwrap();
+        assert!(find_box(&buffer, b"avc3").is_some());
+        assert!(find_box(&buffer, b"avcC").is_some());
+        // The mdat header has a 32-bit size
+        let pos = buffer.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+        assert_eq!(&buffer[pos..pos + 6], &[0, 0, 0, 6, 0x67, 0x42]);
+
+        // Live AVC repeating its parameter sets at each IDR frame
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_inband_parameter_sets(true).unwrap();
+        for i in 0..4 {
+            muxer.encode_video(&avc_frame(i % 2 == 0), 33).unwrap();
+        }
+        muxer.flush().unwrap();
+        assert!(find_box(&buffer, b"avc3").is_some());
+        let samples: Vec<&[u8]> = buffer
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"mdat")
+            .map(|(pos, _)| &buffer[pos + 4..pos + 9])
+            .collect();
+        assert_eq!(samples.len(), 4);
+        for (i, sample) in samples.iter().enumerate() {
+            let first_nalu = if i % 2 == 0 { 0x67 } else { 0x41 };
+            assert_eq!(sample[4], first_nalu, "sample {}", i);
+        }
+    }
+
+    #[test]
+    fn flush_to_last_keyframe_test() {
+        use crate::validate::validate_structure;
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        use std::io::Cursor;
+        // Older moov boxes stay in the file as free boxes, count the samples of the moov box
+        let sample_count = |buffer: &[u8]| {
+            let mut pos = 0;
+            loop {
+                let size = u32::from_be_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
+                if &buffer[pos + 4..pos + 8] == b"moov" {
+                    let stsz = find_box(&buffer[pos..pos + size], b"stsz").unwrap();
+                    return u32::from_be_bytes(stsz[8..12].try_into().unwrap());
+                }
+                pos += size;
+            }
+        };
+
+        let mut buffer = Vec::new();
+        let mut writer = Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        for i in 0..5 {
+            muxer.encode_video(&avc_frame(i % 3 == 0), 33).unwrap();
+        }
+        muxer.flush_to_last_keyframe().unwrap();
+        drop(muxer);
+        // The second GOP is incomplete, only the first one is indexed
+        assert_eq!(sample_count(&buffer), 3);
+        validate_structure(&mut Cursor::new(&buffer)).unwrap();
+
+        // The output is a valid file after each call and while encoding goes on
+        let record = |frames: usize, finish: bool| {
+            let mut buffer = Vec::new();
+            let mut writer = Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_audio_track(48000, 2, Codec::AACLC);
+            for i in 0..frames {
+                muxer.encode_video(&avc_frame(i % 3 == 0), 33).unwrap();
+                muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+                if i == 4 || i == 7 || i == 10 {
+                    muxer.flush_to_last_keyframe().unwrap();
+                }
+            }
+            if finish {
+                muxer.flush().unwrap();
+            }
+            drop(muxer);
+            validate_structure(&mut Cursor::new(&buffer)).unwrap();
+            sample_count(&buffer)
+        };
+        let indexed: Vec<u32> = (5..=11).map(|frames| record(frames, false)).collect();
+        assert_eq!(indexed, [3, 3, 3, 6, 6, 6, 9]);
+        assert_eq!(record(12, true), 12);
+        assert!(Mp4e::new_with_fragment(&mut Cursor::new(Vec::new()))
+            .flush_to_last_keyframe()
+            .is_err());
+    }
+
+    #[test]
+    fn audio_duration_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        for i in 0..10 {
+            if i < 3 {
+                muxer.encode_video(&avc_frame(i == 0), 33).unwrap();
+            }
+            muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // 10240 audio samples at 48 kHz outlast 99 ms of video
+        let mvhd = find_box(&buffer, b"mvhd").unwrap();
+        assert_eq!(&mvhd[12..20], &[0, 0, 0x03, 0xE8, 0, 0, 0, 213]);
+    }
+
+    #[test]
+    fn avcc_to_annexb_test() {
+        use crate::nalu::{avcc_to_annexb, split_nalu};
+        use crate::{Codec, Mp4e};
+        let frame = avc_frame(true);
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&frame, 33).unwrap();
+        muxer.flush().unwrap();
+
+        // The sample holds the IDR slice, the parameter sets went to the avcC box
+        let sample = find_box(&buffer, b"mdat").unwrap();
+        let annexb = avcc_to_annexb(sample, 4).unwrap();
+        let nalus: Vec<&[u8]> = split_nalu(&annexb).collect();
+        let expected: Vec<&[u8]> = split_nalu(&frame).skip(2).collect();
+        assert_eq!(nalus, expected);
+        assert!(avcc_to_annexb(&sample[..sample.len() - 1], 4).is_err());
+    }
+
+    #[test]
+    fn video_frame_rate_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        assert!(muxer.set_video_frame_rate(0, 1).is_err());
+        // Ten minutes at 29.97 fps
+        muxer.set_video_frame_rate(30000, 1001).unwrap();
+        let key = avc_frame(true);
+        let frame = avc_frame(false);
+        for i in 0..17982 {
+            let data = if i % 60 == 0 { &key } else { &frame };
+            muxer.encode_video(data, 0).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        let mdhd = find_box(&buffer, b"mdhd").unwrap();
+        assert_eq!(&mdhd[12..16], &90000u32.to_be_bytes());
+        assert_eq!(&mdhd[16..20], &(17982u32 * 3003).to_be_bytes());
+        let stts = find_box(&buffer, b"stts").unwrap();
+        assert_eq!(
+            &stts[4..16],
+            &[0, 0, 0, 1, 0, 0, 0x46, 0x3E, 0, 0, 0x0B, 0xBB]
+        );
+    }
+
+    #[test]
+    fn video_duration_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        let key = avc_frame(true);
+        let frame = avc_frame(false);
+        for i in 0..100000 {
+            let data = if i % 60 == 0 { &key } else { &frame };
+            muxer.encode_video(data, 33).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // The stts deltas add up to 3300 seconds
+        let mdhd = find_box(&buffer, b"mdhd").unwrap();
+        assert_eq!(&mdhd[16..20], &(3300u32 * 90000).to_be_bytes());
+    }
+
+    #[test]
+    fn capture_timestamp_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_video_ts(&avc_frame(true), 1_000_000).unwrap();
+        muxer.encode_video_ts(&avc_frame(false), 1_033_000).unwrap();
+        // Audio starts 50 ms after the video
+        muxer
+            .encode_audio_ts(&[0x21, 0x10, 0x04], 1_050_000, 1024)
+            .unwrap();
+        muxer
+            .encode_audio_ts(&[0x21, 0x10, 0x04], 1_071_333, 1024)
+            .unwrap();
+        muxer.encode_video_ts(&avc_frame(false), 1_070_000).unwrap();
+        muxer.encode_video_ts(&avc_frame(false), 1_100_000).unwrap();
+        muxer.flush().unwrap();
+
+        // Irregular intervals, the last frame lasts as long as the one before
+        let stts = find_box(&buffer, b"stts").unwrap();
+        let deltas: Vec<u32> = stts[8..]
+            .chunks(8)
+            .map(|entry| u32::from_be_bytes(entry[4..8].try_into().unwrap()))
+            .collect();
+        assert_eq!(deltas, [2970, 3330, 2700]);
+        assert_eq!(&stts[24..28], &2u32.to_be_bytes());
+        // Only the audio track has an edit list, starting with a 50 ms empty edit
+        let elst = find_box(&buffer, b"elst").unwrap();
+        assert_eq!(&elst[4..8], &2u32.to_be_bytes());
+        assert_eq!(
+            &elst[8..20],
+            &[0, 0, 0, 50, 0xFF, 0xFF, 0xFF, 0xFF, 0, 1, 0, 0]
+        );
+        // 2048 samples at 48 kHz last 42.67 ms
+        assert_eq!(&elst[20..24], &43u32.to_be_bytes());
+    }
+
+    #[test]
+    fn pts_only_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video_pts_only(&avc_frame(true), 1000).unwrap();
+        for pts in [1040, 1080, 1100, 1140] {
+            muxer.encode_video_pts_only(&avc_frame(false), pts).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // Each frame lasts until the next one, the last one as long as the average
+        let stts = find_box(&buffer, b"stts").unwrap();
+        let deltas: Vec<(u32, u32)> = stts[8..]
+            .chunks(8)
+            .map(|entry| {
+                (
+                    u32::from_be_bytes(entry[..4].try_into().unwrap()),
+                    u32::from_be_bytes(entry[4..8].try_into().unwrap()),
+                )
+            })
+            .collect();
+        assert_eq!(deltas, [(2, 3600), (1, 1800), (1, 3600), (1, 3150)]);
+    }
+
+    #[test]
+    fn pts_only_reordered_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        // I P B B P B B in decode order
+        for (i, pts) in [0, 120, 40, 80, 240, 160, 200].iter().enumerate() {
+            muxer
+                .encode_video_pts_only(&avc_frame(i == 0), *pts)
+                .unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // The decode times are the presentation times in order, 40 ms apart
+        let stts = find_box(&buffer, b"stts").unwrap();
+        assert_eq!(&stts[8..], [0, 0, 0, 7, 0, 0, 0x0e, 0x10]);
+        // The composition offsets are the presentation minus the decode times, shifted
+        // so the B frames have none
+        let ctts = find_box(&buffer, b"ctts").unwrap();
+        let offsets: Vec<(u32, i32)> = ctts[8..]
+            .chunks(8)
+            .map(|entry| {
+                (
+                    u32::from_be_bytes(entry[..4].try_into().unwrap()),
+                    i32::from_be_bytes(entry[4..8].try_into().unwrap()),
+                )
+            })
+            .collect();
+        assert_eq!(offsets, [(1, 3600), (1, 10800), (2, 0), (1, 10800), (2, 0)]);
+    }
+
+    #[test]
+    fn timescale_conversion_test() {
+        use crate::{ms_to_timescale, timescale_to_ms, Codec, Mp4e};
+        use std::convert::TryInto;
+        for &timescale in [1000, 8000, 12800, 30000, 44100, 48000, 90000].iter() {
+            for ms in (0..10_000).step_by(7) {
+                assert_eq!(
+                    timescale_to_ms(ms_to_timescale(ms, timescale), timescale),
+                    ms
+                );
+            }
+        }
+        assert_eq!(ms_to_timescale(1, 44100), 44);
+        assert_eq!(ms_to_timescale(1, 22050), 22);
+        assert_eq!(timescale_to_ms(1, 0), 1000);
+        // Large times neither overflow nor lose precision
+        assert_eq!(ms_to_timescale(u64::MAX / 90, 90), 18_446_744_073_709_552);
+        assert_eq!(ms_to_timescale(u64::MAX, 90000), u64::MAX);
+        assert_eq!(timescale_to_ms(u64::MAX, 1000), u64::MAX);
+        assert_eq!(timescale_to_ms(u64::MAX, 48000), u64::MAX / 48);
+
+        // One hour at 29.97 fps with timestamps rounded to the millisecond
+        let frames = 107_892u64;
+        let pts = |frame: u64| (frame * 1001 + 15) / 30;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        let (key, frame) = (avc_frame(true), avc_frame(false));
+        for i in 0..frames {
+            let data = if i == 0 { &key } else { &frame };
+            muxer.encode_video_pts_only(data, pts(i) as u32).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // The frame durations add up to the last timestamp, without drift
+        let stts = find_box(&buffer, b"stts").unwrap();
+        let total: u64 = stts[8..]
+            .chunks(8)
+            .map(|entry| {
+                let count = u32::from_be_bytes(entry[..4].try_into().unwrap()) as u64;
+                count * u32::from_be_bytes(entry[4..8].try_into().unwrap()) as u64
+            })
+            .sum();
+        let last = ms_to_timescale(pts(frames - 1), 90000);
+        assert_eq!(total, last + last / (frames - 1));
+        // The last frame starts at 3599.963 s
+        assert_eq!(last, 323_996_670);
+    }
+
+    #[test]
+    fn pts_wrap_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        // The first frames come just before the 32-bit timestamps wrap around
+        let wrap = |pts: i64| (pts + (1 << 32)) as u32;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        for (i, pts) in [-60, -40, 20, 40].iter().enumerate() {
+            muxer
+                .encode_video_pts_only(&avc_frame(i == 0), wrap(*pts))
+                .unwrap();
+        }
+        muxer.flush().unwrap();
+        let stts = find_box(&buffer, b"stts").unwrap();
+        let deltas: Vec<u32> = stts[8..]
+            .chunks(8)
+            .map(|entry| u32::from_be_bytes(entry[4..8].try_into().unwrap()))
+            .collect();
+        assert_eq!(deltas, [1800, 5400, 1800, 3000]);
+
+        // I P B B, the P frame and a B frame are presented after the wrap
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        for (i, pts) in [-51, 48, -18, 15].iter().enumerate() {
+            muxer
+                .encode_video_with_pts(&avc_frame(i == 0), 33, wrap(*pts))
+                .unwrap();
+        }
+        muxer.flush().unwrap();
+        let ctts = find_box(&buffer, b"ctts").unwrap();
+        let entry_count = u32::from_be_bytes(ctts[4..8].try_into().unwrap()) as usize;
+        let offsets: Vec<i32> = ctts[8..8 + entry_count * 8]
+            .chunks(8)
+            .map(|entry| i32::from_be_bytes(entry[4..8].try_into().unwrap()))
+            .collect();
+        // The offsets are those of a stream starting at 99, shifted by the same amount
+        assert_eq!(offsets.len(), 3);
+        assert_eq!(offsets[1] - offsets[0], 5940);
+        assert_eq!(offsets[2] - offsets[0], -2970);
+    }
+
+    #[test]
+    fn state_test() {
+        use crate::validate::validate_structure;
+        use crate::{Codec, Mp4e};
+        use std::io::{Cursor, Seek, SeekFrom};
+        let mut buffer = Vec::new();
+        let mut writer = Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        let state = muxer.export_state().unwrap();
+
+        // Continue the file with a new muxer
+        let mut writer = Cursor::new(buffer);
+        writer.seek(SeekFrom::End(0)).unwrap();
+        let mut fragmented = Mp4e::new_with_fragment(&mut writer);
+        assert!(fragmented.import_state(state.clone()).is_err());
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.import_state(state).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        muxer.flush().unwrap();
+        validate_structure(&mut writer).unwrap();
+
+        let buffer = writer.into_inner();
+        let video_stsz = find_box(&buffer, b"stsz").unwrap();
+        let audio_pos = buffer.windows(4).rposition(|w| w == b"stsz").unwrap();
+        let audio_stsz = find_box(&buffer[audio_pos - 4..], b"stsz").unwrap();
+        assert_eq!(&video_stsz[8..12], &2u32.to_be_bytes());
+        assert_eq!(&audio_stsz[8..12], &2u32.to_be_bytes());
+
+        // Not in the middle of an access unit
+        let mut writer = Cursor::new(Vec::new());
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_streaming_input(true);
+        muxer.encode_video(&avc_frame(true)[..12], 33).unwrap();
+        assert!(muxer.export_state().is_err());
+    }
+
+    #[test]
+    fn state_round_trip_test() {
+        use crate::{Codec, Mp4e};
+        use std::io::{Cursor, Seek, SeekFrom, Write};
+
+        /// Feeds steps `from..to` of a stream to the muxer
+        fn feed<W: Write + Seek>(muxer: &mut Mp4e<W>, fragment: bool, from: usize, to: usize) {
+            for step in from..to {
+                if fragment && step == 3 {
+                    // The video held back so far goes to the first fragment
+                    muxer.set_audio_track(48000, 2, Codec::AACLC);
+                }
+                muxer.encode_video(&avc_frame(step % 5 == 0), 0).unwrap();
+                if step >= 3 || !fragment {
+                    muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+                    muxer.encode_audio_silence(1500).unwrap();
+                }
+            }
+        }
+
+        fn new_muxer(writer: &mut Cursor<Vec<u8>>, fragment: bool) -> Mp4e<'_, Cursor<Vec<u8>>> {
+            if fragment {
+                Mp4e::new_with_fragment(writer)
+            } else {
+                Mp4e::new(writer)
+            }
+        }
+
+        fn set_up<W: Write + Seek>(muxer: &mut Mp4e<W>, fragment: bool) {
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_video_frame_rate(30, 1).unwrap();
+            muxer.set_compact_sample_tables(true);
+            muxer.set_wide_box(true);
+            muxer.set_io_chunk_size(1 << 16);
+            muxer.add_udta_box(b"test", b"state").unwrap();
+            muxer.require_keyframe_interval(10_000, Box::new(|_| {}));
+            muxer.set_record_fragments(true);
+            muxer.set_fragment_checksum(true);
+            muxer.set_segment_index(true);
+            muxer.set_audio_fragment_duration(100);
+            if fragment {
+                muxer.expect_audio_track(0);
+            } else {
+                muxer.set_audio_track(48000, 2, Codec::AACLC);
+                muxer.set_audio_silence_frame(&[0x21, 0x10], 1024).unwrap();
+            }
+        }
+
+        for fragment in [false, true] {
+            // The whole stream with a single muxer
+            let mut writer = Cursor::new(Vec::new());
+            let mut muxer = new_muxer(&mut writer, fragment);
+            set_up(&mut muxer, fragment);
+            feed(&mut muxer, fragment, 0, 11);
+            muxer.flush().unwrap();
+            let mut expected = writer.into_inner();
+
+            // The same stream with a muxer set up again and resuming from the state, after
+            // the sample data gathered so far is written
+            let mut writer = Cursor::new(Vec::new());
+            let mut muxer = new_muxer(&mut writer, fragment);
+            set_up(&mut muxer, fragment);
+            feed(&mut muxer, fragment, 0, 2);
+            // Video held back for the audio track is not part of the state
+            assert_eq!(muxer.export_state().is_err(), fragment);
+            feed(&mut muxer, fragment, 2, 5);
+            let state = muxer.export_state().unwrap();
+            let mut writer = Cursor::new(writer.into_inner());
+            writer.seek(SeekFrom::End(0)).unwrap();
+            let mut muxer = new_muxer(&mut writer, fragment);
+            set_up(&mut muxer, fragment);
+            muxer.import_state(state).unwrap();
+            feed(&mut muxer, fragment, 5, 11);
+            muxer.flush().unwrap();
+            if fragment {
+                // The mehd box keeps the duration written with the moov box, 0
+                let mehd = expected.windows(4).position(|w| w == b"mehd").unwrap();
+                expected[mehd + 8..mehd + 16].fill(0);
+            }
+            assert!(writer.into_inner() == expected, "fragment: {}", fragment);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        use crate::{Codec, MuxerState, SampleInfo, SampleType, Track, TrackType};
+        fn assert_serde<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+        assert_serde::<Track>();
+        assert_serde::<SampleInfo>();
+        assert_serde::<Codec>();
+        assert_serde::<SampleType>();
+        assert_serde::<TrackType>();
+        assert_serde::<MuxerState>();
+    }
+
+    #[test]
+    fn sample_table_limit_test() {
+        use crate::{Codec, Mp4e, SampleInfo, TrackType};
+        use std::io::ErrorKind;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        assert_eq!(muxer.sample_table_memory(), 0);
+        muxer.set_sample_table_limit(1);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        assert!(muxer.sample_table_memory() >= 32);
+        let error = muxer.encode_video(&avc_frame(false), 33).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::OutOfMemory);
+
+        // Raw samples are limited as well
+        let sample = SampleInfo {
+            random_access: false,
+            offset: 0,
+            sample_size: 0,
+            sample_delta: 3000,
+            sample_ct_offset: 0,
+            description_index: 1,
+            temporal_id: 0,
+        };
+        let error = muxer
+            .push_raw_sample(TrackType::Video, &[0, 0, 0, 1, 0x41], sample)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::OutOfMemory);
+
+        // The samples written before the limit make a complete file
+        muxer.flush().unwrap();
+        let stsz = find_box(&buffer, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &1u32.to_be_bytes());
+    }
+
+    #[test]
+    fn keyframe_interval_test() {
+        use crate::{Codec, Mp4e};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        let gaps = Rc::new(RefCell::new(Vec::new()));
+        let reported = gaps.clone();
+        muxer.require_keyframe_interval(2000, Box::new(move |gap| reported.borrow_mut().push(gap)));
+        // A frame starting 2 seconds after the key frame is accepted
+        muxer.encode_video(&avc_frame(true), 1000).unwrap();
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        muxer.encode_video(&avc_frame(true), 1000).unwrap();
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        assert!(gaps.borrow().is_empty());
+        // The next one exceeds the interval, it is reported during the encode call
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        assert_eq!(*gaps.borrow(), [3000]);
+        // Once per GOP
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        muxer.encode_video(&avc_frame(true), 1000).unwrap();
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        muxer.encode_video(&avc_frame(false), 1000).unwrap();
+        assert_eq!(*gaps.borrow(), [3000, 3000]);
+
+        // The reported frames are written all the same
+        muxer.flush().unwrap();
+        let stsz = find_box(&buffer, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &12u32.to_be_bytes());
+        let mvhd = find_box(&buffer, b"mvhd").unwrap();
+        assert_eq!(&mvhd[16..20], &12_000u32.to_be_bytes());
+    }
+
+    #[test]
+    fn wide_box_test() {
+        use crate::{Codec, Mp4e};
+        for wide in [false, true].iter() {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_wide_box(*wide);
+            muxer.encode_video(&avc_frame(true), 33).unwrap();
+            muxer.flush().unwrap();
+
+            let placeholder: &[u8] = if *wide { b"wide" } else { b"free" };
+            assert_eq!(&buffer[32..36], &8u32.to_be_bytes());
+            assert_eq!(&buffer[36..40], placeholder);
+            // 8 bytes of header and the length-prefixed IDR slice
+            assert_eq!(&buffer[40..48], &[0, 0, 0, 17, b'm', b'd', b'a', b't']);
+            let stco = find_box(&buffer, b"stco").unwrap();
+            assert_eq!(&stco[8..12], &48u32.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn audio_silence_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_video(&avc_frame(true), 100).unwrap();
+        muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        // A gap of 3800 samples is rounded to 4 whole frames, 296 samples too many
+        muxer.encode_audio_silence(3800).unwrap();
+        // Which the next gap of 1500 samples makes up for, leaving a single frame
+        muxer.encode_audio_silence(1500).unwrap();
+        // A gap shorter than half a frame is carried over
+        muxer.encode_audio_silence(300).unwrap();
+        muxer.flush().unwrap();
+
+        let stsz_pos = buffer.windows(4).rposition(|w| w == b"stsz").unwrap();
+        let stsz = find_box(&buffer[stsz_pos - 4..], b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &6u32.to_be_bytes());
+        assert_eq!(&stsz[24..28], &9u32.to_be_bytes());
+        let stts_pos = buffer.windows(4).rposition(|w| w == b"stts").unwrap();
+        let stts = find_box(&buffer[stts_pos - 4..], b"stts").unwrap();
+        let deltas: Vec<u32> = stts[8..]
+            .chunks(8)
+            .map(|entry| u32::from_be_bytes(entry[4..8].try_into().unwrap()))
+            .collect();
+        // Every frame lasts the 1024 samples it decodes to
+        assert_eq!(deltas, [1024]);
+
+        let mut writer = std::io::Cursor::new(Vec::new());
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_audio_track(48000, 6, Codec::AACLC);
+        assert!(muxer.encode_audio_silence(1024).is_err());
+        muxer.set_audio_silence_frame(&[0x01], 1024).unwrap();
+        muxer.encode_audio_silence(1024).unwrap();
+    }
+
+    #[test]
+    fn track_name_test() {
+        use crate::{Codec, Mp4e, TrackType};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        assert!(muxer.set_track_name(TrackType::Video, "Camera").is_err());
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_track_name(TrackType::Video, "Camera").unwrap();
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.flush().unwrap();
+
+        let udta = find_box(&buffer, b"udta").unwrap();
+        assert_eq!(udta, b"\0\0\0\x0EnameCamera");
+    }
+
+    #[test]
+    fn compressor_name_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        assert!(muxer.set_compressor_name("AVC Coding").is_err());
+        muxer.set_video_track(320, 240, Codec::AVC);
+        assert!(muxer.set_compressor_name(&"x".repeat(32)).is_err());
+        muxer.set_compressor_name("AVC Coding").unwrap();
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.flush().unwrap();
+
+        // compressorname follows the 42 bytes of the sample entry before it
+        let avc1 = find_box(&buffer, b"avc1").unwrap();
+        let mut field = [0u8; 32];
+        field[0] = 10;
+        field[1..11].copy_from_slice(b"AVC Coding");
+        assert_eq!(&avc1[42..74], &field);
+        assert_eq!(&avc1[74..76], &[0x00, 0x18]);
+
+        // without a name the field stays empty
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.flush().unwrap();
+        drop(muxer);
+        let avc1 = find_box(&buffer, b"avc1").unwrap();
+        assert_eq!(&avc1[42..74], &[0u8; 32]);
+    }
+
+    #[test]
+    fn encode_video_units_test() {
+        use crate::{Codec, Mp4e};
+        let mux = |codec: Codec, frames: &[Vec<u8>], durations: &[u32], together: bool| {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_create_time(0);
+            muxer.set_video_track(320, 240, codec);
+            if together {
+                let count = muxer
+                    .encode_video_units(&frames.concat(), durations)
+                    .unwrap();
+                assert_eq!(count, frames.len());
+            } else {
+                for (frame, duration) in frames.iter().zip(durations) {
+                    muxer.encode_video(frame, *duration).unwrap();
+                }
+            }
+            muxer.flush().unwrap();
+            buffer
+        };
+
+        // Three AVC frames, one sample each, with their own durations or an even split
+        let frames = [avc_frame(true), avc_frame(false), avc_frame(false)];
+        let separate = mux(Codec::AVC, &frames, &[33, 33, 34], false);
+        assert_eq!(mux(Codec::AVC, &frames, &[100], true), separate);
+        assert_eq!(mux(Codec::AVC, &frames, &[33, 33, 34], true), separate);
+        let stsz = find_box(&separate, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &3u32.to_be_bytes());
+
+        // The second slice segment of the HEVC IDR picture stays in its sample
+        let mut idr = vec![0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x42, 0x01, 0x01, 0x01, 0x60, 0x00]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x44, 0x01, 0xC1, 0x72]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x26, 0x01, 0xAF, 0x09]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x26, 0x01, 0x2F, 0x09]);
+        let trail = vec![0, 0, 0, 1, 0x02, 0x01, 0xD0, 0x11];
+        let frames = [idr, trail.clone(), trail];
+        let separate = mux(Codec::HEVC, &frames, &[40, 30, 30], false);
+        assert_eq!(mux(Codec::HEVC, &frames, &[40, 30, 30], true), separate);
+        let stsz = find_box(&separate, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &3u32.to_be_bytes());
+
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        let err = muxer
+            .encode_video_units(&[avc_frame(true), avc_frame(false)].concat(), &[33, 33, 33])
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn would_be_keyframe_test() {
+        use crate::nalu::would_be_keyframe;
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut idr = vec![0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x42, 0x01, 0x01, 0x01, 0x60, 0x00]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x44, 0x01, 0xC1, 0x72]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x26, 0x01, 0xAF, 0x09]);
+        let trail = vec![0, 0, 0, 1, 0x02, 0x01, 0xD0, 0x11];
+        let keys = [true, false, false, true, false];
+        for hevc in [false, true].iter() {
+            let codec = || if *hevc { Codec::HEVC } else { Codec::AVC };
+            let frames: Vec<Vec<u8>> = keys
+                .iter()
+                .map(|&key| match (*hevc, key) {
+                    (true, true) => idr.clone(),
+                    (true, false) => trail.clone(),
+                    (false, key) => avc_frame(key),
+                })
+                .collect();
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, codec());
+            let mut predicted = Vec::new();
+            for (i, frame) in frames.iter().enumerate() {
+                if would_be_keyframe(frame, &codec()) {
+                    predicted.push(i as u32 + 1);
+                }
+                muxer.encode_video(frame, 33).unwrap();
+            }
+            muxer.flush().unwrap();
+
+            // The sync samples of the muxer are the frames classified as key frames
+            let stss = find_box(&buffer, b"stss").unwrap();
+            let sync: Vec<u32> = stss[8..]
+                .chunks(4)
+                .map(|entry| u32::from_be_bytes(entry.try_into().unwrap()))
+                .collect();
+            assert_eq!(sync, predicted);
+            assert_eq!(sync, [1, 4]);
+        }
+        let image = [0xFF, 0xD8, 0xFF, 0xE0, 0xFF, 0xD9];
+        assert!(would_be_keyframe(&image, &Codec::MJPEG));
+        assert!(!would_be_keyframe(&[], &Codec::AVC));
+    }
+
+    #[test]
+    fn assume_starts_on_keyframe_test() {
+        use crate::{Codec, Mp4e};
+        // Parameter sets followed by a non-IDR picture
+        let mut first = avc_frame(true)[..18].to_vec();
+        first.extend_from_slice(&avc_frame(false));
+        for assume in [false, true].iter() {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            if *assume {
+                muxer.assume_starts_on_keyframe();
+            }
+            muxer.encode_video(&first, 33).unwrap();
+            muxer.encode_video(&avc_frame(false), 33).unwrap();
+            muxer.flush().unwrap();
+
+            let stsz = find_box(&buffer, b"stsz").unwrap();
+            let count: u32 = if *assume { 2 } else { 0 };
+            assert_eq!(&stsz[8..12], &count.to_be_bytes());
+            if *assume {
+                // Only the first frame is a sync sample
+                let stss = find_box(&buffer, b"stss").unwrap();
+                assert_eq!(&stss[4..12], &[0, 0, 0, 1, 0, 0, 0, 1]);
+            }
+        }
+    }
+
+    #[test]
+    fn rollover_test() {
+        use crate::validate::validate_structure;
+        use crate::{Codec, Mp4e};
+        use std::io::{Cursor, Error, ErrorKind};
+        let mut files: Vec<Cursor<Vec<u8>>> = (0..3).map(|_| Cursor::new(Vec::new())).collect();
+        let (first, next) = files.split_first_mut().unwrap();
+        let mut next = next.iter_mut();
+        let mut next_file = || next.next().ok_or_else(|| Error::from(ErrorKind::Other));
+        let mut muxer = Mp4e::new(first);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_rollover(2000, 0, &mut next_file);
+        // A key frame every 2 frames of 1 second, 3 files of 2 frames
+        for i in 0..6 {
+            muxer.encode_video(&avc_frame(i % 2 == 0), 1000).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        for file in &files {
+            let file = &file.get_ref()[..];
+            validate_structure(&mut Cursor::new(file)).unwrap();
+            assert_eq!(&file[4..8], b"ftyp");
+            let stsz = find_box(file, b"stsz").unwrap();
+            assert_eq!(&stsz[8..12], &2u32.to_be_bytes());
+            let stss = find_box(file, b"stss").unwrap();
+            assert_eq!(&stss[4..12], &[0, 0, 0, 1, 0, 0, 0, 1]);
+            // Both frames and no more in the track duration
+            let mvhd = find_box(file, b"mvhd").unwrap();
+            assert_eq!(&mvhd[16..20], &2000u32.to_be_bytes());
+        }
+    }
+
+    #[test]
+    fn rollover_held_audio_test() {
+        use crate::validate::validate_structure;
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        use std::io::{Cursor, Error, ErrorKind};
+        let positions = |file: &[u8], box_type: &[u8; 4]| -> Vec<usize> {
+            file.windows(4)
+                .enumerate()
+                .filter(|(_, w)| w == box_type)
+                .map(|(pos, _)| pos)
+                .collect()
+        };
+        let u32_at =
+            |file: &[u8], pos: usize| u32::from_be_bytes(file[pos..pos + 4].try_into().unwrap());
+        for fragment in [true, false] {
+            let mut files: Vec<Cursor<Vec<u8>>> = (0..3).map(|_| Cursor::new(Vec::new())).collect();
+            let (first, next) = files.split_first_mut().unwrap();
+            let mut next = next.iter_mut();
+            let mut next_file = || next.next().ok_or_else(|| Error::from(ErrorKind::Other));
+            let mut muxer = if fragment {
+                Mp4e::new_with_fragment(first)
+            } else {
+                Mp4e::new(first)
+            };
+            // The audio is held back for the fragments or for its own mdat box
+            muxer.set_mdat_per_track(!fragment);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_audio_track(48000, 2, Codec::AACLC);
+            muxer.set_rollover(2000, 0, &mut next_file);
+            for i in 0..6 {
+                muxer.encode_video(&avc_frame(i % 2 == 0), 1000).unwrap();
+                for _ in 0..40 {
+                    muxer.encode_audio(&[0x21; 10], 1024).unwrap();
+                }
+            }
+            muxer.flush().unwrap();
+
+            // Each file holds the audio encoded after its two video frames
+            for file in &files {
+                let file = &file.get_ref()[..];
+                validate_structure(&mut Cursor::new(file)).unwrap();
+                if fragment {
+                    let mut audio_samples = 0;
+                    let mut audio_start = None;
+                    let truns = positions(file, b"trun");
+                    let tfdts = positions(file, b"tfdt");
+                    for (traf, tfhd) in positions(file, b"tfhd").into_iter().enumerate() {
+                        let time = u64::from_be_bytes(
+                            file[tfdts[traf] + 8..tfdts[traf] + 16].try_into().unwrap(),
+                        );
+                        if traf == 0 {
+                            assert_eq!(time, 0);
+                        }
+                        if u32_at(file, tfhd + 8) == 2 {
+                            audio_start.get_or_insert(time);
+                            audio_samples += u32_at(file, truns[traf] + 8);
+                        }
+                    }
+                    assert_eq!((audio_start, audio_samples), (Some(0), 80));
+                } else {
+                    let stsz = positions(file, b"stsz");
+                    assert_eq!(u32_at(file, stsz[0] + 12), 2);
+                    assert_eq!(u32_at(file, stsz[1] + 12), 80);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn video_profile_test() {
+        use crate::nalu::parse_sps_profile;
+        use crate::Codec;
+        let sps = [0x67, 0x42, 0xC0, 0x0D, 0xF4, 0x01];
+        let profile = parse_sps_profile(&Codec::AVC, &sps).unwrap();
+        assert_eq!(
+            profile.to_string(),
+            "H.264 Constrained Baseline Profile Level 1.3"
+        );
+        let hevc_sps = [
+            0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00,
+            0x00, 0x03, 0x00, 0x5D, 0xA0, 0x02, 0x80, 0x80, 0x2D, 0x16, 0x58,
+        ];
+        let profile = parse_sps_profile(&Codec::HEVC, &hevc_sps).unwrap();
+        assert_eq!(profile.to_string(), "HEVC Main Level 3.1");
+        assert_eq!((profile.bit_depth, profile.chroma_format), (8, 1));
+        assert!(parse_sps_profile(&Codec::AACLC, &sps).is_none());
+    }
+
+    #[test]
+    fn split_chunks_test() {
+        use crate::nalu::{split_complete_nalus, split_nalu, NaluSplitter};
+        let mut stream = avc_frame(true);
+        stream.extend_from_slice(&[0, 0, 1, 0x06, 0x05, 0x01, 0x00, 0x80]);
+        stream.extend_from_slice(&avc_frame(false));
+        stream.extend_from_slice(&[0, 0, 1, 0x41, 0x00, 0x00, 0x03, 0x00]);
+        let whole: Vec<&[u8]> = split_nalu(&stream).collect();
+        for chunk_size in 1..=9 {
+            let mut nalus: Vec<Vec<u8>> = Vec::new();
+            let mut rest = Vec::new();
+            for chunk in stream.chunks(chunk_size) {
+                rest.extend_from_slice(chunk);
+                let (complete, left) = split_complete_nalus(&rest);
+                nalus.extend(complete.iter().map(|nalu| nalu.to_vec()));
+                rest = left.to_vec();
+            }
+            nalus.extend(split_nalu(&rest).map(|nalu| nalu.to_vec()));
+            assert_eq!(nalus, whole, "chunks of {} bytes", chunk_size);
+
+            // The splitter only searches the new bytes of each chunk
+            let mut splitter = NaluSplitter::new();
+            let mut nalus: Vec<Vec<u8>> = Vec::new();
+            for chunk in stream.chunks(chunk_size) {
+                nalus.extend(splitter.push(chunk));
+            }
+            nalus.extend(splitter.finish());
+            assert_eq!(nalus, whole, "splitter chunks of {} bytes", chunk_size);
+        }
+    }
+
+    #[test]
+    fn streaming_input_test() {
+        use crate::{Codec, Mp4e};
+        let frames = [
+            avc_frame(true),
+            avc_frame(false),
+            avc_frame(false),
+            avc_frame(true),
+            avc_frame(false),
+        ];
+        let mux = |chunk_size: Option<usize>| {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            match chunk_size {
+                Some(size) => {
+                    muxer.set_streaming_input(true);
+                    for chunk in frames.concat().chunks(size) {
+                        muxer.encode_video(chunk, 33).unwrap();
+                    }
+                }
+                None => {
+                    for frame in frames.iter() {
+                        muxer.encode_video(frame, 33).unwrap();
+                    }
+                }
+            }
+            muxer.flush().unwrap();
+            buffer
+        };
+        let expected = mux(None);
+        assert_eq!(&find_box(&expected, b"stsz").unwrap()[8..12], &[0, 0, 0, 5]);
+        for chunk_size in 1..=9 {
+            assert!(
+                mux(Some(chunk_size)) == expected,
+                "chunks of {} bytes",
+                chunk_size
+            );
+        }
+    }
+
+    #[test]
+    fn encode_video_au_test() {
+        use crate::nalu::split_nalu;
+        use crate::{Codec, Mp4e, SeiPolicy};
+        let frames = [avc_frame(true), avc_frame(false), avc_frame(false)];
+        let mux = |fast: bool| {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            for (i, frame) in frames.iter().enumerate() {
+                if fast {
+                    let nalus: Vec<&[u8]> = split_nalu(frame).collect();
+                    muxer.encode_video_au(&nalus, 33, i == 0).unwrap();
+                } else {
+                    muxer.encode_video(frame, 33).unwrap();
+                }
+            }
+            muxer.flush().unwrap();
+            buffer
+        };
+        assert!(mux(true) == mux(false));
+
+        // Frames before the first key frame are dropped, the track must be AVC or HEVC
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        assert!(muxer.encode_video_au(&[&[0x41, 0x9A]], 33, false).is_err());
+        muxer.set_video_track(320, 240, Codec::AVC);
+        let sps = [0x67, 0x42, 0xC0, 0x0D, 0xF4, 0x01];
+        muxer
+            .encode_video_au(&[&sps, &[0x68, 0xCE], &[0x41, 0x9A]], 33, false)
+            .unwrap();
+        muxer.encode_video_au(&[&[0x65, 0x88]], 33, true).unwrap();
+        muxer.flush().unwrap();
+        assert_eq!(&find_box(&buffer, b"stsz").unwrap()[8..12], &[0, 0, 0, 1]);
+
+        // The codec of an automatic track is detected from the access units
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track_auto(320, 240);
+        let vps = [0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+        let sps = [0x42, 0x01, 0x01, 0x01, 0x60, 0x00];
+        let pps = [0x44, 0x01, 0xC1, 0x72];
+        muxer
+            .encode_video_au(&[&vps, &sps, &pps, &[0x26, 0x01, 0xAF]], 33, true)
+            .unwrap();
+        muxer
+            .encode_video_au(&[&[0x02, 0x01, 0xD0]], 33, false)
+            .unwrap();
+        muxer.flush().unwrap();
+        assert!(find_box(&buffer, b"hvcC").is_some());
+        assert_eq!(&find_box(&buffer, b"stsz").unwrap()[8..12], &[0, 0, 0, 2]);
+
+        // SEI NAL units follow the SEI policy as in encode_video
+        let hdr_sei = [0x06, 137, 1, 0x00, 0x80];
+        let other_sei = [0x06, 5, 1, 0x00, 0x80];
+        // The SEI NAL units come after the parameter sets
+        let key = avc_frame(true);
+        let mut frame = key[..18].to_vec();
+        for sei in [&hdr_sei, &other_sei] {
+            frame.extend_from_slice(&[0, 0, 0, 1]);
+            frame.extend_from_slice(sei);
+        }
+        frame.extend_from_slice(&key[18..]);
+        for policy in [SeiPolicy::Keep, SeiPolicy::Strip, SeiPolicy::KeepHdrOnly] {
+            let mux = |fast: bool| {
+                let mut buffer = Vec::new();
+                let mut writer = std::io::Cursor::new(&mut buffer);
+                let mut muxer = Mp4e::new(&mut writer);
+                muxer.set_video_track(320, 240, Codec::AVC);
+                muxer.set_sei_policy(policy.clone());
+                if fast {
+                    let nalus: Vec<&[u8]> = split_nalu(&frame).collect();
+                    muxer.encode_video_au(&nalus, 33, true).unwrap();
+                } else {
+                    muxer.encode_video(&frame, 33).unwrap();
+                }
+                muxer.flush().unwrap();
+                buffer
+            };
+            let buffer = mux(true);
+            assert!(buffer == mux(false));
+            let contains = |sei: &[u8]| {
+                let mut unit = vec![0, 0, 0, 5];
+                unit.extend_from_slice(sei);
+                buffer.windows(unit.len()).any(|w| w == unit.as_slice())
+            };
+            let (hdr, other) = match policy {
+                SeiPolicy::Keep => (true, true),
+                SeiPolicy::Strip => (false, false),
+                SeiPolicy::KeepHdrOnly => (true, false),
+            };
+            assert_eq!((contains(&hdr_sei), contains(&other_sei)), (hdr, other));
+        }
+    }
+
+    #[test]
+    fn audio_timescale_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(44100, 2, Codec::AACLC);
+        assert!(muxer.set_audio_timescale(0).is_err());
+        muxer.set_audio_timescale(90000).unwrap();
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        for _ in 0..3 {
+            muxer.encode_audio(&[0x21, 0x10], 1024).unwrap();
+        }
+        assert!(muxer.set_audio_timescale(44100).is_err());
+        muxer.flush().unwrap();
+
+        // 1024 samples at 44.1 kHz are 2089.79 units at 90 kHz, the end times are rounded
+        let pos = buffer
+            .windows(8)
+            .position(|w| w == b"trun\x00\x00\x03\x01")
+            .unwrap();
+        let trun = &buffer[pos + 4..];
+        assert_eq!(&trun[4..8], &3u32.to_be_bytes());
+        let durations: Vec<u32> = trun[12..36]
+            .chunks(8)
+            .map(|entry| u32::from_be_bytes(entry[..4].try_into().unwrap()))
+            .collect();
+        assert_eq!(durations, [2090, 2090, 2089]);
+    }
+
+    #[test]
+    fn default_sample_duration_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+
+        // The frame duration is the tfhd default, the trun entries only have a size
+        let tfhd = find_box(&buffer, b"tfhd").unwrap();
+        assert_eq!(&tfhd[..4], &[0, 2, 0, 0x28]);
+        assert_eq!(&tfhd[8..12], &2970u32.to_be_bytes());
+        let trun = find_box(&buffer, b"trun").unwrap();
+        assert_eq!(&trun[..4], &[0, 0, 0x02, 0x05]);
+        assert_eq!(trun.len(), 20);
+    }
+
+    #[test]
+    fn trun_composition_offset_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        // I P B, only the fragments of the I and P frames have a composition offset
+        for (i, pts) in [66, 132, 99].iter().enumerate() {
+            muxer
+                .encode_video_with_pts(&avc_frame(i == 0), 33, *pts)
+                .unwrap();
+        }
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+
+        let truns: Vec<&[u8]> = buffer
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"trun")
+            .map(|(pos, _)| {
+                let size = u32::from_be_bytes(buffer[pos - 4..pos].try_into().unwrap());
+                &buffer[pos + 4..pos - 4 + size as usize]
+            })
+            .collect();
+        assert_eq!(truns.len(), 3);
+        assert_eq!(truns[0][2] & 0x08, 0x08);
+        assert_eq!(&truns[0][truns[0].len() - 4..], &2970i32.to_be_bytes());
+        assert_eq!(truns[1][2] & 0x08, 0x08);
+        // The B frame is presented at its decode time, its trun has no offset field
+        assert_eq!(truns[2][2] & 0x08, 0);
+        assert_eq!(truns[2].len(), 16);
+    }
+
+    #[test]
+    fn fragment_data_offset_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.set_audio_fragment_duration(0);
+        for i in 0..3 {
+            muxer.encode_video(&avc_frame(i == 0), 33).unwrap();
+            muxer.encode_audio(&[0x21, 0x10, i], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // Every traf counts its data offset from the start of its moof box
+        let moofs: Vec<usize> = buffer
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"moof")
+            .map(|(pos, _)| pos - 4)
+            .collect();
+        assert_eq!(moofs.len(), 6);
+        for moof in moofs {
+            let tfhd = find_box(&buffer[moof..], b"tfhd").unwrap();
+            assert_eq!(tfhd[1] & 0x02, 0x02);
+            let trun = find_box(&buffer[moof..], b"trun").unwrap();
+            let data_offset = u32::from_be_bytes(trun[8..12].try_into().unwrap()) as usize;
+            assert_eq!(&buffer[moof + data_offset - 4..moof + data_offset], b"mdat");
+        }
+    }
+
+    #[test]
+    fn zero_duration_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        for fragment in [false, true].iter() {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = if *fragment {
+                Mp4e::new_with_fragment(&mut writer)
+            } else {
+                Mp4e::new(&mut writer)
+            };
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_audio_track(48000, 2, Codec::AACLC);
+            // A poster frame without duration, then a still shown until the end
+            muxer.encode_video(&avc_frame(true), 0).unwrap();
+            muxer.encode_video(&avc_frame(false), 40).unwrap();
+            assert!(muxer.encode_audio(&[0x21, 0x10], 0).is_err());
+            muxer.encode_video(&avc_frame(false), 0).unwrap();
+            muxer.flush().unwrap();
+            muxer.validate().unwrap();
+            if !*fragment {
+                let stts = find_box(&buffer, b"stts").unwrap();
+                let entries: Vec<u32> = stts[8..]
+                    .chunks(4)
+                    .take(6)
+                    .map(|value| u32::from_be_bytes(value.try_into().unwrap()))
+                    .collect();
+                assert_eq!(entries, [1, 0, 1, 3600, 1, 0]);
+                let mvhd = find_box(&buffer, b"mvhd").unwrap();
+                assert_eq!(&mvhd[16..20], &40u32.to_be_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn user_boxes_test() {
+        use crate::{Codec, Mp4e};
+        let mux = |vendor: bool| {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_create_time(0);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            if vendor {
+                muxer.add_moov_box(b"xyzV", &[1, 2, 3]).unwrap();
+                muxer.add_udta_box(b"xyzU", &[4, 5]).unwrap();
+            }
+            muxer.encode_video(&avc_frame(true), 33).unwrap();
+            muxer.flush().unwrap();
+            assert!(muxer.add_moov_box(b"late", &[]).is_err());
+            muxer.validate().unwrap();
+            buffer
+        };
+        let plain = mux(false);
+        let buffer = mux(true);
+        let moov = buffer.windows(4).position(|w| w == b"moov").unwrap() - 4;
+        assert_eq!(&buffer[..moov], &plain[..moov]);
+        let plain_size = plain.len() - moov;
+        // The udta box with its box, then the vendor box, at the end of the moov box
+        assert_eq!(
+            &buffer[moov..moov + 4],
+            &(plain_size as u32 + 29).to_be_bytes()
+        );
+        assert_eq!(
+            &buffer[moov + plain_size..],
+            &b"\x00\x00\x00\x12udta\x00\x00\x00\x0axyzU\x04\x05\x00\x00\x00\x0bxyzV\x01\x02\x03"[..]
+        );
+    }
+
+    #[test]
+    fn cover_art_test() {
+        use crate::{Codec, ImageFormat, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        assert!(muxer.set_cover_art(&[], ImageFormat::Png).is_err());
+        muxer
+            .set_cover_art(&[0x89, b'P', b'N', b'G'], ImageFormat::Png)
+            .unwrap();
+        // The second image replaces the first one
+        muxer
+            .set_cover_art(&[0xFF, 0xD8, 0xFF], ImageFormat::Jpeg)
+            .unwrap();
+        muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+        assert!(muxer.set_cover_art(&[0xFF], ImageFormat::Jpeg).is_err());
+
+        // udta > meta > hdlr (mdir) + ilst > covr > data (JPEG)
+        let udta = find_box(&buffer, b"udta").unwrap();
+        assert_eq!(&udta[4..8], b"meta");
+        assert_eq!(&udta[16..20], b"hdlr");
+        assert_eq!(&udta[28..36], b"mdirappl");
+        let ilst = find_box(&buffer, b"ilst").unwrap();
+        assert_eq!(
+            ilst,
+            &b"\x00\x00\x00\x1bcovr\x00\x00\x00\x13data\x00\x00\x00\x0d\x00\x00\x00\x00\xff\xd8\xff"[..]
+        );
+        assert_eq!(buffer.windows(4).filter(|w| w == b"covr").count(), 1);
+    }
+
+    #[test]
+    fn duration_rounding_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_video_frame_rate(30000, 1001).unwrap();
+        for i in 0..7 {
+            muxer.encode_video(&avc_frame(i == 0), 0).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // 7 frames of 3003 units at 90 kHz last 233.57 ms
+        let mvhd = find_box(&buffer, b"mvhd").unwrap();
+        assert_eq!(&mvhd[16..20], &234u32.to_be_bytes());
+        let tkhd = find_box(&buffer, b"tkhd").unwrap();
+        assert_eq!(&tkhd[20..24], &234u32.to_be_bytes());
+    }
+
+    #[test]
+    fn sample_dependencies_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let sync_samples = |all_intra: bool| {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_all_intra(all_intra);
+            muxer.set_sample_dependency_box(true).unwrap();
+            for key in [true, false, false, true, false].iter() {
+                muxer.encode_video(&avc_frame(*key), 33).unwrap();
+            }
+            // A second slice continues the last picture
+            muxer.encode_video(&[0, 0, 1, 0x41, 0x20], 0).unwrap();
+            muxer.flush().unwrap();
+            muxer.validate().unwrap();
+
+            let sdtp = find_box(&buffer, b"sdtp").unwrap();
+            assert_eq!(sdtp.len(), 4 + 5);
+            let from_sdtp: Vec<u32> = (1..=5).filter(|i| sdtp[3 + *i as usize] == 0x20).collect();
+            let from_stss: Vec<u32> = match find_box(&buffer, b"stss") {
+                Some(stss) => stss[8..]
+                    .chunks(4)
+                    .map(|entry| u32::from_be_bytes(entry.try_into().unwrap()))
+                    .collect(),
+                None => (1..=5).collect(),
+            };
+            assert_eq!(from_sdtp, from_stss);
+            from_stss
+        };
+        assert_eq!(sync_samples(false), [1, 4]);
+        assert_eq!(sync_samples(true), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn on_nalu_test() {
+        use crate::{Codec, Mp4e};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        let log = seen.clone();
+        muxer.set_on_nalu(Some(Box::new(move |nalu_type, size, vcl| {
+            log.borrow_mut().push((nalu_type, size, vcl));
+        })));
+        // A P frame before the first key frame is seen, then dropped
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.set_on_nalu(None);
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.flush().unwrap();
+        assert_eq!(
+            *seen.borrow(),
+            [(1, 4, true), (7, 6, false), (8, 4, false), (5, 5, true)]
+        );
+    }
+
+    #[test]
+    fn object_type_indication_test() {
+        use crate::{Codec, Mp4e};
+        // The objectTypeIndication follows the DecoderConfigDescriptor tag and size
+        let oti = |buffer: &[u8]| {
+            let esds = find_box(buffer, b"esds").unwrap();
+            let dcd = esds.iter().position(|&byte| byte == 0x04).unwrap();
+            (esds[dcd + 2], esds.len())
+        };
+        let mux = |codec: Codec, object_type_indication: Option<u8>| {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_audio_track(44100, 2, codec);
+            if let Some(object_type_indication) = object_type_indication {
+                muxer
+                    .set_audio_object_type_indication(object_type_indication)
+                    .unwrap();
+            }
+            muxer.encode_video(&avc_frame(true), 33).unwrap();
+            muxer.encode_audio(&[0xFF, 0xFB, 0x90, 0x64], 1152).unwrap();
+            muxer.flush().unwrap();
+            muxer.validate().unwrap();
+            buffer
+        };
+        // MP3 has no DecoderSpecificInfo
+        assert_eq!(oti(&mux(Codec::MP3, None)), (0x6B, 4 + 2 + 3 + 2 + 13));
+        assert_eq!(oti(&mux(Codec::AACLC, None)).0, 0x40);
+        assert_eq!(oti(&mux(Codec::AACLC, Some(0x67))).0, 0x67);
+
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        assert!(muxer.set_audio_object_type_indication(0x6B).is_err());
+        muxer.set_audio_track(48000, 2, Codec::OPUS);
+        assert!(muxer.set_audio_object_type_indication(0x6B).is_err());
+    }
+
+    #[test]
+    fn expect_audio_track_test() {
+        use crate::{Codec, Mp4e};
+        let count =
+            |buffer: &[u8], box_type: &[u8; 4]| buffer.windows(4).filter(|w| w == box_type).count();
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.expect_audio_track(0);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+
+        // The audio track set up after the first video frames is in the moov box
+        assert!(find_box(&buffer, b"mp4a").is_some());
+        assert_eq!(count(&buffer, b"trak"), 2);
+        assert_eq!(count(&buffer, b"moof"), 4);
+        let moov = buffer.windows(4).position(|w| w == b"moov").unwrap();
+        let moof = buffer.windows(4).position(|w| w == b"moof").unwrap();
+        assert!(moov < moof);
+
+        // Without an audio track within the longest wait, the video is written alone
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.expect_audio_track(50);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.flush().unwrap();
+        assert_eq!(count(&buffer, b"trak"), 1);
+        assert_eq!(count(&buffer, b"moof"), 2);
+    }
+
+    #[test]
+    fn hevc_missing_pps_test() {
+        use crate::{Codec, Mp4e};
+        let mut no_pps = vec![0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+        no_pps.extend_from_slice(&[0, 0, 0, 1, 0x42, 0x01, 0x01, 0x01, 0x60, 0x00]);
+        no_pps.extend_from_slice(&[0, 0, 0, 1, 0x26, 0x01, 0xAF, 0x09]);
+        let mut idr = no_pps[..20].to_vec();
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x44, 0x01, 0xC1, 0x72]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x26, 0x01, 0xAF, 0x09]);
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(1280, 720, Codec::HEVC);
+
+        // No sample is written before a PPS is seen
+        muxer.encode_video(&no_pps, 33).unwrap();
+        muxer.encode_video(&idr, 33).unwrap();
+        muxer.encode_video(&no_pps, 33).unwrap();
+        muxer.flush().unwrap();
+        assert_eq!(buffer.windows(4).filter(|w| w == b"moof").count(), 2);
+        // The hvcC box holds the PPS array: VPS, SPS and PPS with one NAL unit each
+        let hvcc = find_box(&buffer, b"hvcC").unwrap();
+        assert_eq!(hvcc[22], 3);
+        assert!(hvcc.windows(2).any(|w| w == [0x44, 0x01]));
+    }
+
+    #[test]
+    fn temporal_layer_group_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        // SPS with two temporal sub-layers, then an IDR frame
+        let mut idr = vec![0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x42, 0x01, 0x03, 0x01, 0x60, 0x00]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x44, 0x01, 0xC1, 0x72]);
+        idr.extend_from_slice(&[0, 0, 0, 1, 0x26, 0x01, 0xAF, 0x09]);
+        // TRAIL_R frame of sub-layer 0 and TSA_N frame of sub-layer 1
+        let base = [0, 0, 0, 1, 0x02, 0x01, 0xD0, 0x09];
+        let enhancement = [0, 0, 0, 1, 0x04, 0x02, 0xD0, 0x09];
+        for fragment in [false, true] {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = if fragment {
+                Mp4e::new_with_fragment(&mut writer)
+            } else {
+                Mp4e::new(&mut writer)
+            };
+            muxer.set_video_track(1280, 720, Codec::HEVC);
+            muxer.set_temporal_layer_group(true).unwrap();
+            muxer.encode_video(&idr, 33).unwrap();
+            for frame in [&base[..], &enhancement, &base, &enhancement] {
+                muxer.encode_video(frame, 33).unwrap();
+            }
+            muxer.flush().unwrap();
+            muxer.validate().unwrap();
+
+            // One entry per sub-layer, only sub-layer 0 decodes on its own
+            let sgpd = find_box(&buffer, b"sgpd").unwrap();
+            assert_eq!(&sgpd[4..8], b"tele");
+            assert_eq!(&sgpd[8..], &[0, 0, 0, 1, 0, 0, 0, 2, 0x80, 0x00]);
+            let sbgp: Vec<&[u8]> = buffer
+                .windows(4)
+                .enumerate()
+                .filter(|(_, w)| w == b"sbgp")
+                .map(|(pos, _)| &find_box(&buffer[pos - 4..], b"sbgp").unwrap()[8..])
+                .collect();
+            if fragment {
+                // A run of the single sample of each fragment
+                let entries: Vec<u8> = sbgp.iter().map(|sbgp| sbgp[11]).collect();
+                assert_eq!(entries, [1, 1, 2, 1, 2]);
+            } else {
+                // Runs of sample count and entry
+                let runs: Vec<u32> = sbgp[0]
+                    .chunks(4)
+                    .map(|value| u32::from_be_bytes(value.try_into().unwrap()))
+                    .collect();
+                assert_eq!(runs, [4, 2, 1, 1, 2, 1, 1, 1, 2]);
+            }
+        }
+    }
+
+    #[test]
+    fn audio_only_fragmented_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.set_audio_fragment_duration(1000);
+        muxer.set_segment_index(true);
+        // 3.2 seconds of audio
+        for _ in 0..150 {
+            muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+
+        let mut top_level = Vec::new();
+        let mut pos = 0;
+        while pos < buffer.len() {
+            let size = u32::from_be_bytes(buffer[pos..pos + 4].try_into().unwrap()) as usize;
+            top_level.push((pos, buffer[pos + 4..pos + 8].to_vec()));
+            pos += size;
+        }
+        let types: Vec<&[u8]> = top_level.iter().map(|(_, t)| &t[..]).collect();
+        let mut expected: Vec<&[u8]> = vec![b"ftyp", b"moov"];
+        for _ in 0..4 {
+            expected.extend_from_slice(&[b"sidx", b"moof", b"mdat"]);
+        }
+        expected.push(b"mfra");
+        assert_eq!(types, expected);
+
+        // A single sound track, whose fragments follow each other in time
+        let moov = find_box(&buffer, b"moov").unwrap();
+        assert_eq!(moov.windows(4).filter(|w| w == b"trak").count(), 1);
+        assert_eq!(&find_box(moov, b"hdlr").unwrap()[8..12], b"soun");
+        assert!(find_box(moov, b"mvex").is_some());
+        let decode_times: Vec<u64> = buffer
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"tfdt")
+            .map(|(pos, _)| u64::from_be_bytes(buffer[pos + 8..pos + 16].try_into().unwrap()))
+            .collect();
+        assert_eq!(decode_times, [0, 47 * 1024, 94 * 1024, 141 * 1024]);
+        // Each sidx box indexes the fragment up to the next sidx box
+        for (i, fragment) in top_level[2..14].chunks(3).enumerate() {
+            let (sidx_pos, moof_pos) = (fragment[0].0, fragment[1].0);
+            let end = top_level[2 + 3 * (i + 1)].0;
+            let sidx = &buffer[sidx_pos + 8..moof_pos];
+            assert_eq!(&sidx[..4], &[1, 0, 0, 0]);
+            assert_eq!(&sidx[8..12], &48000u32.to_be_bytes());
+            assert_eq!(&sidx[12..20], &(decode_times[i]).to_be_bytes());
+            assert_eq!(&sidx[32..36], &((end - moof_pos) as u32).to_be_bytes());
+            let duration = if i < 3 { 47 * 1024 } else { 9 * 1024 };
+            assert_eq!(&sidx[36..40], &(duration as u32).to_be_bytes());
+            assert_eq!(sidx[40], 0x90);
+        }
+        // The duration of all the fragments is written to the mehd box, the mvhd box
+        // only covers the samples of the moov box
+        let mehd = find_box(moov, b"mehd").unwrap();
+        assert_eq!(&mehd[..4], &[1, 0, 0, 0]);
+        assert_eq!(&mehd[4..12], &3200u64.to_be_bytes());
+        let mvhd = find_box(moov, b"mvhd").unwrap();
+        assert_eq!(&mvhd[16..20], &0u32.to_be_bytes());
+        // The mfra box indexes the moof box of every fragment
+        let tfra = find_box(&buffer, b"tfra").unwrap();
+        assert_eq!(&tfra[12..16], &4u32.to_be_bytes());
+        assert_eq!(&tfra[24..32], &(top_level[3].0 as u64).to_be_bytes());
+    }
+
+    #[test]
+    fn out_of_band_parameter_sets_test() {
+        use crate::{Codec, Mp4e};
+        let idr = [0, 0, 0, 1, 0x65, 0x88, 0x84, 0x21, 0xA0];
+
+        // Without parameter sets, no frame can be written and flush says why, after
+        // finalizing the file
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_video(&idr, 33).unwrap();
+        muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        let err = muxer.flush().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        muxer.validate().unwrap();
+        drop(muxer);
+        assert!(find_box(&buffer, b"moov").is_some());
+
+        // With the parameter sets of the SDP description, the frames are written
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        let sps = [0x67, 0x42, 0xC0, 0x0D, 0xF4, 0x01];
+        let pps = [0x68, 0xCE, 0x3C, 0x80];
+        assert!(muxer.set_video_parameter_sets(&[], &pps, None).is_err());
+        assert!(muxer.set_video_parameter_sets(&pps, &sps, None).is_err());
+        muxer.set_video_parameter_sets(&sps, &pps, None).unwrap();
+        muxer.encode_video(&idr, 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+        let stsz = find_box(&buffer, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &2u32.to_be_bytes());
+        let avcc = find_box(&buffer, b"avcC").unwrap();
+        assert!(avcc.windows(4).any(|w| w == [0x68, 0xCE, 0x3C, 0x80]));
+
+        // HEVC needs a VPS as well
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(1280, 720, Codec::HEVC);
+        let vps = [0x40, 0x01, 0x0C, 0x01, 0xFF, 0xFF];
+        let hevc_sps = [
+            0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00,
+            0x00, 0x03, 0x00, 0x5D,
+        ];
+        let hevc_pps = [0x44, 0x01, 0xC1, 0x72, 0xB4];
+        assert!(muxer
+            .set_video_parameter_sets(&hevc_sps, &hevc_pps, None)
+            .is_err());
+        // The NAL unit types of AVC are rejected, and so is a truncated SPS
+        assert!(muxer
+            .set_video_parameter_sets(&sps, &pps, Some(&vps))
+            .is_err());
+        assert!(muxer
+            .set_video_parameter_sets(&[0x42], &hevc_pps, Some(&vps))
+            .is_err());
+        assert!(muxer
+            .set_video_parameter_sets(&hevc_sps[..6], &hevc_pps, Some(&vps))
+            .is_err());
+        muxer
+            .set_video_parameter_sets(&hevc_sps, &hevc_pps, Some(&vps))
+            .unwrap();
+    }
+
+    #[test]
+    fn vectored_write_test() {
+        use crate::{Codec, Mp4e};
+
+        // Each frame is written with one call, its length prefixes included
+        let mut large = vec![0, 0, 0, 1, 0x41, 0x9A];
+        large.resize(100_006, 0x21);
+        large.extend_from_slice(&[0, 0, 0, 1, 0x41, 0x9A, 0x21]);
+        let mut writer = MockWriter::default();
+        let calls = writer.clone();
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        let before = calls.calls();
+        for _ in 0..3 {
+            muxer.encode_video(&large, 33).unwrap();
+        }
+        assert_eq!(calls.calls() - before, 3);
+        muxer.flush().unwrap();
+
+        // A fragment takes one call for the moof box and one for the mdat box
+        let mut writer = MockWriter::default();
+        let calls = writer.clone();
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        let before = calls.calls();
+        muxer.encode_video(&large, 33).unwrap();
+        assert_eq!(calls.calls() - before, 2);
+    }
+
+    #[test]
+    fn boxes_before_mdat_test() {
+        use crate::boxes::*;
+        use crate::validate::validate_structure;
+        use crate::{Codec, SampleInfo, Track, TrackType};
+        use std::io::{Cursor, Write};
+        let track = Some(Track {
+            sample_rate: 48000,
+            channel_count: 2,
+            ..Track::new(1, TrackType::Audio, Codec::AACLC, 48000)
+        });
+        let sample = SampleInfo {
+            random_access: true,
+            offset: 0,
+            sample_size: 3,
+            sample_delta: 1024,
+            sample_ct_offset: 0,
+            description_index: 1,
+            temporal_id: 0,
+        };
+        let fragment = |boxes_size: u32| {
+            let mut cursor = Cursor::new(Vec::new());
+            write_ftyp(&mut cursor).unwrap();
+            write_moov(&[&track], 0, 1000, b"und", true, &mut cursor).unwrap();
+            let track = track.as_ref().unwrap();
+            write_styp(&mut cursor).unwrap();
+            write_moof_samples_before(1, track, &[sample], boxes_size, &mut cursor).unwrap();
+            cursor.write_all(b"\0\0\0\x18free").unwrap();
+            cursor.write_all(&[0; 16]).unwrap();
+            write_mdat(&[&[0x21, 0x10, 0x04]], false, &mut cursor).unwrap();
+            cursor
+        };
+
+        // The data offset covers the box between the moof and the mdat box
+        let mut cursor = fragment(24);
+        validate_structure(&mut cursor).unwrap();
+        let buffer = cursor.into_inner();
+        let mdat = buffer.windows(4).position(|w| w == b"mdat").unwrap() + 4;
+        assert_eq!(&buffer[mdat..], &[0x21, 0x10, 0x04]);
+
+        // Without it, the samples would be read from the free box
+        let err = validate_structure(&mut fragment(0)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn external_data_reference_test() {
+        use crate::boxes::*;
+        use crate::validate::validate_structure;
+        use crate::{Codec, SampleInfo, Track, TrackType};
+        use std::io::Cursor;
+        let samples: Vec<SampleInfo> = (0..3)
+            .map(|i| SampleInfo {
+                random_access: true,
+                offset: i * 3,
+                sample_size: 3,
+                sample_delta: 1024,
+                sample_ct_offset: 0,
+                description_index: 1,
+                temporal_id: 0,
+            })
+            .collect();
+        let moov_only = |data_reference_index: u16| {
+            let track = Some(Track {
+                sample_rate: 48000,
+                channel_count: 2,
+                duration: 3072,
+                samples: samples.clone(),
+                data_references: vec!["media.dat".to_string()],
+                data_reference_index,
+                ..Track::new(1, TrackType::Audio, Codec::AACLC, 48000)
+            });
+            let mut cursor = Cursor::new(Vec::new());
+            write_ftyp(&mut cursor).unwrap();
+            write_moov(&[&track], 0, 1000, b"und", false, &mut cursor).unwrap();
+            cursor
+        };
+
+        let mut cursor = moov_only(2);
+        validate_structure(&mut cursor).unwrap();
+        let buffer = cursor.into_inner();
+        assert!(find_box(&buffer, b"mdat").is_none());
+        // The file itself comes first, then the external file
+        let dref = find_box(&buffer, b"dref").unwrap();
+        assert_eq!(&dref[4..8], &2u32.to_be_bytes());
+        assert_eq!(&dref[8..20], b"\x00\x00\x00\x0curl \x00\x00\x00\x01");
+        assert_eq!(&dref[24..28], b"url ");
+        assert_eq!(&dref[28..32], &[0x00; 4]);
+        assert_eq!(&dref[32..42], b"media.dat\x00");
+        // The sample entry points to the external file
+        let stsd = find_box(&buffer, b"stsd").unwrap();
+        assert_eq!(&stsd[12..16], b"mp4a");
+        assert_eq!(&stsd[22..24], &2u16.to_be_bytes());
+        let stco = find_box(&buffer, b"stco").unwrap();
+        assert_eq!(&stco[8..20], &[0, 0, 0, 0, 0, 0, 0, 3, 0, 0, 0, 6]);
+
+        // The same offsets in the file itself are outside any mdat box
+        let err = validate_structure(&mut moov_only(1)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn poisoned_test() {
+        use crate::{Codec, Mp4e};
+        let mut writer = MockWriter::new(200, 0..0);
+        let writes = writer.clone();
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        // The moov box does not fit
+        let err = muxer.flush().unwrap_err();
+        assert_eq!(err.to_string(), "no space left on device");
+
+        // Later calls fail without writing
+        let before = writes.calls();
+        let err = muxer.encode_video(&avc_frame(false), 33).unwrap_err();
+        assert!(err.to_string().contains("poisoned"));
+        let err = muxer.flush().unwrap_err();
+        assert!(err.to_string().contains("poisoned"));
+        assert_eq!(writes.calls(), before);
+    }
+
+    #[test]
+    fn io_chunk_size_test() {
+        use crate::{Codec, Mp4e};
+        use std::io::Cursor;
+
+        let mux = |chunk_size: usize| {
+            let mut writer = MockWriter::default();
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_audio_track(48000, 2, Codec::AACLC);
+            muxer.set_io_chunk_size(chunk_size);
+            for i in 0..100 {
+                muxer.encode_video(&avc_frame(i % 30 == 0), 33).unwrap();
+                muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+            }
+            muxer.flush().unwrap();
+            (writer.data(), writer.calls())
+        };
+
+        // The same file is written with a fraction of the write calls
+        let (direct, direct_calls) = mux(0);
+        let (chunked, chunked_calls) = mux(256);
+        assert_eq!(direct, chunked);
+        assert!(chunked_calls * 10 < direct_calls, "{} calls", chunked_calls);
+        let mut reader = Cursor::new(chunked);
+        crate::validate::validate_structure(&mut reader).unwrap();
+    }
+
+    #[test]
+    fn opus_pre_roll_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::OPUS);
+        assert!(muxer.set_audio_dsi(b"OpusHead").is_err());
+        // The OpusHead of libopus with its lookahead of 312 samples
+        let mut opus_head = b"OpusHead\x01\x02".to_vec();
+        opus_head.extend_from_slice(&312u16.to_le_bytes());
+        opus_head.extend_from_slice(&48000u32.to_le_bytes());
+        opus_head.extend_from_slice(&[0; 3]);
+        muxer.set_audio_dsi(&opus_head).unwrap();
+        muxer.encode_video(&avc_frame(true), 200).unwrap();
+        for _ in 0..10 {
+            muxer.encode_audio(&[0xFC, 0xFF, 0xFE], 960).unwrap();
+        }
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+
+        // Stereo at 48 kHz with the libopus pre-skip of 312 samples
+        let dops = find_box(&buffer, b"dOps").unwrap();
+        assert_eq!(dops, &[0, 2, 0x01, 0x38, 0, 0, 0xBB, 0x80, 0, 0, 0]);
+        // The presentation starts after the pre-skip
+        let elst = find_box(&buffer, b"elst").unwrap();
+        assert_eq!(&elst[12..16], &312u32.to_be_bytes());
+        assert_eq!(&elst[8..12], &194u32.to_be_bytes());
+        // Every sample is decoded after the 4 frames of 20 ms before it
+        let sgpd = find_box(&buffer, b"sgpd").unwrap();
+        assert_eq!(&sgpd[4..8], b"roll");
+        assert_eq!(i16::from_be_bytes(sgpd[16..18].try_into().unwrap()), -4);
+        let sbgp = find_box(&buffer, b"sbgp").unwrap();
+        assert_eq!(&sbgp[4..8], b"roll");
+        assert_eq!(&sbgp[12..20], &[0, 0, 0, 10, 0, 0, 0, 1]);
+
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        assert!(muxer.set_opus_pre_skip(0).is_err());
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        assert!(muxer.set_opus_pre_skip(0).is_err());
+        muxer.set_audio_track(48000, 2, Codec::OPUS);
+        muxer.flush().unwrap();
+        // Without an OpusHead or a pre-skip nothing is trimmed
+        assert_eq!(&find_box(&buffer, b"dOps").unwrap()[2..4], &[0, 0]);
+        assert!(find_box(&buffer, b"elst").is_none());
+        assert!(find_box(&buffer, b"sgpd").is_some());
+    }
+
+    #[test]
+    fn audio_sample_type_test() {
+        use crate::{Codec, Mp4e, SampleType};
+        let types = [
+            SampleType::Default,
+            SampleType::Default,
+            SampleType::RandomAccess,
+            SampleType::Default,
+        ];
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::OPUS);
+        muxer.encode_video(&avc_frame(true), 80).unwrap();
+        for sample_type in types {
+            muxer
+                .encode_audio_with_type(&[0xFC, 0xFF, 0xFE], 960, sample_type)
+                .unwrap();
+        }
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+        // The single video key frame needs no stss box, the audio track lists its sync sample
+        let stss = find_box(&buffer, b"stss").unwrap();
+        assert_eq!(&stss[4..], &[0, 0, 0, 1, 0, 0, 0, 3]);
+        let sdtp = find_box(&buffer, b"sdtp").unwrap();
+        assert_eq!(&sdtp[4..], &[0x10, 0x10, 0x20, 0x10]);
+        assert!(find_box(&buffer, b"sbgp").is_some());
+
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::OPUS);
+        muxer.encode_video(&avc_frame(true), 80).unwrap();
+        muxer
+            .encode_audio_with_type(&[0xFC, 0xFF, 0xFE], 960, SampleType::Default)
+            .unwrap();
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+        // The trun of the audio fragment carries the flags of a non-sync sample
+        let trun_flags: Vec<&[u8]> = buffer
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"trun")
+            .map(|(pos, _)| &buffer[pos + 4..pos + 8])
+            .collect();
+        assert_eq!(trun_flags.len(), 2);
+        assert_eq!(trun_flags[1], &[0, 0, 0x06, 0x01]);
+        let trun = find_box(&buffer[buffer.len() - 200..], b"trun").unwrap();
+        assert_eq!(&trun[trun.len() - 4..], &0x1010000u32.to_be_bytes());
+
+        // Frames written with encode_audio stay sync samples
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_video(&avc_frame(true), 80).unwrap();
+        muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        muxer.flush().unwrap();
+        assert!(find_box(&buffer, b"stss").is_none());
+        assert!(find_box(&buffer, b"sdtp").is_none());
+    }
+
+    #[test]
+    fn empty_input_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        let table = |payload: &[u8]| -> Vec<u32> {
+            payload[8..]
+                .chunks(4)
+                .map(|value| u32::from_be_bytes(value.try_into().unwrap()))
+                .collect()
+        };
+        for fragment in [false, true] {
+            for opus in [false, true] {
+                let mut buffer = Vec::new();
+                let mut writer = std::io::Cursor::new(&mut buffer);
+                let mut muxer = if fragment {
+                    Mp4e::new_with_fragment(&mut writer)
+                } else {
+                    Mp4e::new(&mut writer)
+                };
+                muxer.set_video_track(320, 240, Codec::AVC);
+                muxer.set_audio_track(48000, 2, if opus { Codec::OPUS } else { Codec::AACLC });
+                // Empty video buffers are ignored, before and after the first key frame
+                muxer.encode_video(&[], 33).unwrap();
+                muxer.encode_video(&[0, 0, 0, 1], 33).unwrap();
+                muxer.encode_video(&avc_frame(true), 33).unwrap();
+                muxer.encode_video(&[], 33).unwrap();
+                muxer.encode_video_with_pts(&[], 33, 0).unwrap();
+                muxer.encode_video_au(&[], 33, false).unwrap();
+                // Zero-sample audio is rejected, empty or not
+                for data in [&[][..], &[0x21, 0x10, 0x04][..]] {
+                    let error = muxer.encode_audio(data, 0).unwrap_err();
+                    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+                }
+                muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+                muxer.encode_audio(&[], 1024).unwrap();
+                muxer.flush().unwrap();
+                muxer.validate().unwrap();
+                let report = muxer.sync_report().unwrap();
+                assert_eq!(report.video_end_ms, 33.0);
+                if fragment {
+                    continue;
+                }
+                // A single video sample, and the empty audio packet keeps its duration
+                let mut stsz = buffer
+                    .windows(4)
+                    .enumerate()
+                    .filter(|(_, w)| w == b"stsz")
+                    .map(|(pos, _)| find_box(&buffer[pos - 4..], b"stsz").unwrap());
+                assert_eq!(&table(stsz.next().unwrap())[..2], &[1, 9]);
+                let audio_sizes = table(stsz.next().unwrap());
+                if opus {
+                    // No silent frame for Opus, the sample is empty
+                    assert_eq!(&audio_sizes[..3], &[2, 3, 0]);
+                } else {
+                    // Written as the built-in silent AAC frame
+                    assert_eq!(&audio_sizes[..2], &[2, 3]);
+                    assert!(audio_sizes[2] > 0);
+                }
+                let audio_ms = report.audio_end_ms - report.audio_start_ms;
+                assert!((audio_ms - 2048.0 / 48.0).abs() < 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_silence_frame_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        // An empty silent frame would be written again and again for empty buffers
+        let error = muxer.set_audio_silence_frame(&[], 1024).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        muxer.encode_audio(&[], 1024).unwrap();
+        muxer.set_audio_silence_frame(&[0x01], 1024).unwrap();
+        muxer.encode_audio(&[], 1024).unwrap();
+        muxer.flush().unwrap();
+
+        // The built-in silent frame, then the one set
+        let stsz = find_box(&buffer, b"stsz").unwrap();
+        assert_eq!(&stsz[8..12], &2u32.to_be_bytes());
+        assert_eq!(&stsz[16..20], &1u32.to_be_bytes());
+    }
+
+    #[test]
+    fn sync_report_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        assert!(muxer.sync_report().is_none());
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.encode_video_ts(&avc_frame(true), 1_000_000).unwrap();
+        muxer.encode_video_ts(&avc_frame(false), 1_033_000).unwrap();
+        // Audio starts 50 ms after the video
+        for i in 0..4 {
+            muxer
+                .encode_audio_ts(&[0x21, 0x10, 0x04], 1_050_000 + i * 21_333, 1024)
+                .unwrap();
+        }
+        muxer.encode_video_ts(&avc_frame(false), 1_066_000).unwrap();
+        muxer.encode_video_ts(&avc_frame(false), 1_100_000).unwrap();
+        muxer.flush().unwrap();
+
+        let report = muxer.sync_report().unwrap();
+        assert_eq!(report.video_start_ms, 0.0);
+        assert_eq!(report.audio_start_ms, 50.0);
+        assert!((report.video_start_ms - report.audio_start_ms).abs() < 60.0);
+        // The last frame lasts as long as the one before
+        assert_eq!(report.video_end_ms, 134.0);
+        // 4096 samples at 48 kHz last 85.33 ms
+        assert!((report.audio_end_ms - 135.33).abs() < 0.01);
+    }
+
+    #[test]
+    fn moov_reserve_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        use std::io::Cursor;
+
+        let mux = |expected_samples: Option<u32>, frames: usize| {
+            let mut writer = Cursor::new(Vec::new());
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.set_audio_track(48000, 2, Codec::AACLC);
+            if let Some(expected_samples) = expected_samples {
+                muxer.reserve_moov_space(expected_samples).unwrap();
+            }
+            for i in 0..frames {
+                muxer.encode_video(&avc_frame(i % 30 == 0), 33).unwrap();
+                muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+            }
+            // The reserve is part of the layout of the output started by the first sample
+            assert_eq!(
+                muxer.reserve_moov_space(200).unwrap_err().kind(),
+                std::io::ErrorKind::InvalidInput
+            );
+            muxer.flush().unwrap();
+            muxer.validate().unwrap();
+            writer.into_inner()
+        };
+        let top_level = |buffer: &[u8]| {
+            let mut boxes = Vec::new();
+            let mut pos = 0;
+            while pos < buffer.len() {
+                let size = u32::from_be_bytes(buffer[pos..pos + 4].try_into().unwrap());
+                boxes.push(buffer[pos + 4..pos + 8].to_vec());
+                pos += size as usize;
+            }
+            boxes
+        };
+
+        // The moov box is written into the reserve, the rest of it stays a free box
+        let faststart = mux(Some(2000), 1000);
+        assert_eq!(
+            top_level(&faststart),
+            [b"ftyp", b"moov", b"free", b"free", b"mdat"]
+        );
+        let plain = mux(None, 1000);
+        assert_eq!(top_level(&plain), [b"ftyp", b"free", b"mdat", b"moov"]);
+        assert_eq!(
+            find_box(&faststart, b"moov").unwrap().len(),
+            find_box(&plain, b"moov").unwrap().len()
+        );
+
+        // A reserve too small for the moov box is left as a free box
+        let outgrown = mux(Some(0), 1000);
+        assert_eq!(
+            top_level(&outgrown),
+            [b"ftyp", b"free", b"free", b"mdat", b"moov"]
+        );
+
+        // Unless the data is moved forward to make room for the moov box
+        let mut writer = Cursor::new(Vec::new());
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        muxer.reserve_moov_space(0).unwrap();
+        for i in 0..1000 {
+            muxer.encode_video(&avc_frame(i % 30 == 0), 33).unwrap();
+            muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+        assert!(!muxer.moov_in_reserve());
+        muxer.move_moov_to_front().unwrap();
+        assert!(muxer.moov_in_reserve());
+        muxer.validate().unwrap();
+        let moved = writer.into_inner();
+        assert_eq!(&top_level(&moved)[..2], [b"ftyp", b"moov"]);
+        assert_eq!(
+            top_level(&moved).iter().filter(|b| *b == b"moov").count(),
+            1
+        );
+        assert_eq!(find_box(&moved, b"mdat"), find_box(&plain, b"mdat"));
+        assert_eq!(find_box(&moved, b"stsz"), find_box(&plain, b"stsz"));
+        // The chunk offsets follow the data
+        let first_chunk = |buffer: &[u8]| {
+            let stco = find_box(buffer, b"stco").unwrap();
+            let offset = u32::from_be_bytes(stco[8..12].try_into().unwrap()) as usize;
+            buffer[offset..offset + 13].to_vec()
+        };
+        assert_eq!(first_chunk(&moved), first_chunk(&plain));
+
+        // The moov box that fits the reserve stays there
+        let mut writer = Cursor::new(Vec::new());
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        assert!(muxer.move_moov_to_front().is_err());
+        muxer.reserve_moov_space(10).unwrap();
+        muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+        muxer.flush().unwrap();
+        assert!(muxer.moov_in_reserve());
+        muxer.move_moov_to_front().unwrap();
+    }
+
+    #[test]
+    fn smooth_streaming_test() {
+        use crate::{Codec, Mp4e, TFRF_UUID, TFXD_UUID};
+        use std::convert::TryInto;
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new_with_fragment(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_smooth_streaming(true);
+        for i in 0..3 {
+            muxer.encode_video(&avc_frame(i == 0), 40).unwrap();
+        }
+        muxer.flush().unwrap();
+        muxer.validate().unwrap();
+
+        // Each traf box ends with a tfxd box holding the time and duration of the fragment
+        let tfxd: Vec<(u64, u64)> = buffer
+            .windows(16)
+            .enumerate()
+            .filter(|(_, w)| *w == TFXD_UUID)
+            .map(|(pos, _)| {
+                assert_eq!(&buffer[pos - 4..pos], b"uuid");
+                assert_eq!(buffer[pos + 16], 1);
+                let time = u64::from_be_bytes(buffer[pos + 20..pos + 28].try_into().unwrap());
+                let duration = u64::from_be_bytes(buffer[pos + 28..pos + 36].try_into().unwrap());
+                // Followed by an empty tfrf box
+                assert_eq!(&buffer[pos + 36..pos + 40], &[0, 0, 0, 29]);
+                assert_eq!(&buffer[pos + 44..pos + 60], &TFRF_UUID);
+                assert_eq!(buffer[pos + 64], 0);
+                (time, duration)
+            })
+            .collect();
+        assert_eq!(tfxd, [(0, 3600), (3600, 3600), (7200, 3600)]);
+    }
+
+    #[test]
+    fn multi_nalu_sample_size_test() {
+        use crate::{Codec, Mp4e};
+        use std::convert::TryInto;
+        // Key frame and predicted frame of two slices each
+        let mut key = avc_frame(true);
+        key.extend_from_slice(&[0, 0, 0, 1, 0x65, 0x44, 0x84, 0x21, 0xA0]);
+        let mut predicted = avc_frame(false);
+        predicted.extend_from_slice(&[0, 0, 1, 0x41, 0x44, 0x21, 0x6C]);
+        let mux = |fragment: bool| {
+            let mut buffer = Vec::new();
+            let mut writer = std::io::Cursor::new(&mut buffer);
+            let mut muxer = if fragment {
+                Mp4e::new_with_fragment(&mut writer)
+            } else {
+                Mp4e::new(&mut writer)
+            };
+            muxer.set_video_track(320, 240, Codec::AVC);
+            muxer.encode_video(&key, 33).unwrap();
+            muxer.encode_video(&predicted, 33).unwrap();
+            muxer.flush().unwrap();
+            muxer.validate().unwrap();
+            buffer
+        };
+        let u32_at = |buffer: &[u8], pos: usize| {
+            u32::from_be_bytes(buffer[pos..pos + 4].try_into().unwrap())
+        };
+
+        // Each NAL unit takes its 4-byte length prefix in the sample size
+        let expected = [5 + 4 + 5 + 4, 4 + 4 + 4 + 4];
+        let file = mux(false);
+        let stsz = find_box(&file, b"stsz").unwrap();
+        assert_eq!([u32_at(stsz, 12), u32_at(stsz, 16)], expected);
+        let fragmented = mux(true);
+        let sizes: Vec<u32> = fragmented
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"trun")
+            .map(|(pos, _)| {
+                let trun = find_box(&fragmented[pos - 4..], b"trun").unwrap();
+                // The size of the single sample ends the trun box
+                u32_at(trun, trun.len() - 4)
+            })
+            .collect();
+        assert_eq!(sizes, expected);
+        // The mdat boxes of the fragments hold exactly the length-prefixed NAL units
+        let mdat_sizes: Vec<u32> = fragmented
+            .windows(4)
+            .enumerate()
+            .filter(|(_, w)| w == b"mdat")
+            .map(|(pos, _)| u32_at(&fragmented, pos - 4) - 8)
+            .collect();
+        assert_eq!(mdat_sizes, expected);
+    }
+
+    #[test]
+    fn mux_annexb_test() {
+        use crate::nalu::parse_sps_dimensions;
+        use crate::{Codec, Mp4e};
+        let hevc_sps = [
+            0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00,
+            0x00, 0x03, 0x00, 0x5D, 0xA0, 0x02, 0x80, 0x80, 0x2D, 0x16, 0x58,
+        ];
+        assert_eq!(
+            parse_sps_dimensions(&Codec::HEVC, &hevc_sps),
+            Some((1280, 720))
+        );
+
+        // 1920x1080 SPS with frame cropping, then three frames
+        let mut stream = vec![
+            0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0x40,
+        ];
+        stream.extend_from_slice(&avc_frame(true)[10..]);
+        stream.extend_from_slice(&avc_frame(false));
+        stream.extend_from_slice(&avc_frame(false));
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.mux_annexb(&mut &stream[..], Codec::AVC, 33).unwrap();
+        let tkhd = find_box(&buffer, b"tkhd").unwrap();
+        assert_eq!(
+            &tkhd[tkhd.len() - 8..],
+            &[0x07, 0x80, 0, 0, 0x04, 0x38, 0, 0]
+        );
+        assert_eq!(&find_box(&buffer, b"stsz").unwrap()[8..12], &[0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn large_file_test() {
+        use crate::{Codec, Mp4e, SampleInfo, TrackType};
+        use std::convert::TryInto;
+        const CHUNK: usize = 64 << 20;
+        // Only the header and the moov box after the samples are kept
+        let mut writer = MockWriter::new(u64::MAX, 48..65 * CHUNK as u64);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        let payload = vec![0; CHUNK];
+        for _ in 0..65 {
+            let sample = SampleInfo {
+                random_access: true,
+                offset: 0,
+                sample_size: 0,
+                sample_delta: 1024,
+                sample_ct_offset: 0,
+                description_index: 1,
+                temporal_id: 0,
+            };
+            muxer
+                .push_raw_sample(TrackType::Audio, &payload, sample)
+                .unwrap();
+        }
+        muxer.flush().unwrap();
+
+        // 64-bit mdat size covering all samples
+        let data = writer.data();
+        let (head, tail) = data.split_at(48);
+        let mdat_size = u64::from_be_bytes(head[40..48].try_into().unwrap());
+        assert_eq!(mdat_size, 16 + 65 * CHUNK as u64);
+        // The moov box follows the mdat box and uses 64-bit chunk offsets
+        assert!(find_box(tail, b"stco").is_none());
+        let co64 = find_box(tail, b"co64").unwrap();
+        assert_eq!(&co64[4..8], &65u32.to_be_bytes());
+        let last = u64::from_be_bytes(co64[8 + 64 * 8..8 + 65 * 8].try_into().unwrap());
+        assert_eq!(last, 48 + 64 * CHUNK as u64);
+        assert!(last > u32::MAX as u64);
+    }
+
+    #[test]
+    fn mdat_per_track_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_mdat_per_track(true);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        for i in 0..3 {
+            muxer.encode_video(&avc_frame(i == 0), 33).unwrap();
+            muxer.encode_audio(&[0xAA; 100], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        assert_eq!(buffer.windows(4).filter(|w| w == b"mdat").count(), 2);
+        // The audio samples are contiguous in the second mdat box
+        let stco = buffer.windows(4).rposition(|w| w == b"stco").unwrap();
+        let offset = |i: usize| {
+            let pos = stco + 12 + i * 4;
+            u32::from_be_bytes([
+                buffer[pos],
+                buffer[pos + 1],
+                buffer[pos + 2],
+                buffer[pos + 3],
+            ]) as usize
+        };
+        let first = offset(0);
+        assert_eq!(
+            &buffer[first - 8..first],
+            &[0, 0, 1, 52, b'm', b'd', b'a', b't']
+        );
+        assert_eq!(offset(2), first + 200);
+        assert!(buffer[first..first + 300].iter().all(|&b| b == 0xAA));
+    }
+
+    #[test]
+    fn sample_scheduler_test() {
+        use crate::{Codec, Mp4e, QueueState, SampleScheduler, TrackType};
+
+        /// Writes all audio before the video on flush
+        struct AudioFirst;
+        impl SampleScheduler for AudioFirst {
+            fn next(&mut self, queues: &[QueueState], _: u64, flushing: bool) -> Option<usize> {
+                if !flushing {
+                    return None;
+                }
+                queues
+                    .iter()
+                    .position(|queue| {
+                        matches!(queue.track_type, TrackType::Audio) && queue.samples > 0
+                    })
+                    .or_else(|| queues.iter().position(|queue| queue.samples > 0))
+            }
+        }
+
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_sample_scheduler(AudioFirst);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        for i in 0..3 {
+            muxer.encode_video(&avc_frame(i == 0), 33).unwrap();
+            muxer.encode_audio(&[0xAA; 100], 1024).unwrap();
+        }
+        muxer.flush().unwrap();
+
+        let offset = |stco: usize, i: usize| {
+            let pos = stco + 12 + i * 4;
+            u32::from_be_bytes([
+                buffer[pos],
+                buffer[pos + 1],
+                buffer[pos + 2],
+                buffer[pos + 3],
+            ]) as usize
+        };
+        let video_stco = buffer.windows(4).position(|w| w == b"stco").unwrap();
+        let audio_stco = buffer.windows(4).rposition(|w| w == b"stco").unwrap();
+        assert_eq!(offset(audio_stco, 0), 48);
+        assert_eq!(offset(audio_stco, 2), 248);
+        assert_eq!(offset(video_stco, 0), 348);
+        assert_eq!(&buffer[348..353], &[0, 0, 0, 5, 0x65]);
+        assert_eq!(&buffer[offset(video_stco, 2)..][..5], &[0, 0, 0, 4, 0x41]);
+    }
+
+    #[test]
+    fn recovery_point_test() {
+        use crate::{Codec, Mp4e};
+        let mut buffer = Vec::new();
+        let mut writer = std::io::Cursor::new(&mut buffer);
+        let mut muxer = Mp4e::new(&mut writer);
+        muxer.set_video_track(320, 240, Codec::AVC);
+        muxer.set_recovery_point_keyframes(true);
+        muxer.encode_video(&avc_frame(true), 33).unwrap();
+        muxer.encode_video(&avc_frame(false), 33).unwrap();
+        // Recovery point SEI followed by a non-IDR I slice (slice_type 7)
+        let frame = [
+            0, 0, 0, 1, 0x06, 0x06, 0x01, 0x84, 0x80, 0, 0, 0, 1, 0x41, 0x88, 0x80,
+        ];
+        muxer.encode_video(&frame, 33).unwrap();
+        // The same I slice without recovery point is not a key frame
+        muxer
+            .encode_video(&[0, 0, 0, 1, 0x41, 0x88, 0x80], 33)
+            .unwrap();
+        muxer.flush().unwrap();
+
+        let stss = find_box(&buffer, b"stss").unwrap();
+        assert_eq!(&stss[4..16], &[0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 3]);
+    }
+
+    /// Same muxing as the fuzz target in fuzz/fuzz_targets/mux.rs
+    fn mux_untrusted(config: u8, stream: &[u8]) {
+        use crate::{Codec, Mp4e, NaluFormat};
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        let mut muxer = if config & 0x01 == 0 {
+            Mp4e::new(&mut buffer)
+        } else {
+            Mp4e::new_with_fragment(&mut buffer)
+        };
+        let codec = if config & 0x02 == 0 {
+            Codec::AVC
+        } else {
+            Codec::HEVC
+        };
+        muxer.set_video_track(320, 240, codec);
+        muxer.set_audio_track(48000, 2, Codec::AACLC);
+        if config & 0x04 != 0 {
+            let size = [1, 2, 4][(config >> 3) as usize % 3];
+            muxer.set_nalu_format(NaluFormat::LengthPrefixed { size });
+        }
+        let frame_size = 16 + (config >> 5) as usize * 48;
+        for (i, frame) in stream.chunks(frame_size).enumerate() {
+            let _ = match i % 4 {
+                1 => muxer.encode_video_with_pts(frame, 33, i as u32 * 33),
+                3 => muxer.encode_audio(frame, 1024),
+                _ => muxer.encode_video(frame, 33),
+            };
+        }
+        let _ = muxer.flush();
+    }
+
+    #[test]
+    fn untrusted_input_test() {
+        // Known troublemakers: empty NAL units, all zeros, short NAL units, oversized SEI
+        let cases: [&[u8]; 5] = [
+            &[0, 0, 1, 0, 0, 1, 0, 0, 0, 1, 0, 0, 1],
+            &[0; 64],
+            &[
+                0, 0, 0, 1, 0x67, 0, 0, 1, 0x68, 0, 0, 1, 0x65, 0, 0, 1, 0x41,
+            ],
+            &[
+                0, 0, 1, 0x06, 0xFF, 0xFF, 0xFF, 0x05, 0xFF, 0xFF, 0x10, 0x01,
+            ],
+            &[
+                0, 0, 1, 0x4E, 0x01, 0x89, 0xFF, 0x40, 0, 0, 1, 0x40, 0x01, 0x2A,
+            ],
+        ];
+        for config in 0..=255u8 {
+            for case in cases.iter() {
+                mux_untrusted(config, case);
+            }
+        }
+        // Pseudo-random streams seeded with parameter sets so that slices get parsed
+        let mut seed = 0x2545_f491_u32;
+        for config in 0..=255u8 {
+            let mut stream = avc_frame(true);
+            for _ in 0..512 {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                // Bias towards start codes and zero bytes
+                stream.push(match seed % 8 {
+                    0 | 1 => 0,
+                    2 => 1,
+                    _ => (seed >> 8) as u8,
+                });
+            }
+            mux_untrusted(config, &stream);
+        }
+    }
+
+    #[test]
+    fn xiph_lacing_test() {
+        use crate::util::xiph_lacing;
+        let headers = vec![vec![1; 30], vec![3; 300], vec![5; 10]];
+        let laced = xiph_lacing(&headers);
+        assert_eq!(&laced[..4], &[2, 30, 0xff, 45]);
+        assert_eq!(laced.len(), 4 + 30 + 300 + 10);
+    }
+
+    #[test]
+    fn rotation_matrix_test() {
+        use crate::{Codec, Mp4e, TrackType};
+        use std::io::Cursor;
+        // The rotated picture is moved back into view by the width or height
+        let (width, height) = (1920 << 16, 1080 << 16);
+        for (degrees, expected) in [
+            (90, [0, 0x10000, 0, -0x10000, 0, 0, height, 0, 0x40000000]),
+            (
+                180,
+                [-0x10000, 0, 0, 0, -0x10000, 0, width, height, 0x40000000],
+            ),
+            (270, [0, -0x10000, 0, 0x10000, 0, 0, 0, width, 0x40000000]),
+        ] {
+            let mut buffer = Vec::new();
+            let mut writer = Cursor::new(&mut buffer);
+            let mut muxer = Mp4e::new(&mut writer);
+            muxer.set_video_track(1920, 1080, Codec::AVC);
+            assert!(muxer.set_rotation(TrackType::Audio, 90).is_err());
+            assert!(muxer.set_rotation(TrackType::Video, 45).is_err());
+            muxer.set_rotation(TrackType::Video, degrees).unwrap();
+            muxer.flush().unwrap();
+
+            let tkhd = buffer.windows(4).position(|w| w == b"tkhd").unwrap();
+            let matrix: Vec<i32> = buffer[tkhd + 44..tkhd + 80]
+                .chunks(4)
+                .map(|c| i32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            assert_eq!(matrix, expected, "{} degrees", degrees);
+        }
+
+        // The translation of 32768 pixels does not fit the 16.16 matrix entries
+        use crate::util::{rotation_matrix, IDENTITY_MATRIX};
+        let matrix = rotation_matrix(180, 32767, 32767).unwrap();
+        assert_eq!(matrix[6..8], [0x7FFF_0000, 0x7FFF_0000]);
+        assert!(rotation_matrix(90, 100, 32768).is_none());
+        assert!(rotation_matrix(270, 32768, 100).is_none());
+        assert!(rotation_matrix(180, u32::MAX, 100).is_none());
+        assert_eq!(
+            rotation_matrix(0, u32::MAX, u32::MAX),
+            Some(IDENTITY_MATRIX)
+        );
+    }
 }
@@ -1,783 +1,5744 @@
-// use mp4e_macros::mp4_box;
-use crate::boxes::*;
-use crate::types::*;
-use std::convert::TryInto;
-use std::io::{Cursor, Error, Seek, SeekFrom, Write};
-use std::vec;
-
-use crate::util::BitReader;
-
-/// Main MP4 muxer structure
-pub struct Mp4e<'a, Writer>
-where
-    Writer: Write,
-{
-    /// Whether to use fragmented mode
-    fragment: bool,
-    /// Whether the header has been initialized
-    init_header: bool,
-    /// Current write position in the output stream
-    write_pos: u64,
-    /// Creation time
-    create_time: u64,
-    /// Fragment ID counter
-    fragment_id: u32,
-    /// Total duration of the media
-    duration: u32,
-    /// Track ID counter
-    track_ids: u32,
-    /// Whether the moov box has been written
-    write_moov: bool,
-    /// Whether the first random access point has been sent
-    send_first_random_access: bool,
-    /// Language setting
-    language: [u8; 3],
-    /// Data writer
-    writer: &'a mut Writer,
-    /// Video track information
-    video_track: Option<Track>,
-    /// Audio track information
-    audio_track: Option<Track>,
-}
-
-impl<'a, Writer> Mp4e<'a, Writer>
-where
-    Writer: Write + Seek,
-{
-    /// Creates a new MP4 muxer instance with fragmented mode disabled
-    ///
-    /// # Arguments
-    /// * `writer` - The writer to output the MP4 data to
-    ///
-    /// # Returns
-    /// * A new `Mp4e` instance with fragmented mode disabled
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::{Cursor, Seek, Write};
-    /// use mp4e::Mp4e;
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new(&mut writer);
-    /// ```
-    pub fn new(writer: &'a mut Writer) -> Self {
-        Self::new_encoder(false, writer)
-    }
-}
-
-impl<'a, Writer> Mp4e<'a, Writer>
-where
-    Writer: Write,
-{
-    /// Creates a new MP4 muxer instance with fragmented mode enabled
-    ///
-    /// # Arguments
-    /// * `writer` - The writer to output the MP4 data to
-    ///
-    /// # Returns
-    /// * A new `Mp4e` instance with fragmented mode and stream mode enabled
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::Cursor;
-    /// use mp4e::Mp4e;
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
-    /// ```
-    pub fn new_with_fragment(writer: &'a mut Writer) -> Self {
-        Self::new_encoder(true, writer)
-    }
-
-    /// Sets the language for the MP4 file
-    ///
-    /// # Arguments
-    /// * `language` - A 3-byte array representing the language code
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::Cursor;
-    /// use mp4e::Mp4e;
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
-    ///
-    /// // Set language to Japanese
-    /// muxer.set_language([b'j', b'p', b'n']);
-    /// ```
-    pub fn set_language(&mut self, language: [u8; 3]) {
-        self.language = language;
-    }
-
-    /// Sets the creation time for the MP4 file
-    ///
-    /// # Arguments
-    /// * `create_time` - The creation time in seconds since Unix epoch
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::Cursor;
-    /// use mp4e::Mp4e;
-    /// use std::time::{SystemTime, UNIX_EPOCH};
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
-    ///
-    /// // Set creation time to current time
-    /// muxer.set_create_time(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
-    /// ```
-    pub fn set_create_time(&mut self, create_time: u64) {
-        self.create_time = create_time + 2082844800;
-    }
-
-    /// Sets up an audio track with the specified parameters
-    ///
-    /// # Arguments
-    /// * `sample_rate` - The audio sample rate in Hz
-    /// * `channel_count` - The number of audio channels
-    /// * `codec` - The audio codec to use
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::Cursor;
-    /// use mp4e::{Mp4e, Codec};
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
-    ///
-    /// // Set up an AAC-LC audio track with 48kHz sample rate and 2 channels
-    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
-    /// ```
-    pub fn set_audio_track(&mut self, sample_rate: u32, channel_count: u32, codec: Codec) {
-        let profile = match codec {
-            Codec::AACMAIN => 1,
-            Codec::AACLC => 2,
-            Codec::AACSSR => 3,
-            Codec::AACLTP => 4,
-            Codec::HEAAC => 5,
-            Codec::HEAACV2 => 29,
-            _ => 0,
-        };
-        let mut dsi = None;
-        match codec {
-            Codec::OPUS => {}
-            _ => {
-                let mut dsi_buf: [u8; 2] = [0; 2];
-                use crate::util::get_sample_rate_idx;
-                let sample_rate_idx = get_sample_rate_idx(sample_rate);
-                dsi_buf[0] = (profile << 3) | ((sample_rate_idx & 0x0e) >> 1) as u8;
-                dsi_buf[1] = ((sample_rate_idx & 0x01) << 7) as u8 | (channel_count << 3) as u8;
-                dsi = Some(dsi_buf);
-            }
-        }
-
-        self.audio_track = Some(Track {
-            id: self.track_ids,
-            duration: 0,
-            timescale: sample_rate,
-            samples: vec![],
-            sample_rate,
-            channel_count,
-            codec,
-            width: 0,
-            height: 0,
-            dsi: dsi,
-            vps: None,
-            sps: None,
-            pps: None,
-            track_type: TrackType::Audio,
-        });
-
-        self.track_ids += 1;
-    }
-
-    /// Sets up a video track with the specified parameters
-    ///
-    /// # Arguments
-    /// * `width` - The video width in pixels
-    /// * `height` - The video height in pixels
-    /// * `codec` - The video codec to use
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::Cursor;
-    /// use mp4e::{Mp4e, Codec};
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
-    ///
-    /// // Set up an H.264 video track with 1920x1080 resolution
-    /// muxer.set_video_track(1920, 1080, Codec::AVC);
-    /// ```
-    pub fn set_video_track(&mut self, width: u32, height: u32, codec: Codec) {
-        self.video_track = Some(Track {
-            id: self.track_ids,
-            duration: 0,
-            timescale: 90000,
-            samples: vec![],
-            width,
-            height,
-            codec,
-            sample_rate: 0,
-            channel_count: 0,
-            dsi: None,
-            vps: None,
-            sps: None,
-            pps: None,
-            track_type: TrackType::Video,
-        });
-        self.track_ids += 1;
-    }
-
-    /// Writes an audio data to the MP4 file
-    ///
-    /// # Arguments
-    /// * `data` - The audio data
-    /// * `samples` - The number of audio samples in this frame. This represents
-    ///               the duration in sample count, not bytes. For example, if you
-    ///               have 1024 PCM samples that were encoded, you pass 1024 here.
-    ///               If you only know the duration in milliseconds, you can estimate
-    ///               the sample count using the formula: duration_ms * sample_rate / 1000.
-    ///               For example, with a 48kHz sample rate and 21.33ms duration:
-    ///               samples = 21.33 * 48000 / 1000 = 1024 samples.
-    ///               
-    ///     
-    /// # Returns
-    /// * `Ok(())` on success, or an error if writing fails
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::Cursor;
-    /// use mp4e::{Mp4e, Codec};
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
-    ///
-    /// // Set up audio track first
-    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
-    ///
-    /// // ... process video frames first to establish synchronization ...
-    ///
-    /// // Encode audio data with 1024 samples
-    /// let audio_data = vec![0; 512]; // Example audio data
-    /// muxer.encode_audio(&audio_data, 1024).unwrap();
-    /// ```
-    pub fn encode_audio(&mut self, data: &[u8], samples: u32) -> Result<(), Error> {
-        self.init_header_if_needed()?;
-        if let Some(track) = self.audio_track.as_mut() {
-            if self.send_first_random_access {
-                let duration = samples;
-                track.duration += duration;
-                self.put_sample(data, duration, false, 0, SampleType::RandomAccess)?;
-            }
-        }
-        Ok(())
-    }
-
-    /// Writes a video frame to the MP4 file (with no b frame)
-    ///
-    /// # Arguments
-    /// * `data` - The video frame data
-    /// * `duration` - The duration of the video frame in milliseconds
-    ///
-    /// # Returns
-    /// * `Ok(())` on success, or an error if writing fails
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::Cursor;
-    /// use mp4e::{Mp4e, Codec};
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
-    ///
-    /// // Set up video track first
-    /// muxer.set_video_track(1920, 1080, Codec::AVC);
-    ///
-    /// // Encode a video frame with 33ms duration (approximately 30fps)
-    /// let video_frame_data = vec![0; 1024]; // Example video frame data
-    /// muxer.encode_video(&video_frame_data, 33).unwrap();
-    /// ```
-    pub fn encode_video(&mut self, data: &[u8], duration: u32) -> Result<(), Error> {
-        self.init_header_if_needed()?;
-        if let Some(track) = self.video_track.as_mut() {
-            let duration = duration * track.timescale / 1000;
-            track.duration += duration;
-            self.duration = if track.duration > self.duration {
-                track.duration
-            } else {
-                self.duration
-            };
-            match track.codec {
-                Codec::AVC => self.write_avc_frame(data, duration, 0)?,
-                Codec::HEVC => self.write_hevc_frame(data, duration, 0)?,
-                _ => {}
-            }
-        }
-
-        Ok(())
-    }
-    /// Writes a video frame to the MP4 file with presentation timestamp (PTS)，support b frame
-    ///
-    /// This method allows for more precise control over video frame timing by accepting
-    /// a presentation timestamp. It calculates the composition time offset (ct_offset)
-    /// which represents the difference between decode time and presentation time.
-    ///
-    /// # Arguments
-    /// * `data` - The video frame data (NAL units)
-    /// * `duration` - The duration of the video frame in milliseconds
-    /// * `pts` - Presentation timestamp in the track's timescale
-    ///
-    /// # Returns
-    /// * `Ok(())` on success, or an error if writing fails
-    ///
-    /// # Example
-    /// ```
-    /// use std::io::Cursor;
-    /// use mp4e::{Mp4e, Codec};
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new(&mut writer);
-    ///
-    /// // Set up video track first
-    /// muxer.set_video_track(1920, 1080, Codec::AVC);
-    ///
-    /// // Encode a video frame with specific PTS
-    /// let video_frame_data = vec![0; 1024]; // Example video frame data
-    /// muxer.encode_video_with_pts(&video_frame_data, 33, 1000).unwrap();
-    /// ```
-    pub fn encode_video_with_pts(
-        &mut self,
-        data: &[u8],
-        duration: u32,
-        pts: u32,
-    ) -> Result<(), Error> {
-        self.init_header_if_needed()?;
-        if let Some(track) = self.video_track.as_mut() {
-            // Convert duration from milliseconds to track timescale
-            let duration = duration * track.timescale / 1000;
-            track.duration += duration;
-
-            // Update the overall media duration if this track is longer
-            self.duration = if track.duration > self.duration {
-                track.duration
-            } else {
-                self.duration
-            };
-
-            // Calculate composition time offset (decode time to presentation time offset)
-            let ct_offset =
-                ((pts as i64) * track.timescale as i64 / 1000 - track.duration as i64) as i32;
-
-            // Process the frame based on codec type
-            match track.codec {
-                Codec::AVC => self.write_avc_frame(data, duration, ct_offset)?,
-                Codec::HEVC => self.write_hevc_frame(data, duration, ct_offset)?,
-                _ => {}
-            }
-        }
-        Ok(())
-    }
-}
-
-impl<'a, Writer> Mp4e<'a, Writer>
-where
-    Writer: Write + Seek,
-{
-    /// Flushes any remaining data and finalizes the MP4 file
-    ///
-    /// This method ensures that all MP4 boxes are properly written to the output,
-    /// including the 'moov' box which contains metadata about the file.
-    ///
-    /// # Returns
-    /// * `Ok(())` on success, or an error if writing fails
-    /// # Example
-    /// ```
-    /// use std::io::{Cursor, Seek, Write};
-    /// use mp4e::{Mp4e, Codec};
-    ///
-    /// let mut buffer = Vec::new();
-    /// let mut writer = Cursor::new(&mut buffer);
-    /// let mut muxer = Mp4e::new(&mut writer);
-    ///
-    /// // ... encode audio/video data ...
-    ///
-    /// muxer.flush().unwrap();
-    /// ```
-    pub fn flush(&mut self) -> Result<(), Error> {
-        self.init_header_if_needed()?;
-        if !self.write_moov {
-            self.write_mdat_size()?;
-            self.write_moov_if_needed()?;
-        }
-        Ok(())
-    }
-}
-
-impl<'a, Writer> Mp4e<'a, Writer>
-where
-    Writer: Write + Seek,
-{
-    /// Updates the size field of the mdat box
-    ///
-    /// In MP4 files, the mdat box header needs to contain the total size of the box (including the header itself).
-    /// Since the final size of media data cannot be known at initialization time, this value needs to be updated
-    /// after all data has been written.
-    ///
-    /// This implementation uses the large size format (64-bit) for the mdat box.
-    fn write_mdat_size(&mut self) -> Result<(), Error> {
-        // Seek to the size field position of the mdat box (mdat box starts at offset 32, size field takes first 8 bytes for large size)
-        self.writer.seek(SeekFrom::Start(40))?;
-        // Calculate and write the actual mdat size (write_pos is current total write position, minus 32 bytes for headers)
-        // Using large size format (64-bit)
-        self.writer
-            .write_all(&(self.write_pos - 32).to_be_bytes())?;
-        // Restore file cursor to current write position
-        self.writer.seek(SeekFrom::Start(self.write_pos))?;
-        Ok(())
-    }
-}
-impl<'a, Writer> Mp4e<'a, Writer>
-where
-    Writer: Write,
-{
-    /// Creates a new MP4 encoder instance with the specified configuration
-    ///
-    /// This is the internal constructor used by both `new` and `new_with_fragment` methods
-    /// to initialize the Mp4e struct with default values.
-    ///
-    /// # Arguments
-    /// * `fragment` - Whether to use fragmented MP4 mode (true) or standard mode (false)
-    /// * `writer` - The writer object to output the MP4 data to
-    ///
-    /// # Returns
-    /// * A new `Mp4e` instance with initialized fields
-    fn new_encoder(fragment: bool, writer: &'a mut Writer) -> Self {
-        Self {
-            // Current position in the output stream, starts at 0
-            write_pos: 0,
-            // Media creation time, defaults to 0 (will be set later if needed)
-            create_time: 0,
-            // Whether to use fragmented mode (true) or standard mode (false)
-            fragment: fragment,
-            // Fragment sequence ID counter, starts at 0
-            fragment_id: 0,
-            // Total media duration, starts at 0
-            duration: 0,
-            // Track ID counter, starts at 1 (ID 0 is reserved)
-            track_ids: 1,
-            // Whether the MP4 header has been initialized
-            init_header: false,
-            // Whether the first random access point (keyframe) has been processed
-            send_first_random_access: false,
-            // Whether the moov box has been written to the output
-            write_moov: false,
-            // Default language code ("und" = undetermined)
-            language: "und".as_bytes().try_into().unwrap(),
-            // The writer object for outputting MP4 data
-            writer,
-            // Video track information, initially empty
-            video_track: None,
-            // Audio track information, initially empty
-            audio_track: None,
-        }
-    }
-    /// Processes and writes HEVC (H.265) video frames to the MP4 file
-    ///
-    /// This function takes HEVC NAL units, parses them, and handles different types appropriately:
-    /// - VPS (Video Parameter Set): Stores configuration data
-    /// - SPS (Sequence Parameter Set): Stores sequence configuration data
-    /// - PPS (Picture Parameter Set): Stores picture configuration data
-    /// - Other NAL units: Writes as video samples when key configuration is available
-    ///
-    /// For HEVC, key frames are identified by specific NAL unit types in the range
-    /// [HEVC_NAL_BLA_W_LP, HEVC_NAL_CRA_NUT].
-    ///
-    /// # Arguments
-    /// * `data` - The raw HEVC NAL unit data to process
-    /// * `duration` - The duration of the frame in the track's timescale
-    /// * `ct_offset` - The composition time offset for the frame
-    ///
-    ///
-    /// # Returns
-    /// * `Ok(())` on successful processing, or an error if writing fails
-    fn write_hevc_frame(
-        &mut self,
-        data: &[u8],
-        duration: u32,
-        ct_offset: i32,
-    ) -> Result<(), Error> {
-        use crate::nalu::*;
-        // Split the input data into individual NAL units
-        for frame_data in split_nalu(data) {
-            // Extract the NAL unit type (HEVC uses 6 bits for type, shifted right by 1)
-            let nalu_type = (frame_data[0] & 0x7e) >> 1;
-            // Get mutable reference to the video track
-            let video_track = self.video_track.as_mut().unwrap();
-
-            match nalu_type {
-                // Handle Video Parameter Set
-                HEVC_NALU_TYPE_VPS => {
-                    // Only store the first VPS NAL unit
-                    if video_track.vps.is_none() {
-                        video_track.vps = Some(frame_data.to_vec());
-                    }
-                }
-                // Handle Sequence Parameter Set
-                HEVC_NALU_TYPE_SPS => {
-                    // Only store the first SPS NAL unit
-                    if video_track.sps.is_none() {
-                        video_track.sps = Some(frame_data.to_vec());
-                    }
-                }
-                // Handle Picture Parameter Set
-                HEVC_NALU_TYPE_PPS => {
-                    // Only store the first PPS NAL unit
-                    if video_track.pps.is_none() {
-                        video_track.pps = Some(frame_data.to_vec());
-                    }
-                }
-                // Handle all other NAL unit types (video data)
-                _ => {
-                    // Only process video data NAL units after we have the essential configuration
-                    if !video_track.vps.is_none()
-                        && !video_track.sps.is_none()
-                        && !video_track.vps.is_none()
-                    {
-                        // Check if this is a key frame (Random Access Point)
-                        // Key frame types are in the range [BLA_W_LP, CRA_NUT]
-                        if nalu_type >= HEVC_NAL_BLA_W_LP && nalu_type <= HEVC_NAL_CRA_NUT {
-                            // Write the key frame as a random access sample
-                            self.put_sample(
-                                frame_data,
-                                duration,
-                                true,
-                                ct_offset,
-                                SampleType::RandomAccess,
-                            )?;
-                            // Mark that we've received our first key frame
-                            self.send_first_random_access = true;
-                        }
-                        // For non-key frames, only write them after we've received the first key frame
-                        else if self.send_first_random_access {
-                            // Write as a default (non-key) sample
-                            self.put_sample(
-                                frame_data,
-                                duration,
-                                true,
-                                ct_offset,
-                                SampleType::Default,
-                            )?;
-                        }
-                    }
-                }
-            }
-        }
-        Ok(())
-    }
-
-    /// Processes and writes AVC (H.264) video frames to the MP4 file
-    ///
-    /// This function takes AVC NAL units, parses them, and handles different types appropriately:
-    /// - SPS (Sequence Parameter Set): Stores sequence configuration data
-    /// - PPS (Picture Parameter Set): Stores picture configuration data
-    /// - Other NAL units: Writes as video samples when key configuration is available
-    ///
-    /// For AVC, key frames are identified by I-Slice NAL units (AVC_NAL_ISLICE_NALU).
-    /// Additionally, it analyzes slice headers to determine if a NAL unit is a continuation
-    /// of a previous frame or a new frame.
-    ///
-    /// # Arguments
-    /// * `data` - The raw AVC NAL unit data to process
-    /// * `duration` - The duration of the frame in the track's timescale
-    /// * `ct_offset` - The composition time offset for the frame
-    ///
-    /// # AVC Specifics
-    /// - NAL unit types are determined by the last 5 bits of the first byte
-    /// - Frame boundaries are determined by parsing the slice header using UE-Golomb decoding
-    /// - The first_mb_in_slice parameter indicates if this is a new frame (0) or continuation (!=0)
-    ///
-    /// # Returns
-    /// * `Ok(())` on successful processing, or an error if writing fails
-    fn write_avc_frame(&mut self, data: &[u8], duration: u32, ct_offset: i32) -> Result<(), Error> {
-        use crate::nalu::*;
-        // Split the input data into individual NAL units
-        for frame_data in split_nalu(data) {
-            // Extract the NAL unit type (AVC uses last 5 bits of the first byte)
-            let nalu_type = frame_data[0] & 0x1f;
-            // Get mutable reference to the video track
-            let video_track = self.video_track.as_mut().unwrap();
-
-            match nalu_type {
-                // Handle Sequence Parameter Set
-                AVC_NALU_TYPE_SPS => {
-                    // Only store the first SPS NAL unit
-                    if video_track.sps.is_none() {
-                        video_track.sps = Some(frame_data.to_vec());
-                    }
-                }
-                // Handle Picture Parameter Set
-                AVC_NALU_TYPE_PPS => {
-                    // Only store the first PPS NAL unit
-                    if video_track.pps.is_none() {
-                        video_track.pps = Some(frame_data.to_vec());
-                    }
-                }
-                // Handle all other NAL unit types (video data including I-frames, P-frames, B-frames, etc.)
-                _ => {
-                    // Only process video data NAL units after we have the essential configuration (SPS and PPS)
-                    if !video_track.sps.is_none() && !video_track.pps.is_none() {
-                        // Default sample type is a regular frame
-                        let mut sample_type = SampleType::Default;
-
-                        // Create a bit reader to parse the slice header (starting from the second byte)
-                        let mut br: BitReader<'_> = BitReader::new(&frame_data[1..]);
-                        // Read the first_mb_in_slice value using UE-Golomb decoding
-                        // If it's 0, this is the start of a new frame; otherwise, it's a continuation
-                        let first_mb_in_slice = br.ue_bits(1);
-
-                        // Determine the sample type based on slice header information
-                        if first_mb_in_slice != 0 {
-                            // This NAL unit is a continuation of the previous frame
-                            sample_type = SampleType::Continuation;
-                        } else if nalu_type == AVC_NAL_ISLICE_NALU {
-                            // This is the start of an I-frame (key frame)
-                            sample_type = SampleType::RandomAccess;
-                        }
-
-                        // Process the NAL unit based on its type
-                        if nalu_type == AVC_NAL_ISLICE_NALU {
-                            // For I-frames (key frames):
-                            // Mark that we've received our first key frame
-                            self.send_first_random_access = true;
-                            // Write the frame data as a video sample
-                            self.put_sample(frame_data, duration, true, ct_offset, sample_type)?;
-                        }
-                        // For non-I frames, only write them after we've received the first key frame
-                        else if self.send_first_random_access {
-                            // Write as a regular or continuation sample
-                            self.put_sample(frame_data, duration, true, ct_offset, sample_type)?;
-                        }
-                    }
-                }
-            }
-        }
-        Ok(())
-    }
-
-    fn init_mp4(&mut self) -> Result<(), Error> {
-        self.write_pos += write_ftyp(self.writer)?;
-        if !self.fragment {
-            self.write_pos += write_mdat_header(self.writer)?;
-        }
-        Ok(())
-    }
-    fn put_sample(
-        &mut self,
-        data: &[u8],
-        duration: u32,
-        video: bool,
-        ct_offset: i32,
-        sample_type: SampleType,
-    ) -> Result<(), Error> {
-        if self.fragment {
-            self.write_moov_if_needed()?;
-            self.fragment_id += 1;
-            let mut buf: [u8; 4096] = [0; 4096];
-            let mut cursor = Cursor::new(&mut buf[..]);
-            write_moof(
-                self.fragment_id,
-                data,
-                duration,
-                if video {
-                    self.video_track.as_ref().unwrap()
-                } else {
-                    self.audio_track.as_ref().unwrap()
-                },
-                ct_offset,
-                sample_type,
-                &mut cursor,
-            )?;
-            let end_pos = cursor.position();
-            self.writer.write_all(&buf[..end_pos as usize])?;
-            self.write_pos += end_pos as u64;
-            let box_size = write_mdat(data, video, self.writer)?;
-            self.write_pos += box_size as u64;
-            return Ok(());
-        }
-        if !video {
-            let sample_info = SampleInfo {
-                random_access: true,
-                offset: self.write_pos,
-                sample_size: data.len() as u32,
-                sample_delta: duration,
-                sample_ct_offset: ct_offset,
-            };
-            self.audio_track.as_mut().unwrap().samples.push(sample_info);
-            self.writer.write_all(data)?;
-            self.write_pos += data.len() as u64;
-        } else {
-            if let SampleType::Default | SampleType::RandomAccess = sample_type {
-                let sample_info = SampleInfo {
-                    random_access: if let SampleType::RandomAccess = sample_type {
-                        true
-                    } else {
-                        false
-                    },
-                    offset: self.write_pos,
-                    sample_size: data.len() as u32 + 4,
-                    sample_delta: duration,
-                    sample_ct_offset: ct_offset,
-                };
-                self.video_track.as_mut().unwrap().samples.push(sample_info);
-            } else {
-                let samples = &mut self.video_track.as_mut().unwrap().samples;
-                let last_sample = samples.last_mut().unwrap();
-                last_sample.sample_size += data.len() as u32 + 4;
-            }
-            let nal_size_buf = (data.len() as u32).to_be_bytes();
-            self.writer.write_all(&nal_size_buf[..])?;
-            self.writer.write_all(data)?;
-            self.write_pos += data.len() as u64 + 4;
-        }
-
-        Ok(())
-    }
-
-    fn init_header_if_needed(&mut self) -> Result<(), Error> {
-        if !self.init_header {
-            self.init_mp4()?;
-            self.init_header = true;
-        }
-        Ok(())
-    }
-    fn write_moov_if_needed(&mut self) -> Result<(), Error> {
-        if !self.write_moov {
-            let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-            write_moov(
-                &self.video_track,
-                &self.audio_track,
-                self.create_time,
-                self.track_ids,
-                &self.language,
-                self.fragment,
-                &mut cursor,
-            )?;
-            let end_pos = cursor.position();
-            let buf = cursor.into_inner();
-            self.writer.write_all(&buf[..end_pos as usize])?;
-            self.write_pos += end_pos;
-            self.write_moov = true;
-        }
-        Ok(())
-    }
-}
+// use mp4e_macros::mp4_box;
+use crate::aac::{aac_asc, aac_profile, aac_silent_frame};
+use crate::boxes::*;
+use crate::nalu::{
+    detect_codec_nalus, group_access_units, hevc_temporal_id, keep_sei, parse_sps_dimensions,
+    parse_sps_profile, split_access_units, split_length_prefixed, split_nalu, starts_access_unit,
+    vcl_first_slice, NaluSplitter, AVC_NALU_TYPE_PPS, AVC_NALU_TYPE_SPS, AVC_NAL_SEI,
+    HEVC_NALU_TYPE_PPS, HEVC_NALU_TYPE_SPS, HEVC_NALU_TYPE_VPS, HEVC_NAL_PREFIX_SEI,
+    HEVC_NAL_SUFFIX_SEI,
+};
+use crate::scheduler::{QueueState, SampleScheduler};
+use crate::types::*;
+use crate::validate::validate_structure;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::{Cursor, Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::vec;
+
+use crate::util::{
+    is_vorbis_header, ms_to_timescale, rescale, rotation_matrix, timescale_to_ms, xiph_lacing,
+    BitReader, Crc32,
+};
+
+/// Main MP4 muxer structure
+pub struct Mp4e<'a, Writer>
+where
+    Writer: Write,
+{
+    /// Whether to use fragmented mode
+    fragment: bool,
+    /// State of the output file being written
+    file: FileState,
+    /// Creation time
+    create_time: u64,
+    /// Fragment ID counter
+    fragment_id: u32,
+    /// Whether the byte ranges of written fragments are recorded
+    record_fragments: bool,
+    /// Byte ranges of the written fragments
+    fragments: Vec<FragmentInfo>,
+    /// Track ID counter
+    track_ids: u32,
+    /// Whether the first random access point has been sent
+    send_first_random_access: bool,
+    /// Whether the video codec is detected from the first frames
+    detect_video_codec: bool,
+    /// Language setting
+    language: [u8; 3],
+    /// Time scale of the movie header and the track header durations
+    movie_timescale: u32,
+    /// Framing of the input video NAL units
+    nalu_format: NaluFormat,
+    /// Handling of SEI NAL units in video samples
+    sei_policy: SeiPolicy,
+    /// Features of the output restricted for older players
+    compatibility_profile: CompatibilityProfile,
+    /// Whether every video frame is a key frame
+    all_intra: bool,
+    /// Video frame rate as numerator and denominator, for frames without a duration
+    video_frame_rate: Option<(u32, u32)>,
+    /// Capture time of the first frame passed with a timestamp, in microseconds
+    ts_start: Option<u64>,
+    /// Video frame waiting for the capture time of the next frame, with its capture time
+    pending_video_ts: Option<(Vec<u8>, u64)>,
+    /// Duration of the last video frame passed with a timestamp, in the track's timescale
+    video_ts_delta: u32,
+    /// Video frames passed with their presentation time only, in decode order, waiting
+    /// for their decode time and duration, with their presentation time in milliseconds
+    pending_video_pts: VecDeque<(Vec<u8>, u64)>,
+    /// Presentation times of the frames in `pending_video_pts` in ascending order, the
+    /// first one is the decode time of the first frame
+    pts_slots: Vec<u64>,
+    /// Total duration in the track's timescale and number of the video frames whose
+    /// duration was inferred from the presentation time of the next frame
+    pts_durations: (u64, u64),
+    /// Last presentation time passed to the video encode methods in milliseconds, with
+    /// the number of times the 32-bit timestamps wrapped around before it
+    pts_wraps: Option<(u32, u64)>,
+    /// Access unit being assembled from video data passed as stream chunks
+    stream_input: Option<StreamInput>,
+    /// Longest time allowed between two video key frames in milliseconds, 0 for no limit
+    max_keyframe_interval: u32,
+    /// Called with the time since the last key frame when it exceeds the interval
+    on_keyframe_gap: Option<OnKeyframeGap>,
+    /// Audio frame written by `encode_audio_silence` with its number of audio samples
+    audio_silence: Option<(Vec<u8>, u32)>,
+    /// Audio samples of the silence gaps not covered by whole silent frames yet, negative
+    /// when more silence was written than asked for
+    audio_silence_remainder: i64,
+    /// Whether the first video frame is a key frame whatever its NAL unit types
+    assume_first_keyframe: bool,
+    /// Whether I pictures with a recovery point SEI are key frames
+    recovery_point_keyframes: bool,
+    /// Whether the mdat box is preceded by a QuickTime wide box instead of a free box
+    wide_box: bool,
+    /// Whether audio and caption data get their own mdat box after the video data
+    mdat_per_track: bool,
+    /// Whether a uuid box with the CRC-32 of each fragment follows the fragment
+    fragment_checksum: bool,
+    /// Whether the traf boxes carry the tfxd and tfrf boxes of Smooth Streaming
+    smooth_streaming: bool,
+    /// Whether each fragment is preceded by a sidx box indexing it
+    segment_index: bool,
+    /// Whether the sample tables of the moov box are written in their most compact form
+    compact_tables: bool,
+    /// Duration of audio gathered in each audio fragment in milliseconds (fragmented mode)
+    audio_fragment_duration: u32,
+    /// Size in bytes above which new samples are rejected in non-fragmented mode, 0 for no limit
+    sample_table_limit: usize,
+    /// Whether each video key frame starts a new segment (fragmented mode)
+    segmenting: bool,
+    /// Scheduler deciding the order of the samples in the mdat box
+    scheduler: Option<Box<dyn SampleScheduler>>,
+    /// Rollover of the output to a new file at video key frames
+    rollover: Option<Rollover<'a, Writer>>,
+    /// Whether video slices were dropped because the parameter sets were missing
+    parameter_sets_missing: bool,
+    /// Whether writing to the output failed, which leaves the output inconsistent
+    poisoned: bool,
+    /// Called with the type, size and VCL flag of each video NAL unit processed
+    on_nalu: Option<OnNalu>,
+    /// Boxes given by the application for the moov box
+    user_boxes: UserBoxes,
+    /// Buffer the moov and moof boxes are built in, kept to be reused by the next box
+    scratch: Vec<u8>,
+    /// Size of the sample data gathered before it is written to the output, 0 to write
+    /// each sample as it comes (non-fragmented mode)
+    io_chunk_size: usize,
+    /// Sample data gathered for the next write to the output
+    io_buffer: Vec<u8>,
+    /// Size of the free box after the ftyp box the moov box is written into, 0 for none
+    /// (non-fragmented mode)
+    moov_reserve: u32,
+    /// Longest duration of video held back while waiting for the audio track in
+    /// milliseconds, 0 for no limit, or `None` if the moov box does not wait for it
+    /// (fragmented mode)
+    expected_audio: Option<u32>,
+    /// Samples held back until the moov box is written, with their track (fragmented mode)
+    held_samples: Vec<(TrackType, PendingSample)>,
+    /// Data writer
+    writer: &'a mut Writer,
+    /// Video track information
+    video_track: Option<Track>,
+    /// Audio track information
+    audio_track: Option<Track>,
+    /// Closed caption track information
+    caption_track: Option<Track>,
+}
+
+/// Callback observing the type, size and VCL flag of each video NAL unit, see
+/// `Mp4e::set_on_nalu`
+pub type OnNalu = Box<dyn FnMut(u8, usize, bool)>;
+
+/// Callback told the time in milliseconds since the last video key frame when it
+/// exceeds the required interval, see `Mp4e::require_keyframe_interval`
+pub type OnKeyframeGap = Box<dyn FnMut(u64)>;
+
+/// Limits of an output file and the switch to the next file
+struct Rollover<'a, Writer>
+where
+    Writer: Write,
+{
+    /// Duration of video after which a file is finished in milliseconds, 0 for no limit
+    max_duration: u32,
+    /// Size after which a file is finished in bytes, 0 for no limit
+    max_bytes: u64,
+    /// Callback returning the writer of the next file, borrowed like the writer: a boxed
+    /// closure would make the destructor of the muxer keep the writers borrowed as long
+    /// as the muxer
+    on_rollover: &'a mut dyn FnMut() -> Result<&'a mut Writer, Error>,
+    /// `Mp4e::roll_over`, which needs a writer that can seek, unlike the encode methods
+    /// checking the limits
+    roll_over: fn(&mut Mp4e<'a, Writer>) -> Result<(), Error>,
+}
+
+/// State of the output file being written, started anew when the rollover switches to
+/// the next file
+#[derive(Default)]
+struct FileState {
+    /// Current write position in the output stream
+    write_pos: u64,
+    /// Whether the header has been initialized
+    init_header: bool,
+    /// Whether the moov box has been written
+    write_moov: bool,
+    /// Total duration of the media, the longest track in the movie timescale
+    duration: u32,
+    /// Position of the moov box written by `flush_to_last_keyframe`, 0 if there is none
+    index_pos: u64,
+    /// Position of the mdat box taking the samples after `flush_to_last_keyframe`, 0 for
+    /// the one after the ftyp box and the reserve
+    mdat_pos: u64,
+    /// Whether the next fragment starts after a discontinuity
+    discontinuity: bool,
+    /// Write positions where segments not yet taken from the output start
+    segment_starts: VecDeque<u64>,
+    /// Number of bytes taken from the start of the output with `take_segment`
+    taken_bytes: u64,
+    /// Size of the moov box written before the first fragment, 0 if there is none
+    /// (fragmented mode)
+    moov_size: u64,
+    /// Position of the fragment_duration field of the mehd box in the output, 0 if
+    /// there is none (fragmented mode)
+    mehd_pos: u64,
+    /// Whether the last moov box was written into the reserve (non-fragmented mode)
+    moov_in_reserve: bool,
+    /// Total of the video durations given in milliseconds, converted as a timeline
+    video_duration_ms: u64,
+    /// Total of the audio sample counts converted to a timescale other than the sample rate
+    audio_duration_samples: u64,
+    /// Number of video frames whose duration came from the frame rate
+    video_frame_count: u64,
+    /// Decode time of the last video key frame, in the track's timescale
+    last_keyframe_time: u64,
+    /// Whether the GOP of the last video key frame was reported as too long
+    keyframe_gap_reported: bool,
+}
+
+/// Video access unit assembled from an Annex-B stream arriving in chunks
+#[derive(Clone, Default)]
+struct StreamInput {
+    /// NAL units split from the chunks so far
+    splitter: NaluSplitter,
+    /// NAL units of the access unit being assembled
+    unit: Vec<Vec<u8>>,
+    /// Whether the access unit has a slice yet
+    has_slice: bool,
+    /// Duration passed with the last chunk, used for the access unit written by `flush`
+    duration: u32,
+}
+
+/// Muxing state of an `Mp4e`, to continue the file with another muxer, see
+/// `Mp4e::export_state`
+///
+/// The tracks with their sample tables, the write position, the fragment sequence number
+/// and the duration are public, the other fields hold the position of the mdat box and
+/// the timelines of the input. With the `serde` feature, the state is serializable.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MuxerState {
+    /// Current write position in the output stream
+    pub write_pos: u64,
+    /// Fragment ID counter
+    pub fragment_id: u32,
+    /// Total duration of the media, the longest track in the movie timescale
+    pub duration: u32,
+    /// Video track information with its sample table
+    pub video_track: Option<Track>,
+    /// Audio track information with its sample table
+    pub audio_track: Option<Track>,
+    /// Closed caption track information with its sample table
+    pub caption_track: Option<Track>,
+    // The other fields are those of `Mp4e`
+    fragment: bool,
+    init_header: bool,
+    write_moov: bool,
+    create_time: u64,
+    track_ids: u32,
+    send_first_random_access: bool,
+    /// Position of the mdat box the samples are written to, whatever the reserve before it
+    mdat_pos: u64,
+    index_pos: u64,
+    video_frame_count: u64,
+    video_duration_ms: u64,
+    audio_duration_samples: u64,
+    ts_start: Option<u64>,
+    video_ts_delta: u32,
+    pts_durations: (u64, u64),
+    pts_wraps: Option<(u32, u64)>,
+    last_keyframe_time: u64,
+    audio_silence_remainder: i64,
+}
+
+impl<'a, Writer> Mp4e<'a, Writer>
+where
+    Writer: Write + Seek,
+{
+    /// Creates a new MP4 muxer instance with fragmented mode disabled
+    ///
+    /// # Arguments
+    /// * `writer` - The writer to output the MP4 data to
+    ///
+    /// # Returns
+    /// * A new `Mp4e` instance with fragmented mode disabled
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::{Cursor, Seek, Write};
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// ```
+    pub fn new(writer: &'a mut Writer) -> Self {
+        Self::new_encoder(false, writer)
+    }
+}
+
+impl<'a, Writer> Mp4e<'a, Writer>
+where
+    Writer: Write,
+{
+    /// Creates a new MP4 muxer instance with fragmented mode enabled
+    ///
+    /// # Arguments
+    /// * `writer` - The writer to output the MP4 data to
+    ///
+    /// # Returns
+    /// * A new `Mp4e` instance with fragmented mode and stream mode enabled
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    /// ```
+    pub fn new_with_fragment(writer: &'a mut Writer) -> Self {
+        Self::new_encoder(true, writer)
+    }
+
+    /// Sets the language for the MP4 file
+    ///
+    /// # Arguments
+    /// * `language` - A 3-byte array representing the language code
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
+    ///
+    /// // Set language to Japanese
+    /// muxer.set_language([b'j', b'p', b'n']);
+    /// ```
+    pub fn set_language(&mut self, language: [u8; 3]) {
+        self.language = language;
+    }
+
+    /// Sets the time scale of the movie
+    ///
+    /// The movie header and track header durations are written in this time scale, the
+    /// media of each track keep their own time scale. The default is 1000 (milliseconds),
+    /// editing tools may prefer the time scale of the video, e.g. 24000 for a 24fps project.
+    ///
+    /// # Arguments
+    /// * `timescale` - The number of time units per second
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if the time scale is 0
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_movie_timescale(24000).unwrap();
+    /// ```
+    pub fn set_movie_timescale(&mut self, timescale: u32) -> Result<(), Error> {
+        if timescale == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "movie time scale must not be 0",
+            ));
+        }
+        self.movie_timescale = timescale;
+        Ok(())
+    }
+
+    /// Sets the framing of the video data passed to the encode methods
+    ///
+    /// # Arguments
+    /// * `nalu_format` - Annex-B start codes (the default) or length-prefixed NAL units
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, NaluFormat};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // Samples taken from another MP4 file with 2-byte NAL lengths
+    /// muxer.set_nalu_format(NaluFormat::LengthPrefixed { size: 2 });
+    /// ```
+    pub fn set_nalu_format(&mut self, nalu_format: NaluFormat) {
+        self.nalu_format = nalu_format;
+    }
+
+    /// Sets whether SEI NAL units are written into the video samples
+    ///
+    /// HDR10 static metadata is still picked up from the SEI messages of HEVC streams
+    /// when the SEI NAL units themselves are stripped.
+    ///
+    /// # Arguments
+    /// * `sei_policy` - Keep all SEI (the default), strip all SEI, or only keep HDR and caption SEI
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, SeiPolicy};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // Remove timing and user data SEI from the output
+    /// muxer.set_sei_policy(SeiPolicy::Strip);
+    /// ```
+    pub fn set_sei_policy(&mut self, sei_policy: SeiPolicy) {
+        self.sei_policy = sei_policy;
+    }
+
+    /// Sets the compatibility profile of the output
+    ///
+    /// With `CompatibilityProfile::Legacy`, the file has neither a ctts box nor an edit
+    /// list: the encode methods return an error for frames whose presentation time differs
+    /// from their decode time (B-frames), so such streams must be re-encoded without them.
+    ///
+    /// # Arguments
+    /// * `profile` - Standard (the default) or legacy output
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Codec, CompatibilityProfile, Mp4e};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_compatibility_profile(CompatibilityProfile::Legacy);
+    ///
+    /// // A frame presented after its decode time is rejected
+    /// assert!(muxer.encode_video_with_pts(&[0, 0, 0, 1, 0x41, 0x9A], 33, 100).is_err());
+    /// ```
+    pub fn set_compatibility_profile(&mut self, profile: CompatibilityProfile) {
+        self.compatibility_profile = profile;
+    }
+
+    /// Sets whether every video frame is treated as a key frame
+    ///
+    /// Use this for all-intra content (e.g. AVC-Intra) whose intra pictures are not
+    /// coded as IDR slices. When every video sample is a key frame, the stss box is
+    /// omitted, which signals that all samples are sync samples.
+    ///
+    /// # Arguments
+    /// * `all_intra` - Whether all video frames are key frames
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_all_intra(true);
+    /// ```
+    pub fn set_all_intra(&mut self, all_intra: bool) {
+        self.all_intra = all_intra;
+    }
+
+    /// Sets the frame rate of the video track
+    ///
+    /// Frames encoded with a duration of 0 then get their duration from the frame rate,
+    /// computed in the track's timescale. The durations of consecutive frames add up to
+    /// the exact length of the stream, e.g. 3003 units per frame at 30000/1001 fps, where
+    /// millisecond durations drift by seconds over an hour.
+    ///
+    /// # Arguments
+    /// * `num` - The numerator of the frame rate in frames per second
+    /// * `den` - The denominator of the frame rate
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if either value is 0
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_video_frame_rate(30000, 1001).unwrap();
+    /// // ... muxer.encode_video(&frame, 0) ...
+    /// ```
+    pub fn set_video_frame_rate(&mut self, num: u32, den: u32) -> Result<(), Error> {
+        if num == 0 || den == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "frame rate numerator and denominator must not be 0",
+            ));
+        }
+        self.video_frame_rate = Some((num, den));
+        Ok(())
+    }
+
+    /// Treats the first video frame as a key frame, whatever its NAL unit types
+    ///
+    /// Video frames before the first key frame are dropped, as a raw capture may start
+    /// in the middle of a GOP. For a stream known to start cleanly, for example on an HEVC
+    /// CRA picture that is not detected as a key frame, this keeps the leading frames.
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::HEVC);
+    /// muxer.assume_starts_on_keyframe();
+    /// ```
+    pub fn assume_starts_on_keyframe(&mut self) {
+        self.assume_first_keyframe = true;
+    }
+
+    /// Sets a function called for each video NAL unit the muxer processes
+    ///
+    /// This is a debugging hook: it sees every non-empty NAL unit of the AVC and HEVC
+    /// frames passed to the encode methods, before the muxer decides what to do with it,
+    /// which helps to find out why frames are dropped or merged. The arguments are the
+    /// NAL unit type, the size of the NAL unit without start code or length prefix and
+    /// whether it is a VCL NAL unit (a slice).
+    ///
+    /// # Arguments
+    /// * `on_nalu` - The function to call, it may capture state such as a log or a channel,
+    ///   or `None` to stop calling it
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// let stream = "camera 1";
+    /// muxer.set_on_nalu(Some(Box::new(move |nalu_type, size, vcl| {
+    ///     eprintln!("{}: NAL unit type {} of {} bytes, VCL: {}", stream, nalu_type, size, vcl);
+    /// })));
+    /// ```
+    pub fn set_on_nalu(&mut self, on_nalu: Option<OnNalu>) {
+        self.on_nalu = on_nalu;
+    }
+
+    /// Sets whether the data passed to `encode_video` is a stream of arbitrary chunks
+    ///
+    /// When enabled, the Annex-B data passed to `encode_video` need not hold whole frames,
+    /// e.g. depacketized network packets: NAL units cut by the end of a chunk are kept
+    /// until the rest arrives, and each access unit is written once the first NAL unit of
+    /// the next one is complete. Every access unit completed by a call gets the duration
+    /// passed with it, 0 to derive it from the frame rate, which suits chunks that do not
+    /// match frames. The last access unit of the stream is written by `flush`.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether video data arrives in chunks (disabled by default)
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_video_frame_rate(30, 1).unwrap();
+    /// muxer.set_streaming_input(true);
+    /// // ... muxer.encode_video(&packet_payload, 0) for each packet ...
+    /// ```
+    pub fn set_streaming_input(&mut self, enabled: bool) {
+        self.stream_input = if enabled {
+            Some(StreamInput::default())
+        } else {
+            None
+        };
+    }
+
+    /// Sets whether AVC I pictures at recovery points are treated as key frames
+    ///
+    /// Open-GOP streams only have an IDR picture at the start, later seek points are
+    /// non-IDR I pictures signaled by a recovery point SEI. When enabled, a picture made
+    /// only of I slices with a recovery point SEI is written as a sync sample.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether recovery points are key frames
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_recovery_point_keyframes(true);
+    /// ```
+    pub fn set_recovery_point_keyframes(&mut self, enabled: bool) {
+        self.recovery_point_keyframes = enabled;
+    }
+
+    /// Sets whether each track gets its own mdat box
+    ///
+    /// The video data is written to the first mdat box as it arrives, the audio and
+    /// caption data are held in memory and written to one mdat box per track on flush.
+    /// Only used in non-fragmented mode.
+    ///
+    /// # Arguments
+    /// * `mdat_per_track` - Whether to write one mdat box per track
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_mdat_per_track(true);
+    /// ```
+    pub fn set_mdat_per_track(&mut self, mdat_per_track: bool) {
+        self.mdat_per_track = mdat_per_track;
+    }
+
+    /// Sets the scheduler deciding the order of the samples in the mdat box
+    ///
+    /// Samples are queued in memory per track and written in the order chosen by the
+    /// scheduler, e.g. `ChunkScheduler` groups the samples of each track in chunks of
+    /// about one second. Only used in non-fragmented mode.
+    ///
+    /// # Arguments
+    /// * `scheduler` - The sample scheduler
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{ChunkScheduler, Mp4e};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_sample_scheduler(ChunkScheduler::default());
+    /// ```
+    pub fn set_sample_scheduler<S>(&mut self, scheduler: S)
+    where
+        S: SampleScheduler + 'static,
+    {
+        self.scheduler = Some(Box::new(scheduler));
+    }
+
+    /// Sets the creation time for the MP4 file
+    ///
+    /// # Arguments
+    /// * `create_time` - The creation time in seconds since Unix epoch
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    /// use std::time::{SystemTime, UNIX_EPOCH};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
+    ///
+    /// // Set creation time to current time
+    /// muxer.set_create_time(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+    /// ```
+    pub fn set_create_time(&mut self, create_time: u64) {
+        self.create_time = create_time + 2082844800;
+    }
+
+    /// Sets the sequence number of the next fragment
+    ///
+    /// The sequence number is written to the mfhd box of each fragment and is
+    /// incremented for every fragment after that. Only used in fragmented mode.
+    ///
+    /// # Arguments
+    /// * `seq` - The sequence number of the next fragment
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// // Restart numbering after an encoder reconnect
+    /// muxer.set_fragment_sequence(1);
+    /// ```
+    pub fn set_fragment_sequence(&mut self, seq: u32) {
+        self.fragment_id = seq.wrapping_sub(1);
+    }
+
+    /// Sets whether the byte ranges of written fragments are recorded
+    ///
+    /// Recorded fragments can be patched afterwards with `rewrite_fragment`.
+    /// Only used in fragmented mode.
+    ///
+    /// # Arguments
+    /// * `record` - Whether to record the fragments written from now on
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// muxer.set_record_fragments(true);
+    /// ```
+    pub fn set_record_fragments(&mut self, record: bool) {
+        self.record_fragments = record;
+    }
+
+    /// Sets whether each fragment is followed by a checksum box
+    ///
+    /// The checksum is written in a uuid box of user type `FRAGMENT_CHECKSUM_UUID`, right
+    /// after the mdat box of the fragment. Its payload is the big-endian CRC-32 (IEEE 802.3,
+    /// as computed by zlib) of the moof and mdat boxes, so a relay can detect corrupted
+    /// fragments by computing it again. Only used in fragmented mode.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write checksums for the fragments written from now on
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// muxer.set_fragment_checksum(true);
+    /// ```
+    pub fn set_fragment_checksum(&mut self, enabled: bool) {
+        self.fragment_checksum = enabled;
+    }
+
+    /// Sets whether the fragments carry the boxes of Microsoft Smooth Streaming
+    ///
+    /// Smooth Streaming clients such as Silverlight take the timing of a fragment from a
+    /// tfxd uuid box in its traf box rather than from the tfdt box. With this mode, each
+    /// traf box gets a tfxd box with the absolute decode time and the duration of the
+    /// fragment in the track's timescale, and a tfrf box. The tfrf box references none
+    /// of the next fragments since they are not written yet; live clients find them
+    /// through the manifest, which stays with the application. Only used in fragmented
+    /// mode.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write the Smooth Streaming boxes in the fragments written
+    ///   from now on
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// muxer.set_smooth_streaming(true);
+    /// ```
+    pub fn set_smooth_streaming(&mut self, enabled: bool) {
+        self.smooth_streaming = enabled;
+    }
+
+    /// Sets whether each fragment is preceded by a sidx box indexing it
+    ///
+    /// The sidx box gives the earliest presentation time, the duration and the size of
+    /// the fragment that follows, and whether it starts with a key frame, so a player
+    /// fetching a fragmented file by byte ranges finds the fragment of a time without
+    /// reading the fragments before it. The mfra box at the end of the file indexes the
+    /// key frames as well, for players reading the whole file. Only used in fragmented
+    /// mode.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write a sidx box before the fragments written from now on
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// muxer.set_segment_index(true);
+    /// ```
+    pub fn set_segment_index(&mut self, enabled: bool) {
+        self.segment_index = enabled;
+    }
+
+    /// Sets whether the sample tables of the moov box are written in their most compact form
+    ///
+    /// By default every sample is its own chunk with an entry in the chunk offset box,
+    /// and the stsz box lists the size of every sample. In compact mode, the samples
+    /// following each other in the file are gathered in one chunk, and a track whose
+    /// samples all have the same size only has the single size field of the stsz box.
+    /// The stts and ctts boxes are run-length encoded in any case. For regular content,
+    /// e.g. a constant frame rate MJPEG stream at a fixed size without audio, the
+    /// sample table then stays a few entries long however long the recording. Not used
+    /// in fragmented mode, whose moov box has no samples.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write compact sample tables (disabled by default)
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_compact_sample_tables(true);
+    /// ```
+    pub fn set_compact_sample_tables(&mut self, enabled: bool) {
+        self.compact_tables = enabled;
+    }
+
+    /// Sets the duration of audio gathered in each audio fragment
+    ///
+    /// Audio frames are short, so they are held back until this much audio is queued
+    /// and then written as one fragment with a sample entry per frame. The queued audio
+    /// is also written before a segment boundary, by `finish_segment` and by `flush`.
+    /// Only used in fragmented mode.
+    ///
+    /// # Arguments
+    /// * `duration` - The duration in milliseconds, 0 writes each audio frame as a fragment
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// muxer.set_audio_fragment_duration(1000);
+    /// ```
+    pub fn set_audio_fragment_duration(&mut self, duration: u32) {
+        self.audio_fragment_duration = duration;
+    }
+
+    /// Delays the moov box until the audio track is set up
+    ///
+    /// The moov box lists the tracks of a fragmented file and is written with the first
+    /// fragment, so a track set up later is left out. When the audio configuration only
+    /// becomes known after the first video frames, e.g. from a live source, the video
+    /// samples are held back instead: the moov box with the audio track and the held
+    /// samples are written once `set_audio_track` has been called and the next sample
+    /// arrives. If no audio track is set up within `max_wait` milliseconds of video, or
+    /// by `finish_segment` or `flush`, the file is written without it.
+    /// Only used in fragmented mode.
+    ///
+    /// # Arguments
+    /// * `max_wait` - The longest duration of video held back in milliseconds, 0 for no limit
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.expect_audio_track(2000);
+    ///
+    /// // ... encode the first video frames ...
+    ///
+    /// // The audio track still makes it into the moov box
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// ```
+    pub fn expect_audio_track(&mut self, max_wait: u32) {
+        self.expected_audio = Some(max_wait);
+    }
+
+    /// Sets whether a QuickTime wide box precedes the mdat box
+    ///
+    /// Classic QuickTime writes an 8-byte wide box before the mdat box, the space its
+    /// 64-bit size takes if the data grows beyond 4 GB. Older QuickTime versions expect
+    /// it, while other players skip it like the free box written by default. The sample
+    /// offsets are the same either way. Only used in non-fragmented mode.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write a wide box instead of a free box
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_wide_box(true);
+    /// ```
+    pub fn set_wide_box(&mut self, enabled: bool) {
+        self.wide_box = enabled;
+    }
+
+    /// Sets how much sample data is gathered before it is written to the output
+    ///
+    /// Each sample is normally written to the output as it comes, which means a system
+    /// call per frame for an unbuffered file. With a chunk size, the samples are copied
+    /// into a buffer that is written in one piece once it holds this many bytes, so
+    /// small frames at a high frame rate, or recordings on spinning disks, take fewer and
+    /// larger writes. The rest of the buffer is written by `flush`. Only used in
+    /// non-fragmented mode, fragments are written in one piece anyway.
+    ///
+    /// # Arguments
+    /// * `bytes` - The size of the chunks written to the output, 0 to write each sample
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_io_chunk_size(1 << 20);
+    /// ```
+    pub fn set_io_chunk_size(&mut self, bytes: usize) {
+        self.io_chunk_size = bytes;
+    }
+
+    /// Reserves space for the moov box in front of the mdat box for faststart files
+    ///
+    /// Players streaming a file need the moov box before the samples. Instead of moving
+    /// all the sample data once the moov box is known, a free box sized for the expected
+    /// number of samples is written after the ftyp box, and `flush` writes the moov box
+    /// into it, followed by a smaller free box for the space left. The file is then
+    /// written in a single pass. Only used in non-fragmented mode.
+    ///
+    /// `flush` does not move any data: if the moov box outgrows the reserved space, it is
+    /// written after the mdat box as without a reserve and the free box stays in place,
+    /// so the file is not a faststart file. Callers needing one must check
+    /// `moov_in_reserve` after `flush` and, if it is `false`, call `move_moov_to_front`,
+    /// which reads the sample data back from outputs implementing `Read`.
+    ///
+    /// # Arguments
+    /// * `expected_samples` - The number of video, audio and caption samples expected
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error once samples have been written
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    ///
+    /// // Ten minutes of 30 fps video and 48 kHz AAC
+    /// muxer.reserve_moov_space(10 * 60 * (30 + 47)).unwrap();
+    ///
+    /// // ... encode audio/video data ...
+    ///
+    /// muxer.flush().unwrap();
+    /// if !muxer.moov_in_reserve() {
+    ///     muxer.move_moov_to_front().unwrap();
+    /// }
+    /// ```
+    pub fn reserve_moov_space(&mut self, expected_samples: u32) -> Result<(), Error> {
+        if self.file.init_header {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the moov space is reserved before the first sample",
+            ));
+        }
+        self.moov_reserve = estimate_moov_size(expected_samples);
+        Ok(())
+    }
+
+    /// Returns whether the moov box was written into the space reserved with
+    /// `reserve_moov_space`
+    ///
+    /// # Returns
+    /// * `true` if the file written by `flush` is a faststart file with the moov box in
+    ///   the reserve, `false` if the moov box outgrew it or no space was reserved
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// muxer.reserve_moov_space(100).unwrap();
+    /// muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+    /// muxer.flush().unwrap();
+    ///
+    /// assert!(muxer.moov_in_reserve());
+    /// ```
+    pub fn moov_in_reserve(&self) -> bool {
+        self.file.moov_in_reserve
+    }
+
+    /// Requires a video key frame at least every `max_ms` milliseconds
+    ///
+    /// Segments of DASH and HLS streams start on key frames, so an encoder with a longer
+    /// GOP than configured produces uneven segments. When the first frame of a GOP starts
+    /// more than `max_ms` after its key frame, `on_gap` is called with the time since the
+    /// key frame during the encode call, once per GOP. The frames are written all the
+    /// same, the stream and the file stay consistent; the application decides whether to
+    /// stop.
+    ///
+    /// # Arguments
+    /// * `max_ms` - The longest interval between key frames in milliseconds, 0 for no limit
+    /// * `on_gap` - The function to call with the time since the key frame in milliseconds,
+    ///   it may capture state such as a log or a channel
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// // 2 second segments
+    /// muxer.require_keyframe_interval(
+    ///     2000,
+    ///     Box::new(|gap| eprintln!("no key frame for {} ms", gap)),
+    /// );
+    /// ```
+    pub fn require_keyframe_interval(&mut self, max_ms: u32, on_gap: OnKeyframeGap) {
+        self.max_keyframe_interval = max_ms;
+        self.on_keyframe_gap = Some(on_gap);
+    }
+
+    /// Sets the size limit of the sample tables kept in memory
+    ///
+    /// In non-fragmented mode the information of every sample is kept until the moov box
+    /// is written by `flush`, so a recording of many hours holds millions of entries.
+    /// Once `sample_table_memory` reaches the limit, new samples are rejected with an
+    /// `OutOfMemory` error: the file can then be flushed and a new one started. For
+    /// recordings without an end, the fragmented mode keeps the memory use bounded.
+    ///
+    /// # Arguments
+    /// * `limit` - The limit in bytes, 0 for no limit (the default)
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // About 2.7 million samples
+    /// muxer.set_sample_table_limit(64 << 20);
+    /// ```
+    pub fn set_sample_table_limit(&mut self, limit: usize) {
+        self.sample_table_limit = limit;
+    }
+
+    /// Estimates the memory used by the sample tables of the tracks
+    ///
+    /// The moov box written by `flush` is built from these tables, in non-fragmented mode
+    /// they grow with each sample until then. The sample data held back for the sample
+    /// scheduler or for the per-track mdat boxes is not included.
+    ///
+    /// # Returns
+    /// * The number of bytes allocated for the sample information of all tracks
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// assert_eq!(muxer.sample_table_memory(), 0);
+    ///
+    /// // ... encode video frames ...
+    ///
+    /// let memory = muxer.sample_table_memory();
+    /// ```
+    pub fn sample_table_memory(&self) -> usize {
+        [&self.video_track, &self.audio_track, &self.caption_track]
+            .iter()
+            .filter_map(|track| track.as_ref())
+            .map(|track| {
+                track.samples.capacity() * std::mem::size_of::<SampleInfo>()
+                    + track.random_access_points.capacity() * std::mem::size_of::<(u64, u64)>()
+            })
+            .sum()
+    }
+
+    /// Reports the presentation times of the video and audio tracks
+    ///
+    /// The start and end of each track are computed from the durations and composition
+    /// offsets of its samples and from its edit list, as a player presents them, so
+    /// QA can check the synchronization of a recording. In fragmented mode the sample
+    /// tables are not kept: the tracks start at their delay and last their duration.
+    ///
+    /// # Returns
+    /// * The presentation times in milliseconds, or `None` without a video and an audio track
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    ///
+    /// // ... encode audio/video data ...
+    ///
+    /// muxer.flush().unwrap();
+    /// let report = muxer.sync_report().unwrap();
+    /// assert!((report.video_start_ms - report.audio_start_ms).abs() < 40.0);
+    /// ```
+    pub fn sync_report(&self) -> Option<SyncReport> {
+        let (video_start_ms, video_end_ms) = presentation_range(self.video_track.as_ref()?);
+        let (audio_start_ms, audio_end_ms) = presentation_range(self.audio_track.as_ref()?);
+        Some(SyncReport {
+            video_start_ms,
+            audio_start_ms,
+            video_end_ms,
+            audio_end_ms,
+        })
+    }
+
+    /// Marks a discontinuity before the next fragment
+    ///
+    /// The next fragment is preceded by a styp box so it can start a new segment,
+    /// and the decode time (tfdt) of every track restarts from zero.
+    /// Only used in fragmented mode.
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// // ... source restarted ...
+    /// muxer.mark_discontinuity();
+    /// muxer.set_fragment_sequence(1);
+    /// ```
+    pub fn mark_discontinuity(&mut self) {
+        if !self.fragment {
+            return;
+        }
+        // The decode times restart at 0 with the next fragment
+        self.file.discontinuity = true;
+    }
+
+    /// Sets whether the output is cut into segments
+    ///
+    /// Each video key frame starts a new segment, beginning with a styp box, and
+    /// `finish_segment` ends the current one. With a `Vec<u8>` output, the completed
+    /// segments are taken out of the output with `take_segment`.
+    /// Only used in fragmented mode.
+    ///
+    /// # Arguments
+    /// * `segmenting` - Whether to start segments from now on
+    ///
+    /// # Example
+    /// ```
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut output = Vec::new();
+    /// let mut muxer = Mp4e::new_with_fragment(&mut output);
+    ///
+    /// muxer.set_segmenting(true);
+    /// ```
+    pub fn set_segmenting(&mut self, segmenting: bool) {
+        self.segmenting = segmenting;
+    }
+
+    /// Ends the current segment
+    ///
+    /// The queued audio is written and the data written so far becomes a completed
+    /// segment, e.g. for the last segment of a stream. Only used in segmenting mode.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing the queued audio fails
+    pub fn finish_segment(&mut self) -> Result<(), Error> {
+        if !self.segmenting {
+            return Ok(());
+        }
+        self.write_held_samples()?;
+        self.write_audio_fragment()?;
+        if self.file.segment_starts.back() != Some(&self.file.write_pos) {
+            self.file.segment_starts.push_back(self.file.write_pos);
+        }
+        Ok(())
+    }
+
+    /// Sets up an audio track with the specified parameters
+    ///
+    /// # Arguments
+    /// * `sample_rate` - The audio sample rate in Hz
+    /// * `channel_count` - The number of audio channels
+    /// * `codec` - The audio codec to use
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
+    ///
+    /// // Set up an AAC-LC audio track with 48kHz sample rate and 2 channels
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// ```
+    pub fn set_audio_track(&mut self, sample_rate: u32, channel_count: u32, codec: Codec) {
+        // AAC variants carry an AudioSpecificConfig, Opus and Vorbis have their own configuration
+        let dsi = aac_profile(&codec).map(|profile| aac_asc(profile, sample_rate, channel_count));
+
+        self.audio_track = Some(Track {
+            sample_rate,
+            channel_count,
+            dsi,
+            ..Track::new(self.track_ids, TrackType::Audio, codec, sample_rate)
+        });
+
+        self.track_ids += 1;
+    }
+
+    /// Sets the timescale of the audio track
+    ///
+    /// By default the audio timescale is the sample rate, so each sample is one time
+    /// unit. Another timescale, e.g. 90000 to share the clock of an MPEG-TS source, is
+    /// supported as well: the sample counts passed to the encode methods are converted
+    /// to it, in the sample table and in the fragments.
+    ///
+    /// # Arguments
+    /// * `timescale` - The number of time units per second, not 0
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the audio track is not set up,
+    ///   already has samples or the timescale is 0
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// muxer.set_audio_timescale(90000).unwrap();
+    /// ```
+    pub fn set_audio_timescale(&mut self, timescale: u32) -> Result<(), Error> {
+        let track = self.track_mut(TrackType::Audio)?;
+        if timescale == 0 || track.duration > 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the audio timescale must be set to a value other than 0 before any audio",
+            ));
+        }
+        track.timescale = timescale;
+        self.file.audio_duration_samples = 0;
+        Ok(())
+    }
+
+    /// Sets the objectTypeIndication of the audio track written to its esds box
+    ///
+    /// By default it follows the codec: 0x40 (MPEG-4 audio) for AAC, 0x6B (MPEG-1 audio)
+    /// for MP3 and 0xDD for Vorbis. Other values signal related formats carried the same
+    /// way, e.g. 0x66 to 0x68 for MPEG-2 AAC or 0x69 for MPEG-2 audio.
+    ///
+    /// # Arguments
+    /// * `object_type_indication` - The objectTypeIndication of the MP4 registration authority
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the audio track is not set up
+    ///   or its codec has no esds box
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // MPEG-2 AAC LC
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// muxer.set_audio_object_type_indication(0x67).unwrap();
+    /// ```
+    pub fn set_audio_object_type_indication(
+        &mut self,
+        object_type_indication: u8,
+    ) -> Result<(), Error> {
+        let track = self.track_mut(TrackType::Audio)?;
+        if matches!(track.codec, Codec::OPUS | Codec::CEA608) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the audio codec has no esds box",
+            ));
+        }
+        track.object_type_indication = Some(object_type_indication);
+        Ok(())
+    }
+
+    /// Sets the pre-skip of the Opus audio track
+    ///
+    /// The first samples an Opus decoder outputs are the encoder's lookahead, and are not
+    /// presented: the pre-skip from the OpusHead header of the stream. It is written to
+    /// the dOps box and trimmed by an edit list, and a roll sample group gives the 80 ms
+    /// of audio the decoder needs to converge, so playback starts cleanly on the first
+    /// sample. No pre-skip is signaled until it is set here or by passing the OpusHead
+    /// header to `set_audio_dsi`.
+    ///
+    /// # Arguments
+    /// * `pre_skip` - The number of samples to skip at 48 kHz, 0 for none
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the audio track is not set up
+    ///   or is not Opus
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_audio_track(48000, 2, Codec::OPUS);
+    /// muxer.set_opus_pre_skip(3840).unwrap();
+    /// ```
+    pub fn set_opus_pre_skip(&mut self, pre_skip: u16) -> Result<(), Error> {
+        let track = self.track_mut(TrackType::Audio)?;
+        if !matches!(track.codec, Codec::OPUS) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the pre-skip is only set on Opus tracks",
+            ));
+        }
+        track.pre_skip = pre_skip;
+        Ok(())
+    }
+
+    /// Sets the decoder specific info of the audio track
+    ///
+    /// Replaces the AudioSpecificConfig computed by `set_audio_track`, e.g. to use
+    /// HE-AAC backward compatible signaling or a configuration with a program config element.
+    /// The bytes are written as they are in the esds box. For Opus the OpusHead header
+    /// of the stream is expected instead, and its pre-skip is written to the dOps box.
+    ///
+    /// # Arguments
+    /// * `dsi` - The decoder specific info, e.g. an AAC AudioSpecificConfig or an OpusHead
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the audio track is not set up
+    ///   or an Opus track is not given an OpusHead header
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    /// use mp4e::aac::{aac_asc, AacProfile};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_audio_track(48000, 2, Codec::HEAACV2);
+    /// muxer.set_audio_dsi(&aac_asc(AacProfile::HEV2, 48000, 2)).unwrap();
+    /// ```
+    pub fn set_audio_dsi(&mut self, dsi: &[u8]) -> Result<(), Error> {
+        let track = self.track_mut(TrackType::Audio)?;
+        if matches!(track.codec, Codec::OPUS) {
+            // "OpusHead", version, channel count, then the little endian pre-skip
+            if dsi.len() < 19 || &dsi[..8] != b"OpusHead" {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "the Opus configuration is not an OpusHead header",
+                ));
+            }
+            track.pre_skip = u16::from_le_bytes([dsi[10], dsi[11]]);
+        }
+        track.dsi = Some(dsi.to_vec());
+        Ok(())
+    }
+
+    /// Sets the bitrates written to the esds box of the audio track
+    ///
+    /// By default the bitrates are measured from the samples when the file is finalized.
+    /// In fragmented mode the moov box is written before any sample, so the bitrates
+    /// are only known if they are set here.
+    ///
+    /// # Arguments
+    /// * `max_bitrate` - The maximum bitrate in bits per second
+    /// * `avg_bitrate` - The average bitrate in bits per second
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the audio track is not set up
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// muxer.set_audio_bitrate(160000, 128000).unwrap();
+    /// ```
+    pub fn set_audio_bitrate(&mut self, max_bitrate: u32, avg_bitrate: u32) -> Result<(), Error> {
+        let track = self.track_mut(TrackType::Audio)?;
+        track.bitrate = Some((max_bitrate, avg_bitrate));
+        Ok(())
+    }
+
+    /// Sets the silent audio frame written by `encode_audio_silence`
+    ///
+    /// Built-in silent frames only exist for AAC-LC mono and stereo, other codecs and
+    /// channel layouts need a silent frame from the audio encoder.
+    ///
+    /// # Arguments
+    /// * `frame` - The encoded silent frame, not empty
+    /// * `samples` - The number of audio samples in the frame, not 0
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if `frame` is empty or `samples`
+    ///   is 0
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::OPUS);
+    ///
+    /// // Opus frame of 20 ms without any coded data
+    /// muxer.set_audio_silence_frame(&[0xF8], 960).unwrap();
+    /// ```
+    pub fn set_audio_silence_frame(&mut self, frame: &[u8], samples: u32) -> Result<(), Error> {
+        if frame.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a silent frame is not empty",
+            ));
+        }
+        if samples == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a silent frame needs at least one audio sample",
+            ));
+        }
+        self.audio_silence = Some((frame.to_vec(), samples));
+        Ok(())
+    }
+
+    /// Sets up a video track with the specified parameters
+    ///
+    /// # Arguments
+    /// * `width` - The video width in pixels
+    /// * `height` - The video height in pixels
+    /// * `codec` - The video codec to use
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
+    ///
+    /// // Set up an H.264 video track with 1920x1080 resolution
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// ```
+    pub fn set_video_track(&mut self, width: u32, height: u32, codec: Codec) {
+        self.video_track = Some(Track {
+            width,
+            height,
+            ..Track::new(self.track_ids, TrackType::Video, codec, 90000)
+        });
+        self.detect_video_codec = false;
+        self.track_ids += 1;
+    }
+
+    /// Sets the display rotation of a track
+    ///
+    /// The rotation is written as the transformation matrix of the track's tkhd box,
+    /// players apply it when presenting the track. The stored width and height are unchanged.
+    ///
+    /// # Arguments
+    /// * `track` - The track to rotate, it must have been set up already
+    /// * `degrees` - The clockwise rotation in degrees, one of 0, 90, 180 or 270
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the track is not set up, the rotation
+    ///   is unsupported or the track is 32768 pixels or more across
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec, TrackType};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // Portrait video stored as landscape
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_rotation(TrackType::Video, 90).unwrap();
+    /// ```
+    pub fn set_rotation(&mut self, track: TrackType, degrees: u32) -> Result<(), Error> {
+        let track = self.track_mut(track)?;
+        track.matrix = rotation_matrix(degrees, track.width, track.height).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "unsupported rotation or track size for the matrix",
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Sets whether the parameter sets of the video stream are kept in the samples
+    ///
+    /// By default, parameter sets only go to the sample description (avc1 or hvc1), as
+    /// those sample entries require. With in-band parameter sets, the VPS, SPS and PPS
+    /// NAL units also stay in the samples and the track uses the avc3 or hev1 sample entry,
+    /// so live streams that repeat or change their parameter sets at each IDR frame are
+    /// carried as they are. Some CMAF profiles require these sample entries for live
+    /// streams. The avcC or hvcC box still holds the first parameter sets of the stream.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to keep the parameter sets in the samples
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if no AVC or HEVC video track is set up
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    ///
+    /// // Live HEVC with parameter sets before every IDR frame
+    /// muxer.set_video_track(1920, 1080, Codec::HEVC);
+    /// muxer.set_inband_parameter_sets(true).unwrap();
+    /// ```
+    pub fn set_inband_parameter_sets(&mut self, enabled: bool) -> Result<(), Error> {
+        let track = self.track_mut(TrackType::Video)?;
+        if let Codec::MJPEG = track.codec {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "in-band parameter sets need an AVC or HEVC video track",
+            ));
+        }
+        track.inband_parameter_sets = enabled;
+        Ok(())
+    }
+
+    /// Sets the name of the compressor written to the video sample entry
+    ///
+    /// The avc1, avc3, hvc1, hev1 and jpeg sample entries have a 32-byte compressorname
+    /// field holding the length of the name followed by the name, which some tools show
+    /// as the codec of the track (e.g. "AVC Coding"). It is left empty by default.
+    ///
+    /// # Arguments
+    /// * `name` - The compressor name, at most 31 bytes long
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if no video track is set up or the
+    ///   name is longer than 31 bytes
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::HEVC);
+    /// muxer.set_compressor_name("HEVC Coding").unwrap();
+    /// ```
+    pub fn set_compressor_name(&mut self, name: &str) -> Result<(), Error> {
+        if name.len() > 31 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "compressor name longer than 31 bytes",
+            ));
+        }
+        let track = self.track_mut(TrackType::Video)?;
+        track.compressor_name = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Sets whether a cslg box is written for video with composition offsets
+    ///
+    /// The composition shift least greatest box sums up the ctts box: the smallest and
+    /// largest composition offsets and the first and last presentation times, so players
+    /// can map presentation times to decode times without scanning all offsets, which makes
+    /// seeking in B-frame content more precise. It is only written when the track has
+    /// composition offsets.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write the cslg box (disabled by default)
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if no video track is set up
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_composition_shift_box(true).unwrap();
+    /// // ... muxer.encode_video_with_pts(&frame, 33, pts) ...
+    /// ```
+    pub fn set_composition_shift_box(&mut self, enabled: bool) -> Result<(), Error> {
+        self.track_mut(TrackType::Video)?.composition_shift = enabled;
+        Ok(())
+    }
+
+    /// Sets whether an sdtp box describes the dependency of each video sample
+    ///
+    /// Key frames are marked as not depending on other samples and the other frames as
+    /// depending on them. The sdtp box, the stss box and the sync flags of the samples
+    /// all come from the key frame flag of each sample, so they always agree, including
+    /// for all-intra streams (see `set_all_intra`). Fragments carry the same information
+    /// in their sample flags.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write the sdtp box (disabled by default)
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if no video track is set up
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_sample_dependency_box(true).unwrap();
+    /// ```
+    pub fn set_sample_dependency_box(&mut self, enabled: bool) -> Result<(), Error> {
+        self.track_mut(TrackType::Video)?.sample_dependencies = enabled;
+        Ok(())
+    }
+
+    /// Sets whether the video samples are grouped by HEVC temporal sub-layer
+    ///
+    /// The `TemporalId` of the slices of each HEVC frame is recorded in its
+    /// `SampleInfo::temporal_id` in any case. With the group enabled, a sample group
+    /// description of type tele gets an entry per sub-layer, the number of sub-layers
+    /// coming from the SPS, and a sample to group box maps each sample to the entry of
+    /// its sub-layer, in the sample table or in each fragment. A player or packager can
+    /// then drop the frames of the higher sub-layers for a rendition at a lower frame
+    /// rate, sub-layer 0 decoding on its own.
+    ///
+    /// # Arguments
+    /// * `enabled` - Whether to write the temporal level sample group (disabled by default)
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if no video track is set up
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::HEVC);
+    /// muxer.set_temporal_layer_group(true).unwrap();
+    /// ```
+    pub fn set_temporal_layer_group(&mut self, enabled: bool) -> Result<(), Error> {
+        self.track_mut(TrackType::Video)?.temporal_layers = enabled;
+        Ok(())
+    }
+
+    /// Adds an external file to the data references of a track
+    ///
+    /// The dref box of each track always lists the file itself first. Each call adds a
+    /// url entry with the location of another file holding sample data, and returns the
+    /// index of the new entry, to be passed to `set_data_reference_index`.
+    ///
+    /// # Arguments
+    /// * `track` - The track the data reference belongs to
+    /// * `location` - The URL of the file, relative to the MP4 file or absolute
+    ///
+    /// # Returns
+    /// * The index of the new dref entry, or an `InvalidInput` error if the track is not
+    ///   set up, the header is already written or the location contains a null character
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec, TrackType};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// let index = muxer.add_data_reference(TrackType::Video, "media.dat").unwrap();
+    /// assert_eq!(index, 2);
+    /// ```
+    pub fn add_data_reference(&mut self, track: TrackType, location: &str) -> Result<u16, Error> {
+        if self.file.init_header {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "data references must be added before the header is written",
+            ));
+        }
+        if location.contains('\0') {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "a data reference location cannot contain a null character",
+            ));
+        }
+        let track = self.track_mut(track)?;
+        if track.data_references.len() >= u16::MAX as usize - 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "too many data references",
+            ));
+        }
+        track.data_references.push(location.to_string());
+        Ok(track.data_references.len() as u16 + 1)
+    }
+
+    /// Sets the data reference index of the sample entry of a track
+    ///
+    /// Index 1, the default, is the file itself. A higher index selects one of the
+    /// files added with `add_data_reference`, in which case the chunk offsets of the
+    /// sample table are offsets in that file. The muxer still writes the samples it
+    /// receives to its own mdat box, so external references are meant for moov-only
+    /// files written with the boxes API from tracks whose `SampleInfo::offset` values
+    /// point into the external file.
+    ///
+    /// # Arguments
+    /// * `track` - The track whose sample entry is updated
+    /// * `index` - The 1-based index of the dref entry holding the samples
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the track is not set up or
+    ///   the index is not in the dref box
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec, TrackType};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// let index = muxer.add_data_reference(TrackType::Video, "media.dat").unwrap();
+    /// muxer.set_data_reference_index(TrackType::Video, index).unwrap();
+    /// assert!(muxer.set_data_reference_index(TrackType::Video, 3).is_err());
+    /// ```
+    pub fn set_data_reference_index(&mut self, track: TrackType, index: u16) -> Result<(), Error> {
+        let track = self.track_mut(track)?;
+        if index == 0 || index as usize > track.data_references.len() + 1 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the data reference index is not in the dref box",
+            ));
+        }
+        track.data_reference_index = index;
+        Ok(())
+    }
+
+    /// Sets up a video track whose codec is detected from the stream
+    ///
+    /// The codec (AVC or HEVC) is determined from the first parameter set found in the
+    /// data passed to the encode methods. Frames received before the codec is known
+    /// are dropped, like frames received before the parameter sets.
+    ///
+    /// # Arguments
+    /// * `width` - The video width in pixels
+    /// * `height` - The video height in pixels
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // H.264 or H.265, decided by the first SPS
+    /// muxer.set_video_track_auto(1920, 1080);
+    /// ```
+    pub fn set_video_track_auto(&mut self, width: u32, height: u32) {
+        self.set_video_track(width, height, Codec::AVC);
+        self.detect_video_codec = true;
+    }
+
+    /// Sets up a closed caption track fed from the video stream
+    ///
+    /// CEA-608 captions carried in the registered user data SEI messages (ATSC A/53)
+    /// of the video frames are extracted and written as a `c608` track, with one caption
+    /// sample per video sample. CEA-708 service data is not carried over.
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // Broadcast H.264 with embedded captions
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_caption_track();
+    /// ```
+    pub fn set_caption_track(&mut self) {
+        self.caption_track = Some(Track::new(
+            self.track_ids,
+            TrackType::Caption,
+            Codec::CEA608,
+            90000,
+        ));
+        self.track_ids += 1;
+    }
+
+    /// Adds a kind box to the user data of a track
+    ///
+    /// The kind box signals the role of a track, such as a main or commentary track,
+    /// for packagers and players. It can be added several times with different schemes.
+    ///
+    /// # Arguments
+    /// * `track` - The track to describe, it must have been set up already
+    /// * `scheme` - The scheme URI, e.g. "urn:mpeg:dash:role:2011"
+    /// * `value` - The value in the scheme, e.g. "main" or "commentary"
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the track is not set up
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec, TrackType};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// muxer
+    ///     .set_track_kind(TrackType::Audio, "urn:mpeg:dash:role:2011", "commentary")
+    ///     .unwrap();
+    /// ```
+    pub fn set_track_kind(
+        &mut self,
+        track: TrackType,
+        scheme: &str,
+        value: &str,
+    ) -> Result<(), Error> {
+        let track = self.track_mut(track)?;
+        track.kinds.push((scheme.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Sets the HDR10 static metadata of the video track
+    ///
+    /// The metadata is written as mdcv and clli boxes in the HEVC sample entry.
+    /// Without this call, the values are taken from the first mastering display
+    /// and content light level SEI messages of the stream.
+    ///
+    /// # Arguments
+    /// * `max_cll` - The maximum content light level in cd/m2
+    /// * `max_fall` - The maximum frame average light level in cd/m2
+    /// * `mastering_display` - The mastering display colour volume
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the video track is not set up
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec, MasteringDisplay};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // BT.2020 primaries, D65 white point, 1000 cd/m2 mastering display
+    /// muxer.set_video_track(3840, 2160, Codec::HEVC);
+    /// let mastering_display = MasteringDisplay {
+    ///     display_primaries: [[8500, 39850], [6550, 2300], [35400, 14600]],
+    ///     white_point: [15635, 16450],
+    ///     max_luminance: 10000000,
+    ///     min_luminance: 50,
+    /// };
+    /// muxer.set_hdr10_metadata(1000, 400, mastering_display).unwrap();
+    /// ```
+    pub fn set_hdr10_metadata(
+        &mut self,
+        max_cll: u16,
+        max_fall: u16,
+        mastering_display: MasteringDisplay,
+    ) -> Result<(), Error> {
+        let track = self
+            .video_track
+            .as_mut()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "track is not set up"))?;
+        track.content_light_level = Some([max_cll, max_fall]);
+        track.mastering_display = Some(mastering_display);
+        Ok(())
+    }
+
+    /// Sets the name of a track
+    ///
+    /// The name is written as a name box to the user data of the track. QuickTime shows it
+    /// in its track inspector; it is distinct from the handler name of the track.
+    ///
+    /// # Arguments
+    /// * `track` - The track to name, it must have been set up already
+    /// * `name` - The name of the track
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the track is not set up
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec, TrackType};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// muxer.set_track_name(TrackType::Audio, "Director's commentary").unwrap();
+    /// ```
+    pub fn set_track_name(&mut self, track: TrackType, name: &str) -> Result<(), Error> {
+        let track = self.track_mut(track)?;
+        track.name = Some(name.to_string());
+        Ok(())
+    }
+
+    /// Adds a box to the moov box, e.g. a vendor atom required by an asset manager
+    ///
+    /// The box is written after the boxes of the muxer, with a header made of its size
+    /// and type followed by `data` as it is. Boxes are written in the order they are added.
+    ///
+    /// # Arguments
+    /// * `box_type` - The four-character code of the box
+    /// * `data` - The payload of the box
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the moov box has already been
+    ///   written
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.add_moov_box(b"xyzV", &[0, 0, 0, 1]).unwrap();
+    /// muxer.flush().unwrap();
+    /// ```
+    pub fn add_moov_box(&mut self, box_type: &[u8; 4], data: &[u8]) -> Result<(), Error> {
+        self.check_moov_not_written()?;
+        self.user_boxes.moov.push((*box_type, data.to_vec()));
+        Ok(())
+    }
+
+    /// Adds a box to the user data (udta box) of the movie
+    ///
+    /// The udta box is written at the end of the moov box when boxes have been added,
+    /// each with a header made of its size and type followed by `data` as it is.
+    ///
+    /// # Arguments
+    /// * `box_type` - The four-character code of the box
+    /// * `data` - The payload of the box
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the moov box has already been
+    ///   written
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::Mp4e;
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.add_udta_box(b"\xa9cmt", b"\x00\x05\x00\x00Hello").unwrap();
+    /// muxer.flush().unwrap();
+    /// ```
+    pub fn add_udta_box(&mut self, box_type: &[u8; 4], data: &[u8]) -> Result<(), Error> {
+        self.check_moov_not_written()?;
+        self.user_boxes.udta.push((*box_type, data.to_vec()));
+        Ok(())
+    }
+
+    /// Sets the cover art of the file, e.g. the artwork of a podcast or an album
+    ///
+    /// The image is written as the covr item of the iTunes metadata list, in a meta box
+    /// of the movie's udta box, where the Apple players and the Finder look for it.
+    /// Setting the cover art again replaces the image.
+    ///
+    /// # Arguments
+    /// * `image` - The encoded image
+    /// * `format` - The format of the image
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if the image is empty or the
+    ///   moov box has already been written
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec, ImageFormat};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// let cover = vec![0xFF, 0xD8, 0xFF, 0xE0]; // Example JPEG data
+    /// muxer.set_cover_art(&cover, ImageFormat::Jpeg).unwrap();
+    /// ```
+    pub fn set_cover_art(&mut self, image: &[u8], format: ImageFormat) -> Result<(), Error> {
+        self.check_moov_not_written()?;
+        if image.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the cover art is empty",
+            ));
+        }
+        // Well-known types of the iTunes metadata
+        let type_indicator = match format {
+            ImageFormat::Jpeg => 13,
+            ImageFormat::Png => 14,
+        };
+        self.user_boxes
+            .ilst
+            .retain(|(box_type, _)| box_type != b"covr");
+        self.user_boxes
+            .ilst
+            .push((*b"covr", itunes_item(type_indicator, image)));
+        Ok(())
+    }
+
+    /// Returns an `InvalidInput` error once the moov box has been written
+    fn check_moov_not_written(&self) -> Result<(), Error> {
+        if self.file.write_moov {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the moov box has already been written",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Gets the video parameter sets picked up from the stream
+    ///
+    /// # Returns
+    /// * `Some((sps, pps, vps))` once the SPS and PPS have been received, the VPS is only
+    ///   present for HEVC. `None` if there is no video track or a parameter set is missing.
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// assert!(muxer.video_parameter_sets().is_none());
+    ///
+    /// let parameter_sets = [0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x0D, 0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80];
+    /// muxer.encode_video(&parameter_sets, 33).unwrap();
+    /// let (sps, pps, vps) = muxer.video_parameter_sets().unwrap();
+    /// assert_eq!(sps, &[0x67, 0x42, 0xC0, 0x0D]);
+    /// assert_eq!(pps, &[0x68, 0xCE, 0x3C, 0x80]);
+    /// assert!(vps.is_none());
+    /// ```
+    pub fn video_parameter_sets(&self) -> Option<ParameterSets<'_>> {
+        let track = self.video_track.as_ref()?;
+        Some((
+            track.sps.as_deref()?,
+            track.pps.as_deref()?,
+            track.vps.as_deref(),
+        ))
+    }
+
+    /// Sets the video parameter sets given out-of-band
+    ///
+    /// Streams whose parameter sets are signaled outside of the video data, e.g. in the
+    /// `sprop-parameter-sets` of an SDP description, have no SPS and PPS to pick up, so
+    /// their frames would be dropped. The parameter sets given here are written to the
+    /// sample description instead, and frames are written from the first key frame on.
+    /// `flush` returns an error if frames were dropped and no parameter sets are known.
+    ///
+    /// # Arguments
+    /// * `sps` - The SPS NAL unit, without start code
+    /// * `pps` - The PPS NAL unit, without start code
+    /// * `vps` - The VPS NAL unit without start code, required for HEVC
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if there is no AVC or HEVC video
+    ///   track, a parameter set is too short or of the wrong NAL unit type, or the VPS of
+    ///   an HEVC track is missing
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// let sps = [0x67, 0x42, 0xC0, 0x0D];
+    /// let pps = [0x68, 0xCE, 0x3C, 0x80];
+    /// muxer.set_video_parameter_sets(&sps, &pps, None).unwrap();
+    /// assert!(muxer.video_parameter_sets().is_some());
+    /// ```
+    pub fn set_video_parameter_sets(
+        &mut self,
+        sps: &[u8],
+        pps: &[u8],
+        vps: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        let track = self.track_mut(TrackType::Video)?;
+        // Each parameter set needs its NAL unit type, and the SPS its profile and level
+        let avc = |nalu: &[u8], nalu_type: u8, min_len: usize| {
+            nalu.len() >= min_len && nalu[0] & 0x1f == nalu_type
+        };
+        let hevc = |nalu: &[u8], nalu_type: u8, min_len: usize| {
+            nalu.len() >= min_len && (nalu[0] & 0x7e) >> 1 == nalu_type
+        };
+        let valid = match track.codec {
+            Codec::AVC => avc(sps, AVC_NALU_TYPE_SPS, 4) && avc(pps, AVC_NALU_TYPE_PPS, 2),
+            Codec::HEVC => {
+                vps.is_some_and(|vps| hevc(vps, HEVC_NALU_TYPE_VPS, 3))
+                    && hevc(sps, HEVC_NALU_TYPE_SPS, 15)
+                    && hevc(pps, HEVC_NALU_TYPE_PPS, 3)
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid parameter sets for the video track",
+            ));
+        }
+        track.sps = Some(sps.to_vec());
+        track.pps = Some(pps.to_vec());
+        if matches!(track.codec, Codec::HEVC) {
+            track.vps = vps.map(|vps| vps.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Checks that the video track, if any, has the parameter sets it needs
+    fn video_configured(&self) -> bool {
+        match self.video_track.as_ref() {
+            Some(track) => {
+                track.sps.is_some()
+                    && track.pps.is_some()
+                    && (!matches!(track.codec, Codec::HEVC) || track.vps.is_some())
+            }
+            None => true,
+        }
+    }
+
+    /// Gets the profile, level and sample format of the video stream
+    ///
+    /// They are read from the SPS picked up from the stream, so they are known once the
+    /// first key frame with its parameter sets has been encoded.
+    ///
+    /// # Returns
+    /// * The video profile, or `None` if there is no AVC or HEVC video track or no SPS yet
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// assert!(muxer.video_profile().is_none());
+    ///
+    /// let sps = [0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0x40];
+    /// muxer.encode_video(&sps, 33).unwrap();
+    /// let profile = muxer.video_profile().unwrap();
+    /// assert_eq!(profile.to_string(), "H.264 High Profile Level 4.0");
+    /// ```
+    pub fn video_profile(&self) -> Option<VideoProfile> {
+        let track = self.video_track.as_ref()?;
+        parse_sps_profile(&track.codec, track.sps.as_deref()?)
+    }
+
+    /// Writes an audio data to the MP4 file
+    ///
+    /// For Vorbis, the identification, comment and setup headers must be passed first,
+    /// one packet per call. They are stored in the decoder configuration and the
+    /// audio packets after them are written as samples.
+    ///
+    /// With a video track, audio frames are written from the first video key frame on so
+    /// both tracks start together. Without one, as in an audio-only podcast, every frame
+    /// is written.
+    ///
+    /// An empty buffer, like the empty packets live sources deliver at times, keeps the
+    /// audio timeline going: it is written as silence like `encode_audio_silence` does,
+    /// or as an empty sample of the frame's duration when there is no silent frame for
+    /// the codec. A frame of zero samples is rejected.
+    ///
+    /// # Arguments
+    /// * `data` - The audio data
+    /// * `samples` - The number of audio samples in this frame. This represents
+    ///   the duration in sample count, not bytes. For example, if you
+    ///   have 1024 PCM samples that were encoded, you pass 1024 here.
+    ///   If you only know the duration in milliseconds, you can estimate
+    ///   the sample count using the formula: duration_ms * sample_rate / 1000.
+    ///   For example, with a 48kHz sample rate and 21.33ms duration:
+    ///   samples = 21.33 * 48000 / 1000 = 1024 samples.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, an `InvalidInput` error if `samples` is 0, or an error if
+    ///   writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
+    ///
+    /// // Set up audio track first
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    ///
+    /// // ... process video frames first to establish synchronization ...
+    ///
+    /// // Encode audio data with 1024 samples
+    /// let audio_data = vec![0; 512]; // Example audio data
+    /// muxer.encode_audio(&audio_data, 1024).unwrap();
+    /// ```
+    pub fn encode_audio(&mut self, data: &[u8], samples: u32) -> Result<(), Error> {
+        self.encode_audio_with_type(data, samples, SampleType::RandomAccess)
+    }
+
+    /// Writes an audio frame with the given sample type
+    ///
+    /// `encode_audio` writes every frame as a sync sample, which fits codecs like AAC
+    /// whose frames decode on their own. Frames of codecs with inter-frame dependencies,
+    /// like the Opus frames of the pre-roll before a sync point, are written with
+    /// `SampleType::Default` instead. The sync samples are then listed in the stss box,
+    /// the sdtp box marks the others as depending on earlier frames, and in fragmented
+    /// mode so do the sample flags of the trun box. Decoders start at a sync sample and
+    /// decode the pre-roll given by the roll group of Opus tracks before it.
+    ///
+    /// # Arguments
+    /// * `data` - The audio data
+    /// * `samples` - The number of audio samples in this frame, see `encode_audio`
+    /// * `sample_type` - `SampleType::RandomAccess` for frames decoding on their own,
+    ///   `SampleType::Default` for frames depending on earlier ones
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec, SampleType};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::OPUS);
+    ///
+    /// let frame = vec![0xfc; 120]; // Example Opus frame
+    /// muxer.encode_audio_with_type(&frame, 960, SampleType::RandomAccess).unwrap();
+    /// muxer.encode_audio_with_type(&frame, 960, SampleType::Default).unwrap();
+    /// ```
+    pub fn encode_audio_with_type(
+        &mut self,
+        data: &[u8],
+        samples: u32,
+        sample_type: SampleType,
+    ) -> Result<(), Error> {
+        self.init_header_if_needed()?;
+        if let Some(track) = self.audio_track.as_mut() {
+            if let Codec::VORBIS = track.codec {
+                // Setup headers go to the decoder configuration, not to the samples
+                if is_vorbis_header(data) {
+                    if track.dsi.is_none() {
+                        track.headers.push(data.to_vec());
+                        if track.headers.len() == 3 {
+                            track.dsi = Some(xiph_lacing(&track.headers));
+                        }
+                    }
+                    return Ok(());
+                }
+                // Only write audio packets once all three headers are available
+                if track.dsi.is_none() {
+                    return Ok(());
+                }
+            }
+            if samples == 0 {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "an audio frame lasts at least one sample",
+                ));
+            }
+            if data.is_empty() && self.audio_silent_frame().is_some() {
+                return self.encode_audio_silence(samples);
+            }
+            self.write_audio_frame(data, samples, sample_type)?;
+        }
+        Ok(())
+    }
+
+    /// Writes an audio frame as a sample of the audio track
+    ///
+    /// Audio starts with the first video key frame, or right away without video, the
+    /// frames before are dropped.
+    ///
+    /// # Arguments
+    /// * `data` - The audio data
+    /// * `samples` - The number of audio samples in this frame
+    /// * `sample_type` - The sample type of the frame
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn write_audio_frame(
+        &mut self,
+        data: &[u8],
+        samples: u32,
+        sample_type: SampleType,
+    ) -> Result<(), Error> {
+        if self.send_first_random_access || self.video_track.is_none() {
+            self.check_sample_table_limit()?;
+            let duration = self.audio_duration(samples);
+            let track = self.audio_track.as_mut().unwrap();
+            track.duration = track.duration.wrapping_add(duration);
+            self.put_sample(&[data], duration, TrackType::Audio, 0, sample_type)?;
+            self.update_duration(TrackType::Audio);
+        }
+        Ok(())
+    }
+
+    /// Converts a number of audio samples to the audio track's timescale
+    ///
+    /// The remainder of the division is carried to the next frame so the durations
+    /// do not drift, with the default timescale the duration is the number of samples.
+    ///
+    /// # Arguments
+    /// * `samples` - The number of audio samples of the frame
+    ///
+    /// # Returns
+    /// * The duration in the track's timescale
+    fn audio_duration(&mut self, samples: u32) -> u32 {
+        let track = match self.audio_track.as_ref() {
+            Some(track) if track.sample_rate != track.timescale && track.sample_rate > 0 => track,
+            _ => return samples,
+        };
+        // The end time of the frame is converted, the durations add up without drift
+        let start = self.file.audio_duration_samples;
+        let end = start + samples as u64;
+        self.file.audio_duration_samples = end;
+        let time = |samples: u64| rescale(samples, track.sample_rate, track.timescale);
+        (time(end) - time(start)) as u32
+    }
+
+    /// Writes silent audio frames to fill a gap in the audio
+    ///
+    /// The gap is filled with the whole number of silent frames closest to its duration,
+    /// as a frame always decodes to all of its samples. The part of the gap left over, or
+    /// written in excess, is carried over to the next gap, so the audio timeline stays
+    /// continuous and in sync with the video. The frames are the ones set with
+    /// `set_audio_silence_frame`, or the built-in silent frame of AAC-LC mono and stereo.
+    ///
+    /// # Arguments
+    /// * `samples` - The duration of the gap in audio samples
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if there is no audio track or
+    ///   no silent frame for its codec
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    ///
+    /// // 100 ms of silence
+    /// muxer.encode_audio_silence(4800).unwrap();
+    /// ```
+    pub fn encode_audio_silence(&mut self, samples: u32) -> Result<(), Error> {
+        if self.audio_track.is_none() {
+            return Err(Error::new(ErrorKind::InvalidInput, "track is not set up"));
+        }
+        let (frame, frame_samples) = self.audio_silent_frame().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "no silent frame for the audio codec, set one with set_audio_silence_frame",
+            )
+        })?;
+        self.init_header_if_needed()?;
+        let gap = samples as i64 + self.audio_silence_remainder;
+        let frame_samples = frame_samples as i64;
+        let frames = (gap + frame_samples / 2).div_euclid(frame_samples).max(0);
+        self.audio_silence_remainder = gap - frames * frame_samples;
+        for _ in 0..frames {
+            self.write_audio_frame(&frame, frame_samples as u32, SampleType::RandomAccess)?;
+        }
+        Ok(())
+    }
+
+    /// Gets the silent frame of the audio track and its number of audio samples
+    ///
+    /// The frame set with `set_audio_silence_frame`, or the built-in silent frame of
+    /// AAC-LC mono and stereo, `None` if there is neither.
+    fn audio_silent_frame(&self) -> Option<(Vec<u8>, u32)> {
+        if let Some(silence) = &self.audio_silence {
+            return Some(silence.clone());
+        }
+        let track = self.audio_track.as_ref()?;
+        aac_profile(&track.codec)
+            .and_then(|profile| aac_silent_frame(profile, track.channel_count))
+            .map(|frame| (frame.to_vec(), 1024))
+    }
+
+    /// Writes a video frame to the MP4 file (with no b frame)
+    ///
+    /// Buffers without a NAL unit, empty ones included, are ignored.
+    ///
+    /// Without a frame rate, a duration of 0 gives a sample of zero duration, e.g. a
+    /// poster frame presented together with the next frame.
+    ///
+    /// # Arguments
+    /// * `data` - The video frame data
+    /// * `duration` - The duration of the video frame in milliseconds, 0 to derive it from
+    ///   the frame rate set with `set_video_frame_rate`
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer); // or Mp4e::new(&mut writer);
+    ///
+    /// // Set up video track first
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// // Encode a video frame with 33ms duration (approximately 30fps)
+    /// let video_frame_data = vec![0; 1024]; // Example video frame data
+    /// muxer.encode_video(&video_frame_data, 33).unwrap();
+    /// ```
+    pub fn encode_video(&mut self, data: &[u8], duration: u32) -> Result<(), Error> {
+        if self.stream_input.is_some() && matches!(self.nalu_format, NaluFormat::AnnexB) {
+            return self.encode_video_chunk(data, duration);
+        }
+        let nalus = self.split_video(data)?;
+        self.encode_video_nalus(&nalus, duration, None)
+    }
+
+    /// Writes the video frames of a buffer holding several access units
+    ///
+    /// Some hardware encoders return several frames at once. The NAL units of the buffer
+    /// are grouped into access units, a new one starting at the first slice of each
+    /// picture (`first_mb_in_slice` of 0 for AVC, `first_slice_segment_in_pic_flag`
+    /// set for HEVC) or at the parameter sets, SEI or delimiter NAL units preceding it.
+    /// Each access unit is written as one frame, as by `encode_video`.
+    ///
+    /// # Arguments
+    /// * `data` - The video data of the frames
+    /// * `durations` - The durations of the frames in milliseconds, one per access unit,
+    ///   or a single duration for the whole buffer, split evenly between the frames
+    ///
+    /// # Returns
+    /// * The number of frames in the buffer, or an `InvalidInput` error if there are
+    ///   several durations but not one per frame
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// // SPS, PPS, IDR slice, then two P slices: three frames lasting 100ms together
+    /// let data = [
+    ///     0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x0D, 0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80,
+    ///     0, 0, 0, 1, 0x65, 0x88, 0x84, 0, 0, 0, 1, 0x41, 0x9A, 0, 0, 0, 1, 0x41, 0x9B,
+    /// ];
+    /// assert_eq!(muxer.encode_video_units(&data, &[100]).unwrap(), 3);
+    /// ```
+    pub fn encode_video_units(&mut self, data: &[u8], durations: &[u32]) -> Result<usize, Error> {
+        let nalus = self.split_video(data)?;
+        if !self.detect_video_codec_if_needed(&nalus) {
+            return Ok(0);
+        }
+        let units = match self.video_track.as_ref() {
+            Some(Track {
+                codec: codec @ (Codec::AVC | Codec::HEVC),
+                ..
+            }) => group_access_units(nalus, codec),
+            Some(_) => vec![nalus],
+            None => return Ok(0),
+        };
+        let count = units.len();
+        let durations: Vec<u32> = match durations {
+            [total] => {
+                // Frame n ends at (n + 1) / count of the total, rounding does not add up
+                let end = |frame: usize| (*total as u64 * frame as u64 / count as u64) as u32;
+                (0..count)
+                    .map(|frame| end(frame + 1) - end(frame))
+                    .collect()
+            }
+            _ if durations.len() == count => durations.to_vec(),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "the durations do not match the number of frames",
+                ))
+            }
+        };
+        for (unit, duration) in units.iter().zip(durations) {
+            self.encode_video_nalus(unit, duration, None)?;
+        }
+        Ok(count)
+    }
+
+    /// Writes a whole access unit whose key frame status is known, without parsing it
+    ///
+    /// This is a fast path for trusted pipelines: the NAL units are not split from a
+    /// stream and no slice header is parsed, only the NAL unit type byte is read to pick
+    /// up the parameter sets, which are required as for `encode_video`. The access unit
+    /// is written as one sample, key frames are taken from `is_keyframe` and closed
+    /// captions are not extracted. SEI NAL units follow the SEI policy as in
+    /// `encode_video`, their messages are only parsed under `SeiPolicy::KeepHdrOnly`.
+    /// The codec of a track set up with `set_video_track_auto` is detected from the NAL
+    /// unit types as by `encode_video`.
+    ///
+    /// # Arguments
+    /// * `nalus` - The NAL units of the access unit, without start codes
+    /// * `duration` - The duration of the video frame in milliseconds, 0 to derive it from
+    ///   the frame rate set with `set_video_frame_rate`
+    /// * `is_keyframe` - Whether the access unit is a random access point
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if no AVC or HEVC video track
+    ///   is set up, or an error if writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(320, 240, Codec::AVC);
+    ///
+    /// let sps = [0x67, 0x42, 0xC0, 0x0D, 0xF4, 0x01];
+    /// let pps = [0x68, 0xCE, 0x3C, 0x80];
+    /// muxer.encode_video_au(&[&sps, &pps, &[0x65, 0x88, 0x84]], 33, true).unwrap();
+    /// muxer.encode_video_au(&[&[0x41, 0x9A, 0x21]], 33, false).unwrap();
+    /// muxer.flush().unwrap();
+    /// ```
+    pub fn encode_video_au(
+        &mut self,
+        nalus: &[&[u8]],
+        duration: u32,
+        is_keyframe: bool,
+    ) -> Result<(), Error> {
+        // The codec of a track set up with set_video_track_auto comes from the first frames
+        if !self.detect_video_codec_if_needed(nalus) {
+            return Ok(());
+        }
+        let (hevc, timescale) = match self.video_track.as_ref() {
+            Some(Track {
+                codec: codec @ (Codec::AVC | Codec::HEVC),
+                timescale,
+                ..
+            }) => (matches!(codec, Codec::HEVC), *timescale),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "encoding access units needs an AVC or HEVC video track",
+                ))
+            }
+        };
+        self.init_header_if_needed()?;
+        self.check_sample_table_limit()?;
+        let track = self.video_track.as_mut().unwrap();
+        // The access unit is written as passed, unless NAL units are left out of it
+        let mut kept: Option<Vec<&[u8]>> = None;
+        for (i, &nalu) in nalus.iter().enumerate() {
+            let keep = if nalu.is_empty() {
+                false
+            } else {
+                if let Some(on_nalu) = self.on_nalu.as_mut() {
+                    let (nalu_type, vcl) = if hevc {
+                        let nalu_type = (nalu[0] & 0x7e) >> 1;
+                        (nalu_type, nalu_type < 32)
+                    } else {
+                        let nalu_type = nalu[0] & 0x1f;
+                        (nalu_type, (1..=5).contains(&nalu_type))
+                    };
+                    on_nalu(nalu_type, nalu.len(), vcl);
+                }
+                let (parameter_set, sei) = if hevc {
+                    match (nalu[0] & 0x7e) >> 1 {
+                        HEVC_NALU_TYPE_VPS => (Some(&mut track.vps), false),
+                        HEVC_NALU_TYPE_SPS => (Some(&mut track.sps), false),
+                        HEVC_NALU_TYPE_PPS => (Some(&mut track.pps), false),
+                        nalu_type => (
+                            None,
+                            nalu_type == HEVC_NAL_PREFIX_SEI || nalu_type == HEVC_NAL_SUFFIX_SEI,
+                        ),
+                    }
+                } else {
+                    match nalu[0] & 0x1f {
+                        AVC_NALU_TYPE_SPS => (Some(&mut track.sps), false),
+                        AVC_NALU_TYPE_PPS => (Some(&mut track.pps), false),
+                        nalu_type => (None, nalu_type == AVC_NAL_SEI),
+                    }
+                };
+                if let Some(parameter_set) = parameter_set {
+                    // Only the first parameter sets go to the sample description
+                    if parameter_set.is_none() {
+                        *parameter_set = Some(nalu.to_vec());
+                    }
+                    track.inband_parameter_sets
+                } else if sei {
+                    // The SEI messages follow the NAL unit header
+                    let header_len = if hevc { 2 } else { 1 };
+                    keep_sei(&self.sei_policy, nalu.get(header_len..).unwrap_or_default())
+                } else {
+                    true
+                }
+            };
+            match kept.as_mut() {
+                Some(kept) if keep => kept.push(nalu),
+                None if !keep => kept = Some(nalus[..i].to_vec()),
+                _ => {}
+            }
+        }
+        let configured =
+            track.sps.is_some() && track.pps.is_some() && (!hevc || track.vps.is_some());
+        let sample = kept.as_deref().unwrap_or(nalus);
+        self.encode_video_parts(sample, duration, is_keyframe, timescale, configured)
+    }
+
+    /// Writes the NAL units gathered by `encode_video_au` as one video sample
+    fn encode_video_parts(
+        &mut self,
+        sample: &[&[u8]],
+        duration: u32,
+        is_keyframe: bool,
+        timescale: u32,
+        configured: bool,
+    ) -> Result<(), Error> {
+        if sample.is_empty() {
+            return Ok(());
+        }
+        if !configured {
+            self.parameter_sets_missing = true;
+            return Ok(());
+        }
+        let sample_type = if is_keyframe {
+            self.send_first_random_access = true;
+            SampleType::RandomAccess
+        } else if self.send_first_random_access {
+            SampleType::Default
+        } else {
+            // Non-key frames are only written after the first key frame
+            return Ok(());
+        };
+        let duration = self.video_duration(duration, timescale);
+        let track = self.video_track.as_mut().unwrap();
+        track.duration = track.duration.wrapping_add(duration);
+        self.roll_over_if_needed(&sample_type, duration)?;
+        self.check_keyframe_gap(&sample_type, duration);
+        self.put_sample(sample, duration, TrackType::Video, 0, sample_type)?;
+        // The caption track gets an empty sample to stay in sync with the video
+        self.put_caption_sample(&[Vec::new(), Vec::new()], duration, 0)?;
+        self.update_duration(TrackType::Video);
+        Ok(())
+    }
+
+    /// Writes a video frame to the MP4 file with presentation timestamp (PTS)，support b frame
+    ///
+    /// This method allows for more precise control over video frame timing by accepting
+    /// a presentation timestamp. It calculates the composition time offset (ct_offset)
+    /// which represents the difference between decode time and presentation time.
+    ///
+    /// Timestamps wrapping around the 32-bit range are unwrapped: a jump back by more
+    /// than half the range starts a new period of a monotonic 64-bit timeline, so
+    /// counters wrapping during the recording keep the composition offsets steady.
+    ///
+    /// # Arguments
+    /// * `data` - The video frame data (NAL units)
+    /// * `duration` - The duration of the video frame in milliseconds, 0 to derive it from
+    ///   the frame rate set with `set_video_frame_rate`
+    /// * `pts` - Presentation timestamp in the track's timescale
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // Set up video track first
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// // Encode a video frame with specific PTS
+    /// let video_frame_data = vec![0; 1024]; // Example video frame data
+    /// muxer.encode_video_with_pts(&video_frame_data, 33, 1000).unwrap();
+    /// ```
+    pub fn encode_video_with_pts(
+        &mut self,
+        data: &[u8],
+        duration: u32,
+        pts: u32,
+    ) -> Result<(), Error> {
+        let nalus = self.split_video(data)?;
+        self.encode_video_nalus(&nalus, duration, Some(pts))
+    }
+
+    /// Writes a video frame stamped with its presentation time only
+    ///
+    /// The frames are passed in decode order. The decode times are the presentation
+    /// times in ascending order, so the duration of each frame is the time between its
+    /// decode time and the next presentation time, and reordered frames keep the
+    /// difference between their presentation and decode times as composition offset.
+    /// Frames are held back until a later frame is presented after the next decode
+    /// time, two frames for a stream without reordering. The last frame is written by
+    /// `flush` with the average duration of the frames before it; a frame whose next
+    /// presentation time is not later also gets the average duration. Times are
+    /// converted to the track's timescale from the timestamps themselves, so the
+    /// durations add up without drift, and timestamps wrapping around are unwrapped like
+    /// in `encode_video_with_pts`.
+    ///
+    /// # Arguments
+    /// * `data` - The video frame data
+    /// * `pts` - Presentation timestamp in milliseconds
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing the previous frame fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// let video_frame_data = vec![0; 1024]; // Example video frame data
+    /// muxer.encode_video_pts_only(&video_frame_data, 0).unwrap();
+    /// muxer.encode_video_pts_only(&video_frame_data, 40).unwrap();
+    /// ```
+    pub fn encode_video_pts_only(&mut self, data: &[u8], pts: u32) -> Result<(), Error> {
+        if self.video_track.is_none() {
+            return Ok(());
+        }
+        let pts = self.unwrap_pts(pts);
+        let slot = self.pts_slots.partition_point(|&slot| slot <= pts);
+        self.pts_slots.insert(slot, pts);
+        self.pending_video_pts.push_back((data.to_vec(), pts));
+        // The next decode time is settled once a frame is presented after it
+        while self.pending_video_pts.len() > 1 && self.pts_slots[1] < pts {
+            self.write_first_pts_video()?;
+        }
+        Ok(())
+    }
+
+    /// Writes a video frame stamped with its capture time
+    ///
+    /// The duration of each frame is the time until the capture of the next frame, so each
+    /// frame is held back until the next one arrives, and the last one is written by `flush`
+    /// with the duration of the frame before it. Irregular frame intervals of live sources
+    /// are kept as they are. The timestamps of both tracks share the same clock: the first
+    /// frame of either track starts the file, and a track starting later is delayed by an
+    /// edit list, except under the legacy profile.
+    ///
+    /// # Arguments
+    /// * `data` - The video frame data
+    /// * `capture_us` - The capture time of the frame in microseconds
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing the previous frame fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// let video_frame_data = vec![0; 1024]; // Example video frame data
+    /// muxer.encode_video_ts(&video_frame_data, 1_000_000).unwrap();
+    /// muxer.encode_video_ts(&video_frame_data, 1_034_100).unwrap();
+    /// ```
+    pub fn encode_video_ts(&mut self, data: &[u8], capture_us: u64) -> Result<(), Error> {
+        let timescale = match self.video_track.as_ref() {
+            Some(track) => track.timescale,
+            None => return Ok(()),
+        };
+        let start = *self.ts_start.get_or_insert(capture_us);
+        // Times are converted from the start, the durations add up without drift
+        let time = |us: u64| rescale(us.saturating_sub(start), 1_000_000, timescale);
+        match self.pending_video_ts.take() {
+            Some((frame, frame_us)) => {
+                let duration = time(capture_us).saturating_sub(time(frame_us)) as u32;
+                self.video_ts_delta = duration;
+                self.write_timestamped_video(&frame, duration, 0)?;
+            }
+            None => {
+                if let (Some(track), CompatibilityProfile::Standard) =
+                    (self.video_track.as_mut(), &self.compatibility_profile)
+                {
+                    track.edit_delay = time(capture_us) as u32;
+                }
+            }
+        }
+        self.pending_video_ts = Some((data.to_vec(), capture_us));
+        Ok(())
+    }
+
+    /// Writes an audio frame stamped with its capture time
+    ///
+    /// The duration of the frame is its number of samples, which follows the audio clock
+    /// exactly; the capture time of the first frame written places the start of the audio
+    /// track against the video track, see `encode_video_ts`.
+    ///
+    /// # Arguments
+    /// * `data` - The audio data
+    /// * `capture_us` - The capture time of the frame in microseconds
+    /// * `samples` - The number of audio samples in this frame
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    ///
+    /// let audio_data = vec![0; 512]; // Example audio data
+    /// muxer.encode_audio_ts(&audio_data, 1_000_000, 1024).unwrap();
+    /// ```
+    pub fn encode_audio_ts(
+        &mut self,
+        data: &[u8],
+        capture_us: u64,
+        samples: u32,
+    ) -> Result<(), Error> {
+        let start = *self.ts_start.get_or_insert(capture_us);
+        if let (Some(track), CompatibilityProfile::Standard) =
+            (self.audio_track.as_mut(), &self.compatibility_profile)
+        {
+            // Until the first frame is written, e.g. while waiting for a video key frame
+            if track.duration == 0 {
+                let delay = rescale(capture_us.saturating_sub(start), 1_000_000, track.timescale);
+                track.edit_delay = delay as u32;
+            }
+        }
+        self.encode_audio(data, samples)
+    }
+
+    /// Writes a video frame held back by `encode_video_ts` or `encode_video_pts_only`
+    ///
+    /// # Arguments
+    /// * `frame` - The video frame data
+    /// * `duration` - The duration of the frame in the track's timescale
+    /// * `ct_offset` - The presentation time minus the decode time in the track's timescale
+    fn write_timestamped_video(
+        &mut self,
+        frame: &[u8],
+        duration: u32,
+        ct_offset: i32,
+    ) -> Result<(), Error> {
+        let nalus = self.split_video(frame)?;
+        self.init_header_if_needed()?;
+        if !self.detect_video_codec_if_needed(&nalus) {
+            return Ok(());
+        }
+        // The presentation time is taken relative to the end of the frame
+        let pts = match self.video_track.as_ref() {
+            Some(track) if ct_offset != 0 => {
+                let end_time = track.duration.wrapping_add(duration);
+                Some((end_time as i64 + ct_offset as i64) as u64)
+            }
+            _ => None,
+        };
+        self.encode_video_frame(&nalus, duration, pts)
+    }
+
+    /// Writes the first video frame held back by `encode_video_pts_only` at the first
+    /// decode time, lasting until the next one or for the average duration if there is
+    /// no later one
+    fn write_first_pts_video(&mut self) -> Result<(), Error> {
+        let timescale = match self.video_track.as_ref() {
+            Some(track) => track.timescale,
+            None => return Ok(()),
+        };
+        let (frame, pts) = match self.pending_video_pts.pop_front() {
+            Some(frame) => frame,
+            None => return Ok(()),
+        };
+        let time = |ms: u64| ms_to_timescale(ms, timescale);
+        let dts = self.pts_slots.remove(0);
+        let duration = match self.pts_slots.first() {
+            Some(&next) if next > dts => {
+                let duration = time(next) - time(dts);
+                self.pts_durations.0 += duration;
+                self.pts_durations.1 += 1;
+                duration as u32
+            }
+            _ => self.average_pts_duration(),
+        };
+        let ct_offset = (time(pts) as i64 - time(dts) as i64) as i32;
+        self.write_timestamped_video(&frame, duration, ct_offset)
+    }
+
+    /// Adds a chunk of an Annex-B stream, writing the access units it completes
+    fn encode_video_chunk(&mut self, data: &[u8], duration: u32) -> Result<(), Error> {
+        let nalus = match self.stream_input.as_mut() {
+            Some(input) => {
+                input.duration = duration;
+                input.splitter.push(data)
+            }
+            None => return Ok(()),
+        };
+        self.encode_stream_nalus(nalus, duration)
+    }
+
+    /// Adds complete NAL units of a stream to the access unit being assembled, writing
+    /// the access units they complete
+    fn encode_stream_nalus(&mut self, nalus: Vec<Vec<u8>>, duration: u32) -> Result<(), Error> {
+        for nalu in nalus {
+            if self.detect_video_codec {
+                self.detect_video_codec_if_needed(&[&nalu]);
+            }
+            let codec = match self.video_track.as_ref() {
+                Some(track) => &track.codec,
+                None => &Codec::AVC,
+            };
+            let first_slice = vcl_first_slice(codec, &nalu);
+            let starts = first_slice.unwrap_or_else(|| starts_access_unit(codec, &nalu));
+            let input = self.stream_input.as_mut().unwrap();
+            let unit = if starts && input.has_slice {
+                input.has_slice = false;
+                Some(std::mem::take(&mut input.unit))
+            } else {
+                None
+            };
+            input.has_slice |= first_slice.is_some();
+            input.unit.push(nalu);
+            if let Some(unit) = unit {
+                let unit: Vec<&[u8]> = unit.iter().map(|nalu| nalu.as_slice()).collect();
+                self.encode_video_nalus(&unit, duration, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the access unit left at the end of a stream passed in chunks
+    fn write_pending_stream_video(&mut self) -> Result<(), Error> {
+        let (nalus, duration) = match self.stream_input.as_mut() {
+            Some(input) => (input.splitter.finish(), input.duration),
+            None => return Ok(()),
+        };
+        self.encode_stream_nalus(nalus, duration)?;
+        let unit = match self.stream_input.as_mut() {
+            Some(input) => {
+                input.has_slice = false;
+                std::mem::take(&mut input.unit)
+            }
+            None => return Ok(()),
+        };
+        if unit.is_empty() {
+            return Ok(());
+        }
+        let unit: Vec<&[u8]> = unit.iter().map(|nalu| nalu.as_slice()).collect();
+        self.encode_video_nalus(&unit, duration, None)
+    }
+
+    /// Writes the last video frame held back by `encode_video_ts`, if any
+    fn write_pending_timestamped_video(&mut self) -> Result<(), Error> {
+        match self.pending_video_ts.take() {
+            Some((frame, _)) => self.write_timestamped_video(&frame, self.video_ts_delta, 0),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the video frames held back by `encode_video_pts_only`, the last one with the
+    /// average duration of the frames before it
+    fn write_pending_pts_video(&mut self) -> Result<(), Error> {
+        while !self.pending_video_pts.is_empty() {
+            self.write_first_pts_video()?;
+        }
+        Ok(())
+    }
+
+    /// Average duration of the video frames timed by `encode_video_pts_only`, in the
+    /// track's timescale, 0 before the first one
+    fn average_pts_duration(&self) -> u32 {
+        let (total, count) = self.pts_durations;
+        total.checked_div(count).unwrap_or(0) as u32
+    }
+
+    /// Places a 32-bit presentation time on a monotonic 64-bit timeline
+    ///
+    /// A jump of more than half the 32-bit range from the last presentation time is a
+    /// wrap around: backwards, the timestamps start a new period, forwards, the frame
+    /// belongs to the period before the wrap (a frame reordered across it).
+    ///
+    /// # Arguments
+    /// * `pts` - The presentation time in milliseconds as passed by the caller
+    ///
+    /// # Returns
+    /// * The presentation time in milliseconds since the start of the first period
+    fn unwrap_pts(&mut self, pts: u32) -> u64 {
+        const HALF_RANGE: u32 = 1 << 31;
+        let (last, mut wraps) = self.pts_wraps.unwrap_or((pts, 0));
+        if pts < last && last - pts > HALF_RANGE {
+            wraps += 1;
+        } else if pts > last && pts - last > HALF_RANGE && wraps > 0 {
+            // The frame is reordered across the wrap, the timeline stays in its period
+            return ((wraps - 1) << 32) | pts as u64;
+        }
+        self.pts_wraps = Some((pts, wraps));
+        (wraps << 32) | pts as u64
+    }
+
+    /// Splits video data into NAL units according to the configured NAL unit format
+    ///
+    /// Motion JPEG frames are not split, the whole image is returned as one unit.
+    ///
+    /// # Arguments
+    /// * `data` - The video frame data
+    ///
+    /// # Returns
+    /// * The NAL units of the frame, or an error if length-prefixed data is malformed
+    pub(crate) fn split_video<'d>(&self, data: &'d [u8]) -> Result<Vec<&'d [u8]>, Error> {
+        if let Some(Track {
+            codec: Codec::MJPEG,
+            ..
+        }) = self.video_track
+        {
+            return Ok(vec![data]);
+        }
+        match self.nalu_format {
+            NaluFormat::AnnexB => Ok(split_nalu(data).collect()),
+            NaluFormat::LengthPrefixed { size } => split_length_prefixed(data, size as usize),
+        }
+    }
+
+    /// Writes a video frame whose NAL units have already been split from the stream
+    ///
+    /// This is the common path of the video encode methods, it lets several muxers
+    /// share the result of a single `split_nalu` pass.
+    ///
+    /// # Arguments
+    /// * `nalus` - The NAL units of the frame, without start codes
+    /// * `duration` - The duration of the video frame in milliseconds, 0 to derive it from
+    ///   the frame rate set with `set_video_frame_rate`
+    /// * `pts` - Presentation timestamp in milliseconds, or `None` for frames without reordering
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    pub(crate) fn encode_video_nalus(
+        &mut self,
+        nalus: &[&[u8]],
+        duration: u32,
+        pts: Option<u32>,
+    ) -> Result<(), Error> {
+        self.init_header_if_needed()?;
+        if !self.detect_video_codec_if_needed(nalus) {
+            return Ok(());
+        }
+        if let Some(timescale) = self.video_track.as_ref().map(|track| track.timescale) {
+            let duration = self.video_duration(duration, timescale);
+            let pts = pts.map(|pts| ms_to_timescale(self.unwrap_pts(pts), timescale));
+            self.encode_video_frame(nalus, duration, pts)?;
+        }
+        Ok(())
+    }
+
+    /// Converts the duration of a video frame to the track's timescale
+    ///
+    /// # Arguments
+    /// * `duration` - The duration of the video frame in milliseconds, 0 to derive it from
+    ///   the frame rate set with `set_video_frame_rate`
+    /// * `timescale` - The timescale of the video track
+    ///
+    /// # Returns
+    /// * The duration in the track's timescale
+    fn video_duration(&mut self, duration: u32, timescale: u32) -> u32 {
+        match (duration, self.video_frame_rate) {
+            (0, Some((num, den))) => {
+                // Frame n ends at n * timescale * den / num, rounding does not add up
+                let frame = self.file.video_frame_count;
+                let end = |frame: u64| frame * timescale as u64 * den as u64 / num as u64;
+                self.file.video_frame_count += 1;
+                (end(frame + 1) - end(frame)) as u32
+            }
+            // Convert the end time of the frame from milliseconds to the track timescale,
+            // so the durations add up without drift
+            _ => {
+                let start = self.file.video_duration_ms;
+                let end = start + duration as u64;
+                self.file.video_duration_ms = end;
+                (ms_to_timescale(end, timescale) - ms_to_timescale(start, timescale)) as u32
+            }
+        }
+    }
+
+    /// Writes a video frame with its duration and presentation time in the track's timescale
+    ///
+    /// # Arguments
+    /// * `nalus` - The NAL units of the frame, without start codes
+    /// * `duration` - The duration of the video frame in the track's timescale
+    /// * `pts` - Presentation timestamp in the track's timescale, or `None` for frames without reordering
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn encode_video_frame(
+        &mut self,
+        nalus: &[&[u8]],
+        duration: u32,
+        pts: Option<u64>,
+    ) -> Result<(), Error> {
+        // An empty frame takes no time, like a frame that was never encoded
+        if nalus.iter().all(|nalu| nalu.is_empty()) {
+            return Ok(());
+        }
+        self.check_sample_table_limit()?;
+        if let Some(track) = self.video_track.as_mut() {
+            let end_time = track.duration.wrapping_add(duration);
+
+            // Calculate composition time offset (decode time to presentation time offset)
+            let ct_offset = match pts {
+                Some(pts) => (pts as i64 - end_time as i64) as i32,
+                None => 0,
+            };
+            if ct_offset != 0 {
+                check_composition_offset(&self.compatibility_profile)?;
+            }
+            track.duration = end_time;
+
+            // Process the frame based on codec type
+            match track.codec {
+                Codec::AVC => self.write_avc_frame(nalus, duration, ct_offset)?,
+                Codec::HEVC => self.write_hevc_frame(nalus, duration, ct_offset)?,
+                Codec::MJPEG => self.write_jpeg_frame(nalus, duration, ct_offset)?,
+                _ => {}
+            }
+            // Update the overall media duration if this track is longer
+            self.update_duration(TrackType::Video);
+        }
+        Ok(())
+    }
+
+    /// Writes a sample that has been prepared outside of the muxer
+    ///
+    /// The payload is written as-is, bypassing NAL unit parsing, and the sample is
+    /// appended to the sample table of the track. This lets GOPs encoded in parallel
+    /// be assembled into one file. Video payloads must already be in the length-prefixed
+    /// format of the sample description (4-byte lengths), and the parameter sets of the
+    /// track must be known, e.g. from a previous `encode_video` call.
+    ///
+    /// # Arguments
+    /// * `track` - The track of the sample, it must have been set up already
+    /// * `payload` - The sample data
+    /// * `sample` - The sample record, `offset` and `sample_size` are set by the muxer,
+    ///   `sample_delta` is in the track's timescale, `description_index` selects the stsd
+    ///   entry of the sample (not signalled per sample in fragmented mode)
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if the track is not set up, the description
+    ///   index is 0 or writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Codec, Mp4e, SampleInfo, TrackType};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    ///
+    /// // An AAC frame produced by a worker thread
+    /// let sample = SampleInfo {
+    ///     random_access: true,
+    ///     offset: 0,
+    ///     sample_size: 0,
+    ///     sample_delta: 1024,
+    ///     sample_ct_offset: 0,
+    ///     description_index: 1,
+    ///     temporal_id: 0,
+    /// };
+    /// muxer.push_raw_sample(TrackType::Audio, &[0; 256], sample).unwrap();
+    /// muxer.flush().unwrap();
+    /// ```
+    pub fn push_raw_sample(
+        &mut self,
+        track: TrackType,
+        payload: &[u8],
+        sample: SampleInfo,
+    ) -> Result<(), Error> {
+        if sample.description_index == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "sample description index starts at 1",
+            ));
+        }
+        if sample.sample_ct_offset != 0 {
+            check_composition_offset(&self.compatibility_profile)?;
+        }
+        self.check_sample_table_limit()?;
+        self.check_poisoned()?;
+        let result = self.init_header_if_needed();
+        self.poison_on_error(result)?;
+        let track_info = self.track_mut(track)?;
+        track_info.duration = track_info.duration.wrapping_add(sample.sample_delta);
+        self.update_duration(track);
+        let sample = SampleInfo {
+            sample_size: payload.len() as u32,
+            ..sample
+        };
+        let result = if self.fragment {
+            self.fragment_sample(&[payload], false, track, sample)
+                .map(|_| None)
+        } else if self.scheduler.is_some() {
+            self.queue_sample(track, sample, payload.to_vec(), false)
+                .map(|_| None)
+        } else {
+            self.write_sample_data(track, &[payload], false).map(Some)
+        };
+        let offset = match self.poison_on_error(result)? {
+            Some(offset) => offset,
+            None => return Ok(()),
+        };
+        self.track_mut(track)?
+            .samples
+            .push(SampleInfo { offset, ..sample });
+        Ok(())
+    }
+}
+
+impl<'a, Writer> Mp4e<'a, Writer>
+where
+    Writer: Write + Seek,
+{
+    /// Flushes any remaining data and finalizes the MP4 file
+    ///
+    /// This method ensures that all MP4 boxes are properly written to the output,
+    /// including the 'moov' box which contains metadata about the file.
+    /// A video frame held back by `encode_video_ts`, or the last access unit of a stream
+    /// passed in chunks, is written first.
+    /// In fragmented mode, the queued audio is written and an 'mfra' box indexes the
+    /// fragments starting with a sync sample, so players can seek in the file.
+    /// A moov box outgrowing the space reserved with `reserve_moov_space` is written after
+    /// the mdat box; `move_moov_to_front` moves it in front afterwards.
+    ///
+    /// The moov box is built in memory and written in one piece, but the output is left
+    /// incomplete if writing fails, e.g. on a full disk. The error is returned and the
+    /// muxer is poisoned: every later call writing to the output fails right away rather
+    /// than writing more data after the inconsistent part.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, an `InvalidData` error if video slices were dropped because
+    ///   the parameter sets never arrived, in which case the file is still finalized with
+    ///   the other samples, or an error if writing fails or failed before
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::{Cursor, Seek, Write};
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // ... encode audio/video data ...
+    ///
+    /// muxer.flush().unwrap();
+    /// ```
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.check_poisoned()?;
+        let result = self.finalize();
+        self.poison_on_error(result)
+    }
+
+    /// Writes the remaining data and the moov box, see `flush`
+    fn finalize(&mut self) -> Result<(), Error> {
+        self.init_header_if_needed()?;
+        self.write_pending_stream_video()?;
+        self.write_pending_timestamped_video()?;
+        self.write_pending_pts_video()?;
+        if self.fragment {
+            self.write_held_samples()?;
+            self.write_audio_fragment()?;
+            self.write_mfra_if_needed()?;
+            self.update_fragment_duration()?;
+        }
+        if !self.file.write_moov {
+            if self.scheduler.is_some() {
+                self.run_scheduler(true)?;
+            }
+            // Fragmented output has no mdat box to patch before the moov box
+            if !self.fragment {
+                self.write_mdat_size()?;
+            }
+            self.write_pending_mdats()?;
+            self.normalize_composition_offsets();
+            if self.fill_moov_reserve()? {
+                self.file.write_moov = true;
+            }
+            self.write_moov_if_needed()?;
+            self.release_index()?;
+        }
+        // The file is complete with the other tracks, the error only reports the loss
+        if self.parameter_sets_missing && !self.video_configured() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "video frames were dropped because the stream has no parameter sets, \
+                 set them with set_video_parameter_sets",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Splits the output into files of a limited duration or size
+    ///
+    /// Once a limit is reached, the next video key frame starts a new file: the current
+    /// file is finished as with `flush` and the muxer goes on with the writer returned by
+    /// `on_rollover`. Every file is a complete MP4 file, or init segment and fragments in
+    /// fragmented mode, starting with a key frame. The last file is finished by `flush`.
+    ///
+    /// # Arguments
+    /// * `max_duration` - The duration of video in each file in milliseconds, 0 for no limit
+    /// * `max_bytes` - The size of each file in bytes, 0 for no limit
+    /// * `on_rollover` - Returns the writer of the next file, an error stops the encoding
+    ///
+    /// # Example
+    /// ```no_run
+    /// use std::fs::File;
+    /// use std::io::{Error, ErrorKind};
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// // One file per hour over a day
+    /// let mut files = (0..24)
+    ///     .map(|hour| File::create(format!("recording-{}.mp4", hour)))
+    ///     .collect::<Result<Vec<_>, _>>()
+    ///     .unwrap();
+    /// let (first, next) = files.split_first_mut().unwrap();
+    /// let mut next = next.iter_mut();
+    /// let mut next_file = || next.next().ok_or_else(|| Error::from(ErrorKind::Other));
+    ///
+    /// let mut muxer = Mp4e::new(first);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_rollover(3_600_000, 0, &mut next_file);
+    /// ```
+    pub fn set_rollover(
+        &mut self,
+        max_duration: u32,
+        max_bytes: u64,
+        on_rollover: &'a mut dyn FnMut() -> Result<&'a mut Writer, Error>,
+    ) {
+        self.rollover = Some(Rollover {
+            max_duration,
+            max_bytes,
+            on_rollover,
+            roll_over: Self::roll_over,
+        });
+    }
+
+    /// Finishes the current file and switches to the writer returned by the callback of
+    /// `set_rollover`
+    fn roll_over(&mut self) -> Result<(), Error> {
+        self.flush()?;
+        let rollover = self.rollover.as_mut().unwrap();
+        self.writer = (rollover.on_rollover)()?;
+        Ok(())
+    }
+
+    /// Finalizes the MP4 file up to the most recent video key frame
+    ///
+    /// The file is made playable with the complete GOPs written so far: the moov box
+    /// indexes the samples before the most recent key frame, and the samples of the other
+    /// tracks before its decode time. The key frame and the samples after it stay out of
+    /// the index and encoding continues into a new mdat box after the moov box; the next
+    /// call, or `flush`, writes a new moov box at the end and turns the old one into a
+    /// free box, so the file stays playable and each call makes it longer, ending on a
+    /// key frame boundary. Without a video track, all samples written so far are indexed.
+    /// Only for the non-fragmented mode without sample scheduler or per-track mdat boxes.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if the mode is not supported or writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// // ... encode video data ...
+    ///
+    /// // Playable DVR window so far, encoding goes on
+    /// muxer.flush_to_last_keyframe().unwrap();
+    /// ```
+    pub fn flush_to_last_keyframe(&mut self) -> Result<(), Error> {
+        if self.fragment || self.scheduler.is_some() || self.mdat_per_track {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "flushing to a key frame needs the non-fragmented mode with a single mdat box",
+            ));
+        }
+        if self.file.write_moov {
+            return Ok(());
+        }
+        self.check_poisoned()?;
+        let result = self.init_header_if_needed();
+        self.poison_on_error(result)?;
+        // Decode time of the most recent key frame, with the video timescale
+        let cut = self.video_track.as_ref().map(|track| {
+            let index = track
+                .samples
+                .iter()
+                .rposition(|sample| sample.random_access)
+                .unwrap_or(0);
+            let time: u64 = track.samples[..index]
+                .iter()
+                .map(|sample| sample.sample_delta as u64)
+                .sum();
+            (time, track.timescale as u64)
+        });
+        let mut tracks = [
+            self.video_track.as_mut(),
+            self.audio_track.as_mut(),
+            self.caption_track.as_mut(),
+        ];
+        let mut held = Vec::new();
+        for track in tracks.iter_mut().flatten() {
+            let mut decode_time = 0;
+            let count = match cut {
+                Some((time, timescale)) => track
+                    .samples
+                    .iter()
+                    .take_while(|sample| {
+                        let before = decode_time * timescale < time * track.timescale as u64;
+                        decode_time += sample.sample_delta as u64;
+                        before
+                    })
+                    .count(),
+                None => track.samples.len(),
+            };
+            let tail = track.samples.split_off(count);
+            let tail_duration: u32 = tail.iter().map(|sample| sample.sample_delta).sum();
+            track.duration = track.duration.wrapping_sub(tail_duration);
+            held.push((tail, tail_duration));
+        }
+        // The offsets of the later samples are only known at the next flush
+        let ct_shift = self.normalize_composition_offsets();
+        let result = self.write_index();
+        let result = self.poison_on_error(result);
+        if let (Some(track), Some(shift)) = (self.video_track.as_mut(), ct_shift) {
+            for sample in track.samples.iter_mut() {
+                sample.sample_ct_offset += shift;
+            }
+            track.edit_media_time = None;
+        }
+        let mut tracks = [
+            self.video_track.as_mut(),
+            self.audio_track.as_mut(),
+            self.caption_track.as_mut(),
+        ];
+        for (track, (mut tail, tail_duration)) in tracks.iter_mut().flatten().zip(held) {
+            track.samples.append(&mut tail);
+            track.duration = track.duration.wrapping_add(tail_duration);
+        }
+        result
+    }
+
+    /// Writes the moov box of `flush_to_last_keyframe` after the samples and opens a new
+    /// mdat box for the next ones
+    ///
+    /// The new mdat box extends to the end of the output until the next flush writes its
+    /// size, so the output stays a valid MP4 file while encoding goes on.
+    fn write_index(&mut self) -> Result<(), Error> {
+        self.write_mdat_size()?;
+        let index_pos = self.file.write_pos;
+        self.file.write_pos += self.write_moov_box()?;
+        self.release_index()?;
+        self.file.index_pos = index_pos;
+        // A size of 0 extends the mdat box to the end of the output
+        let mut header = mdat_header(16, self.wide_box)?;
+        header[8..12].copy_from_slice(&0u32.to_be_bytes());
+        self.writer.write_all(&header)?;
+        self.file.mdat_pos = self.file.write_pos;
+        self.file.write_pos += header.len() as u64;
+        Ok(())
+    }
+
+    /// Turns the moov box written by `flush_to_last_keyframe` into a free box
+    ///
+    /// Called once a newer moov box is written, so the output always has one.
+    fn release_index(&mut self) -> Result<(), Error> {
+        if self.file.index_pos == 0 {
+            return Ok(());
+        }
+        self.writer.seek(SeekFrom::Start(self.file.index_pos + 4))?;
+        self.writer.write_all(b"free")?;
+        self.writer.seek(SeekFrom::Start(self.file.write_pos))?;
+        self.file.index_pos = 0;
+        Ok(())
+    }
+
+    /// Muxes a complete Annex-B elementary stream and finalizes the MP4 file
+    ///
+    /// The stream is read to the end and split into access units, each of which is
+    /// written as one frame. If no video track is set up, one is set up with the given
+    /// codec and the dimensions of the first SPS of the stream.
+    ///
+    /// # Arguments
+    /// * `reader` - The source of the elementary stream
+    /// * `codec` - The video codec of the stream, `Codec::AVC` or `Codec::HEVC`
+    /// * `duration` - The duration of each frame in milliseconds
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if reading or writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let stream = [
+    ///     0, 0, 0, 1, 0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0x40,
+    ///     0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80, 0, 0, 0, 1, 0x65, 0x88, 0x84, 0x21, 0xA0,
+    ///     0, 0, 0, 1, 0x41, 0x9A, 0x21, 0x6C, 0, 0, 0, 1, 0x41, 0x9A, 0x21, 0x6C,
+    /// ];
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // Three frames of 33ms, the 1920x1080 video track is set up from the SPS
+    /// muxer.mux_annexb(&mut &stream[..], Codec::AVC, 33).unwrap();
+    /// ```
+    pub fn mux_annexb<R>(
+        &mut self,
+        reader: &mut R,
+        codec: Codec,
+        duration: u32,
+    ) -> Result<(), Error>
+    where
+        R: Read,
+    {
+        if !matches!(codec, Codec::AVC | Codec::HEVC) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "codec is not a video codec",
+            ));
+        }
+        let mut stream = Vec::new();
+        reader.read_to_end(&mut stream)?;
+        let units = split_access_units(&stream, &codec);
+        if self.video_track.is_none() {
+            let (width, height) = units
+                .iter()
+                .flatten()
+                .find(|nalu| match codec {
+                    Codec::AVC => nalu[0] & 0x1f == AVC_NALU_TYPE_SPS,
+                    _ => (nalu[0] & 0x7e) >> 1 == HEVC_NALU_TYPE_SPS,
+                })
+                .and_then(|sps| parse_sps_dimensions(&codec, sps))
+                .unwrap_or((0, 0));
+            self.set_video_track(width, height, codec);
+        }
+        for unit in units {
+            self.encode_video_nalus(&unit, duration, None)?;
+        }
+        self.flush()
+    }
+
+    /// Writes a single HEVC coded image as a HEIF still image file
+    ///
+    /// Instead of a timed track, the image is stored as the primary item of a file-level
+    /// meta box. The video track must be set up with `Codec::HEVC` and the image dimensions,
+    /// and nothing else may be written to the file, which is complete once this returns.
+    ///
+    /// # Arguments
+    /// * `data` - The coded image, with its VPS, SPS and PPS
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if the track is not an HEVC track, the image is
+    ///   missing parameter sets or slices, data has already been written or writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_video_track(1280, 720, Codec::HEVC);
+    ///
+    /// // An image without parameter sets cannot be decoded
+    /// assert!(muxer.encode_image(&[0, 0, 0, 1, 0x26, 0x01, 0xAF]).is_err());
+    /// ```
+    pub fn encode_image(&mut self, data: &[u8]) -> Result<(), Error> {
+        if self.file.init_header {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "an image must be the only content of the file",
+            ));
+        }
+        let nalus = self.split_video(data)?;
+        let track = match self.video_track.as_mut() {
+            Some(
+                track @ Track {
+                    codec: Codec::HEVC, ..
+                },
+            ) => track,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "an image needs an HEVC video track",
+                ))
+            }
+        };
+        let mut image = vec![];
+        for nalu in nalus.into_iter().filter(|nalu| nalu.len() >= 2) {
+            let parameter_set = match (nalu[0] & 0x7e) >> 1 {
+                HEVC_NALU_TYPE_VPS => &mut track.vps,
+                HEVC_NALU_TYPE_SPS => &mut track.sps,
+                HEVC_NALU_TYPE_PPS => &mut track.pps,
+                // Access unit delimiters have no use in an image item
+                35 => continue,
+                _ => {
+                    image.push(nalu);
+                    continue;
+                }
+            };
+            if parameter_set.is_none() {
+                *parameter_set = Some(nalu.to_vec());
+            }
+        }
+        if track.vps.is_none() || track.sps.is_none() || track.pps.is_none() || image.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "image is missing parameter sets or slices",
+            ));
+        }
+
+        // The meta box has the same size whatever the offset of the image
+        let data_size = sample_data_size(&image, true);
+        let mut meta = Cursor::new(Vec::new());
+        write_heif_meta(track, 0, data_size, &mut meta)?;
+        // ftyp, meta and the mdat header precede the image
+        let data_offset = (24 + meta.get_ref().len() + 8) as u32;
+        let mut meta = Cursor::new(Vec::new());
+        write_heif_meta(track, data_offset, data_size, &mut meta)?;
+
+        self.file.write_pos += write_heif_ftyp(self.writer)?;
+        self.writer.write_all(meta.get_ref())?;
+        self.file.write_pos += meta.get_ref().len() as u64;
+        self.file.write_pos += write_mdat(&image, true, self.writer)?;
+        // Nothing is left to write on flush
+        self.file.init_header = true;
+        self.file.write_moov = true;
+        Ok(())
+    }
+}
+
+impl<'a, Writer> Mp4e<'a, Writer>
+where
+    Writer: Write + Seek,
+{
+    /// Updates the size field of the mdat box
+    ///
+    /// In MP4 files, the mdat box header needs to contain the total size of the box (including the header itself).
+    /// Since the final size of media data cannot be known at initialization time, this value needs to be updated
+    /// after all data has been written.
+    ///
+    /// The 16 bytes reserved after the ftyp box and the moov reserve are rewritten with the
+    /// header form fitting the size.
+    fn write_mdat_size(&mut self) -> Result<(), Error> {
+        self.write_io_buffer()?;
+        let mdat_pos = match self.file.mdat_pos {
+            0 => 32 + self.moov_reserve as u64,
+            mdat_pos => mdat_pos,
+        };
+        self.writer.seek(SeekFrom::Start(mdat_pos))?;
+        self.writer
+            .write_all(&mdat_header(self.file.write_pos - mdat_pos, self.wide_box)?)?;
+        // Restore file cursor to current write position
+        self.writer.seek(SeekFrom::Start(self.file.write_pos))?;
+        Ok(())
+    }
+
+    /// Writes the duration of all the fragments to the mehd box of a fragmented file
+    ///
+    /// The moov box is written before the first fragment, when the duration is not
+    /// known, so players reading the file from a seekable output, like an audio element
+    /// seeking a podcast, find it in the mehd box reserved with a 64-bit duration. The
+    /// durations of the mvhd and tkhd boxes only cover the samples of the moov box, none.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidData` error if the moov box has no mehd box
+    ///   to update
+    fn update_fragment_duration(&mut self) -> Result<(), Error> {
+        if self.file.moov_size == 0 {
+            return Ok(());
+        }
+        if self.file.mehd_pos == 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the moov box has no mehd box to write the duration of the fragments to",
+            ));
+        }
+        let duration = [&self.video_track, &self.audio_track, &self.caption_track]
+            .iter()
+            .filter_map(|track| track.as_ref())
+            .map(|track| track_movie_duration(track, self.movie_timescale))
+            .max()
+            .unwrap_or(0);
+        self.writer.seek(SeekFrom::Start(self.file.mehd_pos))?;
+        self.writer.write_all(&(duration as u64).to_be_bytes())?;
+        self.writer.seek(SeekFrom::Start(self.file.write_pos))?;
+        Ok(())
+    }
+
+    /// Writes the moov box into the space reserved with `reserve_moov_space` if it fits
+    ///
+    /// The rest of the reserve becomes a free box, so the moov box either fills it
+    /// exactly or leaves at least the 8 bytes of a free box header.
+    ///
+    /// # Returns
+    /// * Whether the moov box was written, `false` if there is no reserve or it is too small
+    fn fill_moov_reserve(&mut self) -> Result<bool, Error> {
+        let reserve = self.moov_reserve as usize;
+        if self.fragment || reserve == 0 {
+            return Ok(false);
+        }
+        let buf = self.build_moov_box()?;
+        self.file.moov_in_reserve = buf.len() == reserve || buf.len() + 8 <= reserve;
+        if !self.file.moov_in_reserve {
+            self.scratch = buf;
+            return Ok(false);
+        }
+        self.writer.seek(SeekFrom::Start(32))?;
+        self.writer.write_all(&buf)?;
+        if buf.len() < reserve {
+            write_free((reserve - buf.len()) as u32, self.writer)?;
+        }
+        self.writer.seek(SeekFrom::Start(self.file.write_pos))?;
+        self.scratch = buf;
+        Ok(true)
+    }
+}
+impl<'a, Writer> Mp4e<'a, Writer>
+where
+    Writer: Write,
+{
+    /// Creates a new MP4 encoder instance with the specified configuration
+    ///
+    /// This is the internal constructor used by both `new` and `new_with_fragment` methods
+    /// to initialize the Mp4e struct with default values.
+    ///
+    /// # Arguments
+    /// * `fragment` - Whether to use fragmented MP4 mode (true) or standard mode (false)
+    /// * `writer` - The writer object to output the MP4 data to
+    ///
+    /// # Returns
+    /// * A new `Mp4e` instance with initialized fields
+    fn new_encoder(fragment: bool, writer: &'a mut Writer) -> Self {
+        Self {
+            // Nothing has been written to the output file yet
+            file: FileState::default(),
+            // Media creation time, defaults to 0 (will be set later if needed)
+            create_time: 0,
+            // Whether to use fragmented mode (true) or standard mode (false)
+            fragment,
+            // Fragment sequence ID counter, starts at 0
+            fragment_id: 0,
+            // Fragments are not recorded unless asked for
+            record_fragments: false,
+            // Fragments are written without checksum by default
+            fragment_checksum: false,
+            // Fragments have no Smooth Streaming boxes by default
+            smooth_streaming: false,
+            // Fragments are not indexed by sidx boxes by default
+            segment_index: false,
+            // One chunk per sample and a size per sample by default
+            compact_tables: false,
+            // The sample tables may grow without limit by default
+            sample_table_limit: 0,
+            // Audio fragments hold about half a second of audio by default
+            audio_fragment_duration: 500,
+            // The output is not cut into segments by default
+            segmenting: false,
+            fragments: Vec::new(),
+            // Track ID counter, starts at 1 (ID 0 is reserved)
+            track_ids: 1,
+            // Whether the first random access point (keyframe) has been processed
+            send_first_random_access: false,
+            // Whether the video codec is still to be detected from the stream
+            detect_video_codec: false,
+            // Default language code ("und" = undetermined)
+            language: "und".as_bytes().try_into().unwrap(),
+            // Movie durations are in milliseconds by default
+            movie_timescale: 1000,
+            // Input video is an Annex-B stream by default
+            nalu_format: NaluFormat::AnnexB,
+            // SEI NAL units are written into the samples by default
+            sei_policy: SeiPolicy::Keep,
+            // Composition offsets are allowed by default
+            compatibility_profile: CompatibilityProfile::Standard,
+            // Key frames are detected from the NAL unit types by default
+            all_intra: false,
+            // Frame durations are given with each frame by default
+            video_frame_rate: None,
+            // Timestamped frames set the start of the file when they are used
+            ts_start: None,
+            pending_video_ts: None,
+            video_ts_delta: 0,
+            pending_video_pts: VecDeque::new(),
+            pts_slots: Vec::new(),
+            pts_durations: (0, 0),
+            pts_wraps: None,
+            // Each call to encode_video passes whole frames by default
+            stream_input: None,
+            // Key frames may be any distance apart by default
+            max_keyframe_interval: 0,
+            on_keyframe_gap: None,
+            // Silence is the built-in silent frame of the audio codec by default
+            audio_silence: None,
+            audio_silence_remainder: 0,
+            // Frames before the first detected key frame are dropped by default
+            assume_first_keyframe: false,
+            // Only IDR pictures are key frames by default
+            recovery_point_keyframes: false,
+            // The 8 bytes reserved before a small mdat box are a free box by default
+            wide_box: false,
+            // Samples of all tracks are interleaved in a single mdat box by default
+            mdat_per_track: false,
+            // Samples are written as they arrive by default
+            scheduler: None,
+            // Everything is written to a single output by default
+            rollover: None,
+            // No video slice has been dropped yet
+            parameter_sets_missing: false,
+            // The output is consistent until a write fails
+            poisoned: false,
+            // NAL units are processed silently by default
+            on_nalu: None,
+            // The moov box only holds the boxes of the muxer by default
+            user_boxes: UserBoxes::default(),
+            // The box buffer grows to the size of the largest box built
+            scratch: Vec::new(),
+            // Samples are written to the output as they come by default
+            io_chunk_size: 0,
+            io_buffer: Vec::new(),
+            // The moov box is written after the mdat box by default
+            moov_reserve: 0,
+            // The moov box is written with the first fragment by default
+            expected_audio: None,
+            held_samples: Vec::new(),
+            // The writer object for outputting MP4 data
+            writer,
+            // Video track information, initially empty
+            video_track: None,
+            // Audio track information, initially empty
+            audio_track: None,
+            // Closed caption track information, initially empty
+            caption_track: None,
+        }
+    }
+    /// Processes and writes HEVC (H.265) video frames to the MP4 file
+    ///
+    /// This function takes HEVC NAL units, parses them, and handles different types appropriately:
+    /// - VPS (Video Parameter Set): Stores configuration data
+    /// - SPS (Sequence Parameter Set): Stores sequence configuration data
+    /// - PPS (Picture Parameter Set): Stores picture configuration data
+    /// - Other NAL units: Written together as one video sample when key configuration is available
+    ///
+    /// For HEVC, key frames are identified by specific NAL unit types in the range
+    /// [HEVC_NAL_BLA_W_LP, HEVC_NAL_CRA_NUT].
+    ///
+    /// # Arguments
+    /// * `nalus` - The HEVC NAL units to process, without start codes
+    /// * `duration` - The duration of the frame in the track's timescale
+    /// * `ct_offset` - The composition time offset for the frame
+    ///
+    ///
+    /// # Returns
+    /// * `Ok(())` on successful processing, or an error if writing fails
+    fn write_hevc_frame(
+        &mut self,
+        nalus: &[&[u8]],
+        duration: u32,
+        ct_offset: i32,
+    ) -> Result<(), Error> {
+        use crate::nalu::*;
+        let mut sample = Vec::with_capacity(nalus.len());
+        let mut sample_type = SampleType::Default;
+        let mut captions = [Vec::new(), Vec::new()];
+        for &frame_data in nalus {
+            // Empty NAL units carry nothing, e.g. between two start codes
+            if frame_data.is_empty() {
+                continue;
+            }
+            // Extract the NAL unit type (HEVC uses 6 bits for type, shifted right by 1)
+            let nalu_type = (frame_data[0] & 0x7e) >> 1;
+            if let Some(on_nalu) = self.on_nalu.as_mut() {
+                on_nalu(nalu_type, frame_data.len(), nalu_type < 32);
+            }
+            // Get mutable reference to the video track
+            let video_track = self.video_track.as_mut().unwrap();
+
+            // Pick up closed captions when a caption track is set up
+            if nalu_type == HEVC_NAL_PREFIX_SEI && self.caption_track.is_some() {
+                collect_cea608_pairs(frame_data.get(2..).unwrap_or_default(), &mut captions);
+            }
+
+            // Pick up HDR10 static metadata unless it was set explicitly
+            if nalu_type == HEVC_NAL_PREFIX_SEI && frame_data.len() > 2 {
+                let rbsp = remove_emulation_prevention(&frame_data[2..]);
+                for (payload_type, payload) in sei_messages(&rbsp) {
+                    match payload_type {
+                        SEI_MASTERING_DISPLAY_COLOUR_VOLUME
+                            if video_track.mastering_display.is_none() =>
+                        {
+                            video_track.mastering_display = parse_mastering_display(payload);
+                        }
+                        SEI_CONTENT_LIGHT_LEVEL_INFO
+                            if video_track.content_light_level.is_none() =>
+                        {
+                            video_track.content_light_level = parse_content_light_level(payload);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            match nalu_type {
+                // Handle Video Parameter Set
+                HEVC_NALU_TYPE_VPS => {
+                    // Only store the first VPS NAL unit
+                    if video_track.vps.is_none() {
+                        video_track.vps = Some(frame_data.to_vec());
+                    }
+                    if video_track.inband_parameter_sets {
+                        sample.push(frame_data);
+                    }
+                }
+                // Handle Sequence Parameter Set
+                HEVC_NALU_TYPE_SPS => {
+                    // Only store the first SPS NAL unit
+                    if video_track.sps.is_none() {
+                        video_track.sps = Some(frame_data.to_vec());
+                    }
+                    if video_track.inband_parameter_sets {
+                        sample.push(frame_data);
+                    }
+                }
+                // Handle Picture Parameter Set
+                HEVC_NALU_TYPE_PPS => {
+                    // Only store the first PPS NAL unit
+                    if video_track.pps.is_none() {
+                        video_track.pps = Some(frame_data.to_vec());
+                    }
+                    if video_track.inband_parameter_sets {
+                        sample.push(frame_data);
+                    }
+                }
+                // Collect all other NAL unit types (video data)
+                _ => {
+                    // Only process video data NAL units after we have the essential configuration
+                    if video_track.vps.is_some()
+                        && video_track.sps.is_some()
+                        && video_track.pps.is_some()
+                    {
+                        // Check if this is a key frame (Random Access Point)
+                        // Key frame types are in the range [BLA_W_LP, CRA_NUT]
+                        if is_random_access_nalu(&Codec::HEVC, nalu_type) {
+                            sample_type = SampleType::RandomAccess;
+                        }
+                        if (nalu_type == HEVC_NAL_PREFIX_SEI || nalu_type == HEVC_NAL_SUFFIX_SEI)
+                            && !keep_sei(&self.sei_policy, frame_data.get(2..).unwrap_or_default())
+                        {
+                            continue;
+                        }
+                        sample.push(frame_data);
+                    } else if nalu_type < 32 {
+                        // A slice of a stream without parameter sets
+                        self.parameter_sets_missing = true;
+                    }
+                }
+            }
+        }
+        if sample.is_empty() {
+            return Ok(());
+        }
+        if self.all_intra && !matches!(sample_type, SampleType::Continuation) {
+            // Every picture of an all-intra stream is a key frame
+            sample_type = SampleType::RandomAccess;
+        }
+        if self.assume_first_keyframe
+            && !self.send_first_random_access
+            && !matches!(sample_type, SampleType::Continuation)
+        {
+            // The stream is known to start on a key frame
+            sample_type = SampleType::RandomAccess;
+        }
+        if let SampleType::RandomAccess = sample_type {
+            // Mark that we've received our first key frame
+            self.send_first_random_access = true;
+        } else if !self.send_first_random_access {
+            // Non-key frames are only written after the first key frame
+            return Ok(());
+        }
+        // All NAL units of the picture are written as a single sample
+        // Continuation frames extend the previous video sample, they get no caption sample
+        self.roll_over_if_needed(&sample_type, duration)?;
+        let continuation = matches!(sample_type, SampleType::Continuation);
+        self.check_keyframe_gap(&sample_type, duration);
+        self.put_sample(&sample, duration, TrackType::Video, ct_offset, sample_type)?;
+        if !continuation {
+            self.put_caption_sample(&captions, duration, ct_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a Motion JPEG frame to the MP4 file
+    ///
+    /// Every JPEG image is coded on its own, so every frame is written as a sync sample.
+    ///
+    /// # Arguments
+    /// * `images` - The JPEG image of the frame, as returned by `split_video`
+    /// * `duration` - The duration of the frame in the track's timescale
+    /// * `ct_offset` - The composition time offset for the frame
+    ///
+    /// # Returns
+    /// * `Ok(())` on successful processing, or an error if writing fails
+    fn write_jpeg_frame(
+        &mut self,
+        images: &[&[u8]],
+        duration: u32,
+        ct_offset: i32,
+    ) -> Result<(), Error> {
+        if images.iter().all(|image| image.is_empty()) {
+            return Ok(());
+        }
+        self.send_first_random_access = true;
+        self.roll_over_if_needed(&SampleType::RandomAccess, duration)?;
+        self.put_sample(
+            images,
+            duration,
+            TrackType::Video,
+            ct_offset,
+            SampleType::RandomAccess,
+        )
+    }
+
+    /// Processes and writes AVC (H.264) video frames to the MP4 file
+    ///
+    /// This function takes AVC NAL units, parses them, and handles different types appropriately:
+    /// - SPS (Sequence Parameter Set): Stores sequence configuration data
+    /// - PPS (Picture Parameter Set): Stores picture configuration data
+    /// - Other NAL units: Written together as one video sample when key configuration is available
+    ///
+    /// For AVC, key frames are identified by I-Slice NAL units (AVC_NAL_ISLICE_NALU).
+    /// Additionally, it analyzes the first slice header to determine if the frame is a continuation
+    /// of the previous picture or a new picture.
+    ///
+    /// # Arguments
+    /// * `nalus` - The AVC NAL units to process, without start codes
+    /// * `duration` - The duration of the frame in the track's timescale
+    /// * `ct_offset` - The composition time offset for the frame
+    ///
+    /// # AVC Specifics
+    /// - NAL unit types are determined by the last 5 bits of the first byte
+    /// - Frame boundaries are determined by parsing the slice header using UE-Golomb decoding
+    /// - The first_mb_in_slice parameter indicates if this is a new frame (0) or continuation (!=0)
+    ///
+    /// # Returns
+    /// * `Ok(())` on successful processing, or an error if writing fails
+    fn write_avc_frame(
+        &mut self,
+        nalus: &[&[u8]],
+        duration: u32,
+        ct_offset: i32,
+    ) -> Result<(), Error> {
+        use crate::nalu::*;
+        let mut sample = Vec::with_capacity(nalus.len());
+        let mut sample_type = SampleType::Default;
+        let mut first_slice_seen = false;
+        let mut captions = [Vec::new(), Vec::new()];
+        // Whether the frame has a recovery point SEI and only non-IDR I slices
+        let mut recovery_point = false;
+        let mut intra_slices = true;
+        for &frame_data in nalus {
+            // Empty NAL units carry nothing, e.g. between two start codes
+            if frame_data.is_empty() {
+                continue;
+            }
+            // Extract the NAL unit type (AVC uses last 5 bits of the first byte)
+            let nalu_type = frame_data[0] & 0x1f;
+            if let Some(on_nalu) = self.on_nalu.as_mut() {
+                on_nalu(nalu_type, frame_data.len(), (1..=5).contains(&nalu_type));
+            }
+            // Get mutable reference to the video track
+            let video_track = self.video_track.as_mut().unwrap();
+
+            // Pick up closed captions when a caption track is set up
+            if nalu_type == AVC_NAL_SEI && self.caption_track.is_some() {
+                collect_cea608_pairs(&frame_data[1..], &mut captions);
+            }
+            if self.recovery_point_keyframes {
+                if nalu_type == AVC_NAL_SEI {
+                    let rbsp = remove_emulation_prevention(&frame_data[1..]);
+                    recovery_point |= sei_messages(&rbsp)
+                        .iter()
+                        .any(|(payload_type, _)| *payload_type == SEI_RECOVERY_POINT);
+                } else if nalu_type == AVC_NAL_SLICE_NALU {
+                    // slice_type follows first_mb_in_slice, 2 and 7 are I slices
+                    let mut br: BitReader<'_> = BitReader::new(&frame_data[1..]);
+                    br.ue_bits(32);
+                    intra_slices &= br.ue_bits(32) % 5 == 2;
+                }
+            }
+
+            match nalu_type {
+                // Handle Sequence Parameter Set
+                AVC_NALU_TYPE_SPS => {
+                    // Only store the first SPS NAL unit, avcC needs its profile and level bytes
+                    if video_track.sps.is_none() && frame_data.len() >= 4 {
+                        video_track.sps = Some(frame_data.to_vec());
+                    }
+                    if video_track.inband_parameter_sets {
+                        sample.push(frame_data);
+                    }
+                }
+                // Handle Picture Parameter Set
+                AVC_NALU_TYPE_PPS => {
+                    // Only store the first PPS NAL unit
+                    if video_track.pps.is_none() {
+                        video_track.pps = Some(frame_data.to_vec());
+                    }
+                    if video_track.inband_parameter_sets {
+                        sample.push(frame_data);
+                    }
+                }
+                // Collect all other NAL unit types (video data including I-frames, P-frames, B-frames, etc.)
+                _ => {
+                    // Only process video data NAL units after we have the essential configuration (SPS and PPS)
+                    if video_track.sps.is_some() && video_track.pps.is_some() {
+                        if is_random_access_nalu(&Codec::AVC, nalu_type) {
+                            // An IDR slice makes the whole picture a key frame
+                            sample_type = SampleType::RandomAccess;
+                        } else if (AVC_NAL_SLICE_NALU..AVC_NAL_ISLICE_NALU).contains(&nalu_type)
+                            && !first_slice_seen
+                            && frame_data.len() > 1
+                        {
+                            // Read the first_mb_in_slice value of the first slice using UE-Golomb decoding
+                            // If it's not 0, the frame continues the picture of the previous frame
+                            let mut br: BitReader<'_> = BitReader::new(&frame_data[1..]);
+                            if br.ue_bits(32) != 0 {
+                                sample_type = SampleType::Continuation;
+                            }
+                        }
+                        if (AVC_NAL_SLICE_NALU..=AVC_NAL_ISLICE_NALU).contains(&nalu_type) {
+                            first_slice_seen = true;
+                        }
+                        if nalu_type == AVC_NAL_SEI && !keep_sei(&self.sei_policy, &frame_data[1..])
+                        {
+                            continue;
+                        }
+                        sample.push(frame_data);
+                    } else if (AVC_NAL_SLICE_NALU..=AVC_NAL_ISLICE_NALU).contains(&nalu_type) {
+                        // A slice of a stream without parameter sets
+                        self.parameter_sets_missing = true;
+                    }
+                }
+            }
+        }
+        if sample.is_empty() {
+            return Ok(());
+        }
+        if self.all_intra && !matches!(sample_type, SampleType::Continuation) {
+            // Every picture of an all-intra stream is a key frame
+            sample_type = SampleType::RandomAccess;
+        }
+        if recovery_point && intra_slices && first_slice_seen {
+            if let SampleType::Default = sample_type {
+                // An I picture at a recovery point is a key frame of an open GOP
+                sample_type = SampleType::RandomAccess;
+            }
+        }
+        if self.assume_first_keyframe
+            && !self.send_first_random_access
+            && !matches!(sample_type, SampleType::Continuation)
+        {
+            // The stream is known to start on a key frame
+            sample_type = SampleType::RandomAccess;
+        }
+        if let SampleType::RandomAccess = sample_type {
+            // Mark that we've received our first key frame
+            self.send_first_random_access = true;
+        } else if !self.send_first_random_access {
+            // Non-key frames are only written after the first key frame
+            return Ok(());
+        }
+        // All NAL units of the picture are written as a single sample
+        // Continuation frames extend the previous video sample, they get no caption sample
+        self.roll_over_if_needed(&sample_type, duration)?;
+        let continuation = matches!(sample_type, SampleType::Continuation);
+        self.check_keyframe_gap(&sample_type, duration);
+        self.put_sample(&sample, duration, TrackType::Video, ct_offset, sample_type)?;
+        if !continuation {
+            self.put_caption_sample(&captions, duration, ct_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the closed captions of a video frame to the caption track
+    ///
+    /// Every video sample gets a caption sample of the same duration, empty when the
+    /// frame carries no captions, so that the caption track stays in sync with the video.
+    ///
+    /// # Arguments
+    /// * `captions` - The CEA-608 byte pairs of field 1 and field 2 of the frame
+    /// * `duration` - The duration of the frame in the track's timescale
+    /// * `ct_offset` - The composition time offset for the frame
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn put_caption_sample(
+        &mut self,
+        captions: &[Vec<u8>; 2],
+        duration: u32,
+        ct_offset: i32,
+    ) -> Result<(), Error> {
+        if self.caption_track.is_none() {
+            return Ok(());
+        }
+        let data = c608_sample(captions);
+        let caption_track = self.caption_track.as_mut().unwrap();
+        caption_track.duration = caption_track.duration.wrapping_add(duration);
+        self.put_sample(
+            &[&data],
+            duration,
+            TrackType::Caption,
+            ct_offset,
+            SampleType::RandomAccess,
+        )
+    }
+
+    /// Extends the media duration to the end of a track if the track is longer
+    ///
+    /// The durations of the tracks are compared in the movie timescale, so audio counts
+    /// as much as video.
+    fn update_duration(&mut self, track_type: TrackType) {
+        let track = match track_type {
+            TrackType::Video => self.video_track.as_ref(),
+            TrackType::Audio => self.audio_track.as_ref(),
+            TrackType::Caption => self.caption_track.as_ref(),
+        };
+        if let Some(track) = track {
+            let duration = track_movie_duration(track, self.movie_timescale);
+            self.file.duration = self.file.duration.max(duration);
+        }
+    }
+
+    /// Finishes the output file and continues in the next one when a limit is reached
+    ///
+    /// Only video key frames start a new file. The key frame goes to the new file, its
+    /// duration is moved there from the finished file.
+    ///
+    /// # Arguments
+    /// * `sample_type` - The type of the video sample about to be written
+    /// * `duration` - The duration of the sample, already added to the track duration
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if finishing the file or switching fails
+    fn roll_over_if_needed(
+        &mut self,
+        sample_type: &SampleType,
+        duration: u32,
+    ) -> Result<(), Error> {
+        let (rollover, track) = match (&self.rollover, &self.video_track) {
+            (Some(rollover), Some(track)) if matches!(sample_type, SampleType::RandomAccess) => {
+                (rollover, track)
+            }
+            _ => return Ok(()),
+        };
+        let time = track.duration.wrapping_sub(duration);
+        let due = (rollover.max_duration > 0
+            && time as u64 >= ms_to_timescale(rollover.max_duration as u64, track.timescale))
+            || (rollover.max_bytes > 0 && self.file.write_pos >= rollover.max_bytes);
+        if time == 0 || !due {
+            return Ok(());
+        }
+        let roll_over = rollover.roll_over;
+        self.video_track.as_mut().unwrap().duration = time;
+        roll_over(self)?;
+
+        // Start the next file with the tracks and their decoder configuration, no samples
+        self.file = FileState::default();
+        for track in [
+            self.video_track.as_mut(),
+            self.audio_track.as_mut(),
+            self.caption_track.as_mut(),
+        ]
+        .iter_mut()
+        .flatten()
+        {
+            track.clear_samples();
+        }
+        self.video_track.as_mut().unwrap().duration = duration;
+        self.init_header_if_needed()
+    }
+
+    /// Records the decode time of a video key frame and checks the time since the last one
+    ///
+    /// The first frame of a GOP starting more than the interval set with
+    /// `require_keyframe_interval` after its key frame is reported to the callback set
+    /// with it.
+    ///
+    /// # Arguments
+    /// * `sample_type` - The type of the video sample
+    /// * `duration` - The duration of the sample, already added to the track duration
+    fn check_keyframe_gap(&mut self, sample_type: &SampleType, duration: u32) {
+        let track = match self.video_track.as_ref() {
+            Some(track) => track,
+            None => return,
+        };
+        let decode_time = track.duration.wrapping_sub(duration);
+        match sample_type {
+            SampleType::RandomAccess => {
+                self.file.last_keyframe_time = decode_time as u64;
+                self.file.keyframe_gap_reported = false;
+            }
+            SampleType::Default
+                if self.max_keyframe_interval > 0 && !self.file.keyframe_gap_reported =>
+            {
+                let gap = timescale_to_ms(
+                    decode_time as u64 - self.file.last_keyframe_time.min(decode_time as u64),
+                    track.timescale,
+                );
+                if gap > self.max_keyframe_interval as u64 {
+                    self.file.keyframe_gap_reported = true;
+                    if let Some(on_gap) = self.on_keyframe_gap.as_mut() {
+                        on_gap(gap);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Checks that the sample tables have room for a new sample in non-fragmented mode
+    ///
+    /// # Returns
+    /// * `Ok(())` if a sample can be added, or an `OutOfMemory` error once the limit set
+    ///   with `set_sample_table_limit` is reached
+    fn check_sample_table_limit(&self) -> Result<(), Error> {
+        if self.fragment
+            || self.sample_table_limit == 0
+            || self.sample_table_memory() < self.sample_table_limit
+        {
+            return Ok(());
+        }
+        Err(Error::new(
+            ErrorKind::OutOfMemory,
+            "the sample tables reached their size limit, flush the file or use the fragmented mode",
+        ))
+    }
+
+    /// Gets a track that has been set up
+    ///
+    /// # Returns
+    /// * The track, or an `InvalidInput` error if it is not set up
+    fn track_mut(&mut self, track: TrackType) -> Result<&mut Track, Error> {
+        match track {
+            TrackType::Video => self.video_track.as_mut(),
+            TrackType::Audio => self.audio_track.as_mut(),
+            TrackType::Caption => self.caption_track.as_mut(),
+        }
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "track is not set up"))
+    }
+
+    /// Detects the video codec from the frame data if the track was set up without one
+    ///
+    /// # Returns
+    /// * `true` if the video codec is known, `false` if the frame should be dropped
+    fn detect_video_codec_if_needed(&mut self, nalus: &[&[u8]]) -> bool {
+        if !self.detect_video_codec {
+            return true;
+        }
+        let codec = detect_codec_nalus(nalus.iter().copied());
+        if let (Some(track), Some(codec)) = (self.video_track.as_mut(), codec) {
+            track.codec = codec;
+            self.detect_video_codec = false;
+            return true;
+        }
+        false
+    }
+
+    fn init_mp4(&mut self) -> Result<(), Error> {
+        self.file.write_pos += write_ftyp(self.writer)?;
+        if !self.fragment && self.moov_reserve > 0 {
+            self.file.write_pos += write_free(self.moov_reserve, self.writer)?;
+        }
+        if !self.fragment {
+            self.file.write_pos += write_mdat_header(self.writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a sample to the output, or queues it, unless the muxer is poisoned
+    fn put_sample(
+        &mut self,
+        data: &[&[u8]],
+        duration: u32,
+        track_type: TrackType,
+        ct_offset: i32,
+        sample_type: SampleType,
+    ) -> Result<(), Error> {
+        self.check_poisoned()?;
+        let result = self.store_sample(data, duration, track_type, ct_offset, sample_type);
+        self.poison_on_error(result)
+    }
+
+    /// Returns an error once writing to the output has failed
+    fn check_poisoned(&self) -> Result<(), Error> {
+        if self.poisoned {
+            return Err(Error::other(
+                "the muxer is poisoned by an earlier output error",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Poisons the muxer if the result is an output error
+    ///
+    /// `InvalidInput` and `InvalidData` errors reject the input before it is written,
+    /// the other errors come from the writer.
+    fn poison_on_error<T>(&mut self, result: Result<T, Error>) -> Result<T, Error> {
+        if let Err(e) = &result {
+            if !matches!(e.kind(), ErrorKind::InvalidInput | ErrorKind::InvalidData) {
+                self.poisoned = true;
+            }
+        }
+        result
+    }
+
+    fn store_sample(
+        &mut self,
+        data: &[&[u8]],
+        duration: u32,
+        track_type: TrackType,
+        ct_offset: i32,
+        sample_type: SampleType,
+    ) -> Result<(), Error> {
+        // Video samples are made of length-prefixed NAL units, except for Motion JPEG
+        let video = matches!(track_type, TrackType::Video)
+            && !matches!(
+                self.video_track,
+                Some(Track {
+                    codec: Codec::MJPEG,
+                    ..
+                })
+            );
+        // HEVC samples are in the temporal sub-layer of their slices
+        let temporal_id = match &self.video_track {
+            Some(Track {
+                codec: Codec::HEVC, ..
+            }) if matches!(track_type, TrackType::Video) => data
+                .iter()
+                .find(|nalu| nalu.first().is_some_and(|header| (header >> 1) & 0x3f < 32))
+                .and_then(|nalu| hevc_temporal_id(nalu))
+                .unwrap_or(0),
+            _ => 0,
+        };
+        // Caption and Motion JPEG samples are sync samples, other video and audio samples
+        // when they are marked so
+        let independent = matches!(sample_type, SampleType::RandomAccess)
+            || match track_type {
+                TrackType::Video => !video,
+                TrackType::Audio => false,
+                TrackType::Caption => true,
+            };
+        if self.fragment {
+            let sample = SampleInfo {
+                random_access: independent,
+                offset: 0,
+                sample_size: sample_data_size(data, video),
+                sample_delta: duration,
+                sample_ct_offset: ct_offset,
+                description_index: 1,
+                temporal_id,
+            };
+            return self.fragment_sample(data, video, track_type, sample);
+        }
+        let sample_size = sample_data_size(data, video);
+        let continuation = matches!(sample_type, SampleType::Continuation);
+        // Continuations of a sample that has been written already are written directly
+        if self.scheduler.is_some()
+            && (!continuation || !self.track_mut(track_type)?.pending_samples.is_empty())
+        {
+            let mut bytes = Vec::with_capacity(sample_size as usize);
+            for buf in data {
+                if video {
+                    bytes.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+                }
+                bytes.extend_from_slice(buf);
+            }
+            let sample_info = SampleInfo {
+                random_access: independent,
+                offset: 0,
+                sample_size,
+                sample_delta: duration,
+                sample_ct_offset: ct_offset,
+                description_index: 1,
+                temporal_id,
+            };
+            return self.queue_sample(track_type, sample_info, bytes, continuation);
+        }
+        let offset = self.write_sample_data(track_type, data, video)?;
+        let track = self.track_mut(track_type)?;
+        if let (SampleType::Continuation, Some(last_sample)) =
+            (&sample_type, track.samples.last_mut())
+        {
+            last_sample.sample_size += sample_size;
+        } else {
+            let sample_info = SampleInfo {
+                random_access: independent,
+                offset,
+                sample_size,
+                sample_delta: duration,
+                sample_ct_offset: ct_offset,
+                description_index: 1,
+                temporal_id,
+            };
+            track.samples.push(sample_info);
+        }
+
+        Ok(())
+    }
+
+    /// Queues a sample for the sample scheduler
+    ///
+    /// # Arguments
+    /// * `track_type` - The track of the sample
+    /// * `sample_info` - The sample information
+    /// * `data` - The sample data
+    /// * `continuation` - Whether the data continues the last queued sample
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn queue_sample(
+        &mut self,
+        track_type: TrackType,
+        sample_info: SampleInfo,
+        data: Vec<u8>,
+        continuation: bool,
+    ) -> Result<(), Error> {
+        if continuation {
+            let track = self.track_mut(track_type)?;
+            let pending = track.pending_samples.back_mut().unwrap();
+            pending.info.sample_size += sample_info.sample_size;
+            pending.data.extend_from_slice(&data);
+            return Ok(());
+        }
+        let track = self.track_mut(track_type)?;
+        let decode_time = track.next_decode_time;
+        track.next_decode_time += sample_info.sample_delta as u64;
+        track.pending_samples.push_back(PendingSample {
+            info: sample_info,
+            decode_time,
+            data,
+        });
+        self.run_scheduler(false)
+    }
+
+    /// Writes queued samples in the order chosen by the sample scheduler
+    ///
+    /// # Arguments
+    /// * `flushing` - Whether all queued samples must be written
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn run_scheduler(&mut self, flushing: bool) -> Result<(), Error> {
+        loop {
+            let mut queues = Vec::new();
+            for track in [&self.video_track, &self.audio_track, &self.caption_track]
+                .iter()
+                .filter_map(|track| track.as_ref())
+            {
+                let mut samples = track.pending_samples.len();
+                // The last video sample stays queued for slices of the same picture
+                if !flushing && matches!(track.track_type, TrackType::Video) {
+                    samples = samples.saturating_sub(1);
+                }
+                queues.push(QueueState {
+                    track_type: track.track_type,
+                    timescale: track.timescale,
+                    decode_time: track
+                        .pending_samples
+                        .front()
+                        .map_or(track.next_decode_time, |pending| pending.decode_time),
+                    duration: track
+                        .pending_samples
+                        .iter()
+                        .map(|pending| pending.info.sample_delta as u64)
+                        .sum(),
+                    samples,
+                    bytes: track
+                        .pending_samples
+                        .iter()
+                        .map(|pending| pending.data.len() as u64)
+                        .sum(),
+                });
+            }
+            let scheduler = self.scheduler.as_mut().unwrap();
+            let index = scheduler
+                .next(&queues, self.file.write_pos, flushing)
+                .filter(|&index| queues.get(index).is_some_and(|queue| queue.samples > 0))
+                .or_else(|| {
+                    // All samples are written on flush, whatever the scheduler says
+                    if flushing {
+                        queues.iter().position(|queue| queue.samples > 0)
+                    } else {
+                        None
+                    }
+                });
+            let track_type = match index {
+                Some(index) => queues[index].track_type,
+                None => return Ok(()),
+            };
+            let pending = self
+                .track_mut(track_type)?
+                .pending_samples
+                .pop_front()
+                .unwrap();
+            let offset = self.write_sample_data(track_type, &[&pending.data], false)?;
+            self.track_mut(track_type)?.samples.push(SampleInfo {
+                offset,
+                ..pending.info
+            });
+        }
+    }
+
+    /// Writes sample data to the mdat box, or holds it back for the track's own mdat box
+    ///
+    /// # Arguments
+    /// * `track_type` - The track of the sample
+    /// * `data` - The parts of the sample
+    /// * `length_prefix` - Whether each part gets a 4-byte length prefix (video NAL units)
+    ///
+    /// # Returns
+    /// * The offset of the data in the file, or in the held back data of the track
+    fn write_sample_data(
+        &mut self,
+        track_type: TrackType,
+        data: &[&[u8]],
+        length_prefix: bool,
+    ) -> Result<u64, Error> {
+        if self.mdat_per_track && !matches!(track_type, TrackType::Video) {
+            let pending_data = &mut self.track_mut(track_type)?.pending_data;
+            let offset = pending_data.len() as u64;
+            for buf in data {
+                if length_prefix {
+                    pending_data.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+                }
+                pending_data.extend_from_slice(buf);
+            }
+            return Ok(offset);
+        }
+        let offset = self.file.write_pos;
+        if self.io_chunk_size > 0 {
+            for buf in data {
+                if length_prefix {
+                    self.io_buffer
+                        .extend_from_slice(&(buf.len() as u32).to_be_bytes());
+                }
+                self.io_buffer.extend_from_slice(buf);
+            }
+            if self.io_buffer.len() >= self.io_chunk_size {
+                self.write_io_buffer()?;
+            }
+        } else {
+            self.write_io_buffer()?;
+            // Every video NAL unit of the sample gets its own length prefix
+            write_sample_data(data, length_prefix, self.writer)?;
+        }
+        self.file.write_pos += sample_data_size(data, length_prefix) as u64;
+        Ok(offset)
+    }
+
+    /// Writes the sample data gathered by `set_io_chunk_size` to the output
+    fn write_io_buffer(&mut self) -> Result<(), Error> {
+        if !self.io_buffer.is_empty() {
+            self.writer.write_all(&self.io_buffer)?;
+            self.io_buffer.clear();
+        }
+        Ok(())
+    }
+
+    /// Writes the data held back for each track to its own mdat box
+    ///
+    /// The sample offsets of the tracks are moved from the held back data to the file.
+    fn write_pending_mdats(&mut self) -> Result<(), Error> {
+        self.write_io_buffer()?;
+        for track in [self.audio_track.as_mut(), self.caption_track.as_mut()]
+            .iter_mut()
+            .flatten()
+        {
+            if track.pending_data.is_empty() {
+                continue;
+            }
+            let data = std::mem::take(&mut track.pending_data);
+            // Use the large size format when the data does not fit a 32-bit box size
+            let header_size = if data.len() as u64 + 8 > u32::MAX as u64 {
+                self.writer.write_all(&1u32.to_be_bytes())?;
+                self.writer.write_all(b"mdat")?;
+                self.writer
+                    .write_all(&(data.len() as u64 + 16).to_be_bytes())?;
+                16
+            } else {
+                self.writer
+                    .write_all(&(data.len() as u32 + 8).to_be_bytes())?;
+                self.writer.write_all(b"mdat")?;
+                8
+            };
+            let base = self.file.write_pos + header_size;
+            for sample in track.samples.iter_mut() {
+                sample.offset += base;
+            }
+            self.writer.write_all(&data)?;
+            self.file.write_pos = base + data.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Writes a sample to the output in fragmented mode
+    ///
+    /// Audio samples are held back until enough of them are queued for a fragment,
+    /// other samples are written as a fragment of their own.
+    ///
+    /// # Arguments
+    /// * `data` - The parts of the sample
+    /// * `length_prefix` - Whether each part gets a 4-byte length prefix (video NAL units)
+    /// * `track_type` - The track of the sample
+    /// * `sample` - The sample information, the size is the size of the data
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn fragment_sample(
+        &mut self,
+        data: &[&[u8]],
+        length_prefix: bool,
+        track_type: TrackType,
+        sample: SampleInfo,
+    ) -> Result<(), Error> {
+        if !matches!(track_type, TrackType::Audio) || self.audio_fragment_duration == 0 {
+            return self.write_fragment(data, length_prefix, track_type, &[sample]);
+        }
+        let threshold = self.audio_fragment_duration as u64;
+        let track = self.track_mut(track_type)?;
+        let mut bytes = Vec::with_capacity(sample.sample_size as usize);
+        for buf in data {
+            if length_prefix {
+                bytes.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+            }
+            bytes.extend_from_slice(buf);
+        }
+        track.fragment_samples.push(PendingSample {
+            info: sample,
+            decode_time: 0,
+            data: bytes,
+        });
+        let queued: u64 = track
+            .fragment_samples
+            .iter()
+            .map(|pending| pending.info.sample_delta as u64)
+            .sum();
+        if queued >= ms_to_timescale(threshold, track.timescale) {
+            self.write_audio_fragment()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the audio samples held back for the next fragment, if any
+    fn write_audio_fragment(&mut self) -> Result<(), Error> {
+        let pending = match self.audio_track.as_mut() {
+            Some(track) if !track.fragment_samples.is_empty() => {
+                std::mem::take(&mut track.fragment_samples)
+            }
+            _ => return Ok(()),
+        };
+        let samples: Vec<SampleInfo> = pending.iter().map(|pending| pending.info).collect();
+        let data: Vec<&[u8]> = pending.iter().map(|pending| &pending.data[..]).collect();
+        self.write_fragment(&data, false, TrackType::Audio, &samples)
+    }
+
+    /// Writes samples of a track as a fragment made of a moof and an mdat box
+    ///
+    /// # Arguments
+    /// * `data` - The parts of the samples, in order
+    /// * `length_prefix` - Whether each part gets a 4-byte length prefix (video NAL units)
+    /// * `track_type` - The track of the samples
+    /// * `samples` - The sample information, the sizes add up to the size of the data
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an error if writing fails
+    fn write_fragment(
+        &mut self,
+        data: &[&[u8]],
+        length_prefix: bool,
+        track_type: TrackType,
+        samples: &[SampleInfo],
+    ) -> Result<(), Error> {
+        if self.expected_audio.is_some() && self.audio_track.is_none() && !self.file.write_moov {
+            return self.hold_samples(data, length_prefix, track_type, samples);
+        }
+        self.write_moov_if_needed()?;
+        self.write_held_samples()?;
+        let new_segment = self.segmenting
+            && matches!(track_type, TrackType::Video)
+            && samples.first().is_some_and(|sample| sample.random_access);
+        if self.file.discontinuity || new_segment {
+            // Audio held back belongs before the segment boundary or the discontinuity
+            let discontinuity = std::mem::replace(&mut self.file.discontinuity, false);
+            self.write_audio_fragment()?;
+            self.file.discontinuity = discontinuity;
+        }
+        if new_segment && self.file.segment_starts.back() != Some(&self.file.write_pos) {
+            self.file.segment_starts.push_back(self.file.write_pos);
+        }
+        if self.file.discontinuity {
+            for track in [
+                self.video_track.as_mut(),
+                self.audio_track.as_mut(),
+                self.caption_track.as_mut(),
+            ]
+            .iter_mut()
+            .flatten()
+            {
+                track.fragment_decode_time = 0;
+            }
+        }
+        if self.file.discontinuity || new_segment {
+            self.file.write_pos += write_styp(self.writer)?;
+            self.file.discontinuity = false;
+        }
+        self.fragment_id += 1;
+        let track = match track_type {
+            TrackType::Video => self.video_track.as_mut(),
+            TrackType::Audio => self.audio_track.as_mut(),
+            TrackType::Caption => self.caption_track.as_mut(),
+        }
+        .unwrap();
+        let data_size = sample_data_size(data, length_prefix);
+        // The styp box of a new segment precedes the moof box, only boxes between the moof
+        // and the mdat box would move the samples away from their data offset
+        let mut cursor = Cursor::new(std::mem::take(&mut self.scratch));
+        cursor.get_mut().clear();
+        if self.smooth_streaming {
+            write_smooth_moof_samples(self.fragment_id, track, samples, &mut cursor)?;
+        } else {
+            write_moof_samples(self.fragment_id, track, samples, &mut cursor)?;
+        }
+        let buf = cursor.into_inner();
+        let end_pos = buf.len() as u64;
+        if self.segment_index {
+            // The sidx box references the moof box and everything up to the next fragment
+            let checksum_size = if self.fragment_checksum { 28 } else { 0 };
+            let referenced_size = end_pos + data_size as u64 + 8 + checksum_size;
+            self.file.write_pos += write_sidx(track, samples, referenced_size as u32, self.writer)?;
+        }
+        if let Some(sample) = samples.first().filter(|sample| sample.random_access) {
+            let time = track.fragment_decode_time as i64 + sample.sample_ct_offset as i64;
+            track
+                .random_access_points
+                .push((time.max(0) as u64, self.file.write_pos));
+        }
+        self.writer.write_all(&buf)?;
+        self.file.write_pos += end_pos;
+        let mut checksum = None;
+        if self.fragment_checksum {
+            let mut crc = Crc32::new();
+            crc.update(&buf[..end_pos as usize]);
+            crc.update(&(data_size + 8).to_be_bytes());
+            crc.update(b"mdat");
+            checksum = Some(crc);
+        }
+        if self.record_fragments {
+            self.fragments.push(FragmentInfo {
+                sequence: self.fragment_id,
+                // The payload follows the 8-byte mdat header
+                data_offset: self.file.write_pos + 8,
+                data_size,
+                checksum,
+            });
+        }
+        let box_size = write_mdat(data, length_prefix, self.writer)?;
+        self.file.write_pos += box_size;
+        if let Some(mut crc) = checksum {
+            for buf in data {
+                if length_prefix {
+                    crc.update(&(buf.len() as u32).to_be_bytes());
+                }
+                crc.update(buf);
+            }
+            self.file.write_pos += write_checksum_uuid(crc.value(), self.writer)?;
+        }
+        track.fragment_decode_time += samples
+            .iter()
+            .map(|sample| sample.sample_delta as u64)
+            .sum::<u64>();
+        self.scratch = buf;
+        Ok(())
+    }
+
+    /// Holds samples back until the audio track is set up, see `expect_audio_track`
+    ///
+    /// Once the held video reaches the longest wait, the muxer stops waiting and writes
+    /// the held samples.
+    fn hold_samples(
+        &mut self,
+        data: &[&[u8]],
+        length_prefix: bool,
+        track_type: TrackType,
+        samples: &[SampleInfo],
+    ) -> Result<(), Error> {
+        let mut bytes = Vec::with_capacity(sample_data_size(data, length_prefix) as usize);
+        for buf in data {
+            if length_prefix {
+                bytes.extend_from_slice(&(buf.len() as u32).to_be_bytes());
+            }
+            bytes.extend_from_slice(buf);
+        }
+        let mut offset = 0;
+        for sample in samples {
+            let end = offset + sample.sample_size as usize;
+            self.held_samples.push((
+                track_type,
+                PendingSample {
+                    info: *sample,
+                    decode_time: 0,
+                    data: bytes[offset..end].to_vec(),
+                },
+            ));
+            offset = end;
+        }
+        let max_wait = self.expected_audio.unwrap_or(0) as u64;
+        let timescale = self
+            .video_track
+            .as_ref()
+            .map_or(1000, |track| track.timescale);
+        let held: u64 = self
+            .held_samples
+            .iter()
+            .filter(|(track_type, _)| matches!(track_type, TrackType::Video))
+            .map(|(_, pending)| pending.info.sample_delta as u64)
+            .sum();
+        if max_wait > 0 && held >= ms_to_timescale(max_wait, timescale) {
+            self.write_held_samples()?;
+        }
+        Ok(())
+    }
+
+    /// Stops waiting for the audio track and writes the held samples, each as a fragment
+    fn write_held_samples(&mut self) -> Result<(), Error> {
+        self.expected_audio = None;
+        for (track_type, pending) in std::mem::take(&mut self.held_samples) {
+            self.write_fragment(&[&pending.data], false, track_type, &[pending.info])?;
+        }
+        Ok(())
+    }
+
+    fn init_header_if_needed(&mut self) -> Result<(), Error> {
+        if !self.file.init_header {
+            self.init_mp4()?;
+            self.file.init_header = true;
+        }
+        Ok(())
+    }
+    /// Shifts the composition offsets of the video samples so the smallest one is zero
+    ///
+    /// The first presented frame then starts at time 0, in sync with the audio, and the
+    /// ctts box covers the smallest range. An edit list starting at media time 0 records
+    /// the presentation start, except under the legacy profile.
+    ///
+    /// # Returns
+    /// * The offset subtracted from the composition offsets, `None` if they are unchanged
+    fn normalize_composition_offsets(&mut self) -> Option<i32> {
+        let track = self.video_track.as_mut()?;
+        let min_offset = match track
+            .samples
+            .iter()
+            .map(|sample| sample.sample_ct_offset)
+            .min()
+        {
+            Some(min_offset) if min_offset != 0 => min_offset,
+            _ => return None,
+        };
+        for sample in track.samples.iter_mut() {
+            sample.sample_ct_offset -= min_offset;
+        }
+        if let CompatibilityProfile::Standard = self.compatibility_profile {
+            track.edit_media_time = Some(0);
+        }
+        Some(min_offset)
+    }
+
+    fn write_mfra_if_needed(&mut self) -> Result<(), Error> {
+        let tracks = [&self.video_track, &self.audio_track, &self.caption_track];
+        let indexed = tracks
+            .iter()
+            .copied()
+            .flatten()
+            .any(|track| !track.random_access_points.is_empty());
+        if indexed {
+            let mut cursor: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+            write_mfra(&tracks, &mut cursor)?;
+            let buf = cursor.into_inner();
+            self.writer.write_all(&buf)?;
+            self.file.write_pos += buf.len() as u64;
+            for track in [
+                self.video_track.as_mut(),
+                self.audio_track.as_mut(),
+                self.caption_track.as_mut(),
+            ]
+            .iter_mut()
+            .flatten()
+            {
+                track.random_access_points.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn write_moov_if_needed(&mut self) -> Result<(), Error> {
+        if !self.file.write_moov {
+            self.file.moov_size = self.write_moov_box()?;
+            self.file.write_pos += self.file.moov_size;
+            self.file.write_moov = true;
+        }
+        Ok(())
+    }
+
+    /// Writes the moov box at the write position, returns the number of bytes written
+    fn write_moov_box(&mut self) -> Result<u64, Error> {
+        let buf = self.build_moov_box()?;
+        if self.fragment {
+            self.file.mehd_pos =
+                mehd_duration_offset(&buf).map_or(0, |offset| self.file.write_pos + offset as u64);
+        }
+        self.writer.write_all(&buf)?;
+        let size = buf.len() as u64;
+        self.scratch = buf;
+        Ok(size)
+    }
+
+    /// Writes out the gathered sample data and builds the moov box in the scratch buffer
+    ///
+    /// The buffer is returned to `self.scratch` by the caller once written.
+    fn build_moov_box(&mut self) -> Result<Vec<u8>, Error> {
+        self.write_io_buffer()?;
+        for track in self
+            .video_track
+            .iter_mut()
+            .chain(self.audio_track.iter_mut())
+            .chain(self.caption_track.iter_mut())
+        {
+            track.compact_tables = self.compact_tables;
+        }
+        let mut cursor = Cursor::new(std::mem::take(&mut self.scratch));
+        cursor.get_mut().clear();
+        write_moov_with_boxes(
+            &[&self.video_track, &self.audio_track, &self.caption_track],
+            self.create_time,
+            self.movie_timescale,
+            &self.language,
+            self.fragment,
+            &self.user_boxes,
+            &mut cursor,
+        )?;
+        Ok(cursor.into_inner())
+    }
+}
+
+/// Gets the start and end of the presentation of a track in milliseconds
+fn presentation_range(track: &Track) -> (f64, f64) {
+    let (mut start, mut end) = (i64::MAX, i64::MIN);
+    let mut decode_time = 0i64;
+    for sample in &track.samples {
+        let time = decode_time + sample.sample_ct_offset as i64;
+        start = start.min(time);
+        end = end.max(time + sample.sample_delta as i64);
+        decode_time += sample.sample_delta as i64;
+    }
+    if track.samples.is_empty() {
+        start = 0;
+        end = track.duration as i64;
+    }
+    let shift = track.edit_delay as i64 - edit_media_time(track) as i64;
+    // Fractions of a millisecond are kept, unlike timescale_to_ms, to measure the drift
+    let ms = |time: i64| (time + shift) as f64 * 1000.0 / track.timescale.max(1) as f64;
+    (ms(start), ms(end))
+}
+
+/// Finds the checksum box of a fragment among the boxes after its mdat box
+///
+/// # Arguments
+/// * `reader` - The output holding the fragment
+/// * `pos` - The end of the mdat box of the fragment
+///
+/// # Returns
+/// * The position of the uuid box with the checksum, or `None` if the next fragment or
+///   the end of the output comes first
+pub(crate) fn find_checksum_box<Reader>(
+    reader: &mut Reader,
+    mut pos: u64,
+) -> Result<Option<u64>, Error>
+where
+    Reader: Read + Seek,
+{
+    let end = reader.seek(SeekFrom::End(0))?;
+    while pos + 8 <= end {
+        let mut header = [0u8; 24];
+        let len = if pos + 24 <= end { 24 } else { 8 };
+        reader.seek(SeekFrom::Start(pos))?;
+        reader.read_exact(&mut header[..len])?;
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64;
+        match &header[4..8] {
+            b"uuid" if len == 24 && header[8..] == FRAGMENT_CHECKSUM_UUID => return Ok(Some(pos)),
+            b"moof" | b"styp" | b"sidx" => return Ok(None),
+            _ if size < 8 => return Ok(None),
+            _ => pos += size,
+        }
+    }
+    Ok(None)
+}
+
+/// Checks that samples may have a composition offset under the compatibility profile
+fn check_composition_offset(profile: &CompatibilityProfile) -> Result<(), Error> {
+    match profile {
+        CompatibilityProfile::Standard => Ok(()),
+        CompatibilityProfile::Legacy => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "composition offsets are not allowed by the legacy profile",
+        )),
+    }
+}
+
+impl<'a, Writer> Mp4e<'a, Writer>
+where
+    Writer: Write,
+{
+    /// Exports the muxing state, to continue the file with another muxer later
+    ///
+    /// The state holds the tracks with their sample tables, the write position, the
+    /// fragment sequence number and the durations. The settings, the writer, the sample
+    /// scheduler and the callbacks are left out. The sample data gathered by
+    /// `set_io_chunk_size` is written to the output first, so that the output holds all
+    /// the samples of the state. Input held back outside the tracks is not part of the
+    /// state: it cannot be exported between the chunks of an access unit with streaming
+    /// input, while a video frame waits for the timestamp of the next one, or while
+    /// samples wait for the audio track announced with `expect_audio_track`.
+    ///
+    /// # Returns
+    /// * A copy of the muxing state, or an `InvalidInput` error if input is held back
+    pub fn export_state(&mut self) -> Result<MuxerState, Error> {
+        self.check_poisoned()?;
+        let partial_unit = self
+            .stream_input
+            .as_ref()
+            .is_some_and(|input| !input.unit.is_empty() || !input.splitter.rest.is_empty());
+        if partial_unit
+            || self.pending_video_ts.is_some()
+            || !self.pending_video_pts.is_empty()
+            || !self.held_samples.is_empty()
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the muxing state is exported while no input is held back",
+            ));
+        }
+        let result = self.write_io_buffer();
+        self.poison_on_error(result)?;
+        // The importing muxer does not know the reserve the mdat box follows
+        let mdat_pos = match self.file.mdat_pos {
+            0 if self.file.init_header && !self.fragment => 32 + self.moov_reserve as u64,
+            mdat_pos => mdat_pos,
+        };
+        Ok(MuxerState {
+            write_pos: self.file.write_pos,
+            fragment_id: self.fragment_id,
+            duration: self.file.duration,
+            video_track: self.video_track.clone(),
+            audio_track: self.audio_track.clone(),
+            caption_track: self.caption_track.clone(),
+            fragment: self.fragment,
+            init_header: self.file.init_header,
+            write_moov: self.file.write_moov,
+            create_time: self.create_time,
+            track_ids: self.track_ids,
+            send_first_random_access: self.send_first_random_access,
+            mdat_pos,
+            index_pos: self.file.index_pos,
+            video_frame_count: self.file.video_frame_count,
+            video_duration_ms: self.file.video_duration_ms,
+            audio_duration_samples: self.file.audio_duration_samples,
+            ts_start: self.ts_start,
+            video_ts_delta: self.video_ts_delta,
+            pts_durations: self.pts_durations,
+            pts_wraps: self.pts_wraps,
+            last_keyframe_time: self.file.last_keyframe_time,
+            audio_silence_remainder: self.audio_silence_remainder,
+        })
+    }
+
+    /// Imports a muxing state exported with `export_state`
+    ///
+    /// The writer must hold the output written so far and be positioned at its end. The
+    /// muxer keeps its own settings, which are set up like the ones of the exporting
+    /// muxer, and starts with empty buffers. The moov box is written after the samples,
+    /// even into a file with a reserve, and the mehd box of a fragmented file keeps the
+    /// duration written with the moov box.
+    ///
+    /// # Arguments
+    /// * `state` - The state returned by `export_state`
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or an `InvalidInput` error if it was exported in the other
+    ///   fragment mode
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::{Cursor, Seek, SeekFrom};
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+    /// let state = muxer.export_state().unwrap();
+    ///
+    /// // ... later, with the output written so far ...
+    /// let mut writer = Cursor::new(buffer);
+    /// writer.seek(SeekFrom::End(0)).unwrap();
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.import_state(state).unwrap();
+    /// muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+    /// muxer.flush().unwrap();
+    /// ```
+    pub fn import_state(&mut self, state: MuxerState) -> Result<(), Error> {
+        if state.fragment != self.fragment {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the muxing state was exported in another fragment mode",
+            ));
+        }
+        self.file = FileState {
+            write_pos: state.write_pos,
+            init_header: state.init_header,
+            write_moov: state.write_moov,
+            duration: state.duration,
+            index_pos: state.index_pos,
+            mdat_pos: state.mdat_pos,
+            video_duration_ms: state.video_duration_ms,
+            audio_duration_samples: state.audio_duration_samples,
+            video_frame_count: state.video_frame_count,
+            last_keyframe_time: state.last_keyframe_time,
+            ..FileState::default()
+        };
+        self.fragment_id = state.fragment_id;
+        self.video_track = state.video_track;
+        self.audio_track = state.audio_track;
+        self.caption_track = state.caption_track;
+        self.create_time = state.create_time;
+        self.track_ids = state.track_ids;
+        self.send_first_random_access = state.send_first_random_access;
+        self.ts_start = state.ts_start;
+        self.video_ts_delta = state.video_ts_delta;
+        self.pts_durations = state.pts_durations;
+        self.pts_wraps = state.pts_wraps;
+        self.audio_silence_remainder = state.audio_silence_remainder;
+        // The moov box goes after the samples, the reserve before the mdat box stays free
+        if state.init_header {
+            self.moov_reserve = 0;
+        }
+        // Buffers start empty, as the state was exported with nothing held back
+        if self.stream_input.is_some() {
+            self.stream_input = Some(StreamInput::default());
+        }
+        self.pending_video_ts = None;
+        self.pending_video_pts.clear();
+        self.pts_slots.clear();
+        self.held_samples.clear();
+        self.io_buffer.clear();
+        self.scratch.clear();
+        self.parameter_sets_missing = false;
+        self.poisoned = false;
+        Ok(())
+    }
+}
+
+impl<'a, Writer> Mp4e<'a, Writer>
+where
+    Writer: Read + Write + Seek,
+{
+    /// Replaces the mdat payload of a recorded fragment in place
+    ///
+    /// The payload is rewritten where it is, so it must have the size of the original
+    /// one and each sample must keep its size: the sample sizes of the trun box, the size
+    /// of the mdat box and the offsets of the later fragments are not rewritten, and a
+    /// payload of another size is rejected. When the fragment has a checksum box, it is
+    /// looked up among the boxes after the mdat box and its checksum is updated.
+    /// Fragments are only recorded after `set_record_fragments(true)`.
+    ///
+    /// # Arguments
+    /// * `sequence` - The sequence number of the fragment, as written to its mfhd box
+    /// * `data` - The new mdat payload, including the NAL unit length prefixes of video samples
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, an `InvalidInput` error if the fragment is unknown or the
+    ///   size differs, or an `InvalidData` error if the output does not hold the boxes of
+    ///   the fragment
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Codec, Mp4e};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    /// muxer.set_record_fragments(true);
+    /// muxer.set_video_track(320, 240, Codec::AVC);
+    ///
+    /// let frame = [
+    ///     0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x0D, 0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80,
+    ///     0, 0, 0, 1, 0x65, 0x88, 0x84,
+    /// ];
+    /// muxer.encode_video(&frame, 33).unwrap();
+    ///
+    /// // Re-encrypt the key frame, its payload is one NAL unit of 3 bytes
+    /// muxer.rewrite_fragment(1, &[0, 0, 0, 3, 0x65, 0x12, 0x34]).unwrap();
+    /// ```
+    pub fn rewrite_fragment(&mut self, sequence: u32, data: &[u8]) -> Result<(), Error> {
+        let fragment = self
+            .fragments
+            .iter()
+            .rev()
+            .find(|fragment| fragment.sequence == sequence)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "unknown fragment"))?;
+        if data.len() as u64 != fragment.data_size as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "fragment payload size differs",
+            ));
+        }
+        let mdat_end = fragment.data_offset + fragment.data_size as u64;
+        let mut header = [0u8; 8];
+        self.writer
+            .seek(SeekFrom::Start(fragment.data_offset - 8))?;
+        self.writer.read_exact(&mut header)?;
+        let size = u32::from_be_bytes(header[..4].try_into().unwrap());
+        if size as u64 != fragment.data_size as u64 + 8 || &header[4..] != b"mdat" {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the output does not hold the mdat box of the fragment",
+            ));
+        }
+        let checksum_pos = match fragment.checksum {
+            Some(_) => Some(find_checksum_box(self.writer, mdat_end)?.ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    "the output does not hold the checksum box of the fragment",
+                )
+            })?),
+            None => None,
+        };
+        self.writer.seek(SeekFrom::Start(fragment.data_offset))?;
+        self.writer.write_all(data)?;
+        if let (Some(mut crc), Some(checksum_pos)) = (fragment.checksum, checksum_pos) {
+            crc.update(data);
+            // The checksum follows the header and user type of the uuid box
+            self.writer.seek(SeekFrom::Start(checksum_pos + 24))?;
+            self.writer.write_all(&crc.value().to_be_bytes())?;
+        }
+        // Restore file cursor to current write position
+        self.writer.seek(SeekFrom::Start(self.file.write_pos))?;
+        Ok(())
+    }
+
+    /// Checks the structure of the output written so far
+    ///
+    /// The output is read back after `flush` and checked: the top-level box sizes must add
+    /// up to the length of the output, the sample counts of the stsz, stts, ctts and stsc
+    /// boxes of each track must agree, the sync samples in stss must be in range and each
+    /// chunk must lie inside an mdat box. Useful to catch broken input from untrusted encoders.
+    ///
+    /// # Returns
+    /// * `Ok(())` if the output is consistent, or an `InvalidData` error describing the problem
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+    /// muxer.flush().unwrap();
+    ///
+    /// muxer.validate().unwrap();
+    /// ```
+    pub fn validate(&mut self) -> Result<(), Error> {
+        self.write_io_buffer()?;
+        let pos = self.writer.stream_position()?;
+        let result = validate_structure(self.writer);
+        self.writer.seek(SeekFrom::Start(pos))?;
+        result
+    }
+
+    /// Moves the moov box in front of the mdat box when it outgrew the reserve
+    ///
+    /// When the moov box written by `flush` did not fit into the space reserved with
+    /// `reserve_moov_space`, the sample data is moved forward by the size missing and the
+    /// moov box is written with the new chunk offsets after the ftyp box, making the file
+    /// a faststart file. The data is copied in blocks of 1 MiB, reading it back from the
+    /// output. Nothing is done if the moov box is already in the reserve.
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, an `InvalidInput` error if the file is not finalized with a
+    ///   reserve, or an error if reading or writing fails
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Mp4e, Codec};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    /// muxer.set_audio_track(48000, 2, Codec::AACLC);
+    /// // Far less samples than encoded
+    /// muxer.reserve_moov_space(1).unwrap();
+    /// for _ in 0..1000 {
+    ///     muxer.encode_audio(&[0x21, 0x10, 0x04], 1024).unwrap();
+    /// }
+    /// muxer.flush().unwrap();
+    /// assert!(!muxer.moov_in_reserve());
+    ///
+    /// muxer.move_moov_to_front().unwrap();
+    /// assert!(muxer.moov_in_reserve());
+    /// ```
+    pub fn move_moov_to_front(&mut self) -> Result<(), Error> {
+        self.check_poisoned()?;
+        if self.fragment || self.moov_reserve == 0 || !self.file.write_moov {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the moov box is moved into the space reserved with reserve_moov_space \
+                 after flush",
+            ));
+        }
+        if self.file.moov_in_reserve {
+            return Ok(());
+        }
+        let result = self.shift_mdat();
+        self.poison_on_error(result)
+    }
+
+    /// Moves the data after the reserve forward and writes the moov box in front of it
+    fn shift_mdat(&mut self) -> Result<(), Error> {
+        let reserve = self.moov_reserve as u64;
+        // The mdat box, or the free box before a small one, starts after the reserve
+        let data_start = 32 + reserve;
+        let data_end = self.file.write_pos - self.file.moov_size;
+        let file_end = self.file.write_pos;
+
+        // The moov box is followed by a free box of 0 or at least 8 bytes, chosen so the
+        // bytes left after the moved data are 0 or enough for a free box as well
+        let layout = |moov_len: u64| {
+            [0, 8, 9, 10, 11, 12, 13, 14, 15]
+                .iter()
+                .map(|&free| (free, (moov_len + free).saturating_sub(reserve)))
+                .find(|&(free, delta)| {
+                    let left = file_end.saturating_sub(data_end + delta);
+                    moov_len + free >= reserve && (left == 0 || left >= 8)
+                })
+                .unwrap()
+        };
+        // The chunk offsets grow with the moov box, which grows when they need 64 bits
+        let mut shifted = 0;
+        let mut moov_len = self.file.moov_size;
+        let (buf, free, delta) = loop {
+            let (free, delta) = layout(moov_len);
+            self.shift_sample_offsets(delta - shifted);
+            shifted = delta;
+            let buf = self.build_moov_box()?;
+            if buf.len() as u64 == moov_len {
+                break (buf, free, delta);
+            }
+            moov_len = buf.len() as u64;
+            self.scratch = buf;
+        };
+
+        // Copy from the end so no data is overwritten before it is moved
+        let mut block = vec![0u8; 1 << 20];
+        let mut end = data_end;
+        while end > data_start {
+            let len = (end - data_start).min(block.len() as u64);
+            let start = end - len;
+            self.writer.seek(SeekFrom::Start(start))?;
+            self.writer.read_exact(&mut block[..len as usize])?;
+            self.writer.seek(SeekFrom::Start(start + delta))?;
+            self.writer.write_all(&block[..len as usize])?;
+            end = start;
+        }
+        self.writer.seek(SeekFrom::Start(32))?;
+        self.writer.write_all(&buf)?;
+        if free > 0 {
+            write_free(free as u32, self.writer)?;
+        }
+        // The end of the old moov box becomes a free box
+        let left = file_end.saturating_sub(data_end + delta);
+        if left > 0 {
+            self.writer.seek(SeekFrom::Start(data_end + delta))?;
+            write_free(left as u32, self.writer)?;
+        }
+        self.scratch = buf;
+        self.moov_reserve = (reserve + delta) as u32;
+        self.file.moov_in_reserve = true;
+        self.file.moov_size = 0;
+        self.file.write_pos = file_end.max(data_end + delta);
+        if self.file.mdat_pos > 0 {
+            self.file.mdat_pos += delta;
+        }
+        self.writer.seek(SeekFrom::Start(self.file.write_pos))?;
+        Ok(())
+    }
+
+    /// Moves the samples of the tracks in this file by `delta` bytes
+    fn shift_sample_offsets(&mut self, delta: u64) {
+        for track in [
+            self.video_track.as_mut(),
+            self.audio_track.as_mut(),
+            self.caption_track.as_mut(),
+        ]
+        .iter_mut()
+        .flatten()
+        {
+            // The samples of other files stay where they are
+            if track.data_reference_index == 1 {
+                for sample in track.samples.iter_mut() {
+                    sample.offset += delta;
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Mp4e<'a, Vec<u8>> {
+    /// Takes the next completed segment out of the output
+    ///
+    /// The first call returns the initialization segment (ftyp and moov boxes) once the
+    /// first media segment has started. Media segments are complete when the next one
+    /// starts or after `finish_segment`, the data of the current segment stays in the output.
+    ///
+    /// # Returns
+    /// * The bytes of the segment, or `None` if no segment is complete
+    ///
+    /// # Example
+    /// ```
+    /// use mp4e::{Codec, Mp4e};
+    ///
+    /// let mut output = Vec::new();
+    /// let mut muxer = Mp4e::new_with_fragment(&mut output);
+    /// muxer.set_video_track(1920, 1080, Codec::AVC);
+    /// muxer.set_segmenting(true);
+    ///
+    /// // ... encode video frames ...
+    ///
+    /// let mut segments = Vec::new();
+    /// while let Some(segment) = muxer.take_segment() {
+    ///     segments.push(segment);
+    /// }
+    /// ```
+    pub fn take_segment(&mut self) -> Option<Vec<u8>> {
+        while let Some(&start) = self.file.segment_starts.front() {
+            self.file.segment_starts.pop_front();
+            let size = (start - self.file.taken_bytes) as usize;
+            if size > 0 {
+                self.file.taken_bytes = start;
+                return Some(self.writer.drain(..size).collect());
+            }
+        }
+        None
+    }
+}
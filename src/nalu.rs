@@ -1,3 +1,7 @@
+use crate::types::{Codec, MasteringDisplay, SeiPolicy, VideoProfile};
+use crate::util::BitReader;
+use std::io::{Error, ErrorKind};
+
 /// HEVC NALU types
 pub const HEVC_NALU_TYPE_VPS: u8 = 32;
 pub const HEVC_NALU_TYPE_SPS: u8 = 33;
@@ -13,6 +17,766 @@ pub const HEVC_NAL_CRA_NUT: u8 = 21;
 
 /// AVC NALU type for I-Slice
 pub const AVC_NAL_ISLICE_NALU: u8 = 5;
+/// AVC NALU type for non-IDR slices
+pub const AVC_NAL_SLICE_NALU: u8 = 1;
+
+/// SEI NALU types
+pub const AVC_NAL_SEI: u8 = 6;
+pub const HEVC_NAL_PREFIX_SEI: u8 = 39;
+pub const HEVC_NAL_SUFFIX_SEI: u8 = 40;
+
+/// SEI payload types
+pub const SEI_MASTERING_DISPLAY_COLOUR_VOLUME: u32 = 137;
+pub const SEI_CONTENT_LIGHT_LEVEL_INFO: u32 = 144;
+pub const SEI_USER_DATA_REGISTERED_ITU_T_T35: u32 = 4;
+pub const SEI_RECOVERY_POINT: u32 = 6;
+
+/// Removes emulation prevention bytes (0x000003) from a NAL unit payload.
+///
+/// # Arguments
+///
+/// * `data` - The escaped NAL unit payload
+///
+/// # Returns
+///
+/// The raw byte sequence payload (RBSP)
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::remove_emulation_prevention;
+///
+/// assert_eq!(remove_emulation_prevention(&[0, 0, 3, 1, 0, 0, 3]), vec![0, 0, 1, 0, 0]);
+/// ```
+pub fn remove_emulation_prevention(data: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(data.len());
+    let mut zeros = 0;
+    for &byte in data {
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+    rbsp
+}
+
+/// Parses the messages of an SEI RBSP.
+///
+/// # Arguments
+///
+/// * `rbsp` - The SEI payload without the NAL header and emulation prevention bytes
+///
+/// # Returns
+///
+/// The `(payload_type, payload)` pairs of all complete messages
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::sei_messages;
+///
+/// let messages = sei_messages(&[0x90, 0x04, 0x03, 0xE8, 0x01, 0x90, 0x80]);
+/// assert_eq!(messages, vec![(144, &[0x03, 0xE8, 0x01, 0x90][..])]);
+/// ```
+pub fn sei_messages(rbsp: &[u8]) -> Vec<(u32, &[u8])> {
+    let mut messages = Vec::new();
+    let mut pos = 0;
+    let read_value = |pos: &mut usize| -> Option<u32> {
+        let mut value: u32 = 0;
+        loop {
+            let byte = *rbsp.get(*pos)?;
+            *pos += 1;
+            value = value.saturating_add(byte as u32);
+            if byte != 0xff {
+                return Some(value);
+            }
+        }
+    };
+    // Stop at the rbsp trailing bits
+    while pos < rbsp.len() && rbsp[pos] != 0x80 {
+        let payload_type = match read_value(&mut pos) {
+            Some(value) => value,
+            None => break,
+        };
+        let payload_size = match read_value(&mut pos) {
+            Some(value) => value as usize,
+            None => break,
+        };
+        if payload_size > rbsp.len() - pos {
+            break;
+        }
+        messages.push((payload_type, &rbsp[pos..pos + payload_size]));
+        pos += payload_size;
+    }
+    messages
+}
+
+/// Extracts the CEA-608 byte pairs of a registered user data SEI payload.
+///
+/// The payload is expected to follow ATSC A/53 (`GA94` user data with cc_data).
+/// CEA-708 DTVCC packets and invalid entries are skipped.
+///
+/// # Arguments
+///
+/// * `payload` - The payload of an SEI message of type 4
+///
+/// # Returns
+///
+/// The byte pairs of field 1 and field 2
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::parse_cea608_pairs;
+///
+/// let payload = [0xB5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03, 0xC2, 0xFF,
+///                0xFC, 0x94, 0x2C, 0xFD, 0x80, 0x80, 0xFF];
+/// assert_eq!(parse_cea608_pairs(&payload), [vec![0x94, 0x2C], vec![0x80, 0x80]]);
+/// ```
+pub fn parse_cea608_pairs(payload: &[u8]) -> [Vec<u8>; 2] {
+    let mut fields = [Vec::new(), Vec::new()];
+    // itu_t_t35_country_code (USA), provider code (ATSC), user identifier and cc_data type
+    if payload.len() < 10 || payload[..8] != [0xB5, 0x00, 0x31, b'G', b'A', b'9', b'4', 0x03] {
+        return fields;
+    }
+    // process_cc_data_flag
+    if payload[8] & 0x40 == 0 {
+        return fields;
+    }
+    let cc_count = (payload[8] & 0x1f) as usize;
+    for cc in payload[10..].chunks_exact(3).take(cc_count) {
+        let cc_valid = cc[0] & 0x04 != 0;
+        let cc_type = cc[0] & 0x03;
+        if cc_valid && cc_type < 2 {
+            fields[cc_type as usize].extend_from_slice(&cc[1..]);
+        }
+    }
+    fields
+}
+
+/// Appends the CEA-608 byte pairs carried by an SEI NAL unit to the caption fields.
+///
+/// # Arguments
+///
+/// * `payload` - The SEI NAL unit without its NAL header
+/// * `fields` - The byte pairs of field 1 and field 2 collected so far
+pub(crate) fn collect_cea608_pairs(payload: &[u8], fields: &mut [Vec<u8>; 2]) {
+    let rbsp = remove_emulation_prevention(payload);
+    for (payload_type, payload) in sei_messages(&rbsp) {
+        if payload_type == SEI_USER_DATA_REGISTERED_ITU_T_T35 {
+            let [field1, field2] = parse_cea608_pairs(payload);
+            fields[0].extend(field1);
+            fields[1].extend(field2);
+        }
+    }
+}
+
+/// Decides whether an SEI NAL unit is written into the sample under a policy.
+///
+/// # Arguments
+///
+/// * `policy` - The SEI policy of the muxer
+/// * `payload` - The SEI NAL unit without its NAL header
+///
+/// # Returns
+///
+/// `true` if the NAL unit is kept
+pub(crate) fn keep_sei(policy: &SeiPolicy, payload: &[u8]) -> bool {
+    match policy {
+        SeiPolicy::Keep => true,
+        SeiPolicy::Strip => false,
+        SeiPolicy::KeepHdrOnly => sei_messages(&remove_emulation_prevention(payload))
+            .iter()
+            .any(|(payload_type, _)| {
+                matches!(
+                    *payload_type,
+                    SEI_MASTERING_DISPLAY_COLOUR_VOLUME
+                        | SEI_CONTENT_LIGHT_LEVEL_INFO
+                        | SEI_USER_DATA_REGISTERED_ITU_T_T35
+                )
+            }),
+    }
+}
+
+/// Detects the video codec of an Annex-B byte stream from its parameter sets.
+///
+/// The NAL headers are inspected for an AVC SPS (type 7) or an HEVC VPS/SPS
+/// (types 32 and 33). Slice NAL units alone are ambiguous between the two codecs,
+/// so data without parameter sets yields `None`.
+///
+/// # Arguments
+///
+/// * `data` - A byte slice containing the encoded video data
+///
+/// # Returns
+///
+/// The detected codec, or `None` if no parameter set was found
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::detect_codec;
+/// use mp4e::Codec;
+///
+/// let avc = [0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x0D];
+/// assert!(matches!(detect_codec(&avc), Some(Codec::AVC)));
+/// let hevc = [0, 0, 0, 1, 0x40, 0x01, 0x0C, 0x01];
+/// assert!(matches!(detect_codec(&hevc), Some(Codec::HEVC)));
+/// assert!(detect_codec(&[0, 0, 1, 0x01, 0x9A]).is_none());
+/// ```
+pub fn detect_codec(data: &[u8]) -> Option<Codec> {
+    detect_codec_nalus(split_nalu(data))
+}
+
+/// Whether a NAL unit type makes its picture a random access point.
+///
+/// IDR slices for AVC, and the IRAP slices (BLA, IDR and CRA) for HEVC.
+pub(crate) fn is_random_access_nalu(codec: &Codec, nalu_type: u8) -> bool {
+    match codec {
+        Codec::AVC => nalu_type == AVC_NAL_ISLICE_NALU,
+        Codec::HEVC => (HEVC_NAL_BLA_W_LP..=HEVC_NAL_CRA_NUT).contains(&nalu_type),
+        _ => false,
+    }
+}
+
+/// Tells whether a frame would be written as a key frame by the muxer.
+///
+/// The frame is classified from the types of its NAL units like the muxer does: AVC
+/// frames with an IDR slice and HEVC frames with an IRAP slice are key frames. Every
+/// frame of the other codecs (MJPEG images, audio frames) is a random access point.
+/// Muxer options that promote other frames to key frames, such as
+/// `set_recovery_point_keyframes` or `assume_starts_on_keyframe`, are not taken into
+/// account. This lets a packager choose its segment boundaries before passing the
+/// frame to the muxer.
+///
+/// # Arguments
+///
+/// * `data` - The frame data in Annex-B format
+/// * `codec` - The codec of the frame
+///
+/// # Returns
+///
+/// Whether the frame is a key frame
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::would_be_keyframe;
+/// use mp4e::Codec;
+///
+/// let idr = [0, 0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xCE, 0, 0, 1, 0x65, 0x88];
+/// assert!(would_be_keyframe(&idr, &Codec::AVC));
+/// assert!(!would_be_keyframe(&[0, 0, 0, 1, 0x41, 0x9A], &Codec::AVC));
+/// // CRA picture
+/// assert!(would_be_keyframe(&[0, 0, 0, 1, 0x2A, 0x01, 0xAF], &Codec::HEVC));
+/// ```
+pub fn would_be_keyframe(data: &[u8], codec: &Codec) -> bool {
+    let nalu_type = |nalu: &[u8]| match codec {
+        Codec::HEVC => (nalu[0] & 0x7e) >> 1,
+        _ => nalu[0] & 0x1f,
+    };
+    match codec {
+        Codec::AVC | Codec::HEVC => split_nalu(data)
+            .filter(|nalu| !nalu.is_empty())
+            .any(|nalu| is_random_access_nalu(codec, nalu_type(nalu))),
+        _ => true,
+    }
+}
+
+/// Gets the temporal sub-layer of an HEVC NAL unit.
+///
+/// The `nuh_temporal_id_plus1` field of the NAL unit header, minus one. Frames of the
+/// higher sub-layers can be dropped for a lower frame rate, the frames of sub-layer 0
+/// always remain.
+///
+/// # Arguments
+///
+/// * `nalu` - The NAL unit, starting with its 2-byte header and without start code
+///
+/// # Returns
+///
+/// The `TemporalId` of the NAL unit, or `None` if the header is truncated or invalid
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::hevc_temporal_id;
+///
+/// // TRAIL_R slice of sub-layer 0, TSA_N slice of sub-layer 2
+/// assert_eq!(hevc_temporal_id(&[0x02, 0x01, 0xD0]), Some(0));
+/// assert_eq!(hevc_temporal_id(&[0x04, 0x03, 0xD0]), Some(2));
+/// assert_eq!(hevc_temporal_id(&[0x02, 0x00]), None);
+/// ```
+pub fn hevc_temporal_id(nalu: &[u8]) -> Option<u8> {
+    let temporal_id_plus1 = nalu.get(1)? & 0x07;
+    temporal_id_plus1.checked_sub(1)
+}
+
+/// Detects the video codec from NAL units that have already been split.
+pub(crate) fn detect_codec_nalus<'a, I>(nalus: I) -> Option<Codec>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    for nalu in nalus {
+        if nalu.len() < 2 || nalu[0] & 0x80 != 0 {
+            continue;
+        }
+        if nalu[0] & 0x1f == AVC_NALU_TYPE_SPS {
+            return Some(Codec::AVC);
+        }
+        let hevc_type = (nalu[0] & 0x7e) >> 1;
+        // nuh_temporal_id_plus1 is never zero in HEVC
+        if (hevc_type == HEVC_NALU_TYPE_VPS || hevc_type == HEVC_NALU_TYPE_SPS)
+            && nalu[1] & 0x07 != 0
+        {
+            return Some(Codec::HEVC);
+        }
+    }
+    None
+}
+
+/// Parses the picture dimensions from a sequence parameter set.
+///
+/// The dimensions are those of the decoded picture after the cropping window
+/// (frame cropping for AVC, conformance window for HEVC) has been applied.
+///
+/// # Arguments
+///
+/// * `codec` - The video codec of the SPS, `Codec::AVC` or `Codec::HEVC`
+/// * `sps` - The SPS NAL unit, including its NAL unit header
+///
+/// # Returns
+///
+/// The width and height in pixels, or `None` if the codec is not a video codec
+/// or the SPS is too short
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::parse_sps_dimensions;
+/// use mp4e::Codec;
+///
+/// let sps = [0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0x40];
+/// assert_eq!(parse_sps_dimensions(&Codec::AVC, &sps), Some((1920, 1080)));
+/// ```
+pub fn parse_sps_dimensions(codec: &Codec, sps: &[u8]) -> Option<(u32, u32)> {
+    parse_sps(codec, sps).map(|info| (info.width, info.height))
+}
+
+/// Parses the profile, level and sample format from a sequence parameter set.
+///
+/// # Arguments
+///
+/// * `codec` - The video codec of the SPS, `Codec::AVC` or `Codec::HEVC`
+/// * `sps` - The SPS NAL unit, including its NAL unit header
+///
+/// # Returns
+///
+/// The video profile, or `None` if the codec is not a video codec or the SPS is too short
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::parse_sps_profile;
+/// use mp4e::Codec;
+///
+/// let sps = [0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0x40];
+/// let profile = parse_sps_profile(&Codec::AVC, &sps).unwrap();
+/// assert_eq!(profile.to_string(), "H.264 High Profile Level 4.0");
+/// assert_eq!((profile.bit_depth, profile.chroma_format), (8, 1));
+/// ```
+pub fn parse_sps_profile(codec: &Codec, sps: &[u8]) -> Option<VideoProfile> {
+    let info = parse_sps(codec, sps)?;
+    let (codec, profile) = match codec {
+        Codec::AVC => (
+            Codec::AVC,
+            avc_profile_name(info.profile_idc, info.constraint_flags),
+        ),
+        _ => (Codec::HEVC, hevc_profile_name(info.profile_idc)),
+    };
+    Some(VideoProfile {
+        codec,
+        profile_idc: info.profile_idc,
+        profile,
+        high_tier: info.high_tier,
+        level_idc: info.level_idc,
+        bit_depth: info.bit_depth,
+        chroma_format: info.chroma_format,
+    })
+}
+
+/// Fields of a sequence parameter set
+struct SpsInfo {
+    width: u32,
+    height: u32,
+    profile_idc: u8,
+    /// constraint_set0_flag to constraint_set5_flag in the high bits (AVC)
+    constraint_flags: u8,
+    /// general_tier_flag (HEVC)
+    high_tier: bool,
+    level_idc: u8,
+    bit_depth: u8,
+    chroma_format: u8,
+}
+
+fn parse_sps(codec: &Codec, sps: &[u8]) -> Option<SpsInfo> {
+    match codec {
+        Codec::AVC if sps.len() >= 4 => Some(avc_sps_info(&remove_emulation_prevention(&sps[1..]))),
+        Codec::HEVC if sps.len() >= 15 => {
+            Some(hevc_sps_info(&remove_emulation_prevention(&sps[2..])))
+        }
+        _ => None,
+    }
+}
+
+/// Name of an AVC profile (ITU-T H.264 Annex A)
+fn avc_profile_name(profile_idc: u8, constraint_flags: u8) -> &'static str {
+    match profile_idc {
+        // constraint_set1_flag
+        66 if constraint_flags & 0x40 != 0 => "Constrained Baseline",
+        66 => "Baseline",
+        77 => "Main",
+        88 => "Extended",
+        100 => "High",
+        110 => "High 10",
+        122 => "High 4:2:2",
+        244 => "High 4:4:4 Predictive",
+        44 => "CAVLC 4:4:4 Intra",
+        83 => "Scalable Baseline",
+        86 => "Scalable High",
+        118 => "Multiview High",
+        128 => "Stereo High",
+        _ => "Unknown",
+    }
+}
+
+/// Name of an HEVC profile (ITU-T H.265 Annex A)
+fn hevc_profile_name(profile_idc: u8) -> &'static str {
+    match profile_idc {
+        1 => "Main",
+        2 => "Main 10",
+        3 => "Main Still Picture",
+        4 => "Range Extensions",
+        5 => "High Throughput",
+        9 => "Screen Content Coding",
+        _ => "Unknown",
+    }
+}
+
+/// Reads an AVC SPS RBSP (ITU-T H.264 7.3.2.1.1)
+fn avc_sps_info(rbsp: &[u8]) -> SpsInfo {
+    let mut br = BitReader::new(rbsp);
+    let profile_idc = br.read_bits(8);
+    let constraint_flags = br.read_bits(8) as u8;
+    let level_idc = br.read_bits(8) as u8;
+    // seq_parameter_set_id
+    br.ue_bits(32);
+    let mut chroma_format_idc = 1;
+    let mut chroma_format = 1;
+    let mut bit_depth = 8;
+    if matches!(
+        profile_idc,
+        100 | 110 | 122 | 244 | 44 | 83 | 86 | 118 | 128 | 138 | 139 | 134 | 135
+    ) {
+        chroma_format_idc = br.ue_bits(32);
+        chroma_format = chroma_format_idc;
+        if chroma_format_idc == 3 && br.read_bits(1) == 1 {
+            // separate_colour_plane_flag, the chroma planes are coded like luma
+            chroma_format_idc = 0;
+        }
+        // bit_depth_luma_minus8, bit_depth_chroma_minus8
+        bit_depth = br.ue_bits(32).saturating_add(8);
+        br.ue_bits(32);
+        // qpprime_y_zero_transform_bypass_flag
+        br.read_bits(1);
+        // seq_scaling_matrix_present_flag
+        if br.read_bits(1) == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if br.read_bits(1) == 1 {
+                    let size = if i < 6 { 16 } else { 64 };
+                    let (mut last_scale, mut next_scale) = (8i64, 8i64);
+                    for _ in 0..size {
+                        if next_scale != 0 {
+                            next_scale = (last_scale + br.se_bits(32) as i64).rem_euclid(256);
+                        }
+                        if next_scale != 0 {
+                            last_scale = next_scale;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // log2_max_frame_num_minus4
+    br.ue_bits(32);
+    match br.ue_bits(32) {
+        0 => {
+            // log2_max_pic_order_cnt_lsb_minus4
+            br.ue_bits(32);
+        }
+        1 => {
+            // delta_pic_order_always_zero_flag, offset_for_non_ref_pic, offset_for_top_to_bottom_field
+            br.read_bits(1);
+            br.se_bits(32);
+            br.se_bits(32);
+            for _ in 0..br.ue_bits(32).min(255) {
+                br.se_bits(32);
+            }
+        }
+        _ => {}
+    }
+    // max_num_ref_frames, gaps_in_frame_num_value_allowed_flag
+    br.ue_bits(32);
+    br.read_bits(1);
+    let width_in_mbs = br.ue_bits(32).saturating_add(1);
+    let height_in_map_units = br.ue_bits(32).saturating_add(1);
+    let frame_mbs_only = br.read_bits(1);
+    if frame_mbs_only == 0 {
+        // mb_adaptive_frame_field_flag
+        br.read_bits(1);
+    }
+    // direct_8x8_inference_flag
+    br.read_bits(1);
+    let mut width = width_in_mbs.saturating_mul(16);
+    let mut height = height_in_map_units.saturating_mul(16 * (2 - frame_mbs_only));
+    if br.read_bits(1) == 1 {
+        let (crop_x, crop_y) = match chroma_format_idc {
+            0 => (1, 1),
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let crop_y = crop_y * (2 - frame_mbs_only);
+        let left_right = br.ue_bits(32).saturating_add(br.ue_bits(32));
+        let top_bottom = br.ue_bits(32).saturating_add(br.ue_bits(32));
+        width = width.saturating_sub(left_right.saturating_mul(crop_x));
+        height = height.saturating_sub(top_bottom.saturating_mul(crop_y));
+    }
+    SpsInfo {
+        width,
+        height,
+        profile_idc: profile_idc as u8,
+        constraint_flags,
+        high_tier: false,
+        level_idc,
+        bit_depth: bit_depth.min(255) as u8,
+        chroma_format: chroma_format.min(3) as u8,
+    }
+}
+
+/// Reads an HEVC SPS RBSP (ITU-T H.265 7.3.2.2.1)
+fn hevc_sps_info(rbsp: &[u8]) -> SpsInfo {
+    let mut br = BitReader::new(rbsp);
+    // sps_video_parameter_set_id
+    br.read_bits(4);
+    let max_sub_layers_minus1 = br.read_bits(3) as usize;
+    // sps_temporal_id_nesting_flag
+    br.read_bits(1);
+    // General profile (88 bits) and level (8 bits) of profile_tier_level
+    // general_profile_space
+    br.read_bits(2);
+    let high_tier = br.read_bits(1) == 1;
+    let profile_idc = br.read_bits(5) as u8;
+    // general_profile_compatibility_flags and constraint flags
+    br.read_bits(32);
+    br.read_bits(32);
+    br.read_bits(16);
+    let level_idc = br.read_bits(8) as u8;
+    let sub_layer_flags: Vec<(u32, u32)> = (0..max_sub_layers_minus1)
+        .map(|_| (br.read_bits(1), br.read_bits(1)))
+        .collect();
+    if max_sub_layers_minus1 > 0 {
+        // reserved_zero_2bits up to eight sub-layers
+        br.read_bits(2 * (8 - max_sub_layers_minus1));
+    }
+    for (profile_present, level_present) in sub_layer_flags {
+        if profile_present == 1 {
+            br.read_bits(32);
+            br.read_bits(32);
+            br.read_bits(24);
+        }
+        if level_present == 1 {
+            br.read_bits(8);
+        }
+    }
+    // sps_seq_parameter_set_id
+    br.ue_bits(32);
+    let mut chroma_format_idc = br.ue_bits(32);
+    let chroma_format = chroma_format_idc;
+    if chroma_format_idc == 3 && br.read_bits(1) == 1 {
+        // separate_colour_plane_flag, the chroma planes are coded like luma
+        chroma_format_idc = 0;
+    }
+    let mut width = br.ue_bits(32);
+    let mut height = br.ue_bits(32);
+    // conformance_window_flag
+    if br.read_bits(1) == 1 {
+        let (sub_width, sub_height) = match chroma_format_idc {
+            1 => (2, 2),
+            2 => (2, 1),
+            _ => (1, 1),
+        };
+        let left_right = br.ue_bits(32).saturating_add(br.ue_bits(32));
+        let top_bottom = br.ue_bits(32).saturating_add(br.ue_bits(32));
+        width = width.saturating_sub(left_right.saturating_mul(sub_width));
+        height = height.saturating_sub(top_bottom.saturating_mul(sub_height));
+    }
+    // bit_depth_luma_minus8
+    let bit_depth = br.ue_bits(32).saturating_add(8);
+    SpsInfo {
+        width,
+        height,
+        profile_idc,
+        constraint_flags: 0,
+        high_tier,
+        level_idc,
+        bit_depth: bit_depth.min(255) as u8,
+        chroma_format: chroma_format.min(3) as u8,
+    }
+}
+
+/// Parses a mastering display colour volume SEI payload.
+///
+/// # Arguments
+///
+/// * `payload` - The payload of an SEI message of type 137
+///
+/// # Returns
+///
+/// The mastering display, or `None` if the payload is too short
+pub fn parse_mastering_display(payload: &[u8]) -> Option<MasteringDisplay> {
+    if payload.len() < 24 {
+        return None;
+    }
+    let u16_at = |i: usize| u16::from_be_bytes([payload[i], payload[i + 1]]);
+    let u32_at =
+        |i: usize| u32::from_be_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]]);
+    Some(MasteringDisplay {
+        display_primaries: [
+            [u16_at(0), u16_at(2)],
+            [u16_at(4), u16_at(6)],
+            [u16_at(8), u16_at(10)],
+        ],
+        white_point: [u16_at(12), u16_at(14)],
+        max_luminance: u32_at(16),
+        min_luminance: u32_at(20),
+    })
+}
+
+/// Parses a content light level information SEI payload.
+///
+/// # Arguments
+///
+/// * `payload` - The payload of an SEI message of type 144
+///
+/// # Returns
+///
+/// The maximum content light level and maximum frame average light level,
+/// or `None` if the payload is too short
+pub fn parse_content_light_level(payload: &[u8]) -> Option<[u16; 2]> {
+    if payload.len() < 4 {
+        return None;
+    }
+    Some([
+        u16::from_be_bytes([payload[0], payload[1]]),
+        u16::from_be_bytes([payload[2], payload[3]]),
+    ])
+}
+
+/// Splits length-prefixed NAL units (AVCC/HVCC sample format).
+///
+/// Each NAL unit is preceded by its size as a big-endian integer of `length_size` bytes.
+///
+/// # Arguments
+///
+/// * `data` - A byte slice containing the length-prefixed NAL units
+/// * `length_size` - The size of the length prefix in bytes, 1, 2 or 4
+///
+/// # Returns
+///
+/// The NAL units without their length prefixes, or an `InvalidInput` error for an
+/// unsupported prefix size and an `InvalidData` error for a truncated NAL unit
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::split_length_prefixed;
+///
+/// let data = [0, 3, 10, 20, 30, 0, 2, 40, 50];
+/// let nalus = split_length_prefixed(&data, 2).unwrap();
+/// assert_eq!(nalus, vec![&[10, 20, 30][..], &[40, 50][..]]);
+/// assert!(split_length_prefixed(&data[..8], 2).is_err());
+/// ```
+pub fn split_length_prefixed(data: &[u8], length_size: usize) -> Result<Vec<&[u8]>, Error> {
+    if !matches!(length_size, 1 | 2 | 4) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "NAL length prefix size must be 1, 2 or 4",
+        ));
+    }
+    let mut nalus = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        if pos + length_size > data.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "truncated NAL length prefix",
+            ));
+        }
+        let size = data[pos..pos + length_size]
+            .iter()
+            .fold(0usize, |size, &byte| (size << 8) | byte as usize);
+        pos += length_size;
+        if size > data.len() - pos {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "NAL unit length exceeds the available data",
+            ));
+        }
+        // Zero-length entries carry no NAL unit
+        if size > 0 {
+            nalus.push(&data[pos..pos + size]);
+        }
+        pos += size;
+    }
+    Ok(nalus)
+}
+
+/// Converts length-prefixed NAL units (AVCC/HVCC sample format) to Annex-B.
+///
+/// This is the reverse of muxing: each NAL unit of an MP4 sample gets a 4-byte start code
+/// instead of its length prefix, as decoders reading an elementary stream expect.
+///
+/// # Arguments
+///
+/// * `data` - A byte slice containing the length-prefixed NAL units, e.g. a video sample
+/// * `length_size` - The size of the length prefix in bytes, 1, 2 or 4
+///
+/// # Returns
+///
+/// The NAL units with start codes, or the error of `split_length_prefixed` for malformed data
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::avcc_to_annexb;
+///
+/// let sample = [0, 0, 0, 2, 0x09, 0xF0, 0, 0, 0, 3, 0x65, 0x88, 0x84];
+/// let annexb = avcc_to_annexb(&sample, 4).unwrap();
+/// assert_eq!(annexb, [0, 0, 0, 1, 0x09, 0xF0, 0, 0, 0, 1, 0x65, 0x88, 0x84]);
+/// ```
+pub fn avcc_to_annexb(data: &[u8], length_size: usize) -> Result<Vec<u8>, Error> {
+    let nalus = split_length_prefixed(data, length_size)?;
+    let mut annexb = Vec::with_capacity(data.len() + nalus.len() * 4);
+    for nalu in nalus {
+        annexb.extend_from_slice(&[0, 0, 0, 1]);
+        annexb.extend_from_slice(nalu);
+    }
+    Ok(annexb)
+}
 
 // src/nalu.rs
 /// Splits a byte slice into an iterator over NAL units.
@@ -38,7 +802,7 @@ pub const AVC_NAL_ISLICE_NALU: u8 = 5;
 /// let mut nalus = split_nalu(&data);
 /// assert_eq!(nalus.next().unwrap(), &[10, 20, 30]);
 /// assert_eq!(nalus.next().unwrap(), &[40, 50]);
-/// assert_eq!(nalus.next().unwrap(), &[60, 70, 80])
+/// assert_eq!(nalus.next().unwrap(), &[60, 70, 80]);
 /// assert_eq!(nalus.next(), None);
 /// ```
 pub fn split_nalu<'a>(data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
@@ -135,3 +899,225 @@ pub fn split_nalu<'a>(data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
 
     NaluIterator { data, position: 0 }
 }
+
+/// Splits the complete NAL units from a chunk of an Annex-B stream.
+///
+/// When a stream arrives in chunks, the last NAL unit of a chunk may continue in the next
+/// one, and its start code may even be cut by the end of the chunk. Only the NAL units
+/// followed by a start code are complete; the rest, from the last start code on, is put
+/// in front of the next chunk. Feeding the stream in chunks of any size then gives the
+/// same NAL units as `split_nalu` on the whole stream, once `split_nalu` is applied to
+/// the rest left at the end of the stream.
+///
+/// # Arguments
+///
+/// * `data` - The rest of the previous chunk followed by the new chunk
+///
+/// # Returns
+///
+/// The complete NAL units without their start codes, and the rest of the data
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::split_complete_nalus;
+///
+/// // The second start code is cut after its first two bytes
+/// let (nalus, rest) = split_complete_nalus(&[0, 0, 0, 1, 10, 20, 0, 0]);
+/// assert!(nalus.is_empty());
+/// assert_eq!(rest, &[0, 0, 0, 1, 10, 20, 0, 0]);
+///
+/// let data = [rest, &[1, 30, 40][..]].concat();
+/// let (nalus, rest) = split_complete_nalus(&data);
+/// assert_eq!(nalus, vec![&[10, 20][..]]);
+/// assert_eq!(rest, &[0, 0, 1, 30, 40]);
+/// ```
+pub fn split_complete_nalus(data: &[u8]) -> (Vec<&[u8]>, &[u8]) {
+    match last_start_code(data, 0) {
+        Some(pos) if pos > 0 => (split_nalu(&data[..pos]).collect(), &data[pos..]),
+        _ => (Vec::new(), data),
+    }
+}
+
+/// Finds the position of the last start code of a buffer, searched from `from` on
+fn last_start_code(data: &[u8], from: usize) -> Option<usize> {
+    data[from..]
+        .windows(3)
+        .rposition(|window| window == [0, 0, 1])
+        .map(|pos| from + pos)
+        // A 4-byte start code begins with one more zero byte
+        .map(|pos| {
+            if pos > 0 && data[pos - 1] == 0 {
+                pos - 1
+            } else {
+                pos
+            }
+        })
+}
+
+/// Splits an Annex-B stream arriving in chunks into NAL units
+///
+/// The chunks, such as depacketized network packets, need not be aligned to NAL unit
+/// boundaries: the bytes after the last start code of a chunk are kept until the next
+/// start code arrives, or until `finish` is called at the end of the stream.
+///
+/// # Example
+///
+/// ```
+/// use mp4e::nalu::NaluSplitter;
+///
+/// let mut splitter = NaluSplitter::new();
+/// assert!(splitter.push(&[0, 0, 0, 1, 10, 20, 0]).is_empty());
+/// assert_eq!(splitter.push(&[0, 1, 30]), vec![vec![10, 20]]);
+/// assert_eq!(splitter.finish(), vec![vec![30]]);
+/// ```
+#[derive(Clone, Default)]
+pub struct NaluSplitter {
+    pub(crate) rest: Vec<u8>,
+    /// Number of bytes at the start of `rest` already searched for start codes
+    pub(crate) scanned: usize,
+}
+
+impl NaluSplitter {
+    /// Creates a splitter at the start of a stream
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the next chunk of the stream
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The next bytes of the stream
+    ///
+    /// # Returns
+    ///
+    /// The NAL units completed by the chunk, without their start codes
+    pub fn push(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        // Only the new bytes are searched, and the 2 bytes before them where a start code
+        // may begin, so a large NAL unit arriving in small chunks is searched once
+        let from = self.scanned.saturating_sub(2);
+        self.rest.extend_from_slice(data);
+        let nalus = match last_start_code(&self.rest, from) {
+            Some(pos) if pos > 0 => {
+                let nalus = split_nalu(&self.rest[..pos])
+                    .filter(|nalu| !nalu.is_empty())
+                    .map(|nalu| nalu.to_vec())
+                    .collect();
+                self.rest.drain(..pos);
+                nalus
+            }
+            _ => Vec::new(),
+        };
+        // The bytes left have no start code after the one they may begin with
+        self.scanned = self.rest.len();
+        nalus
+    }
+
+    /// Ends the stream
+    ///
+    /// # Returns
+    ///
+    /// The NAL units left after the last chunk, the splitter is then empty
+    pub fn finish(&mut self) -> Vec<Vec<u8>> {
+        let rest = std::mem::take(&mut self.rest);
+        self.scanned = 0;
+        split_nalu(&rest)
+            .filter(|nalu| !nalu.is_empty())
+            .map(|nalu| nalu.to_vec())
+            .collect()
+    }
+}
+
+/// Returns whether a NAL unit is a VCL NAL unit, with whether it is the first slice of a picture
+pub(crate) fn vcl_first_slice(codec: &Codec, nalu: &[u8]) -> Option<bool> {
+    match codec {
+        Codec::AVC => match nalu[0] & 0x1f {
+            AVC_NAL_SLICE_NALU..=AVC_NAL_ISLICE_NALU => {
+                // first_mb_in_slice is zero for the first slice
+                let mut br = BitReader::new(&nalu[1..]);
+                Some(br.ue_bits(32) == 0)
+            }
+            _ => None,
+        },
+        Codec::HEVC => match (nalu[0] & 0x7e) >> 1 {
+            // first_slice_segment_in_pic_flag follows the 2-byte NAL unit header
+            0..=31 => Some(nalu.get(2).is_some_and(|byte| byte & 0x80 != 0)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Returns whether a non-VCL NAL unit can only appear before the first slice of a picture
+pub(crate) fn starts_access_unit(codec: &Codec, nalu: &[u8]) -> bool {
+    match codec {
+        // SEI, SPS, PPS, access unit delimiter, prefix and reserved types
+        Codec::AVC => matches!(nalu[0] & 0x1f, 6..=9 | 14..=18),
+        // VPS, SPS, PPS, access unit delimiter, prefix SEI, reserved and unspecified types
+        Codec::HEVC => matches!((nalu[0] & 0x7e) >> 1, 32..=35 | 39 | 41..=44 | 48..=55),
+        _ => false,
+    }
+}
+
+/// Splits an Annex-B elementary stream into access units.
+///
+/// A new access unit starts at a parameter set, SEI or access unit delimiter that
+/// follows a slice, or at a slice that begins a new picture (first_mb_in_slice equal
+/// to 0 for AVC, first_slice_segment_in_pic_flag set for HEVC).
+///
+/// # Arguments
+///
+/// * `data` - A byte slice containing the elementary stream
+/// * `codec` - The video codec of the stream, `Codec::AVC` or `Codec::HEVC`
+///
+/// # Returns
+///
+/// The NAL units of each access unit, without start codes
+///
+/// # Examples
+///
+/// ```
+/// use mp4e::nalu::split_access_units;
+/// use mp4e::Codec;
+///
+/// // SPS, PPS, IDR slice, then two P slices
+/// let data = [
+///     0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x0D, 0, 0, 0, 1, 0x68, 0xCE, 0x3C, 0x80,
+///     0, 0, 0, 1, 0x65, 0x88, 0x84, 0, 0, 0, 1, 0x41, 0x9A, 0, 0, 0, 1, 0x41, 0x9B,
+/// ];
+/// let units = split_access_units(&data, &Codec::AVC);
+/// assert_eq!(units.len(), 3);
+/// assert_eq!(units[0].len(), 3);
+/// ```
+pub fn split_access_units<'a>(data: &'a [u8], codec: &Codec) -> Vec<Vec<&'a [u8]>> {
+    group_access_units(split_nalu(data), codec)
+}
+
+/// Groups NAL units that have already been split into access units.
+///
+/// A new access unit starts with the first slice of a picture (`first_mb_in_slice` of 0
+/// for AVC, `first_slice_segment_in_pic_flag` set for HEVC) or a NAL unit that can only
+/// precede one, once the current access unit has a slice, see `split_access_units`.
+pub(crate) fn group_access_units<'a, I>(nalus: I, codec: &Codec) -> Vec<Vec<&'a [u8]>>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut units = Vec::new();
+    let mut current = Vec::new();
+    let mut has_slice = false;
+    for nalu in nalus.into_iter().filter(|nalu| !nalu.is_empty()) {
+        let first_slice = vcl_first_slice(codec, nalu);
+        let starts = first_slice.unwrap_or_else(|| starts_access_unit(codec, nalu));
+        if starts && has_slice {
+            units.push(std::mem::take(&mut current));
+            has_slice = false;
+        }
+        has_slice |= first_slice.is_some();
+        current.push(nalu);
+    }
+    if !current.is_empty() {
+        units.push(current);
+    }
+    units
+}
@@ -0,0 +1,124 @@
+use crate::types::TrackType;
+use crate::util::timescale_to_ms;
+
+/// State of the samples of a track waiting to be written (non-fragmented mode)
+pub struct QueueState {
+    /// Track of the queue
+    pub track_type: TrackType,
+    /// Time scale of the track
+    pub timescale: u32,
+    /// Decode time of the next sample of the queue, in the track's timescale
+    pub decode_time: u64,
+    /// Total duration of the queued samples, in the track's timescale
+    pub duration: u64,
+    /// Number of queued samples that can be written
+    pub samples: usize,
+    /// Total size of the queued samples
+    pub bytes: u64,
+}
+
+impl QueueState {
+    /// Converts a time in the track's timescale to milliseconds
+    fn ms(&self, time: u64) -> u64 {
+        timescale_to_ms(time, self.timescale)
+    }
+}
+
+/// Decides the order in which queued samples are written to the mdat box
+///
+/// Once a scheduler is set with `Mp4e::set_sample_scheduler`, samples are queued per
+/// track instead of being written as they arrive. The scheduler is asked for the next
+/// sample to write every time a sample is queued, and until all queues are empty on flush.
+/// The sample tables always describe the resulting layout, whatever the order.
+pub trait SampleScheduler {
+    /// Picks the track whose next queued sample is written
+    ///
+    /// # Arguments
+    /// * `queues` - The queues of all tracks that are set up
+    /// * `write_pos` - The current write position in the output
+    /// * `flushing` - Whether the file is being finalized, all queued samples must be written
+    ///
+    /// # Returns
+    /// * The index in `queues` of the track to write a sample of, or `None` to wait for more samples
+    fn next(&mut self, queues: &[QueueState], write_pos: u64, flushing: bool) -> Option<usize>;
+}
+
+/// Default scheduler writing time-ordered chunks of each track
+///
+/// The track with the earliest queued sample is written first, followed by its samples
+/// within the chunk duration, so that the data of each track is grouped in chunks of
+/// about one second. Writing waits until every track has a chunk queued, or one track
+/// has ten chunks queued.
+pub struct ChunkScheduler {
+    /// Duration of a chunk in milliseconds
+    chunk_duration: u64,
+    /// Queue index and end time in milliseconds of the chunk being written
+    current: Option<(usize, u64)>,
+}
+
+impl ChunkScheduler {
+    /// Creates a scheduler with the given chunk duration
+    ///
+    /// # Arguments
+    /// * `chunk_duration` - The duration of a chunk in milliseconds
+    ///
+    /// # Returns
+    /// * A new `ChunkScheduler` instance
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{ChunkScheduler, Mp4e};
+    ///
+    /// let mut buffer = Vec::new();
+    /// let mut writer = Cursor::new(&mut buffer);
+    /// let mut muxer = Mp4e::new(&mut writer);
+    ///
+    /// // Half-second chunks for faster start on slow storage
+    /// muxer.set_sample_scheduler(ChunkScheduler::new(500));
+    /// ```
+    pub fn new(chunk_duration: u32) -> Self {
+        Self {
+            chunk_duration: chunk_duration.max(1) as u64,
+            current: None,
+        }
+    }
+}
+
+impl Default for ChunkScheduler {
+    fn default() -> Self {
+        Self::new(1000)
+    }
+}
+
+impl SampleScheduler for ChunkScheduler {
+    fn next(&mut self, queues: &[QueueState], _write_pos: u64, flushing: bool) -> Option<usize> {
+        // Keep writing the current chunk while its samples are queued
+        if let Some((index, chunk_end)) = self.current {
+            if let Some(queue) = queues.get(index) {
+                if queue.samples > 0 && queue.ms(queue.decode_time) < chunk_end {
+                    return Some(index);
+                }
+            }
+            self.current = None;
+        }
+        let ready = flushing
+            || queues
+                .iter()
+                .all(|queue| queue.ms(queue.duration) >= self.chunk_duration)
+            || queues
+                .iter()
+                .any(|queue| queue.ms(queue.duration) >= 10 * self.chunk_duration);
+        if !ready {
+            return None;
+        }
+        // Start a chunk with the earliest queued sample
+        let (index, queue) = queues
+            .iter()
+            .enumerate()
+            .filter(|(_, queue)| queue.samples > 0)
+            .min_by_key(|(_, queue)| queue.ms(queue.decode_time))?;
+        self.current = Some((index, queue.ms(queue.decode_time) + self.chunk_duration));
+        Some(index)
+    }
+}
@@ -0,0 +1,129 @@
+use crate::mp4e::Mp4e;
+use std::io::{Error, Write};
+
+/// Muxer that feeds the same media to two MP4 muxers
+///
+/// A typical use is recording a progressive MP4 file while pushing a fragmented
+/// MP4 stream from the same frames. Each video frame is split into NAL units once,
+/// using the NAL unit format of the first muxer, and the result is shared by both muxers.
+pub struct Mp4eTee<'a, 'b, First, Second>
+where
+    First: Write,
+    Second: Write,
+{
+    /// First muxer
+    first: Mp4e<'a, First>,
+    /// Second muxer
+    second: Mp4e<'b, Second>,
+}
+
+impl<'a, 'b, First, Second> Mp4eTee<'a, 'b, First, Second>
+where
+    First: Write,
+    Second: Write,
+{
+    /// Creates a tee over two muxers
+    ///
+    /// Both muxers should be set up with the same tracks before encoding starts,
+    /// they can use different modes and settings.
+    ///
+    /// # Arguments
+    /// * `first` - The first muxer
+    /// * `second` - The second muxer
+    ///
+    /// # Returns
+    /// * A new `Mp4eTee` instance
+    ///
+    /// # Example
+    /// ```
+    /// use std::io::Cursor;
+    /// use mp4e::{Codec, Mp4e, Mp4eTee};
+    ///
+    /// let mut file = Cursor::new(Vec::new());
+    /// let mut socket = Vec::new();
+    /// let mut recorder = Mp4e::new(&mut file);
+    /// recorder.set_video_track(1920, 1080, Codec::AVC);
+    /// let mut live = Mp4e::new_with_fragment(&mut socket);
+    /// live.set_video_track(1920, 1080, Codec::AVC);
+    ///
+    /// let mut tee = Mp4eTee::new(recorder, live);
+    /// tee.encode_video(&[0, 0, 0, 1, 0x67, 0x42, 0xC0, 0x0D], 33).unwrap();
+    ///
+    /// let (mut recorder, _live) = tee.into_inner();
+    /// recorder.flush().unwrap();
+    /// ```
+    pub fn new(first: Mp4e<'a, First>, second: Mp4e<'b, Second>) -> Self {
+        Self { first, second }
+    }
+
+    /// Gets mutable access to the first muxer
+    pub fn first_mut(&mut self) -> &mut Mp4e<'a, First> {
+        &mut self.first
+    }
+
+    /// Gets mutable access to the second muxer
+    pub fn second_mut(&mut self) -> &mut Mp4e<'b, Second> {
+        &mut self.second
+    }
+
+    /// Splits the tee into its two muxers, to flush them
+    pub fn into_inner(self) -> (Mp4e<'a, First>, Mp4e<'b, Second>) {
+        (self.first, self.second)
+    }
+
+    /// Writes a video frame to both muxers (with no b frame)
+    ///
+    /// # Arguments
+    /// * `data` - The video frame data
+    /// * `duration` - The duration of the video frame in milliseconds, 0 to derive it from
+    ///   the frame rate set with `set_video_frame_rate`
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or the first error of either muxer, the second muxer gets the
+    ///   data even if the first one fails
+    pub fn encode_video(&mut self, data: &[u8], duration: u32) -> Result<(), Error> {
+        let nalus = self.first.split_video(data)?;
+        // The second muxer gets the frame even if the first one fails
+        let first = self.first.encode_video_nalus(&nalus, duration, None);
+        let second = self.second.encode_video_nalus(&nalus, duration, None);
+        first.and(second)
+    }
+
+    /// Writes a video frame with presentation timestamp (PTS) to both muxers, support b frame
+    ///
+    /// # Arguments
+    /// * `data` - The video frame data (NAL units)
+    /// * `duration` - The duration of the video frame in milliseconds, 0 to derive it from
+    ///   the frame rate set with `set_video_frame_rate`
+    /// * `pts` - Presentation timestamp
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or the first error of either muxer, the second muxer gets the
+    ///   data even if the first one fails
+    pub fn encode_video_with_pts(
+        &mut self,
+        data: &[u8],
+        duration: u32,
+        pts: u32,
+    ) -> Result<(), Error> {
+        let nalus = self.first.split_video(data)?;
+        let first = self.first.encode_video_nalus(&nalus, duration, Some(pts));
+        let second = self.second.encode_video_nalus(&nalus, duration, Some(pts));
+        first.and(second)
+    }
+
+    /// Writes an audio data to both muxers
+    ///
+    /// # Arguments
+    /// * `data` - The audio data
+    /// * `samples` - The number of audio samples in this frame
+    ///
+    /// # Returns
+    /// * `Ok(())` on success, or the first error of either muxer, the second muxer gets the
+    ///   data even if the first one fails
+    pub fn encode_audio(&mut self, data: &[u8], samples: u32) -> Result<(), Error> {
+        let first = self.first.encode_audio(data, samples);
+        let second = self.second.encode_audio(data, samples);
+        first.and(second)
+    }
+}
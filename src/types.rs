@@ -1,4 +1,9 @@
+use crate::util::{Crc32, IDENTITY_MATRIX};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+
 /// Sample type enumeration
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SampleType {
     /// Default sample type
     Default,
@@ -9,6 +14,8 @@ pub enum SampleType {
 }
 
 /// Codec types supported
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Codec {
     /// H.264/AVC video coding NALU
     AVC,
@@ -28,17 +35,289 @@ pub enum Codec {
     HEAACV2,
     /// Opus audio coding
     OPUS,
+    /// Vorbis audio coding
+    VORBIS,
+    /// CEA-608 closed captions
+    CEA608,
+    /// Motion JPEG, one JPEG image per video frame
+    MJPEG,
+    /// MPEG-1/2 Audio Layer III (MP3) audio coding
+    MP3,
+}
+
+impl Codec {
+    /// Gets the codec identified by a sample entry type
+    ///
+    /// The parameter set variants `avc3` and `hev1` map to their codec as well. `mp4a`
+    /// maps to AAC-LC, the other codecs carried in an `mp4a` sample entry are told apart
+    /// by the object type of their esds box.
+    ///
+    /// # Arguments
+    /// * `fourcc` - The four-character code of the sample entry
+    ///
+    /// # Returns
+    /// * The codec, or `None` if the code is unknown
+    ///
+    /// # Example
+    /// ```
+    /// use mp4e::Codec;
+    ///
+    /// assert!(matches!(Codec::from_fourcc(b"hvc1"), Some(Codec::HEVC)));
+    /// assert!(Codec::from_fourcc(b"vp09").is_none());
+    /// ```
+    pub fn from_fourcc(fourcc: &[u8; 4]) -> Option<Codec> {
+        match fourcc {
+            b"avc1" | b"avc3" => Some(Codec::AVC),
+            b"hvc1" | b"hev1" => Some(Codec::HEVC),
+            b"mp4a" => Some(Codec::AACLC),
+            b"Opus" => Some(Codec::OPUS),
+            b"c608" => Some(Codec::CEA608),
+            b"jpeg" => Some(Codec::MJPEG),
+            _ => None,
+        }
+    }
+
+    /// Gets the sample entry type identifying the codec
+    ///
+    /// This is the inverse of `from_fourcc`, for the parameter sets out of band.
+    ///
+    /// # Returns
+    /// * The four-character code, or `None` for the codecs sharing the `mp4a` sample
+    ///   entry of AAC-LC (the other AAC profiles, Vorbis and MP3)
+    ///
+    /// # Example
+    /// ```
+    /// use mp4e::Codec;
+    ///
+    /// assert_eq!(Codec::AVC.to_fourcc(), Some(*b"avc1"));
+    /// assert_eq!(Codec::HEAAC.to_fourcc(), None);
+    /// ```
+    pub fn to_fourcc(&self) -> Option<[u8; 4]> {
+        match self {
+            Codec::AVC => Some(*b"avc1"),
+            Codec::HEVC => Some(*b"hvc1"),
+            Codec::AACLC => Some(*b"mp4a"),
+            Codec::OPUS => Some(*b"Opus"),
+            Codec::CEA608 => Some(*b"c608"),
+            Codec::MJPEG => Some(*b"jpeg"),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Codec {
+    type Err = std::io::Error;
+
+    /// Parses a codec name as found in configuration files
+    ///
+    /// Names are case insensitive, with `-` and `_` alike: "avc" or "h264", "hevc" or
+    /// "h265", "aac_lc" or "aac", "aac_main", "aac_ssr", "aac_ltp", "he_aac",
+    /// "he_aac_v2", "opus", "vorbis", "cea608", "mjpeg" and "mp3". The sample entry
+    /// types of `Codec::from_fourcc` are accepted as well.
+    ///
+    /// # Example
+    /// ```
+    /// use mp4e::Codec;
+    ///
+    /// assert!(matches!("hevc".parse::<Codec>(), Ok(Codec::HEVC)));
+    /// assert!(matches!("AAC-LC".parse::<Codec>(), Ok(Codec::AACLC)));
+    /// assert!("vp9".parse::<Codec>().is_err());
+    /// ```
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let codec = match name.to_ascii_lowercase().replace('-', "_").as_str() {
+            "avc" | "h264" | "h.264" => Codec::AVC,
+            "hevc" | "h265" | "h.265" => Codec::HEVC,
+            "aac_lc" | "aac" => Codec::AACLC,
+            "aac_main" => Codec::AACMAIN,
+            "aac_ssr" => Codec::AACSSR,
+            "aac_ltp" => Codec::AACLTP,
+            "he_aac" | "heaac" => Codec::HEAAC,
+            "he_aac_v2" | "heaac_v2" => Codec::HEAACV2,
+            "opus" => Codec::OPUS,
+            "vorbis" => Codec::VORBIS,
+            "cea608" | "cea_608" => Codec::CEA608,
+            "mjpeg" => Codec::MJPEG,
+            "mp3" => Codec::MP3,
+            _ => {
+                let fourcc: Option<&[u8; 4]> = name.as_bytes().try_into().ok();
+                return fourcc.and_then(Codec::from_fourcc).ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("unknown codec {:?}", name),
+                    )
+                });
+            }
+        };
+        Ok(codec)
+    }
+}
+
+/// Mastering display colour volume (SMPTE ST 2086) for HDR10
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MasteringDisplay {
+    /// Display primaries x and y in G, B, R order, in units of 0.00002
+    pub display_primaries: [[u16; 2]; 3],
+    /// White point x and y, in units of 0.00002
+    pub white_point: [u16; 2],
+    /// Maximum display mastering luminance, in units of 0.0001 cd/m2
+    pub max_luminance: u32,
+    /// Minimum display mastering luminance, in units of 0.0001 cd/m2
+    pub min_luminance: u32,
+}
+
+/// Profile, level and sample format of a video stream, read from its SPS
+///
+/// Displayed as for example "H.264 High Profile Level 4.0" or "HEVC Main 10 Level 5.1".
+pub struct VideoProfile {
+    /// Video codec, `Codec::AVC` or `Codec::HEVC`
+    pub codec: Codec,
+    /// Profile indicator, profile_idc for AVC and general_profile_idc for HEVC
+    pub profile_idc: u8,
+    /// Name of the profile, e.g. "High" or "Main 10"
+    pub profile: &'static str,
+    /// Whether the stream uses the high tier (HEVC)
+    pub high_tier: bool,
+    /// Level indicator, ten times the level for AVC and thirty times the level for HEVC
+    pub level_idc: u8,
+    /// Bit depth of the luma samples
+    pub bit_depth: u8,
+    /// Chroma format: 0 for monochrome, 1 for 4:2:0, 2 for 4:2:2 and 3 for 4:4:4
+    pub chroma_format: u8,
+}
+
+impl VideoProfile {
+    /// Gets the level as major and minor number, e.g. (5, 1) for level 5.1
+    ///
+    /// # Returns
+    /// * The major and minor level number
+    ///
+    /// # Example
+    /// ```
+    /// use mp4e::nalu::parse_sps_profile;
+    /// use mp4e::Codec;
+    ///
+    /// let sps = [0x67, 0x64, 0x00, 0x28, 0xAC, 0xD9, 0x40, 0x78, 0x02, 0x27, 0xE5, 0x40];
+    /// assert_eq!(parse_sps_profile(&Codec::AVC, &sps).unwrap().level(), (4, 0));
+    /// ```
+    pub fn level(&self) -> (u8, u8) {
+        match self.codec {
+            Codec::HEVC => (self.level_idc / 30, self.level_idc % 30 / 3),
+            _ => (self.level_idc / 10, self.level_idc % 10),
+        }
+    }
+}
+
+impl std::fmt::Display for VideoProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (major, minor) = self.level();
+        match self.codec {
+            Codec::HEVC => {
+                let tier = if self.high_tier { " High Tier" } else { "" };
+                write!(f, "HEVC {}{} Level {}.{}", self.profile, tier, major, minor)
+            }
+            _ => write!(
+                f,
+                "H.264 {} Profile Level {}.{}",
+                self.profile, major, minor
+            ),
+        }
+    }
+}
+
+/// Presentation times of the video and audio tracks, to check their synchronization
+///
+/// The times are in milliseconds on the movie timeline: after the empty edit delaying a
+/// track and from the media time its edit list starts at, with the composition offsets
+/// of the samples applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyncReport {
+    /// Presentation time of the first video frame
+    pub video_start_ms: f64,
+    /// Presentation time of the first audio sample
+    pub audio_start_ms: f64,
+    /// Presentation time of the end of the last video frame
+    pub video_end_ms: f64,
+    /// Presentation time of the end of the last audio sample
+    pub audio_end_ms: f64,
+}
+
+/// Boxes given by the application, written as they are into the moov box
+#[derive(Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UserBoxes {
+    /// Type and payload of the boxes at the end of the moov box
+    pub moov: Vec<([u8; 4], Vec<u8>)>,
+    /// Type and payload of the boxes in the udta box of the movie
+    pub udta: Vec<([u8; 4], Vec<u8>)>,
+    /// Type and payload of the items of the iTunes metadata list (ilst box) of the movie
+    pub ilst: Vec<([u8; 4], Vec<u8>)>,
+}
+
+/// Format of an image embedded in the metadata
+#[derive(Clone, Copy)]
+pub enum ImageFormat {
+    /// JPEG (JFIF) image
+    Jpeg,
+    /// PNG image
+    Png,
+}
+
+/// Video parameter sets as (SPS, PPS, VPS), the VPS is only present for HEVC
+pub type ParameterSets<'a> = (&'a [u8], &'a [u8], Option<&'a [u8]>);
+
+/// Framing of the NAL units passed to the video encode methods
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NaluFormat {
+    /// NAL units delimited by 3 or 4 byte start codes (Annex-B)
+    AnnexB,
+    /// NAL units preceded by a big-endian length of `size` bytes (1, 2 or 4)
+    LengthPrefixed {
+        /// Size of the length prefix in bytes
+        size: u8,
+    },
+}
+
+/// Handling of SEI NAL units in video samples
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SeiPolicy {
+    /// Write all SEI NAL units into the samples
+    Keep,
+    /// Drop all SEI NAL units
+    Strip,
+    /// Only keep SEI NAL units carrying HDR metadata (mastering display, content light level)
+    /// or registered user data such as CEA-608/708 captions
+    KeepHdrOnly,
+}
+
+/// Compatibility target of the output
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CompatibilityProfile {
+    /// Composition offsets (B-frames) and edit lists are written when needed
+    Standard,
+    /// For players that cannot handle ctts or elst: frames with a composition offset
+    /// are rejected and no edit list is written
+    Legacy,
 }
 
 /// Track type enumeration
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TrackType {
     /// Video track
     Video,
     /// Audio track
     Audio,
+    /// Closed caption track
+    Caption,
 }
 
 /// Sample information structure
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SampleInfo {
     /// Whether this is a random access point
     pub random_access: bool,
@@ -48,11 +327,48 @@ pub struct SampleInfo {
     pub sample_size: u32,
     /// Duration of the sample
     pub sample_delta: u32,
-    // Continuation offset
+    /// Composition time offset
     pub sample_ct_offset: i32,
+    /// Index of the sample description (stsd entry) of the sample, starting at 1
+    pub description_index: u32,
+    /// Temporal sub-layer of the sample, the `TemporalId` of its HEVC slices (0 for
+    /// other codecs)
+    pub temporal_id: u8,
+}
+
+/// Byte range of a fragment written to the output (fragmented mode)
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FragmentInfo {
+    /// Sequence number written to the mfhd box
+    pub sequence: u32,
+    /// Offset of the mdat payload in the output
+    pub data_offset: u64,
+    /// Size of the mdat payload
+    pub data_size: u32,
+    /// Checksum of the moof box and mdat header when fragment checksums are written
+    pub checksum: Option<Crc32>,
+}
+
+/// Sample queued for a sample scheduler, with its data
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct PendingSample {
+    /// Sample information, the offset is set when the sample is written
+    pub info: SampleInfo,
+    /// Decode time of the sample, in the track's timescale
+    pub decode_time: u64,
+    /// Sample data, with the NAL unit length prefixes of video samples
+    pub data: Vec<u8>,
 }
 
 /// Track information structure
+///
+/// The fields holding the working state of the muxer, like the samples of the next
+/// fragment, are private to the crate; tracks are created with `Track::new`. With the
+/// `serde` feature, the track and its working state are serializable.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Track {
     /// Track ID
     pub id: u32,
@@ -76,10 +392,153 @@ pub struct Track {
     pub sps: Option<Vec<u8>>,
     /// PPS data (video)
     pub pps: Option<Vec<u8>>,
+    /// Whether parameter sets are also kept in the samples, with an hev1 or avc3
+    /// sample entry (video)
+    pub inband_parameter_sets: bool,
+    /// Name of the compressor written to the compressorname field of the sample entry
+    /// (video)
+    pub compressor_name: Option<String>,
+    /// Whether a cslg box summing up the composition offsets is written with the ctts
+    /// box (video)
+    pub composition_shift: bool,
+    /// Whether an sdtp box gives the dependency of each sample, as marked by
+    /// `SampleInfo::random_access` like the stss box (video)
+    pub sample_dependencies: bool,
+    /// Whether sample groups map each sample to its temporal sub-layer, as given by
+    /// `SampleInfo::temporal_id` (video)
+    pub temporal_layers: bool,
+    /// Whether the sample table is written in its most compact form: samples following
+    /// each other in the file share a chunk and samples of the same size a single stsz
+    /// size, copied from `Mp4e::set_compact_sample_tables` when the moov box is written
+    pub(crate) compact_tables: bool,
     /// Audio specific configuration information
-    pub dsi: Option<[u8; 2]>,
+    pub dsi: Option<Vec<u8>>,
+    /// objectTypeIndication written to the esds box instead of the one of the codec (audio)
+    pub object_type_indication: Option<u8>,
+    /// Number of samples at 48 kHz the decoder output starts with and that are not
+    /// presented, written to the dOps box and trimmed by the edit list (Opus audio)
+    pub pre_skip: u16,
+    /// Configured maximum and average bitrate in bits per second (audio)
+    pub bitrate: Option<(u32, u32)>,
+    /// Codec setup headers received in-band (Vorbis audio)
+    pub headers: Vec<Vec<u8>>,
+    /// URLs of the files holding sample data, listed in the dref box after the entry
+    /// of the file itself
+    pub data_references: Vec<String>,
+    /// Entry of the dref box the sample entry points to: 1 for the file itself, 2 for
+    /// the first of `data_references`
+    pub data_reference_index: u16,
     /// List of sample information
     pub samples: Vec<SampleInfo>,
     /// Track type
     pub track_type: TrackType,
+    /// Mastering display colour volume (HDR10 video)
+    pub mastering_display: Option<MasteringDisplay>,
+    /// Maximum content light level and maximum frame average light level (HDR10 video)
+    pub content_light_level: Option<[u16; 2]>,
+    /// Role signaling as (scheme URI, value) pairs, written as kind boxes
+    pub kinds: Vec<(String, String)>,
+    /// Name of the track shown by QuickTime, written as a name box
+    pub name: Option<String>,
+    /// Transformation matrix written to the tkhd box
+    pub matrix: [i32; 9],
+    /// Media time where the presentation starts, written as an edit list when set
+    pub edit_media_time: Option<u32>,
+    /// Time before the track starts to be presented, in the track's timescale,
+    /// written as an empty edit when not 0
+    pub edit_delay: u32,
+    /// Decode time of the next fragment, in the track's timescale (fragmented mode)
+    pub(crate) fragment_decode_time: u64,
+    /// Samples held back for the next fragment of the track (fragmented mode)
+    pub(crate) fragment_samples: Vec<PendingSample>,
+    /// Presentation time and moof offset of each fragment starting with a sync sample,
+    /// written to the tfra box (fragmented mode)
+    pub(crate) random_access_points: Vec<(u64, u64)>,
+    /// Sample data held back for the track's own mdat box, sample offsets are relative to it
+    pub(crate) pending_data: Vec<u8>,
+    /// Samples queued for the sample scheduler
+    pub(crate) pending_samples: VecDeque<PendingSample>,
+    /// Decode time of the next queued sample, in the track's timescale
+    pub(crate) next_decode_time: u64,
+}
+
+impl Track {
+    /// Creates an empty track
+    ///
+    /// # Arguments
+    /// * `id` - The track ID, unique in the file and not 0
+    /// * `track_type` - The type of the track
+    /// * `codec` - The codec of the track
+    /// * `timescale` - The number of time units per second of the samples
+    ///
+    /// # Returns
+    /// * A track without samples, the codec specific fields are left empty
+    ///
+    /// # Example
+    /// ```
+    /// use mp4e::{Codec, Track, TrackType};
+    ///
+    /// let mut track = Track::new(1, TrackType::Video, Codec::AVC, 90000);
+    /// track.width = 1920;
+    /// track.height = 1080;
+    /// assert!(track.samples.is_empty());
+    /// ```
+    pub fn new(id: u32, track_type: TrackType, codec: Codec, timescale: u32) -> Self {
+        Self {
+            id,
+            duration: 0,
+            timescale,
+            sample_rate: 0,
+            channel_count: 0,
+            width: 0,
+            height: 0,
+            codec,
+            vps: None,
+            sps: None,
+            pps: None,
+            inband_parameter_sets: false,
+            compressor_name: None,
+            composition_shift: false,
+            sample_dependencies: false,
+            temporal_layers: false,
+            compact_tables: false,
+            dsi: None,
+            object_type_indication: None,
+            pre_skip: 0,
+            bitrate: None,
+            headers: vec![],
+            data_references: vec![],
+            data_reference_index: 1,
+            samples: vec![],
+            track_type,
+            mastering_display: None,
+            content_light_level: None,
+            kinds: vec![],
+            name: None,
+            matrix: IDENTITY_MATRIX,
+            edit_media_time: None,
+            edit_delay: 0,
+            fragment_decode_time: 0,
+            fragment_samples: vec![],
+            random_access_points: vec![],
+            pending_data: vec![],
+            pending_samples: VecDeque::new(),
+            next_decode_time: 0,
+        }
+    }
+
+    /// Removes the samples and the timing of a finished file, the track keeps its
+    /// configuration for the next file
+    pub(crate) fn clear_samples(&mut self) {
+        self.samples.clear();
+        self.duration = 0;
+        self.edit_media_time = None;
+        self.edit_delay = 0;
+        self.fragment_decode_time = 0;
+        self.fragment_samples.clear();
+        self.random_access_points.clear();
+        self.pending_data.clear();
+        self.pending_samples.clear();
+        self.next_decode_time = 0;
+    }
 }
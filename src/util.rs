@@ -4,18 +4,19 @@ pub struct BitReader<'a> {
 }
 impl<'a> BitReader<'a> {
     pub fn new(data: &'a [u8]) -> Self {
-        Self { data: data, pos: 0 }
+        Self { data, pos: 0 }
     }
 
-    // Decodes an unsigned exponential-Golomb-coded value with a specified number of bits
+    // Decodes an unsigned exponential-Golomb-coded value with at most `bits` leading zeros,
+    // a cap of 32 covers every value that fits in a u32
     pub fn ue_bits(&mut self, bits: usize) -> u32 {
         let mut leading_zeros = 0;
 
         // Calculate the number of leading zeros
         while self.get_bit() == 0 {
             leading_zeros += 1;
-            // Prevent exceeding the specified bit limit
-            if leading_zeros >= bits {
+            // Prevent exceeding the specified bit limit, this also stops at the end of the data
+            if leading_zeros >= bits.min(32) {
                 return 0;
             }
         }
@@ -34,6 +35,25 @@ impl<'a> BitReader<'a> {
         value - 1
     }
 
+    // Decodes a signed exponential-Golomb-coded value with at most `bits` leading zeros
+    pub fn se_bits(&mut self, bits: usize) -> i32 {
+        let value = self.ue_bits(bits) as i64;
+        if value % 2 == 1 {
+            ((value + 1) / 2) as i32
+        } else {
+            (-value / 2) as i32
+        }
+    }
+
+    /// Reads `count` bits as an unsigned value, MSB first
+    pub fn read_bits(&mut self, count: usize) -> u32 {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.get_bit();
+        }
+        value
+    }
+
     /// Get the next bit
     fn get_bit(&mut self) -> u32 {
         if self.pos >= self.data.len() * 8 {
@@ -49,6 +69,131 @@ impl<'a> BitReader<'a> {
     }
 }
 
+pub struct BitWriter {
+    data: Vec<u8>,
+    pos: usize,
+}
+impl BitWriter {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// Write the lowest `bits` bits of `value`, MSB first
+    pub fn put_bits(&mut self, value: u32, bits: usize) {
+        for i in (0..bits).rev() {
+            if self.pos & 7 == 0 {
+                self.data.push(0);
+            }
+            let bit = ((value >> i) & 1) as u8;
+            *self.data.last_mut().unwrap() |= bit << (7 - self.pos % 8);
+            self.pos += 1;
+        }
+    }
+
+    /// Get the written bytes, the last byte is padded with zero bits
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// Running CRC-32 (IEEE 802.3, the checksum computed by zlib)
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Crc32 {
+    pub(crate) crc: u32,
+}
+impl Crc32 {
+    pub fn new() -> Self {
+        Self { crc: 0xffffffff }
+    }
+
+    /// Add data to the checksum
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.crc ^= byte as u32;
+            for _ in 0..8 {
+                self.crc = (self.crc >> 1) ^ (0xedb88320 & (self.crc & 1).wrapping_neg());
+            }
+        }
+    }
+
+    /// Get the checksum of the data added so far
+    pub fn value(&self) -> u32 {
+        !self.crc
+    }
+}
+
+/// Identity transformation matrix for the tkhd box
+pub const IDENTITY_MATRIX: [i32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+/// Get the tkhd transformation matrix for a clockwise display rotation
+///
+/// The rotation turns the picture around its top left corner, so the matrix also
+/// translates it back into the visible area by the width or height of the track,
+/// like ffmpeg does.
+///
+/// # Arguments
+/// * `degrees` - The rotation in degrees, must be a multiple of 90
+/// * `width` - The width of the track
+/// * `height` - The height of the track
+///
+/// # Returns
+/// * The 9-entry matrix (a, b, u, c, d, v, x, y, w), or None if the rotation is unsupported
+///   or the width or height is 32768 or more, beyond the 16.16 range of the translation
+pub fn rotation_matrix(degrees: u32, width: u32, height: u32) -> Option<[i32; 9]> {
+    let degrees = degrees % 360;
+    if degrees == 0 {
+        return Some(IDENTITY_MATRIX);
+    }
+    // The translation is a 16.16 fixed-point value
+    let fixed = |size: u32| {
+        if size < 0x8000 {
+            Some((size << 16) as i32)
+        } else {
+            None
+        }
+    };
+    let (x, y) = (fixed(width)?, fixed(height)?);
+    match degrees {
+        90 => Some([0, 0x00010000, 0, -0x00010000, 0, 0, y, 0, 0x40000000]),
+        180 => Some([-0x00010000, 0, 0, 0, -0x00010000, 0, x, y, 0x40000000]),
+        270 => Some([0, -0x00010000, 0, 0x00010000, 0, 0, 0, x, 0x40000000]),
+        _ => None,
+    }
+}
+
+/// Check whether a Vorbis packet is one of the three setup headers
+///
+/// Header packets have an odd packet type (1, 3 or 5) followed by the "vorbis" signature.
+pub fn is_vorbis_header(packet: &[u8]) -> bool {
+    packet.len() >= 7 && packet[0] & 0x01 == 1 && &packet[1..7] == b"vorbis"
+}
+
+/// Pack codec headers with Xiph lacing
+///
+/// The result starts with the packet count minus one, followed by the laced sizes of
+/// all packets but the last, and then the packets themselves.
+///
+/// # Arguments
+/// * `headers` - The header packets to pack
+///
+/// # Returns
+/// * The Xiph laced headers
+pub fn xiph_lacing(headers: &[Vec<u8>]) -> Vec<u8> {
+    let mut laced = vec![headers.len().saturating_sub(1) as u8];
+    for header in headers.iter().take(headers.len().saturating_sub(1)) {
+        laced.resize(laced.len() + header.len() / 255, 0xff);
+        laced.push((header.len() % 255) as u8);
+    }
+    for header in headers.iter() {
+        laced.extend_from_slice(header);
+    }
+    laced
+}
+
 /// Sample rate array containing standard AAC sample rates
 const SAMPLE_RATE_ARRAY: [u32; 13] = [
     96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
@@ -60,11 +205,69 @@ const SAMPLE_RATE_ARRAY: [u32; 13] = [
 /// * `sample_rate` - The sample rate to look up
 ///
 /// # Returns
-/// * The index of the sample rate in the array, or 0x0b (11) if not found
-pub fn get_sample_rate_idx(sample_rate: u32) -> u32 {
+/// * The index of the sample rate in the array, or None if it is not a standard rate
+pub fn get_sample_rate_idx(sample_rate: u32) -> Option<u32> {
     SAMPLE_RATE_ARRAY
         .iter()
         .position(|&rate| rate == sample_rate)
         .map(|pos| pos as u32)
-        .unwrap_or(0x0b)
+}
+
+/// Converts a time from one timescale to another
+///
+/// The result is rounded to the nearest unit and saturates instead of overflowing, a
+/// source timescale of 0 is taken as 1. Every conversion of the crate between clocks
+/// goes through it.
+pub(crate) fn rescale(time: u64, from: u32, to: u32) -> u64 {
+    let from = from.max(1) as u128;
+    let scaled = (time as u128 * to as u128 + from / 2) / from;
+    scaled.min(u64::MAX as u128) as u64
+}
+
+/// Convert a time in milliseconds to a track's timescale
+///
+/// The result is rounded to the nearest unit. Converting absolute times rather than
+/// adding up converted durations keeps a timeline from drifting.
+///
+/// # Arguments
+/// * `ms` - The time in milliseconds
+/// * `timescale` - The number of units per second of the track
+///
+/// # Returns
+/// * The time in units of the timescale
+///
+/// # Example
+/// ```
+/// use mp4e::ms_to_timescale;
+///
+/// assert_eq!(ms_to_timescale(33, 90000), 2970);
+/// // 44.1 units
+/// assert_eq!(ms_to_timescale(1, 44100), 44);
+/// ```
+pub fn ms_to_timescale(ms: u64, timescale: u32) -> u64 {
+    rescale(ms, 1000, timescale)
+}
+
+/// Convert a time in a track's timescale to milliseconds
+///
+/// The result is rounded to the nearest millisecond, a timescale of 0 is taken as 1.
+///
+/// # Arguments
+/// * `ticks` - The time in units of the timescale
+/// * `timescale` - The number of units per second of the track
+///
+/// # Returns
+/// * The time in milliseconds
+///
+/// # Example
+/// ```
+/// use mp4e::timescale_to_ms;
+///
+/// // 1024 samples at 48 kHz last 21.33 ms, 1001 units at 90 kHz 11.12 ms
+/// assert_eq!(timescale_to_ms(1024, 48000), 21);
+/// assert_eq!(timescale_to_ms(1001, 90000), 11);
+/// assert_eq!(timescale_to_ms(2048, 48000), 43);
+/// ```
+pub fn timescale_to_ms(ticks: u64, timescale: u32) -> u64 {
+    rescale(ticks, timescale, 1000)
 }
@@ -0,0 +1,346 @@
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Result<u32, Error> {
+    data.get(pos..pos + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| invalid(format!("box truncated at byte {}", pos)))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Result<u64, Error> {
+    data.get(pos..pos + 8)
+        .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or_else(|| invalid(format!("box truncated at byte {}", pos)))
+}
+
+/// Type and payload of a box
+type ChildBox<'a> = ([u8; 4], &'a [u8]);
+
+/// Splits the payload of a container box into its child boxes
+fn child_boxes(data: &[u8]) -> Result<Vec<ChildBox<'_>>, Error> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let mut size = read_u32(data, pos)? as u64;
+        let box_type: [u8; 4] = read_u32(data, pos + 4)?.to_be_bytes();
+        let mut header = 8;
+        if size == 1 {
+            size = read_u64(data, pos + 8)?;
+            header = 16;
+        } else if size == 0 {
+            size = (data.len() - pos) as u64;
+        }
+        if size < header as u64 || size > (data.len() - pos) as u64 {
+            return Err(invalid(format!(
+                "{} box size {} does not fit its parent",
+                String::from_utf8_lossy(&box_type),
+                size
+            )));
+        }
+        boxes.push((box_type, &data[pos + header..pos + size as usize]));
+        pos += size as usize;
+    }
+    Ok(boxes)
+}
+
+fn find_child<'a>(boxes: &[ChildBox<'a>], box_type: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes
+        .iter()
+        .find(|(child_type, _)| child_type == box_type)
+        .map(|(_, payload)| *payload)
+}
+
+/// Reads the entries of a full box table made of `fields` 32-bit values per entry
+fn table(data: &[u8], fields: usize) -> Result<Vec<Vec<u32>>, Error> {
+    let count = read_u32(data, 4)? as usize;
+    if (data.len() - 8) / (4 * fields) < count {
+        return Err(invalid(format!("table of {} entries is truncated", count)));
+    }
+    (0..count)
+        .map(|i| {
+            (0..fields)
+                .map(|field| read_u32(data, 8 + (i * fields + field) * 4))
+                .collect()
+        })
+        .collect()
+}
+
+/// Checks the sample table of a track against itself and the mdat boxes of the file
+fn check_stbl(track_id: u32, stbl: &[u8], mdats: &[(u64, u64)]) -> Result<(), Error> {
+    let boxes = child_boxes(stbl)?;
+    let missing = |box_type: &str| invalid(format!("track {} has no {} box", track_id, box_type));
+    let stsz = find_child(&boxes, b"stsz").ok_or_else(|| missing("stsz"))?;
+    let sample_size = read_u32(stsz, 4)? as u64;
+    let sample_count = read_u32(stsz, 8)? as usize;
+    // The sample sizes are read from the box when needed, the count of a corrupt box
+    // could be far beyond its actual entries
+    if sample_size == 0 && (stsz.len() - 12) / 4 < sample_count {
+        return Err(invalid(format!(
+            "track {} stsz table of {} entries is truncated",
+            track_id, sample_count
+        )));
+    }
+    let chunk_offsets: Vec<u64> = if let Some(co64) = find_child(&boxes, b"co64") {
+        let count = read_u32(co64, 4)? as usize;
+        if (co64.len() - 8) / 8 < count {
+            return Err(invalid(format!("table of {} entries is truncated", count)));
+        }
+        (0..count)
+            .map(|i| read_u64(co64, 8 + i * 8))
+            .collect::<Result<_, _>>()?
+    } else if let Some(stco) = find_child(&boxes, b"stco") {
+        table(stco, 1)?
+            .iter()
+            .map(|entry| entry[0] as u64)
+            .collect()
+    } else {
+        // Tracks without samples have no chunk offset box
+        vec![]
+    };
+    let stts = find_child(&boxes, b"stts").ok_or_else(|| missing("stts"))?;
+    let stts_count: u64 = table(stts, 2)?.iter().map(|entry| entry[0] as u64).sum();
+    if stts_count != sample_count as u64 {
+        return Err(invalid(format!(
+            "track {} has {} samples in stsz but {} in stts",
+            track_id, sample_count, stts_count
+        )));
+    }
+    if let Some(ctts) = find_child(&boxes, b"ctts") {
+        let ctts_count: u64 = table(ctts, 2)?.iter().map(|entry| entry[0] as u64).sum();
+        if ctts_count != sample_count as u64 {
+            return Err(invalid(format!(
+                "track {} has {} samples in stsz but {} in ctts",
+                track_id, sample_count, ctts_count
+            )));
+        }
+    }
+    if let Some(stss) = find_child(&boxes, b"stss") {
+        let mut previous = 0;
+        for entry in table(stss, 1)? {
+            if entry[0] <= previous || entry[0] as usize > sample_count {
+                return Err(invalid(format!(
+                    "track {} has sync sample {} out of order or out of range",
+                    track_id, entry[0]
+                )));
+            }
+            previous = entry[0];
+        }
+    }
+
+    // Samples of an entry with a data_reference_index other than 1 are in another file
+    let external = find_child(&boxes, b"stsd")
+        .and_then(|stsd| stsd.get(22..24))
+        .is_some_and(|index| index != [0x00, 0x01]);
+
+    // Walk the chunks with the samples per chunk of stsc, each chunk must lie in an mdat box
+    let stsc = find_child(&boxes, b"stsc").ok_or_else(|| missing("stsc"))?;
+    let runs = table(stsc, 3)?;
+    let mut sample: usize = 0;
+    for (chunk, offset) in chunk_offsets.iter().enumerate() {
+        let chunk_number = chunk as u32 + 1;
+        let samples_per_chunk = runs
+            .iter()
+            .take_while(|run| run[0] <= chunk_number)
+            .last()
+            .map_or(0, |run| run[1]) as usize;
+        let chunk_end = sample
+            .checked_add(samples_per_chunk)
+            .filter(|&chunk_end| chunk_end <= sample_count)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "track {} has more samples in its chunks than the {} of stsz",
+                    track_id, sample_count
+                ))
+            })?;
+        let size: u64 = if sample_size == 0 {
+            (sample..chunk_end)
+                .map(|i| read_u32(stsz, 12 + i * 4).map(u64::from))
+                .sum::<Result<_, _>>()?
+        } else {
+            sample_size * samples_per_chunk as u64
+        };
+        sample = chunk_end;
+        let in_mdat = mdats.iter().any(|(start, end)| {
+            *offset >= *start && offset.checked_add(size).is_some_and(|last| last <= *end)
+        });
+        if size > 0 && !in_mdat && !external {
+            return Err(invalid(format!(
+                "track {} chunk {} at offset {} is outside the mdat boxes",
+                track_id, chunk_number, offset
+            )));
+        }
+    }
+    if sample != sample_count {
+        return Err(invalid(format!(
+            "track {} has {} samples in its chunks but {} in stsz",
+            track_id, sample, sample_count
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that the samples of the track runs of a moof box are within an mdat box
+fn check_moof(moof_pos: u64, moof: &[u8], mdats: &[(u64, u64)]) -> Result<(), Error> {
+    for (box_type, traf) in child_boxes(moof)? {
+        if &box_type != b"traf" {
+            continue;
+        }
+        let traf = child_boxes(traf)?;
+        let tfhd = find_child(&traf, b"tfhd")
+            .ok_or_else(|| invalid(format!("fragment at offset {} has no tfhd box", moof_pos)))?;
+        let tfhd_flags = read_u32(tfhd, 0)? & 0xff_ffff;
+        let track_id = read_u32(tfhd, 4)?;
+        let mut pos = 8;
+        // The data offsets count from the explicit base offset or from the moof box
+        let mut base = moof_pos;
+        if tfhd_flags & 0x01 != 0 {
+            base = read_u64(tfhd, pos)?;
+            pos += 8;
+        }
+        pos += [0x02, 0x08]
+            .iter()
+            .filter(|&&flag| tfhd_flags & flag != 0)
+            .count()
+            * 4;
+        let default_size = if tfhd_flags & 0x10 != 0 {
+            read_u32(tfhd, pos)?
+        } else {
+            0
+        };
+        for (_, trun) in traf.iter().filter(|(box_type, _)| box_type == b"trun") {
+            let flags = read_u32(trun, 0)? & 0xff_ffff;
+            if flags & 0x01 == 0 {
+                continue;
+            }
+            let sample_count = read_u32(trun, 4)?;
+            let data_offset = read_u32(trun, 8)? as i32;
+            let mut pos = if flags & 0x04 != 0 { 16 } else { 12 };
+            let entry_size = [0x100, 0x200, 0x400, 0x800]
+                .iter()
+                .filter(|&&flag| flags & flag != 0)
+                .count()
+                * 4;
+            let size_pos = if flags & 0x100 != 0 { 4 } else { 0 };
+            let data_size = if flags & 0x200 != 0 {
+                // Reading past the entries of the box stops a corrupt sample count
+                let mut data_size = 0;
+                for _ in 0..sample_count {
+                    data_size += read_u32(trun, pos + size_pos)? as u64;
+                    pos += entry_size;
+                }
+                data_size
+            } else {
+                default_size as u64 * sample_count as u64
+            };
+            // Wide enough for any base offset, data offset and size without overflowing
+            let start = base as i128 + data_offset as i128;
+            let inside = mdats.iter().any(|&(begin, end)| {
+                start >= begin as i128 && start + data_size as i128 <= end as i128
+            });
+            if !inside {
+                return Err(invalid(format!(
+                    "track {} fragment at offset {} has samples outside the mdat boxes",
+                    track_id, moof_pos
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the box structure and sample tables of an MP4 file
+///
+/// The top-level boxes must add up to the length of the file, the sample tables of
+/// each track must agree on the number of samples and point into the mdat boxes, and
+/// so must the track runs of each fragment.
+pub(crate) fn validate_structure<Reader>(reader: &mut Reader) -> Result<(), Error>
+where
+    Reader: Read + Seek,
+{
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut pos = 0;
+    let mut mdats = Vec::new();
+    let mut moov = None;
+    let mut moofs = Vec::new();
+    let mut meta = false;
+    while pos < file_len {
+        if file_len - pos < 8 {
+            return Err(invalid(format!(
+                "{} bytes left after the last box",
+                file_len - pos
+            )));
+        }
+        reader.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 16];
+        reader.read_exact(&mut header[..8])?;
+        let mut size = read_u32(&header, 0)? as u64;
+        let mut header_size = 8;
+        if size == 1 {
+            reader.read_exact(&mut header[8..])?;
+            size = read_u64(&header, 8)?;
+            header_size = 16;
+        } else if size == 0 {
+            size = file_len - pos;
+        }
+        let box_type = &header[4..8];
+        if size < header_size || size > file_len - pos {
+            return Err(invalid(format!(
+                "{} box at offset {} has size {} beyond the end of the file",
+                String::from_utf8_lossy(box_type),
+                pos,
+                size
+            )));
+        }
+        match box_type {
+            b"mdat" => mdats.push((pos + header_size, pos + size)),
+            b"moov" => {
+                let mut payload = vec![0; (size - header_size) as usize];
+                reader.read_exact(&mut payload)?;
+                moov = Some(payload);
+            }
+            b"moof" => {
+                let mut payload = vec![0; (size - header_size) as usize];
+                reader.read_exact(&mut payload)?;
+                moofs.push((pos, payload));
+            }
+            b"meta" => meta = true,
+            _ => {}
+        }
+        pos += size;
+    }
+    for (moof_pos, moof) in &moofs {
+        check_moof(*moof_pos, moof, &mdats)?;
+    }
+
+    let moov = match moov {
+        Some(moov) => moov,
+        // HEIF images have a meta box instead
+        None if meta => return Ok(()),
+        None => return Err(invalid("no moov box".to_string())),
+    };
+    for (box_type, trak) in child_boxes(&moov)? {
+        if &box_type != b"trak" {
+            continue;
+        }
+        let trak = child_boxes(trak)?;
+        let track_id = find_child(&trak, b"tkhd").map_or(Ok(0), |tkhd| {
+            // version 1 tkhd boxes have 64-bit times
+            let offset = if tkhd.first() == Some(&1) { 20 } else { 12 };
+            read_u32(tkhd, offset)
+        })?;
+        let stbl = find_child(&trak, b"mdia")
+            .map(child_boxes)
+            .transpose()?
+            .and_then(|mdia| find_child(&mdia, b"minf"))
+            .map(child_boxes)
+            .transpose()?
+            .and_then(|minf| find_child(&minf, b"stbl"))
+            .ok_or_else(|| invalid(format!("track {} has no stbl box", track_id)))?;
+        check_stbl(track_id, stbl, &mdats)?;
+    }
+    Ok(())
+}
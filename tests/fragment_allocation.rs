@@ -0,0 +1,55 @@
+//! Counts the allocations of the fragment path
+//!
+//! The counting allocator replaces the global allocator of the whole test binary, so
+//! it lives in its own integration test instead of the unit tests.
+
+use mp4e::{Codec, Mp4e};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::io::Cursor;
+
+/// Allocator counting the allocations made by the current thread
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn fragment_allocation_test() {
+    let mut writer = Cursor::new(Vec::with_capacity(1 << 20));
+    let mut muxer = Mp4e::new_with_fragment(&mut writer);
+    muxer.set_video_track(320, 240, Codec::AVC);
+    muxer.set_audio_fragment_duration(0);
+    let sps = [0x67, 0x42, 0xC0, 0x0D, 0xF4, 0x01];
+    let pps = [0x68, 0xCE, 0x3C, 0x80];
+    let idr = [0x65, 0x88, 0x84, 0x21, 0xA0];
+    let slice = [0x41, 0x9A, 0x21, 0x6C];
+    muxer
+        .encode_video_au(&[&sps, &pps, &idr], 33, true)
+        .unwrap();
+    muxer.encode_video_au(&[&slice], 33, false).unwrap();
+
+    // Once the moov box is written, the box buffer is reused by every fragment and the
+    // access units are written as passed
+    let before = ALLOCATIONS.with(|count| count.get());
+    for _ in 0..100 {
+        muxer.encode_video_au(&[&slice], 33, false).unwrap();
+    }
+    let allocations = ALLOCATIONS.with(|count| count.get()) - before;
+    assert!(allocations <= 2, "{} allocations", allocations);
+}